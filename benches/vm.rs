@@ -0,0 +1,92 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use iridium::assembler::Assembler;
+use iridium::vm::VM;
+
+/// Counts $0 up to 50000, so the run is dominated by the dispatch loop.
+const COUNTING_LOOP: &str = ".data
+.code
+load $1 #50000
+load $2 #1
+load $3 @loop
+loop: add $0 $2 $0
+neq $0 $1
+jeq $3
+hlt";
+
+fn counting_loop(c: &mut Criterion) {
+    let program = Assembler::new().assemble(COUNTING_LOOP).unwrap();
+    let mut group = c.benchmark_group("counting_loop");
+    group.bench_function("decode_each_instruction", |b| {
+        b.iter(|| {
+            let mut vm = VM::new();
+            vm.add_bytes(program.clone());
+            vm.run()
+        })
+    });
+    group.bench_function("predecoded", |b| {
+        b.iter(|| {
+            let mut vm = VM::new();
+            vm.add_bytes(program.clone());
+            vm.predecode();
+            vm.run()
+        })
+    });
+    group.bench_function("fused", |b| {
+        b.iter(|| {
+            let mut vm = VM::new();
+            vm.add_bytes(program.clone());
+            vm.fuse_instructions();
+            vm.run()
+        })
+    });
+    #[cfg(feature = "unchecked")]
+    group.bench_function("unchecked", |b| {
+        b.iter(|| {
+            let mut vm = VM::new();
+            vm.add_bytes(program.clone());
+            // The counting loop only jumps to instruction boundaries.
+            unsafe { vm.run_unchecked() }
+        })
+    });
+    group.finish();
+}
+
+/// Loads a multi-megabyte program into a fresh VM.
+fn load_large_program(c: &mut Criterion) {
+    let mut source = String::from(".data\n.code\n");
+    for _ in 0..1_000_000 {
+        source.push_str("load $0 #1\n");
+    }
+    source.push_str("hlt\n");
+    let program = Assembler::new().assemble(&source).unwrap();
+    c.bench_function("load_large_program", |b| {
+        b.iter(|| {
+            let mut vm = VM::new();
+            vm.add_slice(&program);
+            vm
+        })
+    });
+}
+
+/// Assembles a program with thousands of labels, each used by a later instruction.
+fn assemble_many_labels(c: &mut Criterion) {
+    let mut source = String::from(".data\n.code\n");
+    for i in 0..5000 {
+        source.push_str(&format!("label{}: load $0 #1\n", i));
+    }
+    for i in 0..5000 {
+        source.push_str(&format!("jmpe @label{}\n", i));
+    }
+    source.push_str("hlt\n");
+    c.bench_function("assemble_many_labels", |b| {
+        b.iter(|| Assembler::new().assemble(&source).unwrap())
+    });
+}
+
+criterion_group!(
+    benches,
+    counting_loop,
+    load_large_program,
+    assemble_many_labels
+);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    compile_protos();
+}
+
+/// Generates the gRPC service from `proto/iridium.proto`. The proto is parsed with `protox`, so
+/// building doesn't require `protoc`.
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    println!("cargo:rerun-if-changed=proto/iridium.proto");
+    let descriptors = protox::compile(["iridium.proto"], ["proto"]).expect("invalid proto");
+    // The generated client needs the 2021 prelude, so only the server is generated.
+    tonic_build::configure()
+        .build_client(false)
+        .compile_fds(descriptors)
+        .expect("unable to generate the gRPC service");
+}
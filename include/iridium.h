@@ -0,0 +1,96 @@
+/* C interface to the Iridium VM. */
+
+#ifndef IRIDIUM_H
+#define IRIDIUM_H
+
+/* Generated from src/ffi.rs by cbindgen. Do not edit; see cbindgen.toml. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+
+// Opaque handle to a VM.
+typedef struct IridiumVM IridiumVM;
+
+// Returned by the FFI functions when they succeed.
+#define IRIDIUM_OK 0
+
+// Returned when a null pointer was passed in.
+#define IRIDIUM_ERR_NULL -1
+
+// Returned when a register index or memory range is out of bounds.
+#define IRIDIUM_ERR_OUT_OF_BOUNDS -2
+
+// Returned when the VM panicked. The VM may be left in an inconsistent state, and should only be
+// freed.
+#define IRIDIUM_ERR_PANIC -3
+
+// Returned by `iridium_vm_run` when the program crashed, or its header was invalid.
+#define IRIDIUM_CRASHED 1
+
+// Returned by `iridium_vm_run` when the VM was stopped through its stop flag.
+#define IRIDIUM_KILLED 2
+
+// Returned by `iridium_vm_run` when the VM paused at a breakpoint.
+#define IRIDIUM_PAUSED 3
+
+// Returned by `iridium_vm_run` when the VM ran out of its instruction budget.
+#define IRIDIUM_BUDGET_EXCEEDED 4
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Allocates a new VM and returns an owning pointer to it, or null if it could not be created.
+IridiumVM *iridium_vm_new(void);
+
+// Frees a VM created by `iridium_vm_new`. Passing null is a no-op.
+//
+// # Safety
+//
+// `vm` must be null or a pointer returned by `iridium_vm_new` that has not been freed yet.
+void iridium_vm_free(IridiumVM *vm);
+
+// Appends `len` bytes of an assembled binary (header included) to the VM's program.
+//
+// # Safety
+//
+// `vm` must be a live VM pointer and `bytes` must point to at least `len` readable bytes.
+int32_t iridium_vm_load(IridiumVM *vm, const uint8_t *bytes, size_t len);
+
+// Runs the loaded program and returns how it stopped: `IRIDIUM_OK` if it halted or exited,
+// `IRIDIUM_CRASHED`, `IRIDIUM_KILLED`, `IRIDIUM_PAUSED` or `IRIDIUM_BUDGET_EXCEEDED` if it did
+// not finish, or a negative error code. The program's exit code, or the code of the crash, is
+// written to `exit_code` unless it is null; it is left alone if the program did not stop.
+//
+// # Safety
+//
+// `vm` must be a live VM pointer and `exit_code` must be null or valid for a write of one `i32`.
+int32_t iridium_vm_run(IridiumVM *vm, int32_t *exit_code);
+
+// Writes the value of register `index` into `out`.
+//
+// # Safety
+//
+// `vm` must be a live VM pointer and `out` must be valid for a write of one `i32`.
+int32_t iridium_vm_register(const IridiumVM *vm, size_t index, int32_t *out);
+
+// Returns the current size of the VM's heap in bytes, or 0 if `vm` is null or the VM panicked.
+//
+// # Safety
+//
+// `vm` must be null or a live VM pointer.
+size_t iridium_vm_heap_len(const IridiumVM *vm);
+
+// Copies `len` bytes of the heap starting at `offset` into `out`.
+//
+// # Safety
+//
+// `vm` must be a live VM pointer and `out` must be valid for writes of `len` bytes.
+int32_t iridium_vm_read_heap(const IridiumVM *vm, size_t offset, uint8_t *out, size_t len);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* IRIDIUM_H */
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Block sizes are rounded up to a multiple of this, so blocks never shrink below a word.
+pub const ALLOCATION_ALIGNMENT: usize = 4;
+
+/// Keeps track of which parts of a VM's heap are in use. Allocations reuse the first free block
+/// that is large enough and only grow the heap when none is; freed blocks are merged with their
+/// free neighbours, and the heap shrinks when the block at its end is freed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Allocator {
+    /// Allocated blocks, from offset to size.
+    allocated: BTreeMap<usize, usize>,
+    /// Free blocks within the heap, from offset to size. Adjacent free blocks are always merged.
+    free: BTreeMap<usize, usize>,
+}
+
+impl Allocator {
+    /// Returns an allocator with nothing allocated.
+    pub fn new() -> Allocator {
+        Allocator::default()
+    }
+
+    /// Allocates a zeroed block of at least `size` bytes in `heap`, growing it if needed, and
+    /// returns the block's offset.
+    pub fn alloc(&mut self, heap: &mut Vec<u8>, size: usize) -> usize {
+        let size = size.max(1).div_ceil(ALLOCATION_ALIGNMENT) * ALLOCATION_ALIGNMENT;
+        let fit = self
+            .free
+            .iter()
+            .find(|&(_, &free)| free >= size)
+            .map(|(&offset, &free)| (offset, free));
+        let offset = match fit {
+            Some((offset, free)) => {
+                self.free.remove(&offset);
+                if free > size {
+                    self.free.insert(offset + size, free - size);
+                }
+                heap[offset..offset + size].iter_mut().for_each(|b| *b = 0);
+                offset
+            }
+            None => {
+                let offset = heap.len();
+                heap.resize(offset + size, 0);
+                offset
+            }
+        };
+        self.allocated.insert(offset, size);
+        offset
+    }
+
+    /// Frees the block at `offset`, shrinking `heap` if it was the last one. Returns false if no
+    /// block was allocated there.
+    pub fn free(&mut self, heap: &mut Vec<u8>, offset: usize) -> bool {
+        let mut size = match self.allocated.remove(&offset) {
+            Some(size) => size,
+            None => return false,
+        };
+        let mut offset = offset;
+        if let Some(next) = self.free.remove(&(offset + size)) {
+            size += next;
+        }
+        let previous = self
+            .free
+            .range(..offset)
+            .next_back()
+            .map(|(&start, &free)| (start, free));
+        if let Some((start, free)) = previous {
+            if start + free == offset {
+                self.free.remove(&start);
+                offset = start;
+                size += free;
+            }
+        }
+        if offset + size == heap.len() {
+            heap.truncate(offset);
+        } else {
+            self.free.insert(offset, size);
+        }
+        true
+    }
+
+    /// Returns the size of the block allocated at `offset`.
+    pub fn block_size(&self, offset: usize) -> Option<usize> {
+        self.allocated.get(&offset).cloned()
+    }
+
+    /// Returns the number of bytes in allocated blocks.
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocated.values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_and_reuse() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        let first = allocator.alloc(&mut heap, 6);
+        let second = allocator.alloc(&mut heap, 4);
+        assert_eq!((first, second), (0, 8));
+        assert_eq!(allocator.block_size(first), Some(8));
+        heap[0] = 7;
+
+        // The freed block is reused, and zeroed.
+        assert!(allocator.free(&mut heap, first));
+        assert!(!allocator.free(&mut heap, first));
+        assert_eq!(allocator.alloc(&mut heap, 2), 0);
+        assert_eq!(heap[0], 0);
+        assert_eq!(allocator.alloc(&mut heap, 4), 4);
+        assert_eq!(heap.len(), 12);
+        assert_eq!(allocator.allocated_bytes(), 12);
+    }
+
+    #[test]
+    fn test_free_merges_and_shrinks() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        let blocks: Vec<usize> = (0..3).map(|_| allocator.alloc(&mut heap, 4)).collect();
+        assert!(allocator.free(&mut heap, blocks[0]));
+        assert!(allocator.free(&mut heap, blocks[1]));
+        // The two free blocks merged into one that fits 8 bytes.
+        assert_eq!(allocator.alloc(&mut heap, 8), 0);
+        assert!(allocator.free(&mut heap, 0));
+        // Freeing the last block returns the whole heap.
+        assert!(allocator.free(&mut heap, blocks[2]));
+        assert!(heap.is_empty());
+        assert_eq!(allocator, Allocator::new());
+    }
+}
@@ -1,15 +1,138 @@
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fmt;
 
-#[derive(Debug, Clone)]
+/// Where in the source an error was found. Lines and columns start at 1.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceLocation {
+    pub line: usize,
+    pub column: usize,
+    /// The text of the line.
+    pub source_line: String,
+}
+
+impl SourceLocation {
+    /// Returns the location of the byte `offset` in `source`.
+    pub fn new(source: &str, offset: usize) -> SourceLocation {
+        let offset = offset.min(source.len());
+        let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[offset..]
+            .find('\n')
+            .map_or(source.len(), |i| offset + i);
+        SourceLocation {
+            line: source[..line_start].matches('\n').count() + 1,
+            column: source[line_start..offset].chars().count() + 1,
+            source_line: source[line_start..line_end].trim_end().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum AssemblerError {
-    NoSegmentDeclarationFound { instruction: u32 },
-    StringConstantDeclaredWithoutLabel { instruction: u32 },
+    NoSegmentDeclarationFound {
+        instruction: u32,
+    },
+    StringConstantDeclaredWithoutLabel {
+        instruction: u32,
+    },
     SymbolAlreadyDeclared,
-    UnknownDirectiveFound { directive: String },
+    UnknownDirectiveFound {
+        directive: String,
+    },
     NonOpcodeInOpcodeField,
     InsufficientSections,
-    ParseError { error: String },
+    ParseError {
+        error: String,
+    },
+    EntryAlreadyDeclared,
+    UnknownEntryLabel {
+        label: String,
+    },
+    ByteOutOfRange {
+        value: i32,
+    },
+    IncludeFailed {
+        path: String,
+        error: String,
+    },
+    IncludeCycle {
+        path: String,
+    },
+    UnknownLocalLabel {
+        label: String,
+    },
+    /// Two of the files being linked declare the same label.
+    DuplicateSymbol {
+        name: String,
+    },
+    /// Code refers to a label that is not declared, or when linking, that no file exports.
+    UndefinedSymbol {
+        name: String,
+    },
+    /// An expression names a constant that has not been declared with `.equ` before it.
+    UnknownConstant {
+        name: String,
+    },
+    /// An expression cannot be evaluated, e.g. because it divides by zero.
+    InvalidExpression {
+        error: String,
+    },
+    /// A file given to the linker as an object file could not be read as one.
+    InvalidObject {
+        error: String,
+    },
+    /// `.space` was given a negative size, or the reservations add up to more than a `u32` holds.
+    SpaceOutOfRange {
+        value: i32,
+    },
+    /// An instruction has an operand it cannot be written with, such as a string.
+    InvalidOperand {
+        operand: String,
+    },
+    /// An instruction's mnemonic names no opcode. `line` is the line of the source it is on.
+    UnknownOpcode {
+        mnemonic: String,
+        line: usize,
+    },
+    /// Code assembled in incremental mode declares data, which would have to go before the code
+    /// already assembled.
+    IncrementalDeclaration,
+    /// The source could not be read, or the program could not be written, by the streaming
+    /// `Assembler::assemble_from_reader` and `Assembler::assemble_to_writer`.
+    IoFailed {
+        error: String,
+    },
+    /// An error found at a known place in the source.
+    Located {
+        location: SourceLocation,
+        error: Box<AssemblerError>,
+    },
+}
+
+impl AssemblerError {
+    /// Returns the error with the location of the byte `offset` in `source` attached.
+    pub fn at(self, source: &str, offset: usize) -> AssemblerError {
+        AssemblerError::Located {
+            location: SourceLocation::new(source, offset),
+            error: Box::new(self),
+        }
+    }
+
+    /// Returns the error without its location, if it has one.
+    pub fn without_location(&self) -> &AssemblerError {
+        match self {
+            AssemblerError::Located { error, .. } => error,
+            error => error,
+        }
+    }
+
+    /// Returns where in the source the error was found, if known.
+    pub fn location(&self) -> Option<&SourceLocation> {
+        match self {
+            AssemblerError::Located { location, .. } => Some(location),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for AssemblerError {
@@ -40,6 +163,73 @@ impl fmt::Display for AssemblerError {
             AssemblerError::ParseError { ref error } => {
                 f.write_str(&format!("There was an error parsing the code: {}", error))
             }
+            AssemblerError::EntryAlreadyDeclared => {
+                f.write_str("The entry point was previously declared.")
+            }
+            AssemblerError::UnknownEntryLabel { ref label } => f.write_str(&format!(
+                "The entry point is not a label on an instruction. Label was: {}",
+                label
+            )),
+            AssemblerError::ByteOutOfRange { value } => f.write_str(&format!(
+                "A value given to .byte does not fit in a byte. Value was: {}",
+                value
+            )),
+            AssemblerError::IncludeFailed {
+                ref path,
+                ref error,
+            } => f.write_str(&format!("Unable to include {}: {}", path, error)),
+            AssemblerError::IncludeCycle { ref path } => f.write_str(&format!(
+                "A file includes itself, directly or through other files. File was: {}",
+                path
+            )),
+            AssemblerError::UnknownLocalLabel { ref label } => f.write_str(&format!(
+                "No local label is declared in the direction referred to. Reference was: {}",
+                label
+            )),
+            AssemblerError::DuplicateSymbol { ref name } => f.write_str(&format!(
+                "More than one of the files being linked declares a label. Label was: {}",
+                name
+            )),
+            AssemblerError::UndefinedSymbol { ref name } => f.write_str(&format!(
+                "A label is used but never declared. Label was: {}",
+                name
+            )),
+            AssemblerError::UnknownConstant { ref name } => f.write_str(&format!(
+                "A constant is used before it is declared with .equ. Constant was: {}",
+                name
+            )),
+            AssemblerError::InvalidExpression { ref error } => {
+                f.write_str(&format!("The expression cannot be evaluated: {}", error))
+            }
+            AssemblerError::InvalidObject { ref error } => {
+                f.write_str(&format!("The object file is invalid: {}", error))
+            }
+            AssemblerError::SpaceOutOfRange { value } => f.write_str(&format!(
+                "A size given to .space is negative, or the reservations are too large in total. Size was: {}",
+                value
+            )),
+            AssemblerError::InvalidOperand { ref operand } => f.write_str(&format!(
+                "An instruction has an operand it cannot take. Operand was: {}",
+                operand
+            )),
+            AssemblerError::UnknownOpcode { ref mnemonic, .. } => f.write_str(&format!(
+                "An instruction names an unknown opcode. Mnemonic was: {}",
+                mnemonic
+            )),
+            AssemblerError::IncrementalDeclaration => {
+                f.write_str("Data cannot be declared when assembling incrementally")
+            }
+            AssemblerError::IoFailed { ref error } => f.write_str(&format!(
+                "Unable to read the source or write the program: {}",
+                error
+            )),
+            AssemblerError::Located {
+                ref location,
+                ref error,
+            } => f.write_str(&format!(
+                "Line {}, column {}: {}\n    {}",
+                location.line, location.column, error, location.source_line
+            )),
         }
     }
 }
@@ -68,6 +258,57 @@ impl Error for AssemblerError {
             AssemblerError::ParseError{ .. } => {
                 "There was an error parsing the code."
             }
+            AssemblerError::EntryAlreadyDeclared => {
+                "The entry point was previously declared."
+            }
+            AssemblerError::UnknownEntryLabel{ .. } => {
+                "The entry point is not a label on an instruction."
+            }
+            AssemblerError::ByteOutOfRange{ .. } => {
+                "A value given to .byte does not fit in a byte."
+            }
+            AssemblerError::IncludeFailed{ .. } => {
+                "An included file could not be read."
+            }
+            AssemblerError::IncludeCycle{ .. } => {
+                "A file includes itself, directly or through other files."
+            }
+            AssemblerError::UnknownLocalLabel{ .. } => {
+                "No local label is declared in the direction referred to."
+            }
+            AssemblerError::DuplicateSymbol{ .. } => {
+                "More than one of the files being linked declares a label."
+            }
+            AssemblerError::UndefinedSymbol{ .. } => {
+                "A label is used but never declared."
+            }
+            AssemblerError::UnknownConstant{ .. } => {
+                "A constant is used before it is declared with .equ."
+            }
+            AssemblerError::InvalidExpression{ .. } => {
+                "The expression cannot be evaluated."
+            }
+            AssemblerError::InvalidObject{ .. } => {
+                "The object file is invalid."
+            }
+            AssemblerError::SpaceOutOfRange{ .. } => {
+                "A size given to .space is negative, or the reservations are too large in total."
+            }
+            AssemblerError::InvalidOperand{ .. } => {
+                "An instruction has an operand it cannot take."
+            }
+            AssemblerError::UnknownOpcode{ .. } => {
+                "An instruction names an unknown opcode."
+            }
+            AssemblerError::IncrementalDeclaration => {
+                "Data cannot be declared when assembling incrementally."
+            }
+            AssemblerError::IoFailed{ .. } => {
+                "The source could not be read, or the program could not be written."
+            }
+            AssemblerError::Located{ .. } => {
+                "An error was found in the source."
+            }
         }
     }
 }
@@ -1,6 +1,7 @@
 use nom::types::CompleteStr;
 use nom::*;
 
+use crate::assembler::expressions::{constant_name, expression};
 use crate::assembler::instruction_parsers::AssemblerInstruction;
 use crate::assembler::label_parsers::label_declaration;
 use crate::assembler::operand_parsers::operand;
@@ -19,29 +20,132 @@ named!(directive_declaration<CompleteStr, Token>,
 named!(directive_combined<CompleteStr, AssemblerInstruction>,
     ws!(
         do_parse!(
-            l: opt!(label_declaration) >>
-            name: directive_declaration >>
-            o1: opt!(operand) >>
-            o2: opt!(operand) >>
-            o3: opt!(operand) >>
+            l: opt!(spanned!(label_declaration)) >>
+            name: spanned!(directive_declaration) >>
+            o1: opt!(spanned!(operand)) >>
+            o2: opt!(spanned!(operand)) >>
+            o3: opt!(spanned!(operand)) >>
             (
-                AssemblerInstruction{
-                    opcode: None,
-                    directive: Some(name),
-                    label: l,
-                    operand1: o1,
-                    operand2: o2,
-                    operand3: o3,
-                }
+                AssemblerInstruction::from_tokens(l, name, [o1, o2, o3])
             )
         )
     )
 );
 
+// Parser for `.equ SIZE, 16`, which declares a constant that the expressions after it can use. Its
+// value can itself be an expression, such as `.equ DOUBLE, SIZE*2`.
+named!(equ_directive<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        opt!(multispace) >>
+        directive: spanned!(tag!(".equ")) >>
+        space1 >>
+        name: spanned!(constant_name) >>
+        ws!(tag!(",")) >>
+        value: spanned!(expression) >>
+        opt!(multispace) >>
+        (
+            AssemblerInstruction::from_tokens(
+                None,
+                (Token::Directive{name: "equ".to_string()}, directive.1),
+                [
+                    Some((Token::LabelUsage{name: name.0.to_string(), offset: 0}, name.1)),
+                    Some((value.0.into_operand(), value.1)),
+                    None,
+                ],
+            )
+        )
+    )
+);
+
+// Parser for directives that name a label without the `@` other label operands need: `.entry main`,
+// which names the label execution starts at, and `.global main` and `.extern main`, which export a
+// label to and import one from the other files a program is linked from. A bare name cannot be an
+// operand of other directives, as `.code` would then take the next line's opcode for one.
+named!(symbol_directive<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        opt!(multispace) >>
+        directive: spanned!(preceded!(tag!("."), alt!(tag!("entry") | tag!("global") | tag!("extern")))) >>
+        space1 >>
+        name: spanned!(alphanumeric) >>
+        opt!(multispace) >>
+        (
+            AssemblerInstruction::from_tokens(
+                None,
+                (Token::Directive{name: directive.0.to_string()}, directive.1),
+                [Some((Token::LabelUsage{name: name.0.to_string(), offset: 0}, name.1)), None, None],
+            )
+        )
+    )
+);
+
+named!(directive_value<CompleteStr, i32>,
+    map_res!(digit, |s: CompleteStr| s.parse::<i32>())
+);
+
+// Parser for `table: .byte 1, 2, 3`, which takes any number of comma-separated values rather than
+// the three operands other directives are limited to.
+named!(byte_directive<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        opt!(multispace) >>
+        l: opt!(spanned!(label_declaration)) >>
+        directive: spanned!(tag!(".byte")) >>
+        space1 >>
+        values: spanned!(separated_nonempty_list!(ws!(tag!(",")), directive_value)) >>
+        opt!(multispace) >>
+        (
+            AssemblerInstruction::from_tokens(
+                l,
+                (Token::Directive{name: "byte".to_string()}, directive.1),
+                [Some((Token::IntegerList{values: values.0}, values.1)), None, None],
+            )
+        )
+    )
+);
+
+// Parser for `buf: .space 256`, whose size is written without the `#` integer operands need.
+named!(space_directive<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        opt!(multispace) >>
+        l: opt!(spanned!(label_declaration)) >>
+        directive: spanned!(tag!(".space")) >>
+        space1 >>
+        value: spanned!(expression) >>
+        opt!(multispace) >>
+        (
+            AssemblerInstruction::from_tokens(
+                l,
+                (Token::Directive{name: "space".to_string()}, directive.1),
+                [Some((value.0.into_operand(), value.1)), None, None],
+            )
+        )
+    )
+);
+
+// Parser for `.include "util.iasm"`, returning the path. Includes are expanded by
+// `include::read_source` before a program is parsed, so this is not one of the directive forms.
+named!(pub include_directive<CompleteStr, CompleteStr>,
+    do_parse!(
+        space0 >>
+        tag!(".include") >>
+        space1 >>
+        tag!("\"") >>
+        path: take_until!("\"") >>
+        tag!("\"") >>
+        space0 >>
+        (
+            path
+        )
+    )
+);
+
 // Will try to parse out any of the Directive forms.
 named!(pub directive<CompleteStr, AssemblerInstruction>,
     do_parse!(
         ins: alt!(
+            symbol_directive |
+            equ_directive |
+            byte_directive |
+            space_directive |
             directive_combined
         ) >>
         (
@@ -53,12 +157,14 @@ named!(pub directive<CompleteStr, AssemblerInstruction>,
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::spans::InstructionSpans;
 
     #[test]
     fn test_string_directive() {
         let result = directive_combined(CompleteStr("test: .asciiz 'Hello'"));
-        assert_eq!(result.is_ok(), true);
-        let (_, directive) = result.unwrap();
+        assert!(result.is_ok());
+        let (_, mut directive) = result.unwrap();
+        directive.spans = InstructionSpans::default();
 
         let correct_instruction = AssemblerInstruction {
             opcode: None,
@@ -73,7 +179,90 @@ mod tests {
             }),
             operand2: None,
             operand3: None,
+            spans: InstructionSpans::default(),
         };
         assert_eq!(directive, correct_instruction);
     }
+
+    #[test]
+    fn test_byte_directive() {
+        let (rest, directive) = directive(CompleteStr("table: .byte 1, 2,3 , 255\n")).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(directive.get_label_name(), Some("table"));
+        assert_eq!(directive.get_directive_name(), Some("byte"));
+        assert_eq!(
+            directive.operand1,
+            Some(Token::IntegerList {
+                values: vec![1, 2, 3, 255]
+            })
+        );
+    }
+
+    #[test]
+    fn test_space_directive() {
+        let (rest, directive) = directive(CompleteStr("buf: .space 256\n")).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(directive.get_label_name(), Some("buf"));
+        assert_eq!(directive.get_directive_name(), Some("space"));
+        assert_eq!(
+            directive.operand1,
+            Some(Token::IntegerOperand { value: 256 })
+        );
+    }
+
+    #[test]
+    fn test_include_directive() {
+        let (rest, path) = include_directive(CompleteStr("  .include \"lib/util.iasm\" ")).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(path, CompleteStr("lib/util.iasm"));
+        assert!(include_directive(CompleteStr(".include util.iasm")).is_err());
+    }
+
+    #[test]
+    fn test_entry_directive() {
+        for source in [".entry main\n", ".entry @main\n"].iter() {
+            let (rest, directive) = directive(CompleteStr(source)).unwrap();
+            assert_eq!(rest, CompleteStr(""));
+            assert_eq!(directive.get_directive_name(), Some("entry"));
+            assert_eq!(
+                directive.operand1,
+                Some(Token::LabelUsage {
+                    name: String::from("main"),
+                    offset: 0,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_equ_directive() {
+        let (rest, equ) = directive(CompleteStr(".equ SIZE, (4*32+1)\n")).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(equ.get_directive_name(), Some("equ"));
+        assert_eq!(equ.operand2, Some(Token::IntegerOperand { value: 129 }));
+        let (_, space) = directive(CompleteStr("buf: .space SIZE*2\n")).unwrap();
+        assert_eq!(
+            space.operand1,
+            Some(Token::Expression {
+                expression: expression(CompleteStr("SIZE*2")).unwrap().1
+            })
+        );
+    }
+
+    #[test]
+    fn test_global_and_extern_directives() {
+        for name in ["global", "extern"].iter() {
+            let source = format!(".{} helper\n", name);
+            let (rest, directive) = directive(CompleteStr(&source)).unwrap();
+            assert_eq!(rest, CompleteStr(""));
+            assert_eq!(directive.get_directive_name(), Some(*name));
+            assert_eq!(
+                directive.operand1,
+                Some(Token::LabelUsage {
+                    name: String::from("helper"),
+                    offset: 0,
+                })
+            );
+        }
+    }
 }
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use nom::types::CompleteStr;
+use nom::{alpha1, alphanumeric0, digit};
+use serde::{Deserialize, Serialize};
+
+use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::Token;
+
+/// A constant expression, such as the `(4*32+1)` of `#(4*32+1)` or the `SIZE*2` of `#SIZE*2`,
+/// evaluated when the program is assembled.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Expression {
+    Number(i32),
+    /// A constant declared with `.equ`, such as `SIZE`.
+    Constant(String),
+    Negate(Box<Expression>),
+    Binary {
+        operator: Operator,
+        left: Box<Expression>,
+        right: Box<Expression>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+impl Operator {
+    fn from_char(c: char) -> Operator {
+        match c {
+            '+' => Operator::Add,
+            '-' => Operator::Subtract,
+            '*' => Operator::Multiply,
+            '/' => Operator::Divide,
+            _ => Operator::Remainder,
+        }
+    }
+
+    /// Applies the operator, returning `None` if the result overflows or divides by zero.
+    fn apply(self, left: i32, right: i32) -> Option<i32> {
+        match self {
+            Operator::Add => left.checked_add(right),
+            Operator::Subtract => left.checked_sub(right),
+            Operator::Multiply => left.checked_mul(right),
+            Operator::Divide => left.checked_div(right),
+            Operator::Remainder => left.checked_rem(right),
+        }
+    }
+}
+
+impl Expression {
+    /// Returns the value of the expression, looking constants up in `constants`.
+    pub fn evaluate(&self, constants: &HashMap<String, i32>) -> Result<i32, AssemblerError> {
+        match self {
+            Expression::Number(value) => Ok(*value),
+            Expression::Constant(name) => constants
+                .get(name)
+                .copied()
+                .ok_or_else(|| AssemblerError::UnknownConstant { name: name.clone() }),
+            Expression::Negate(e) => e.evaluate(constants)?.checked_neg().ok_or_else(overflow),
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } => operator
+                .apply(left.evaluate(constants)?, right.evaluate(constants)?)
+                .ok_or_else(overflow),
+        }
+    }
+
+    /// Returns the operand the expression is written as: an integer if it can be evaluated without
+    /// any constants, and an expression to evaluate once the constants are known otherwise.
+    pub fn into_operand(self) -> Token {
+        match self.evaluate(&HashMap::new()) {
+            Ok(value) => Token::IntegerOperand { value },
+            Err(_) => Token::Expression { expression: self },
+        }
+    }
+
+    /// Builds the expression applying each of `rest`'s operators in turn, from left to right.
+    fn fold(first: Expression, rest: Vec<(char, Expression)>) -> Expression {
+        rest.into_iter()
+            .fold(first, |left, (operator, right)| Expression::Binary {
+                operator: Operator::from_char(operator),
+                left: Box::new(left),
+                right: Box::new(right),
+            })
+    }
+}
+
+fn overflow() -> AssemblerError {
+    AssemblerError::InvalidExpression {
+        error: "The result overflows or divides by zero".to_string(),
+    }
+}
+
+// Looks for the name of a constant, such as `SIZE` or `buffer2`.
+named!(pub constant_name<CompleteStr, CompleteStr>,
+    recognize!(pair!(alpha1, alphanumeric0))
+);
+
+named!(factor<CompleteStr, Expression>,
+    alt!(
+        map_res!(digit, |s: CompleteStr| s.parse::<i32>().map(Expression::Number)) |
+        map!(constant_name, |name| Expression::Constant(name.to_string())) |
+        delimited!(tag!("("), expression, tag!(")")) |
+        map!(preceded!(tag!("-"), factor), |e| Expression::Negate(Box::new(e)))
+    )
+);
+
+named!(term<CompleteStr, Expression>,
+    do_parse!(
+        first: factor >>
+        rest: many0!(pair!(one_of!("*/%"), factor)) >>
+        (
+            Expression::fold(first, rest)
+        )
+    )
+);
+
+// Parser for constant expressions, such as `4*32+1` or `(SIZE-1)*2`. Multiplication, division and
+// remainder bind tighter than addition and subtraction, and there is no whitespace between terms,
+// as whitespace separates operands.
+named!(pub expression<CompleteStr, Expression>,
+    do_parse!(
+        first: term >>
+        rest: many0!(pair!(one_of!("+-"), term)) >>
+        (
+            Expression::fold(first, rest)
+        )
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(source: &str) -> Result<i32, AssemblerError> {
+        let (rest, e) = expression(CompleteStr(source)).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        let mut constants = HashMap::new();
+        constants.insert("SIZE".to_string(), 16);
+        e.evaluate(&constants)
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(evaluate("(4*32+1)"), Ok(129));
+        assert_eq!(evaluate("4*32+1"), Ok(129));
+        assert_eq!(evaluate("1+4*32"), Ok(129));
+        assert_eq!(evaluate("SIZE*2"), Ok(32));
+        assert_eq!(evaluate("(SIZE-1)%5"), Ok(0));
+        assert_eq!(evaluate("10-2-3"), Ok(5));
+        assert_eq!(evaluate("-SIZE/-4"), Ok(4));
+        assert_eq!(
+            evaluate("SIZE*OTHER"),
+            Err(AssemblerError::UnknownConstant {
+                name: "OTHER".to_string()
+            })
+        );
+        assert_eq!(evaluate("1/(SIZE-16)"), Err(overflow()));
+        assert_eq!(evaluate("2147483647+1"), Err(overflow()));
+    }
+
+    #[test]
+    fn test_into_operand() {
+        let operand = |source| expression(CompleteStr(source)).unwrap().1.into_operand();
+        assert_eq!(operand("-5"), Token::IntegerOperand { value: -5 });
+        assert_eq!(operand("(4*32+1)"), Token::IntegerOperand { value: 129 });
+        assert_eq!(
+            operand("-SIZE"),
+            Token::Expression {
+                expression: Expression::Negate(Box::new(Expression::Constant("SIZE".to_string())))
+            }
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nom::types::CompleteStr;
+
+use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::directive_parsers::include_directive;
+use crate::debug_info::SourceLine;
+
+/// Reads the assembly file at `path`, replacing each `.include "file.iasm"` line with the contents
+/// of the file it names. Included paths are resolved relative to the file including them, and may
+/// include other files in turn, as long as no file ends up including itself.
+pub fn read_source(path: &Path) -> Result<String, AssemblerError> {
+    read_source_lines(path).map(|(source, _)| source)
+}
+
+/// Reads the assembly file at `path` like `read_source`, also returning the file and line each
+/// line of the expanded source came from, for debug info.
+pub fn read_source_lines(path: &Path) -> Result<(String, Vec<SourceLine>), AssemblerError> {
+    let mut source = String::new();
+    let mut lines = vec![];
+    expand(path, &mut vec![], &mut source, &mut lines)?;
+    Ok((source, lines))
+}
+
+/// Appends the contents of `path` to `source`, expanding its includes, and the origin of each
+/// line appended to `lines`. `including` holds the files currently being expanded, outermost
+/// first.
+fn expand(
+    path: &Path,
+    including: &mut Vec<PathBuf>,
+    source: &mut String,
+    lines: &mut Vec<SourceLine>,
+) -> Result<(), AssemblerError> {
+    let failed = |e: std::io::Error| AssemblerError::IncludeFailed {
+        path: path.display().to_string(),
+        error: e.to_string(),
+    };
+    let canonical = fs::canonicalize(path).map_err(failed)?;
+    if including.contains(&canonical) {
+        return Err(AssemblerError::IncludeCycle {
+            path: path.display().to_string(),
+        });
+    }
+    let contents = fs::read_to_string(&canonical).map_err(failed)?;
+    let directory = canonical.parent().unwrap_or_else(|| Path::new(""));
+    including.push(canonical.clone());
+    for (number, line) in contents.lines().enumerate() {
+        match include_directive(CompleteStr(line)) {
+            Ok((rest, included)) if rest.is_empty() => {
+                expand(&directory.join(included.0), including, source, lines)?;
+            }
+            _ => {
+                source.push_str(line);
+                source.push('\n');
+                lines.push(SourceLine {
+                    file: path.display().to_string(),
+                    line: number as u32 + 1,
+                });
+            }
+        }
+    }
+    including.pop();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_include() {
+        let root = std::env::temp_dir().join("iridium_test_include");
+        fs::create_dir_all(root.join("lib")).unwrap();
+        fs::write(
+            root.join("main.iasm"),
+            ".data\n.code\nload $0 #1\n.include \"lib/util.iasm\"\nhlt\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("lib/util.iasm"),
+            "add $0 $0 $0\n  .include \"more.iasm\"\n",
+        )
+        .unwrap();
+        fs::write(root.join("lib/more.iasm"), "add $0 $0 $0\n").unwrap();
+
+        let source = read_source(&root.join("main.iasm")).unwrap();
+        assert_eq!(
+            source,
+            ".data\n.code\nload $0 #1\nadd $0 $0 $0\nadd $0 $0 $0\nhlt\n"
+        );
+        assert!(Assembler::new().assemble(&source).is_ok());
+        let (_, lines) = read_source_lines(&root.join("main.iasm")).unwrap();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[3].file.ends_with("util.iasm"));
+        assert_eq!(lines[3].line, 1);
+        assert!(lines[5].file.ends_with("main.iasm"));
+        assert_eq!(lines[5].line, 5);
+
+        // A file that ends up including itself is rejected.
+        fs::write(root.join("lib/more.iasm"), ".include \"util.iasm\"\n").unwrap();
+        match read_source(&root.join("main.iasm")) {
+            Err(AssemblerError::IncludeCycle { path }) => assert!(path.ends_with("util.iasm")),
+            other => panic!("Expected an include cycle, got {:?}", other),
+        }
+
+        fs::remove_file(root.join("lib/more.iasm")).unwrap();
+        match read_source(&root.join("main.iasm")) {
+            Err(AssemblerError::IncludeFailed { path, .. }) => {
+                assert!(path.ends_with("more.iasm"))
+            }
+            other => panic!("Expected a missing include, got {:?}", other),
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,11 +1,18 @@
+use std::collections::HashMap;
+
 use nom::types::CompleteStr;
+use serde::{Deserialize, Serialize};
 
+use crate::assembler::assembler_errors::AssemblerError;
 use crate::assembler::label_parsers::label_declaration;
+use crate::assembler::linker::RelocationKind;
 use crate::assembler::opcode_parsers::*;
 use crate::assembler::operand_parsers::*;
+use crate::assembler::spans::{InstructionSpans, Span};
 use crate::assembler::{SymbolTable, Token};
+use crate::instruction::{Opcode, INSTRUCTION_LENGTH};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct AssemblerInstruction {
     pub opcode: Option<Token>,
     pub label: Option<Token>,
@@ -13,31 +20,186 @@ pub struct AssemblerInstruction {
     pub operand1: Option<Token>,
     pub operand2: Option<Token>,
     pub operand3: Option<Token>,
+    /// Where the instruction and each of its tokens were written in the source.
+    #[serde(default)]
+    pub spans: InstructionSpans,
 }
 
+/// A token, along with the `Span::remaining` its parser consumed.
+pub type SpannedToken = (Token, Span);
+
 impl AssemblerInstruction {
+    /// Builds an instruction or directive from the tokens its parser found. The span of the whole
+    /// instruction is left for `program` to fill in.
+    pub fn from_tokens(
+        label: Option<SpannedToken>,
+        name: SpannedToken,
+        operands: [Option<SpannedToken>; 3],
+    ) -> AssemblerInstruction {
+        let [operand1, operand2, operand3] = operands;
+        let (label, label_span) = label.map_or((None, None), |(t, s)| (Some(t), Some(s)));
+        let (name, name_span) = name;
+        let (opcode, directive) = match name {
+            Token::Directive { .. } => (None, Some(name)),
+            _ => (Some(name), None),
+        };
+        let spans = InstructionSpans {
+            instruction: Span::default(),
+            label: label_span,
+            name: Some(name_span),
+            operands: [
+                operand1.as_ref().map(|(_, s)| *s),
+                operand2.as_ref().map(|(_, s)| *s),
+                operand3.as_ref().map(|(_, s)| *s),
+            ],
+        };
+        AssemblerInstruction {
+            opcode,
+            label,
+            directive,
+            operand1: operand1.map(|(t, _)| t),
+            operand2: operand2.map(|(t, _)| t),
+            operand3: operand3.map(|(t, _)| t),
+            spans,
+        }
+    }
+
     /// Converts assembler instructions to a vector of u8.
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
-        let mut results = vec![];
-        if let Some(token) = &self.opcode {
-            match token {
-                Token::Op { code } => results.push(*code as u8),
-                _ => println!("Non-opcode found in opcode field"),
-            }
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
+        let mut results = Vec::with_capacity(4);
+        self.write_bytes(symbols, &mut results)?;
+        Ok(results)
+    }
+
+    /// Appends the instruction's bytecode to `results`, so whole programs can be written into a
+    /// single buffer. Directives are not part of the code, so nothing is written for them.
+    pub fn write_bytes(
+        &self,
+        symbols: &SymbolTable,
+        results: &mut Vec<u8>,
+    ) -> Result<(), AssemblerError> {
+        if let Some((register, value)) = self.wide_load() {
+            let value = value as u32;
+            results.extend_from_slice(&[Opcode::LOAD as u8, register]);
+            results.extend_from_slice(&(value as u16).to_be_bytes());
+            results.extend_from_slice(&[Opcode::LUI as u8, register]);
+            results.extend_from_slice(&((value >> 16) as u16).to_be_bytes());
+            return Ok(());
+        }
+        if !self.is_opcode() {
+            return Ok(());
+        }
+        let start = results.len();
+        match self.encoded_opcode() {
+            Some(code) => results.push(code as u8),
+            None => return Err(AssemblerError::NonOpcodeInOpcodeField),
+        }
+        if let Some(address) = self.address_operand(symbols) {
+            results.extend_from_slice(&address.to_be_bytes()[1..]);
+            return Ok(());
+        }
+
+        for t in [&self.operand1, &self.operand2, &self.operand3].iter().copied().flatten() {
+            AssemblerInstruction::extract_operand(t, results, symbols)?;
+        }
+
+        results.resize(results.len().max(start + 4), 0);
+        Ok(())
+    }
+
+    /// Returns how many bytes of bytecode the instruction assembles to.
+    pub fn byte_length(&self) -> usize {
+        if self.wide_load().is_some() {
+            2 * INSTRUCTION_LENGTH
+        } else if self.is_opcode() {
+            INSTRUCTION_LENGTH
+        } else {
+            0
+        }
+    }
+
+    /// A `load` of a constant that does not fit in a sign-extended 16 bit immediate is a
+    /// pseudo-instruction: it assembles to a `LOAD` of the lower half followed by a `LUI` of the
+    /// upper half. Returns the register and constant of such a load.
+    fn wide_load(&self) -> Option<(u8, i32)> {
+        match (&self.opcode, &self.operand1, &self.operand2) {
+            (
+                Some(Token::Op { code: Opcode::LOAD }),
+                Some(Token::Register { reg_num }),
+                Some(Token::IntegerOperand { value }),
+            ) if *value < i16::MIN as i32 || *value > i16::MAX as i32 => Some((*reg_num, *value)),
+            _ => None,
         }
+    }
 
-        for operand in vec![&self.operand1, &self.operand2, &self.operand3] {
-            match operand {
-                Some(t) => AssemblerInstruction::extract_operand(t, &mut results, symbols),
-                None => {}
+    /// Returns the opcode the instruction is written as. Jumps to a register that are given a label
+    /// instead, such as `jmp @loop`, are written as the matching jump to an address, so they land
+    /// on the label wherever the code ends up after the header and read-only section. Likewise,
+    /// instructions that take an immediate but are given a register, such as `prts $0`, are
+    /// written as their register form.
+    fn encoded_opcode(&self) -> Option<Opcode> {
+        match (&self.opcode, &self.operand1) {
+            (Some(Token::Op { code }), Some(Token::LabelUsage { .. })) => {
+                Some(code.address_form().unwrap_or(*code))
+            }
+            (Some(Token::Op { code }), Some(Token::Register { .. })) => {
+                Some(code.register_form().unwrap_or(*code))
             }
+            (Some(Token::Op { code }), _) => Some(*code),
+            (Some(Token::UnknownOp { .. }), _) => Some(Opcode::IGL),
+            _ => None,
         }
+    }
 
-        while results.len() < 4 {
-            results.push(0);
+    /// Returns the 24-bit address operand of jumps such as `jmpl @label`, resolving labels from
+    /// the symbol table.
+    fn address_operand(&self, symbols: &SymbolTable) -> Option<u32> {
+        match (self.encoded_opcode(), &self.operand1) {
+            (Some(code), Some(operand)) if code.takes_address() => match operand {
+                // Labels the file does not declare are left as 0 for the linker to fill in.
+                Token::LabelUsage { name, offset } => {
+                    Some(label_address(symbols, name, *offset).unwrap_or(0))
+                }
+                Token::IntegerOperand { value } => Some(*value as u32),
+                _ => None,
+            },
+            _ => None,
         }
+    }
 
-        results
+    /// Returns the labels the instruction's bytecode refers to: where each one is written, counted
+    /// from the start of the instruction, the label and offset it names, and how it is written.
+    pub fn label_references(&self) -> Vec<(usize, &str, i32, RelocationKind)> {
+        let mut references = vec![];
+        if self.wide_load().is_some() || !self.is_opcode() {
+            return references;
+        }
+        if let (Some(code), Some(Token::LabelUsage { name, offset })) =
+            (self.encoded_opcode(), &self.operand1)
+        {
+            if code.takes_address() {
+                references.push((1, name.as_str(), *offset, RelocationKind::Address));
+                return references;
+            }
+        }
+        // Operands are written one after another following the opcode byte.
+        let mut at = 1;
+        for t in [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            match t {
+                Token::Register { .. } => at += 1,
+                Token::IntegerOperand { .. } => at += 2,
+                Token::LabelUsage { name, offset } => {
+                    references.push((at, name.as_str(), *offset, RelocationKind::Immediate));
+                    at += 2;
+                }
+                _ => {}
+            }
+        }
+        references
     }
 
     pub fn is_label(&self) -> bool {
@@ -59,37 +221,32 @@ impl AssemblerInstruction {
         self.operand3.is_some()
     }
 
-    pub fn get_string_constant(&self) -> Option<String> {
+    pub fn get_string_constant(&self) -> Option<&str> {
         match &self.operand1 {
-            Some(o) => match o {
-                Token::IrString { name} => Some(name.clone()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::IrString { name }) => Some(name),
+            _ => None,
         }
     }
 
-    pub fn get_directive_name(&self) -> Option<String> {
+    pub fn get_directive_name(&self) -> Option<&str> {
         match &self.directive {
-            Some(d) => match d {
-                Token::Directive { name } => Some(name.clone()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::Directive { name }) => Some(name),
+            _ => None,
         }
     }
 
-    pub fn get_label_name(&self) -> Option<String> {
+    pub fn get_label_name(&self) -> Option<&str> {
         match &self.label {
-            Some(l) => match l {
-                Token::LabelDeclaration { name } => Some(name.clone()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::LabelDeclaration { name }) => Some(name),
+            _ => None,
         }
     }
 
-    fn extract_operand(t: &Token, results: &mut Vec<u8>, symbols: &SymbolTable) {
+    fn extract_operand(
+        t: &Token,
+        results: &mut Vec<u8>,
+        symbols: &SymbolTable,
+    ) -> Result<(), AssemblerError> {
         match t {
             Token::Register { reg_num } => {
                 results.push(*reg_num);
@@ -102,38 +259,46 @@ impl AssemblerInstruction {
                 results.push(byte2 as u8);
                 results.push(byte1 as u8);
             }
-            Token::LabelUsage { name } => {
-                if let Some(value) = symbols.symbol_value(name) {
-                    let byte1 = value;
-                    let byte2 = value >> 8;
-                    results.push(byte2 as u8);
-                    results.push(byte1 as u8);
-                }
+            Token::LabelUsage { name, offset } => {
+                let value = label_address(symbols, name, *offset).unwrap_or(0);
+                let byte1 = value;
+                let byte2 = value >> 8;
+                results.push(byte2 as u8);
+                results.push(byte1 as u8);
+            }
+            Token::Expression { expression } => {
+                // The assembler evaluates expressions before writing any bytes, so this is only
+                // reachable for code that uses constants without being assembled, e.g. in the REPL,
+                // where there are none to evaluate it with.
+                let value = expression.evaluate(&HashMap::new())?;
+                results.extend_from_slice(&(value as u16).to_be_bytes());
             }
-            _ => {
-                println!("Opcode found in operand field");
-                std::process::exit(1);
+            t => {
+                return Err(AssemblerError::InvalidOperand {
+                    operand: format!("{:?}", t),
+                })
             }
         }
+        Ok(())
     }
 }
 
+/// Returns the address `offset` bytes past the label `name`, if the label has been declared.
+fn label_address(symbols: &SymbolTable, name: &str, offset: i32) -> Option<u32> {
+    symbols
+        .symbol_value(name)
+        .map(|value| value.wrapping_add(offset as u32))
+}
+
 named!(instruction_combined<CompleteStr, AssemblerInstruction>,
     do_parse!(
-        l: opt!(label_declaration) >>
-        o: opcode >>
-        o1: opt!(operand) >>
-        o2: opt!(operand) >>
-        o3: opt!(operand) >>
+        l: opt!(spanned!(label_declaration)) >>
+        o: spanned!(opcode) >>
+        o1: opt!(spanned!(operand)) >>
+        o2: opt!(spanned!(operand)) >>
+        o3: opt!(spanned!(operand)) >>
         (
-            AssemblerInstruction{
-                opcode: Some(o),
-                label: l,
-                directive: None,
-                operand1: o1,
-                operand2: o2,
-                operand3: o3,
-            }
+            AssemblerInstruction::from_tokens(l, o, [o1, o2, o3])
         )
     )
 );
@@ -153,23 +318,32 @@ named!(pub instruction<CompleteStr, AssemblerInstruction>,
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::instruction::Opcode;
+    use crate::assembler::directive_parsers::directive;
 
     #[test]
     fn test_parse_instruction_form_one() {
-        let result = instruction_combined(CompleteStr("load $0 #100\n"));
-        assert_eq!(result.is_ok(), true);
-        let (rest, assembler_instruction) = result.unwrap();
+        let source = "load $0 #100\n";
+        let result = instruction_combined(CompleteStr(source));
+        assert!(result.is_ok());
+        let (rest, mut assembler_instruction) = result.unwrap();
         assert_eq!(rest, CompleteStr(""));
+        let span = |start, end| Span {
+            start,
+            end,
+            line: 1,
+        };
+        // The parsed spans are resolved against the source, as `program` does.
+        let spans = assembler_instruction.spans.resolve(source, &[0]);
+        assert_eq!(spans.name, Some(span(0, 4)));
+        assert_eq!(spans.operands, [Some(span(5, 7)), Some(span(8, 12)), None]);
+        assembler_instruction.spans = InstructionSpans::default();
         assert_eq!(
             assembler_instruction,
             AssemblerInstruction {
                 opcode: Some(Token::Op { code: Opcode::LOAD }),
-                label: None,
-                directive: None,
                 operand1: Some(Token::Register { reg_num: 0 }),
                 operand2: Some(Token::IntegerOperand { value: 100 }),
-                operand3: None,
+                ..Default::default()
             },
         );
     }
@@ -177,38 +351,107 @@ mod tests {
     #[test]
     fn test_parse_instruction_form_two() {
         let result = instruction_combined(CompleteStr("hlt"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, assembler_instruction) = result.unwrap();
         assert_eq!(rest, CompleteStr(""));
         assert_eq!(
-            assembler_instruction,
-            AssemblerInstruction {
-                opcode: Some(Token::Op { code: Opcode::HLT }),
-                label: None,
-                directive: None,
-                operand1: None,
-                operand2: None,
-                operand3: None,
-            }
+            assembler_instruction.opcode,
+            Some(Token::Op { code: Opcode::HLT })
         );
+        assert!(!assembler_instruction.is_label());
+        assert!(!assembler_instruction.has_operands());
     }
 
     #[test]
     fn test_parse_instruction_form_three() {
         let result = instruction_combined(CompleteStr("add $0 $1 $2\n"));
-        assert_eq!(result.is_ok(), true);
-        let (rest, assembler_instruction) = result.unwrap();
+        assert!(result.is_ok());
+        let (rest, mut assembler_instruction) = result.unwrap();
         assert_eq!(rest, CompleteStr(""));
+        assembler_instruction.spans = InstructionSpans::default();
         assert_eq!(
             assembler_instruction,
             AssemblerInstruction {
                 opcode: Some(Token::Op { code: Opcode::ADD }),
-                label: None,
-                directive: None,
                 operand1: Some(Token::Register { reg_num: 0 }),
                 operand2: Some(Token::Register { reg_num: 1 }),
                 operand3: Some(Token::Register { reg_num: 2 }),
+                ..Default::default()
             }
         )
     }
+
+    #[test]
+    fn test_parse_bitwise_instructions() {
+        let (_, xor) = instruction_combined(CompleteStr("xor $0 $1 $2\n")).unwrap();
+        assert_eq!(xor.opcode, Some(Token::Op { code: Opcode::XOR }));
+        assert_eq!(
+            xor.to_bytes(&SymbolTable::new()).unwrap(),
+            vec![24, 0, 1, 2]
+        );
+        let (_, not) = instruction_combined(CompleteStr("not $3 $4\n")).unwrap();
+        assert_eq!(
+            not.to_bytes(&SymbolTable::new()).unwrap(),
+            vec![25, 3, 4, 0]
+        );
+    }
+
+    #[test]
+    fn test_invalid_operands() {
+        let (_, load) = instruction_combined(CompleteStr("load $0 'hi'\n")).unwrap();
+        assert_eq!(
+            load.to_bytes(&SymbolTable::new()),
+            Err(AssemblerError::InvalidOperand {
+                operand: "IrString { name: \"hi\" }".to_string()
+            })
+        );
+        // Constants can only be used in assembled files.
+        let (_, load) = instruction_combined(CompleteStr("load $0 #SIZE\n")).unwrap();
+        assert_eq!(
+            load.to_bytes(&SymbolTable::new()),
+            Err(AssemblerError::UnknownConstant {
+                name: "SIZE".to_string()
+            })
+        );
+        // Directives are not part of the code.
+        let (_, directive) = directive(CompleteStr("hi: .asciiz 'Hi'\n")).unwrap();
+        assert_eq!(directive.to_bytes(&SymbolTable::new()), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_wide_load() {
+        let (_, load) = instruction_combined(CompleteStr("load $2 #65536\n")).unwrap();
+        assert_eq!(load.byte_length(), 8);
+        assert_eq!(
+            load.to_bytes(&SymbolTable::new()).unwrap(),
+            vec![1, 2, 0, 0, 38, 2, 0, 1]
+        );
+        let (_, load) = instruction_combined(CompleteStr("load $2 #32768\n")).unwrap();
+        assert_eq!(load.byte_length(), 8);
+        let (_, load) = instruction_combined(CompleteStr("load $2 #-32768\n")).unwrap();
+        assert_eq!(load.byte_length(), 4);
+        assert_eq!(
+            load.to_bytes(&SymbolTable::new()).unwrap(),
+            vec![1, 2, 0x80, 0]
+        );
+    }
+
+    #[test]
+    fn test_address_operand() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(crate::assembler::symbols::Symbol::new_with_offset(
+            "target".to_string(),
+            crate::assembler::symbols::SymbolType::Label,
+            0x012345,
+        ));
+        let (_, jump) = instruction_combined(CompleteStr("jmpl @target\n")).unwrap();
+        assert_eq!(jump.to_bytes(&symbols).unwrap(), vec![43, 0x01, 0x23, 0x45]);
+        let (_, jump) = instruction_combined(CompleteStr("jeql #300\n")).unwrap();
+        assert_eq!(jump.to_bytes(&symbols).unwrap(), vec![44, 0, 1, 44]);
+        // Register jumps given a label are written as jumps to its address.
+        let (_, jump) = instruction_combined(CompleteStr("jmp @target\n")).unwrap();
+        assert_eq!(jump.to_bytes(&symbols).unwrap(), vec![43, 0x01, 0x23, 0x45]);
+        let (_, jump) = instruction_combined(CompleteStr("jneq @target\n")).unwrap();
+        assert_eq!(jump.to_bytes(&symbols).unwrap(), vec![45, 0x01, 0x23, 0x45]);
+    }
 }
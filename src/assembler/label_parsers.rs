@@ -1,5 +1,5 @@
 use nom::types::CompleteStr;
-use nom::{alphanumeric, multispace};
+use nom::{alphanumeric, digit, multispace};
 
 use crate::assembler::Token;
 
@@ -17,15 +17,28 @@ named!(pub label_declaration<CompleteStr, Token>,
     )
 );
 
-// Looks for a user-defined label, such as `@label1`
+// Looks for the offset added to a label, such as the `+4` in `@table+4`.
+named!(label_offset<CompleteStr, i32>,
+    do_parse!(
+        sign: alt!(tag!("+") | tag!("-")) >>
+        value: map_res!(digit, |s: CompleteStr| s.parse::<i32>()) >>
+        (
+            if sign.0 == "-" { -value } else { value }
+        )
+    )
+);
+
+// Looks for a user-defined label, such as `@label1`, optionally followed by an offset from it, such
+// as `@label1+4` or `@label1-8`.
 named!(pub label_usage<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("@") >>
             name: alphanumeric >>
+            offset: opt!(label_offset) >>
             opt!(multispace) >>
             (
-                Token::LabelUsage{name: name.to_string()}
+                Token::LabelUsage{name: name.to_string(), offset: offset.unwrap_or(0)}
             )
         )
     )
@@ -38,7 +51,7 @@ mod tests {
     #[test]
     fn test_parse_label_declaration() {
         let result = label_declaration(CompleteStr("test:"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, token) = result.unwrap();
         assert_eq!(
             token,
@@ -47,21 +60,34 @@ mod tests {
             }
         );
         let result = label_declaration(CompleteStr("test"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
     }
 
     #[test]
     fn test_parse_label_usage() {
         let result = label_usage(CompleteStr("@test"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, token) = result.unwrap();
         assert_eq!(
             token,
             Token::LabelUsage {
-                name: "test".to_string()
+                name: "test".to_string(),
+                offset: 0,
             }
         );
         let result = label_usage(CompleteStr("test"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+
+        for (source, offset) in [("@test+4", 4), ("@test-8", -8)].iter() {
+            let (rest, token) = label_usage(CompleteStr(source)).unwrap();
+            assert_eq!(rest, CompleteStr(""));
+            assert_eq!(
+                token,
+                Token::LabelUsage {
+                    name: "test".to_string(),
+                    offset: *offset,
+                }
+            );
+        }
     }
 }
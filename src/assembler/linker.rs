@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::{write_checksum, write_pie_header, PIE_HEADER_LENGTH};
+
+/// The bytes object files start with, to tell them apart from programs and source.
+pub const OBJECT_PREFIX: [u8; 4] = [45, 79, 66, 45];
+/// The version of the object format, written after the prefix. It must be bumped whenever
+/// `Fragment` changes.
+pub const OBJECT_VERSION: u8 = 2;
+
+/// One file of a program, assembled on its own by `Assembler::assemble_fragment`. Its sections
+/// are placed after those of the files before it when linked, so the labels it declares are
+/// recorded relative to their section, and every use of a label in its code is recorded as a
+/// relocation to fill in once the label's address is known. Written to disk with `to_bytes`, it
+/// is an object file, which can be linked with other files later.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Fragment {
+    pub ro: Vec<u8>,
+    pub code: Vec<u8>,
+    /// The writable data declared in `.data`.
+    pub data: Vec<u8>,
+    /// The number of bytes the file reserves on the heap with `.space`.
+    pub space_length: u32,
+    /// The offset in `code` named by `.entry`, if the file has one.
+    pub entry_point: Option<u32>,
+    pub symbols: Vec<FragmentSymbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// A label declared by a fragment.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FragmentSymbol {
+    pub name: String,
+    pub section: Section,
+    /// The offset of the label in its section of the fragment.
+    pub offset: u32,
+    /// Whether the label is exported with `.global`, so other fragments can use it.
+    pub global: bool,
+}
+
+impl Fragment {
+    /// Encodes the fragment as the contents of an object file.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = OBJECT_PREFIX.to_vec();
+        bytes.push(OBJECT_VERSION);
+        bytes.extend(bincode::serialize(self).unwrap_or_default());
+        bytes
+    }
+
+    /// Decodes the contents of an object file written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Fragment, AssemblerError> {
+        let invalid = |error: &str| AssemblerError::InvalidObject {
+            error: error.to_string(),
+        };
+        if !is_object(bytes) {
+            return Err(invalid("it does not start with the object file prefix"));
+        }
+        if bytes[OBJECT_PREFIX.len()] != OBJECT_VERSION {
+            return Err(invalid(
+                "it was written for another version of the object format",
+            ));
+        }
+        bincode::deserialize(&bytes[OBJECT_PREFIX.len() + 1..]).map_err(|e| invalid(&e.to_string()))
+    }
+}
+
+/// Returns whether `bytes` are the contents of an object file rather than e.g. source.
+pub fn is_object(bytes: &[u8]) -> bool {
+    bytes.len() > OBJECT_PREFIX.len() && bytes.starts_with(&OBJECT_PREFIX)
+}
+
+/// The part of a program a label points into.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Section {
+    /// The read-only section, whose labels are offsets from its start.
+    ReadOnly,
+    /// The code, whose labels are addresses in the program.
+    Code,
+    /// The writable data, whose labels are offsets from its start, which is addressed after the
+    /// read-only section.
+    Data,
+    /// The bytes reserved on the heap with `.space`, whose labels are offsets from its start.
+    Space,
+}
+
+/// A use of a label in a fragment's code, to be filled in with the label's value when linking.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Relocation {
+    /// Where in the fragment's code the value is written.
+    pub offset: u32,
+    pub symbol: String,
+    /// Added to the label's value, e.g. 4 for `@table+4`.
+    pub addend: i32,
+    pub kind: RelocationKind,
+}
+
+/// How a relocated value is written into an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RelocationKind {
+    /// The 24 bit big endian address operand of jumps such as `jmpl @label`.
+    Address,
+    /// A 16 bit big endian immediate operand, such as the label of `prts @msg`.
+    Immediate,
+}
+
+impl RelocationKind {
+    /// Writes `value` into `code` at `offset`.
+    fn write(self, code: &mut [u8], offset: usize, value: u32) {
+        match self {
+            RelocationKind::Address => {
+                code[offset..offset + 3].copy_from_slice(&value.to_be_bytes()[1..])
+            }
+            RelocationKind::Immediate => {
+                code[offset..offset + 2].copy_from_slice(&(value as u16).to_be_bytes())
+            }
+        }
+    }
+}
+
+/// Where a fragment's sections start in the linked program.
+struct Placement {
+    ro: u32,
+    code: u32,
+    data: u32,
+    space: u32,
+}
+
+impl Placement {
+    /// Returns the value of `symbol`, declared by the fragment placed here.
+    fn value(&self, symbol: &FragmentSymbol) -> u32 {
+        match symbol.section {
+            Section::ReadOnly => self.ro + symbol.offset,
+            Section::Code => self.code + symbol.offset,
+            Section::Data => self.data + symbol.offset,
+            Section::Space => self.space + symbol.offset,
+        }
+    }
+}
+
+/// Links `fragments` into one program, in order: their read-only sections are placed one after
+/// another after the header, followed by their code and then their writable data, and the bytes
+/// they reserve with `.space` one after another on the heap. Each use of a label is relocated to the
+/// label in the same fragment if it declares one, and otherwise to the label another fragment
+/// exports with `.global`.
+pub fn link(fragments: &[Fragment]) -> Result<Vec<u8>, Vec<AssemblerError>> {
+    let ro_length: usize = fragments.iter().map(|f| f.ro.len()).sum();
+    let data_length: usize = fragments.iter().map(|f| f.data.len()).sum();
+    let mut placements = Vec::with_capacity(fragments.len());
+    let mut next = Placement {
+        ro: 0,
+        code: (PIE_HEADER_LENGTH + ro_length) as u32,
+        data: ro_length as u32,
+        space: 0,
+    };
+    for fragment in fragments {
+        placements.push(Placement {
+            ro: next.ro,
+            code: next.code,
+            data: next.data,
+            space: next.space,
+        });
+        next.ro += fragment.ro.len() as u32;
+        next.code += fragment.code.len() as u32;
+        next.data += fragment.data.len() as u32;
+        next.space += fragment.space_length;
+    }
+
+    let mut errors = vec![];
+    let mut globals: HashMap<&str, u32> = HashMap::new();
+    let mut entry_point = None;
+    for (fragment, placement) in fragments.iter().zip(&placements) {
+        for symbol in fragment.symbols.iter().filter(|s| s.global) {
+            if globals
+                .insert(&symbol.name, placement.value(symbol))
+                .is_some()
+            {
+                errors.push(AssemblerError::DuplicateSymbol {
+                    name: symbol.name.clone(),
+                });
+            }
+        }
+        if let Some(entry) = fragment.entry_point {
+            if entry_point.is_some() {
+                errors.push(AssemblerError::EntryAlreadyDeclared);
+            }
+            entry_point = Some(placement.code + entry);
+        }
+    }
+
+    let ro: Vec<u8> = fragments
+        .iter()
+        .flat_map(|f| f.ro.iter().copied())
+        .collect();
+    let mut program = Vec::with_capacity(next.code as usize + data_length);
+    write_pie_header(
+        &mut program,
+        &ro,
+        entry_point,
+        next.space,
+        data_length as u32,
+    );
+    for (fragment, placement) in fragments.iter().zip(&placements) {
+        let start = program.len();
+        program.extend_from_slice(&fragment.code);
+        for relocation in &fragment.relocations {
+            let value = fragment
+                .symbols
+                .iter()
+                .find(|s| s.name == relocation.symbol)
+                .map(|s| placement.value(s))
+                .or_else(|| globals.get(relocation.symbol.as_str()).copied());
+            match value {
+                Some(value) => relocation.kind.write(
+                    &mut program[start..],
+                    relocation.offset as usize,
+                    value.wrapping_add(relocation.addend as u32),
+                ),
+                None => errors.push(AssemblerError::UndefinedSymbol {
+                    name: relocation.symbol.clone(),
+                }),
+            }
+        }
+    }
+    for fragment in fragments {
+        program.extend_from_slice(&fragment.data);
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    write_checksum(&mut program);
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    fn fragment(source: &str) -> Fragment {
+        Assembler::new().assemble_fragment(source).unwrap()
+    }
+
+    #[test]
+    fn test_link() {
+        let main = fragment(
+            ".data\nbanner: .asciiz 'Hi'\n.code\n.global back\n.extern double\nload $0 #1\n1: jmpl @double\nback: load $1 #2\nhlt\n",
+        );
+        let lib = fragment(
+            ".rodata\ntable: .byte 7, 8\nbuf: .space 4\n.code\n.global double\n.extern back\n1: load $2 @table+1\ndouble: add $0 $0 $0\njmp @back\n",
+        );
+        assert_eq!(lib.symbols.len(), 4);
+        assert!(lib.symbols.contains(&FragmentSymbol {
+            name: "double".to_string(),
+            section: Section::Code,
+            offset: 4,
+            global: true,
+        }));
+        assert_eq!(lib.relocations.len(), 2);
+        let lib = Fragment::from_bytes(&lib.to_bytes()).unwrap();
+
+        let program = link(&[main, lib]).unwrap();
+        // The read-only sections come first, with `table` after `banner`.
+        assert_eq!(
+            &program[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 5],
+            b"Hi\0\x07\x08"
+        );
+        let code_start = PIE_HEADER_LENGTH + 5;
+        // `table+1` is the offset of 8 in the merged read-only section.
+        assert_eq!(&program[code_start + 16..code_start + 20], &[1, 2, 0, 4]);
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.exit_code(), Some(0));
+        assert_eq!(vm.registers[0], 2);
+        assert_eq!(vm.registers[1], 2);
+    }
+
+    #[test]
+    fn test_link_data() {
+        let main = fragment(
+            ".rodata\nhi: .asciiz 'Hi'\n.data\nflag: .byte 1\nscratch: .space 2\n.code\n.extern table\nload $0 @table\nldb $0 $0\nload $1 @scratch\nhlt\n",
+        );
+        let lib = fragment(
+            ".data\n.global table\ntable: .byte 2, 3\nbuf: .space 1\n.code\nload $2 @buf\n",
+        );
+        assert_eq!(lib.data, vec![2, 3]);
+        let program = link(&[main, lib]).unwrap();
+        // The writable data of each fragment follows the code, and is addressed after the
+        // read-only section. The reserved bytes are on the heap.
+        assert_eq!(&program[program.len() - 3..], &[1, 2, 3]);
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.exit_code(), Some(0));
+        assert_eq!(vm.registers[0], 2);
+        assert_eq!(vm.registers[1], 0);
+        assert_eq!(vm.heap(), &[0, 0, 0]);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let a = fragment(".data\n.code\n.global start\n.extern missing\nstart: jmpl @missing\n");
+        let b = fragment(".data\n.code\n.global start\nstart: hlt\n");
+        // Labels that are not exported cannot clash with those of other fragments.
+        let c = fragment(".data\n.code\nstart: hlt\n");
+        assert_eq!(
+            link(&[a, b, c]).unwrap_err(),
+            vec![
+                AssemblerError::DuplicateSymbol {
+                    name: "start".to_string()
+                },
+                AssemblerError::UndefinedSymbol {
+                    name: "missing".to_string()
+                },
+            ]
+        );
+        assert!(Fragment::from_bytes(b".data\n.code\n").is_err());
+    }
+
+    #[test]
+    fn test_global_and_extern() {
+        // Labels used without being declared or imported are errors, and so are imports outside
+        // of fragments.
+        for source in [
+            ".data\n.code\njmpl @missing\n",
+            ".data\n.code\n.extern missing\njmpl @missing\n",
+        ]
+        .iter()
+        {
+            let errors = Assembler::new().assemble(source).unwrap_err();
+            assert_eq!(
+                errors[0].without_location(),
+                &AssemblerError::UndefinedSymbol {
+                    name: "missing".to_string()
+                }
+            );
+        }
+        let errors = Assembler::new()
+            .assemble_fragment(".data\n.code\n.global missing\nhlt\n")
+            .unwrap_err();
+        assert_eq!(errors[0].location().unwrap().line, 3);
+        // Only the labels exported with `.global` are.
+        let fragment = fragment(".data\n.code\n.global main\nmain: hlt\nother: hlt\n");
+        let exported: Vec<&str> = fragment
+            .symbols
+            .iter()
+            .filter(|s| s.global)
+            .map(|s| s.name.as_str())
+            .collect();
+        assert_eq!(exported, vec!["main"]);
+    }
+}
@@ -0,0 +1,115 @@
+use std::fmt;
+use std::ops::Range;
+
+/// The number of emitted bytes shown on each row of a listing. Longer runs, such as strings,
+/// continue on rows of their own.
+const BYTES_PER_ROW: usize = 4;
+
+/// One row of a listing: a source line, with the bytes emitted for it and the address they were
+/// written at.
+#[derive(Debug, PartialEq)]
+pub struct ListingLine {
+    /// The offset of the first emitted byte in the assembled program, header included.
+    pub address: Option<usize>,
+    pub bytes: Vec<u8>,
+    pub source: String,
+}
+
+/// A listing of an assembled program, which shows each source line side by side with the address
+/// and bytes it assembled to.
+#[derive(Debug, Default, PartialEq)]
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+}
+
+impl Listing {
+    /// Builds the listing of `program`, assembled from `source`. `spans` holds, for each
+    /// instruction or directive that emitted bytes, its offset in `source` and the range of
+    /// `program` it emitted.
+    pub fn new(source: &str, program: &[u8], spans: &[(usize, Range<usize>)]) -> Listing {
+        let mut lines = vec![];
+        let mut start = 0;
+        for line in source.split_inclusive('\n') {
+            let end = start + line.len();
+            let emitted: Vec<&Range<usize>> = spans
+                .iter()
+                .filter(|(offset, _)| (start..end).contains(offset))
+                .map(|(_, range)| range)
+                .collect();
+            let address = emitted.first().map(|range| range.start);
+            let mut bytes = vec![];
+            for range in emitted {
+                bytes.extend_from_slice(&program[range.clone()]);
+            }
+            lines.push(ListingLine {
+                address,
+                bytes,
+                source: line.trim_end().to_string(),
+            });
+            start = end;
+        }
+        Listing { lines }
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            let mut rows = line.bytes.chunks(BYTES_PER_ROW);
+            let first = rows.next().unwrap_or(&[]);
+            match line.address {
+                Some(address) => write!(f, "{:06X}  ", address)?,
+                None => write!(f, "{:6}  ", "")?,
+            }
+            writeln!(f, "{:<11}  {}", hex(first), line.source)?;
+            let mut address = line.address.unwrap_or(0) + first.len();
+            for row in rows {
+                writeln!(f, "{:06X}  {}", address, hex(row))?;
+                address += row.len();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats `bytes` as space separated pairs of hex digits.
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_listing() {
+        let source = ".data\nhi: .asciiz 'Hello'\n.code\nload $0 #7\nhlt\n";
+        let mut asm = Assembler::new();
+        let program = asm.assemble(source).unwrap();
+        let listing = asm.listing(source, &program);
+        assert_eq!(listing.lines.len(), 5);
+        assert_eq!(listing.lines[0].address, None);
+        assert_eq!(listing.lines[1].address, Some(64));
+        assert_eq!(listing.lines[1].bytes, b"Hello\0".to_vec());
+        assert_eq!(listing.lines[3].address, Some(70));
+        assert_eq!(listing.lines[3].bytes, vec![1, 0, 0, 7]);
+        assert_eq!(listing.lines[4].address, Some(74));
+        let blank = " ".repeat(21);
+        assert_eq!(
+            listing.to_string(),
+            format!(
+                "{}.data\n\
+                 000040  48 65 6C 6C  hi: .asciiz 'Hello'\n\
+                 000044  6F 00\n\
+                 {}.code\n\
+                 000046  01 00 00 07  load $0 #7\n\
+                 00004A  00 00 00 00  hlt\n",
+                blank, blank
+            )
+        );
+    }
+}
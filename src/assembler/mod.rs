@@ -1,62 +1,145 @@
 use nom::types::CompleteStr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::ops::Range;
 
-use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::assembler_errors::{AssemblerError, SourceLocation};
+use crate::assembler::expressions::Expression;
 use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::linker::{Fragment, FragmentSymbol, Relocation, Section};
+use crate::assembler::listing::Listing;
 use crate::assembler::program_parsers::*;
 use crate::assembler::symbols::{Symbol, SymbolTable, SymbolType};
-use crate::instruction::Opcode;
+use crate::debug_info::{DebugInfo, SourceLine};
+use crate::instruction::{Opcode, INSTRUCTION_LENGTH};
+
+#[macro_use]
+pub mod spans;
 
 pub mod assembler_errors;
 pub mod directive_parsers;
+pub mod expressions;
+pub mod include;
 pub mod instruction_parsers;
 pub mod label_parsers;
+pub mod linker;
+pub mod listing;
 pub mod opcode_parsers;
 pub mod operand_parsers;
 pub mod program_parsers;
 pub mod register_parsers;
 pub mod symbols;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum Token {
-    Op { code: Opcode },
-    Register { reg_num: u8 },
-    IntegerOperand { value: i32 },
-    LabelDeclaration { name: String },
-    LabelUsage { name: String },
-    Directive { name: String },
-    IrString { name: String },
+    Op {
+        code: Opcode,
+    },
+    /// A mnemonic that names no opcode, such as a typo like `lod`. It is written as `IGL`, unless
+    /// the assembler is strict, in which case it is an error.
+    UnknownOp {
+        mnemonic: String,
+    },
+    Register {
+        reg_num: u8,
+    },
+    IntegerOperand {
+        value: i32,
+    },
+    LabelDeclaration {
+        name: String,
+    },
+    /// A use of a label, such as `@loop`, or `@table+4` for an address past it.
+    LabelUsage {
+        name: String,
+        offset: i32,
+    },
+    Directive {
+        name: String,
+    },
+    IrString {
+        name: String,
+    },
+    /// The values of a directive that takes a list, such as `.byte 1, 2, 3`.
+    IntegerList {
+        values: Vec<i32>,
+    },
+    /// An integer operand given as an expression, such as `#SIZE*2`, which is replaced by an
+    /// `IntegerOperand` once it is evaluated.
+    Expression {
+        expression: Expression,
+    },
 }
 
 pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
 pub const PIE_HEADER_LENGTH: usize = 64;
+/// Where in the header the length of the read-only section is stored, as a little endian `u32`.
+/// The read-only section directly follows the header, and the code follows it.
+pub const PIE_HEADER_RO_LENGTH_OFFSET: usize = 4;
+/// Where in the header the address execution starts at is stored, as a little endian `u32`. 0 means
+/// execution starts at the first instruction.
+pub const PIE_HEADER_ENTRY_OFFSET: usize = 8;
+/// Where in the header the version of the bytecode format is stored, as a single byte.
+pub const PIE_HEADER_VERSION_OFFSET: usize = 12;
+/// Where in the header the CRC32 of the body (everything after the header) is stored, as a little
+/// endian `u32`.
+pub const PIE_HEADER_CHECKSUM_OFFSET: usize = 16;
+/// Where in the header the number of zeroed bytes `.space` reserves at the start of the heap is
+/// stored, as a little endian `u32`.
+pub const PIE_HEADER_SPACE_OFFSET: usize = 20;
+/// Where in the header the length of the debug section is stored, as a little endian `u32`. The
+/// debug section follows the code and the data section, and is empty unless the program was
+/// assembled with debug info.
+pub const PIE_HEADER_DEBUG_OFFSET: usize = 24;
+/// Where in the header the length of the data section is stored, as a little endian `u32`. The
+/// data section follows the code, and holds the initial contents of the writable `.data`
+/// declarations, whose addresses follow those of the read-only section.
+pub const PIE_HEADER_DATA_OFFSET: usize = 28;
+/// The version of the bytecode format the assembler writes and the VM runs. It must be bumped
+/// whenever the format changes in a way older VMs cannot run.
+///
+/// - 1: the prefix, the read-only section length, the entry point, and the checksum.
+/// - 2: the `.space` reservation, which older VMs would not allocate.
+/// - 3: the debug section, which older VMs would run as code.
+/// - 4: the writable data section, which older VMs would not load.
+pub const PIE_VERSION: u8 = 4;
 
 /// The `AssemblerPhase` enum details which phase an `Assembler` is in. It can be only one of
 /// two variants: `First` or `Second`.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Default)]
 pub enum AssemblerPhase {
+    #[default]
     First,
     Second,
 }
 
-impl Default for AssemblerPhase {
-    fn default() -> Self {
-        AssemblerPhase::First
-    }
-}
-
 #[derive(Debug, PartialEq, Clone)]
 pub enum AssemblerSection {
-    Data { starting_instruction: Option<u32> },
-    Code { starting_instruction: Option<u32> },
+    /// `.data`, whose `.byte` declarations are writable at run time. Strings are constants, and
+    /// are put in the read-only section wherever they are declared.
+    Data {
+        starting_instruction: Option<u32>,
+    },
+    /// `.rodata`, whose declarations are constants in the read-only section.
+    ReadOnlyData {
+        starting_instruction: Option<u32>,
+    },
+    Code {
+        starting_instruction: Option<u32>,
+    },
     Unknown,
 }
 
-impl<'a> From<&'a str> for AssemblerSection {
+impl From<&str> for AssemblerSection {
     fn from(name: &str) -> AssemblerSection {
         match name {
             "data" => AssemblerSection::Data {
                 starting_instruction: None,
             },
+            "rodata" => AssemblerSection::ReadOnlyData {
+                starting_instruction: None,
+            },
             "code" => AssemblerSection::Code {
                 starting_instruction: None,
             },
@@ -69,7 +152,7 @@ impl<'a> From<&'a str> for AssemblerSection {
 /// when assembling. The first is for passing the program string to the parser and constructing
 /// a symbol table, and the second is for converting it into the bytecode that can be read by
 /// the VM.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Assembler {
     /// Denotes which phase the Assembler is in.
     pub phase: AssemblerPhase,
@@ -77,21 +160,77 @@ pub struct Assembler {
     pub symbols: SymbolTable,
     /// The read-only data section that is used for storing constants.
     pub ro: Vec<u8>,
+    /// The initial contents of the writable data declared in `.data`.
+    pub data: Vec<u8>,
     /// The compiled bycode generated from the assembly instructions.
     pub bytecode: Vec<u8>,
     /// The current offset of the read-only section.
     ro_offset: u32,
+    /// The offset of each distinct `.asciiz` string in the read-only section, so a string declared
+    /// more than once is stored once.
+    strings: HashMap<String, u32>,
     /// A list of all sections seen in the code.
     sections: Vec<AssemblerSection>,
     /// The current section of the Assembler.
     current_section: Option<AssemblerSection>,
     /// The current instruction of the Assembler.
     current_instruction: u32,
+    /// The byte offset in the program the next opcode will be written at, not counting the
+    /// read-only section.
+    code_offset: u32,
+    /// The labels declared on instructions, which are moved past the read-only section once its
+    /// length is known.
+    code_labels: Vec<String>,
+    /// The labels declared on writable data, which are moved past the read-only section once its
+    /// length is known.
+    data_labels: Vec<String>,
+    /// The offset in the source of each declaration of writable data, and the range of `data` it
+    /// wrote, which is only placed in the program once the code is written.
+    data_spans: Vec<(usize, Range<usize>)>,
+    /// The number of bytes reserved at the start of the heap with `.space`.
+    space_length: u32,
+    /// The labels declared on `.space` reservations, which point into the heap.
+    space_labels: Vec<String>,
+    /// The constants declared with `.equ`.
+    constants: HashMap<String, i32>,
+    /// The labels exported with `.global`, and the index of the instruction exporting each.
+    globals: Vec<(String, usize)>,
+    /// The labels imported with `.extern`, which other files declare.
+    externs: Vec<String>,
+    /// Whether the code is being assembled into a fragment, whose `.extern` labels are left for
+    /// the linker to resolve.
+    linking: bool,
+    /// Whether unknown mnemonics are errors, rather than being assembled as `IGL`.
+    strict: bool,
+    /// Whether each call to `assemble_fragment` assembles more code after that of the calls
+    /// before it. See `set_incremental`.
+    incremental: bool,
+    /// Whether code before the first section header is in an implicit code section, and programs
+    /// need no other sections. See `set_relaxed`.
+    relaxed: bool,
+    /// The label named by `.entry`, if any, and the index of the instruction naming it.
+    entry_label: Option<(String, usize)>,
+    /// The address of `entry_label`, resolved at the end of the first pass.
+    entry_point: Option<u32>,
+    /// The offset in the source of each instruction or directive that emitted bytes, and the
+    /// range of the assembled program it emitted, for building listings.
+    spans: Vec<(usize, Range<usize>)>,
+    /// Each reference to a label in the code, with its offset counted from the start of the
+    /// program, so the code can be linked with other files.
+    relocations: Vec<Relocation>,
+    /// Where each line of the source came from, if a debug section should be written.
+    source_lines: Option<Vec<SourceLine>>,
     /// Errors encountered when assembling the code. These are presented to the user
     /// at the end of assembly.
     errors: Vec<AssemblerError>,
 }
 
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Assembler {
     /// Returns a new `Assembler`.
     pub fn new() -> Assembler {
@@ -99,62 +238,132 @@ impl Assembler {
             phase: AssemblerPhase::First,
             symbols: SymbolTable::new(),
             ro: vec![],
+            data: vec![],
             bytecode: vec![],
             ro_offset: 0,
+            strings: HashMap::new(),
             sections: vec![],
             current_section: None,
             current_instruction: 0,
+            code_offset: PIE_HEADER_LENGTH as u32,
+            code_labels: vec![],
+            data_labels: vec![],
+            data_spans: vec![],
+            space_length: 0,
+            space_labels: vec![],
+            constants: HashMap::new(),
+            globals: vec![],
+            externs: vec![],
+            linking: false,
+            strict: true,
+            incremental: false,
+            relaxed: false,
+            entry_label: None,
+            entry_point: None,
+            spans: vec![],
+            relocations: vec![],
+            source_lines: None,
             errors: vec![],
         }
     }
 
     /// Assembles the code into bytecode that is readable by the VM in two-passes.
     pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
-        // Pass the raw &str to the parser. Match to see if the program was parsed correctly.
-        match program(CompleteStr(raw)) {
-            Ok((_remainder, program)) => {
-                // First we grab the header for later.
-                let mut assembled_program = self.write_pie_header();
+        match parse(raw) {
+            Ok(mut program) => {
+                // Local labels are renamed so the passes can treat them like any other label.
+                self.rename_local_labels(&mut program, raw);
+                // Expressions are evaluated up front, as the value of a `load` decides how many
+                // bytes it takes.
+                self.evaluate_expressions(&mut program, raw);
+
+                if self.relaxed && self.current_section.is_none() {
+                    self.process_section_header("code");
+                }
+
                 // First pass.
-                self.process_first_phase(&program);
+                self.process_first_phase(&program, raw);
 
                 // Check for errors. If there are any, return and don't do the second pass.
                 if !self.errors.is_empty() {
                     return Err(self.errors.clone());
                 }
 
-                // Ensure we have at least one data section and one code section.
-                if self.sections.len() != 2 {
-                    println!("Did not find at least two sections.");
+                // Ensure we have at least one code section and one data section.
+                let code_sections = self
+                    .sections
+                    .iter()
+                    .filter(|s| matches!(s, AssemblerSection::Code { .. }))
+                    .count();
+                if !self.relaxed && (code_sections == 0 || code_sections == self.sections.len()) {
+                    tracing::error!("Did not find at least two sections.");
                     self.errors.push(AssemblerError::InsufficientSections);
                     return Err(self.errors.clone());
                 }
 
-                // Second pass.
-                let mut body = self.process_second_phase(&program);
-                // Merge the header with the body vector.
-                assembled_program.append(&mut body);
+                // Second pass, which writes the body directly after the header.
+                let code_length: usize = program.instructions.iter().map(|i| i.byte_length()).sum();
+                let mut assembled_program =
+                    Vec::with_capacity(PIE_HEADER_LENGTH + self.ro.len() + code_length);
+                self.write_pie_header(&mut assembled_program);
+                self.process_second_phase(&program, &mut assembled_program, raw);
+                if !self.errors.is_empty() {
+                    return Err(self.errors.clone());
+                }
+                self.write_data(&mut assembled_program);
+                self.write_debug_info(raw, &mut assembled_program);
+                write_checksum(&mut assembled_program);
                 Ok(assembled_program)
             }
-            Err(e) => {
-                println!("There was an error assembling the code: {:?}", e);
-                Err(vec![AssemblerError::ParseError {
-                    error: e.to_string(),
-                }])
-            }
+            Err(errors) => Err(errors),
         }
     }
 
+    /// Assembles the source read from `reader`, such as a file or piped input, without the caller
+    /// having to read it into a `String` first. The whole source is read before it is assembled,
+    /// as labels can be used before they are declared.
+    pub fn assemble_from_reader<R: Read>(
+        &mut self,
+        mut reader: R,
+    ) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let mut source = String::new();
+        reader.read_to_string(&mut source).map_err(|e| {
+            vec![AssemblerError::IoFailed {
+                error: e.to_string(),
+            }]
+        })?;
+        self.assemble(&source)
+    }
+
+    /// Assembles the source read from `reader` like `assemble_from_reader`, and writes the program
+    /// to `writer`, e.g. a file or stdout.
+    pub fn assemble_to_writer<R: Read, W: Write>(
+        &mut self,
+        reader: R,
+        mut writer: W,
+    ) -> Result<(), Vec<AssemblerError>> {
+        let program = self.assemble_from_reader(reader)?;
+        writer
+            .write_all(&program)
+            .and_then(|_| writer.flush())
+            .map_err(|e| {
+                vec![AssemblerError::IoFailed {
+                    error: e.to_string(),
+                }]
+            })
+    }
+
     /// First pass over the code which extracts any label declarations and directives and puts them
-    /// into segments.
-    fn process_first_phase(&mut self, p: &Program) {
+    /// into segments. Errors are given their location in `source`, the code `p` was parsed from.
+    fn process_first_phase(&mut self, p: &Program, source: &str) {
         // We iterate over all the instructions even though we are hunting for label declarations.
-        for i in &p.instructions {
+        for (index, i) in p.instructions.iter().enumerate() {
+            let errors = self.errors.len();
             if i.is_label() {
                 if self.current_section.is_some() {
                     // If we've already hit a segment header (e.g., `.code`), then we're all good to
                     // process the label.
-                    self.process_label_declaration(&i);
+                    self.process_label_declaration(i);
                 } else {
                     // If we haven't hit a segment yet, then we have an error since we have a label
                     // outside of a segment header.
@@ -164,28 +373,181 @@ impl Assembler {
                 }
             }
 
+            if let (true, Some(Token::UnknownOp { mnemonic })) = (self.strict, &i.opcode) {
+                self.errors.push(AssemblerError::UnknownOpcode {
+                    mnemonic: mnemonic.clone(),
+                    line: i.spans.instruction.line,
+                });
+            }
+
             if i.is_directive() {
+                let ro_start = self.ro.len();
+                let data_start = self.data.len();
                 self.process_directive(i);
+                // Constants are written to the read-only section, which directly follows the
+                // header. Where the writable data goes is only known once the code is written.
+                self.record_span(
+                    p,
+                    index,
+                    PIE_HEADER_LENGTH + ro_start..PIE_HEADER_LENGTH + self.ro.len(),
+                );
+                if self.data.len() > data_start {
+                    self.data_spans
+                        .push((i.spans.instruction.start, data_start..self.data.len()));
+                }
             }
+            self.locate_errors(errors, p, index, source);
+            self.code_offset += i.byte_length() as u32;
             self.current_instruction += 1;
         }
+        // The code is written after the read-only section, so labels on instructions point that
+        // much further into the program. Likewise, the writable data is addressed after it.
+        let ro_length = self.ro.len() as u32;
+        for name in self.code_labels.iter().chain(&self.data_labels) {
+            if let Some(offset) = self.symbols.symbol_value(name) {
+                self.symbols.set_symbol_offset(name, offset + ro_length);
+            }
+        }
+        if let Some((label, index)) = self.entry_label.take() {
+            if self.code_labels.contains(&label) {
+                self.entry_point = self.symbols.symbol_value(&label);
+            } else {
+                let errors = self.errors.len();
+                self.errors
+                    .push(AssemblerError::UnknownEntryLabel { label });
+                self.locate_errors(errors, p, index, source);
+            }
+        }
+        for (label, index) in self.globals.clone() {
+            if !self.symbols.has_symbol(&label) {
+                let errors = self.errors.len();
+                self.errors
+                    .push(AssemblerError::UndefinedSymbol { name: label });
+                self.locate_errors(errors, p, index, source);
+            }
+        }
         self.phase = AssemblerPhase::Second;
     }
 
-    /// Second pass over the code which converts the instructions and symbols into bytecode (`Vec<u8>`).
-    fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
+    /// Gives each declaration of a local label such as `1:` a name of its own, and points each
+    /// reference to one at the declaration it means: the closest one at or before it for `@1b`,
+    /// and the closest one after it for `@1f`.
+    fn rename_local_labels(&mut self, p: &mut Program, source: &str) {
+        let mut declarations: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, i) in p.instructions.iter_mut().enumerate() {
+            if let Some(Token::LabelDeclaration { ref mut name }) = i.label {
+                if is_local_label(name) {
+                    let indices = declarations.entry(name.clone()).or_default();
+                    *name = local_label_name(name, indices.len());
+                    indices.push(index);
+                }
+            }
+        }
+        for (index, i) in p.instructions.iter_mut().enumerate() {
+            for operand in [&mut i.operand1, &mut i.operand2, &mut i.operand3] {
+                let name = match operand {
+                    Some(Token::LabelUsage { ref mut name, .. }) => name,
+                    _ => continue,
+                };
+                let (label, forward) = match local_reference(name) {
+                    Some(reference) => reference,
+                    None => continue,
+                };
+                let indices = declarations.get(label).map_or(&[][..], |v| &v[..]);
+                let found = if forward {
+                    indices.iter().position(|&declared| declared > index)
+                } else {
+                    indices.iter().rposition(|&declared| declared <= index)
+                };
+                match found {
+                    Some(n) => *name = local_label_name(label, n),
+                    None => self.errors.push(
+                        AssemblerError::UnknownLocalLabel {
+                            label: name.clone(),
+                        }
+                        .at(source, i.spans.instruction.start),
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Declares the constants of each `.equ` and replaces each expression operand with its value.
+    /// Expressions can only use the constants declared before them.
+    fn evaluate_expressions(&mut self, p: &mut Program, source: &str) {
+        for i in p.instructions.iter_mut() {
+            let errors = self.errors.len();
+            for operand in [&mut i.operand1, &mut i.operand2, &mut i.operand3] {
+                if let Some(Token::Expression { ref expression }) = operand {
+                    match expression.evaluate(&self.constants) {
+                        Ok(value) => *operand = Some(Token::IntegerOperand { value }),
+                        Err(error) => self.errors.push(error),
+                    }
+                }
+            }
+            if let (Some("equ"), Some(Token::LabelUsage { name, .. })) =
+                (i.get_directive_name(), &i.operand1)
+            {
+                if let Some(Token::IntegerOperand { value }) = i.operand2 {
+                    if self.constants.insert(name.clone(), value).is_some() {
+                        self.errors.push(AssemblerError::SymbolAlreadyDeclared);
+                    }
+                }
+            }
+            let offset = i.spans.instruction.start;
+            let located: Vec<AssemblerError> = self
+                .errors
+                .drain(errors..)
+                .map(|error| error.at(source, offset))
+                .collect();
+            self.errors.extend(located);
+        }
+    }
+
+    /// Attaches the location of the `index`th instruction of `p` to the errors from `from` on.
+    fn locate_errors(&mut self, from: usize, p: &Program, index: usize, source: &str) {
+        if let Some(i) = p.instructions.get(index) {
+            let located: Vec<AssemblerError> = self
+                .errors
+                .drain(from..)
+                .map(|error| error.at(source, i.spans.instruction.start))
+                .collect();
+            self.errors.extend(located);
+        }
+    }
+
+    /// Second pass over the code which converts the instructions and symbols into bytecode,
+    /// appending it to `program`. Errors are given their location in `source`, the code `p` was
+    /// parsed from.
+    fn process_second_phase(&mut self, p: &Program, program: &mut Vec<u8>, source: &str) {
         // Restart the counting of instructions.
         self.current_instruction = 0;
-        // We put the bytecode up for execution in a separate Vec so we can do some
-        // post-processing before merging it with the header and read-only sections.
-        let mut program = vec![];
         // Same as first-phase, but now we care about opcodes and directives.
-        for i in &p.instructions {
+        for (index, i) in p.instructions.iter().enumerate() {
             if i.is_opcode() {
                 // Opcodes know how to properly transform themselves into 32-bits, so we can just
-                // call `to_bytes` and append it to our program.
-                let mut bytes = i.to_bytes(&self.symbols);
-                program.append(&mut bytes);
+                // have them write themselves onto the end of our program.
+                let start = program.len();
+                let errors = self.errors.len();
+                if let Err(error) = i.write_bytes(&self.symbols, program) {
+                    self.errors.push(error);
+                }
+                self.record_span(p, index, start..program.len());
+                for (at, name, addend, kind) in i.label_references() {
+                    let imported = self.linking && self.externs.iter().any(|e| e == name);
+                    if !imported && !self.symbols.has_symbol(name) {
+                        self.errors.push(AssemblerError::UndefinedSymbol {
+                            name: name.to_string(),
+                        });
+                    }
+                    self.relocations.push(Relocation {
+                        offset: (start + at) as u32,
+                        symbol: name.to_string(),
+                        addend,
+                        kind,
+                    });
+                }
+                self.locate_errors(errors, p, index, source);
             }
             if i.is_directive() {
                 // We are looking for different types of directives than gathered on the first pass.
@@ -194,7 +556,78 @@ impl Assembler {
             }
             self.current_instruction += 1;
         }
-        program
+    }
+
+    /// Records that the `index`th instruction of `p` emitted the bytes in `range` of the program.
+    fn record_span(&mut self, p: &Program, index: usize, range: Range<usize>) {
+        if let (Some(i), false) = (p.instructions.get(index), range.is_empty()) {
+            self.spans.push((i.spans.instruction.start, range));
+        }
+    }
+
+    /// Sets whether unknown mnemonics are errors, which they are by default. When they are not,
+    /// they are assembled as `IGL`, which crashes the VM when it is executed.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Sets whether programs can leave out their sections, which they cannot by default. In relaxed
+    /// mode, the code before the first section header is in a code section, so a snippet such as
+    /// `load $0 #1` assembles as it is, and a program needs neither `.code` nor `.data`.
+    pub fn set_relaxed(&mut self, relaxed: bool) {
+        self.relaxed = relaxed;
+    }
+
+    /// Makes the assembler write a debug section after the code, mapping each instruction to the
+    /// line in `lines` it was assembled from. `lines` holds the origin of each line of the source,
+    /// as returned by `include::read_source_lines`.
+    pub fn emit_debug_info(&mut self, lines: Vec<SourceLine>) {
+        self.source_lines = Some(lines);
+    }
+
+    /// Appends the debug section to `program`, assembled from `source`, and records its length in
+    /// the header, if debug info was asked for.
+    fn write_debug_info(&self, source: &str, program: &mut Vec<u8>) {
+        let lines = match self.source_lines {
+            Some(ref lines) => lines,
+            None => return,
+        };
+        let code = PIE_HEADER_LENGTH + self.ro.len()..program.len() - self.data.len();
+        let mut info = DebugInfo::default();
+        for (offset, range) in self.spans.iter().filter(|(_, r)| code.contains(&r.start)) {
+            let line = SourceLocation::new(source, *offset).line;
+            if let Some(origin) = lines.get(line - 1) {
+                for address in range.clone().step_by(INSTRUCTION_LENGTH) {
+                    info.lines.push((address as u32, origin.clone()));
+                }
+            }
+        }
+        for name in self.code_labels.iter().filter(|name| !name.contains('.')) {
+            if let Some(address) = self.symbols.symbol_value(name) {
+                info.labels.push((address, name.clone()));
+            }
+        }
+        info.labels.sort();
+        let section = info.to_bytes();
+        program[PIE_HEADER_DEBUG_OFFSET..PIE_HEADER_DEBUG_OFFSET + 4]
+            .copy_from_slice(&(section.len() as u32).to_le_bytes());
+        program.extend_from_slice(&section);
+    }
+
+    /// Appends the writable data to `program`, after the code.
+    fn write_data(&mut self, program: &mut Vec<u8>) {
+        let start = program.len();
+        program.extend_from_slice(&self.data);
+        for (offset, range) in self.data_spans.drain(..) {
+            self.spans
+                .push((offset, start + range.start..start + range.end));
+        }
+    }
+
+    /// Returns a listing of `program`, which must have been assembled by this assembler from
+    /// `source`, showing each source line with its address and the bytes emitted for it.
+    pub fn listing(&self, source: &str, program: &[u8]) -> Listing {
+        Listing::new(source, program, &self.spans)
     }
 
     /// Processes label declarations such as `hello: .asciiz 'Hello'`.
@@ -212,12 +645,25 @@ impl Assembler {
         };
 
         // Check if label is already in use (i.e. has an entry in the symbol table).
-        if self.symbols.has_symbol(&name) {
+        let id = self.symbols.intern(name);
+        if self.symbols.symbol(id).is_some() {
             self.errors.push(AssemblerError::SymbolAlreadyDeclared);
             return;
         }
 
-        let symbol = Symbol::new(name, SymbolType::Label);
+        let symbol_type = if name.contains('.') {
+            SymbolType::LocalLabel
+        } else {
+            SymbolType::Label
+        };
+        // Labels on instructions point at the instruction's address. Labels on constants are given
+        // their offset when the constant is read.
+        let symbol = if i.is_opcode() {
+            self.code_labels.push(name.to_string());
+            Symbol::new_with_offset(name.to_string(), symbol_type, self.code_offset)
+        } else {
+            Symbol::new(name.to_string(), symbol_type)
+        };
         self.symbols.add_symbol(symbol);
     }
 
@@ -226,27 +672,51 @@ impl Assembler {
         let directive_name = match i.get_directive_name() {
             Some(name) => name,
             None => {
-                println!("Directive has an invalid name: {:?}", i);
+                tracing::warn!("Directive has an invalid name: {:?}", i);
                 return;
             }
         };
         // Now check for any operands.
         if i.has_operands() {
             // If yes, determine the directive.
-            match directive_name.as_ref() {
+            match directive_name {
                 "asciiz" => {
-                    self.handle_asciiz(i);
+                    self.handle_string(i, true);
+                }
+                "ascii" => {
+                    self.handle_string(i, false);
+                }
+                "byte" => {
+                    self.handle_byte(i);
+                }
+                "space" => {
+                    self.handle_space(i);
+                }
+                "entry" => {
+                    self.handle_entry(i);
+                }
+                // Constants are declared while evaluating expressions, before either pass.
+                "equ" => {}
+                "global" => {
+                    if let Some(label) = self.symbol_directive_label(i, "global") {
+                        self.globals
+                            .push((label, self.current_instruction as usize));
+                    }
+                }
+                "extern" => {
+                    if let Some(label) = self.symbol_directive_label(i, "extern") {
+                        self.externs.push(label);
+                    }
                 }
                 _ => {
                     self.errors.push(AssemblerError::UnknownDirectiveFound {
-                        directive: directive_name.clone(),
+                        directive: directive_name.to_string(),
                     });
-                    return;
                 }
             }
         } else {
             // If not, then it's a section header.
-            self.process_section_header(&directive_name);
+            self.process_section_header(directive_name);
         }
     }
 
@@ -255,15 +725,63 @@ impl Assembler {
         let new_section: AssemblerSection = header_name.into();
         // Only specific names are allowed.
         if new_section == AssemblerSection::Unknown {
-            println!("Found a section header that is unknown: {:#?}", header_name);
+            tracing::warn!("Found a section header that is unknown: {:?}", header_name);
             return;
         }
         self.sections.push(new_section.clone());
         self.current_section = Some(new_section);
     }
 
-    /// Handles a declaration of a null-terminated string (e.g. `hello: .asciiz 'Hello!'`)
-    fn handle_asciiz(&mut self, i: &AssemblerInstruction) {
+    /// Handles the declaration of the label execution starts at (e.g. `.entry main`).
+    fn handle_entry(&mut self, i: &AssemblerInstruction) {
+        // The label's address is only known once the first pass is over.
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+        let label = match i.operand1 {
+            Some(Token::LabelUsage {
+                ref name,
+                offset: 0,
+            }) => name.clone(),
+            _ => {
+                self.errors.push(AssemblerError::UnknownDirectiveFound {
+                    directive: "entry".to_string(),
+                });
+                return;
+            }
+        };
+        if self.entry_label.is_some() {
+            self.errors.push(AssemblerError::EntryAlreadyDeclared);
+            return;
+        }
+        self.entry_label = Some((label, self.current_instruction as usize));
+    }
+
+    /// Returns the label named by a `.global` or `.extern` directive in the first pass. These
+    /// only change how the labels are resolved, so there is nothing to do in the second.
+    fn symbol_directive_label(&mut self, i: &AssemblerInstruction, name: &str) -> Option<String> {
+        if self.phase != AssemblerPhase::First {
+            return None;
+        }
+        match i.operand1 {
+            Some(Token::LabelUsage {
+                ref name,
+                offset: 0,
+            }) => Some(name.clone()),
+            _ => {
+                self.errors.push(AssemblerError::UnknownDirectiveFound {
+                    directive: name.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    /// Handles a declaration of a string, null-terminated for `.asciiz` (e.g. `hello: .asciiz
+    /// 'Hello!'`) and not for `.ascii`, whose length is usually stored alongside it. An `.asciiz`
+    /// string that was already declared is not stored again; its label points at the first copy.
+    /// Strings are constants, so they are put in the read-only section even in `.data`.
+    fn handle_string(&mut self, i: &AssemblerInstruction, null_terminated: bool) {
         // Being a constant declaration, this is only meaningful in the first pass.
         if self.phase != AssemblerPhase::First {
             return;
@@ -272,73 +790,376 @@ impl Assembler {
         // Operand1 will have the entire string we need to read into RO memory.
         match i.get_string_constant() {
             Some(s) => {
-                match i.get_label_name() {
-                    Some(name) => {
-                        self.symbols.set_symbol_offset(&name, self.ro_offset);
-                    }
+                let name = match i.get_label_name() {
+                    Some(name) => name,
                     None => {
                         // This would be someting typing: .asciiz 'Hello!'
-                        println!("Found a string constant with no associated label!");
+                        tracing::warn!("Found a string constant with no associated label!");
                         return;
                     }
                 };
+                if null_terminated {
+                    if let Some(&offset) = self.strings.get(s) {
+                        self.symbols.set_symbol_offset(name, offset);
+                        return;
+                    }
+                    self.strings.insert(s.to_string(), self.ro_offset);
+                }
+                self.symbols.set_symbol_offset(name, self.ro_offset);
                 // We'll read the string into the read-only section byte-by-byte.
                 for byte in s.as_bytes() {
                     self.ro.push(*byte);
                     self.ro_offset += 1;
                 }
                 // This is the null termination bit we are using to indicate a string has ended.
-                self.ro.push(0);
-                self.ro_offset += 1;
+                if null_terminated {
+                    self.ro.push(0);
+                    self.ro_offset += 1;
+                }
             }
             None => {
                 // This just means someone typed `.asciiz` for some reason.
-                println!("String constant following an .asciiz was empty");
+                tracing::warn!("String constant following an .asciiz or .ascii was empty");
             }
         };
     }
 
-    /// Extracts the labels for the program by looking for label declarations (e.g. `some_name:<opcode>...`).
-    fn extract_labels(&mut self, p: &Program) {
-        let mut c = 0;
-        for i in &p.instructions {
-            if i.is_label() {
-                match i.get_label_name() {
-                    Some(name) => {
-                        let symbol = Symbol::new_with_offset(name, SymbolType::Label, c);
-                        self.symbols.add_symbol(symbol);
-                    }
-                    None => {}
-                };
-                c += 4;
+    /// Handles a declaration of raw bytes (e.g. `table: .byte 1, 2, 3, 255`). The label, if any,
+    /// points at the first byte; without one, the bytes continue the previous declaration.
+    fn handle_byte(&mut self, i: &AssemblerInstruction) {
+        // Being a constant declaration, this is only meaningful in the first pass.
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+        let values = match i.operand1 {
+            Some(Token::IntegerList { ref values }) => values,
+            _ => {
+                self.errors.push(AssemblerError::UnknownDirectiveFound {
+                    directive: "byte".to_string(),
+                });
+                return;
+            }
+        };
+        if let Some(&value) = values.iter().find(|v| !(0..=255).contains(*v)) {
+            self.errors.push(AssemblerError::ByteOutOfRange { value });
+            return;
+        }
+        let bytes: Vec<u8> = values.iter().map(|v| *v as u8).collect();
+        match i.get_label_name() {
+            Some(name) if self.in_writable_data() => self.declare_data(name, &bytes),
+            None if self.in_writable_data() => self.data.extend_from_slice(&bytes),
+            label => {
+                if let Some(name) = label {
+                    self.symbols.set_symbol_offset(name, self.ro_offset);
+                }
+                self.ro.extend_from_slice(&bytes);
+                self.ro_offset += bytes.len() as u32;
             }
         }
     }
 
-    /// Writes the PIE header which is 4 bytes long. The remaining 60 bytes are padded with 0s
-    /// so they can be used later on.
-    fn write_pie_header(&self) -> Vec<u8> {
-        let mut header = vec![];
-        for byte in PIE_HEADER_PREFIX.iter() {
-            header.push(byte.clone());
+    /// Returns whether the declarations being read are writable data, i.e. are in `.data`.
+    fn in_writable_data(&self) -> bool {
+        matches!(self.current_section, Some(AssemblerSection::Data { .. }))
+    }
+
+    /// Declares the writable data `bytes`, pointing the label `name` at their offset in it.
+    fn declare_data(&mut self, name: &str, bytes: &[u8]) {
+        self.symbols.set_symbol_offset(name, self.data.len() as u32);
+        self.data_labels.push(name.to_string());
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Handles a reservation of zeroed memory (e.g. `buf: .space 256`). The bytes are not written
+    /// into the program; the VM reserves them at the start of the heap, and the label points at
+    /// their offset there.
+    fn handle_space(&mut self, i: &AssemblerInstruction) {
+        // Being a declaration, this is only meaningful in the first pass.
+        if self.phase != AssemblerPhase::First {
+            return;
         }
-        while header.len() < PIE_HEADER_LENGTH {
-            header.push(0 as u8);
+        let value = match i.operand1 {
+            Some(Token::IntegerOperand { value }) => value,
+            _ => {
+                self.errors.push(AssemblerError::UnknownDirectiveFound {
+                    directive: "space".to_string(),
+                });
+                return;
+            }
+        };
+        let space_length = if value < 0 {
+            None
+        } else {
+            self.space_length.checked_add(value as u32)
+        };
+        let space_length = match space_length {
+            Some(space_length) => space_length,
+            None => {
+                self.errors.push(AssemblerError::SpaceOutOfRange { value });
+                return;
+            }
+        };
+        if let Some(name) = i.get_label_name() {
+            self.symbols.set_symbol_offset(name, self.space_length);
+            self.space_labels.push(name.to_string());
+        }
+        self.space_length = space_length;
+    }
+
+    /// Writes the PIE header, which starts with the 4 byte prefix followed by the length of the
+    /// read-only section, the entry point, the format version, once the body is written its
+    /// checksum, the number of bytes reserved with `.space`, the length of the debug section if
+    /// there is one, and the length of the writable data. The rest of its 64 bytes are padded with
+    /// 0s so they can be used later on. The read-only section is written directly after it.
+    fn write_pie_header(&self, program: &mut Vec<u8>) {
+        write_pie_header(
+            program,
+            &self.ro,
+            self.entry_point,
+            self.space_length,
+            self.data.len() as u32,
+        );
+    }
+
+    /// Assembles the code of one file of a larger program into a `Fragment`, to be linked with
+    /// the fragments of the other files by `linker::link`. Labels the file imports with `.extern`
+    /// are left for the linker to resolve, and only those it exports with `.global` can be used
+    /// by the other files. In incremental mode, it assembles more code instead; see
+    /// `set_incremental`.
+    pub fn assemble_fragment(&mut self, raw: &str) -> Result<Fragment, Vec<AssemblerError>> {
+        if self.incremental {
+            let checkpoint = self.clone();
+            let fragment = self.assemble_increment(raw);
+            if fragment.is_err() {
+                *self = checkpoint;
+            }
+            return fragment;
+        }
+        self.linking = true;
+        let program = self.assemble(raw)?;
+        let code_start = PIE_HEADER_LENGTH + self.ro.len();
+        let debug_length = u32::from_le_bytes([
+            program[PIE_HEADER_DEBUG_OFFSET],
+            program[PIE_HEADER_DEBUG_OFFSET + 1],
+            program[PIE_HEADER_DEBUG_OFFSET + 2],
+            program[PIE_HEADER_DEBUG_OFFSET + 3],
+        ]) as usize;
+        let mut symbols = vec![];
+        for symbol in self.symbols.symbols() {
+            let value = match symbol.offset() {
+                Some(value) => value,
+                None => continue,
+            };
+            let name = symbol.name().to_string();
+            let (section, offset) = if self.code_labels.contains(&name) {
+                (Section::Code, value - code_start as u32)
+            } else if self.space_labels.contains(&name) {
+                (Section::Space, value)
+            } else if self.data_labels.contains(&name) {
+                (Section::Data, value - self.ro.len() as u32)
+            } else {
+                (Section::ReadOnly, value)
+            };
+            symbols.push(FragmentSymbol {
+                global: self.globals.iter().any(|(global, _)| *global == name),
+                name,
+                section,
+                offset,
+            });
         }
-        header
+        Ok(Fragment {
+            ro: self.ro.clone(),
+            code: program[code_start..program.len() - debug_length - self.data.len()].to_vec(),
+            data: self.data.clone(),
+            space_length: self.space_length,
+            entry_point: self.entry_point.map(|entry| entry - code_start as u32),
+            symbols,
+            relocations: self
+                .relocations
+                .iter()
+                .map(|r| Relocation {
+                    offset: r.offset - code_start as u32,
+                    ..r.clone()
+                })
+                .collect(),
+        })
+    }
+
+    /// Assembles `raw` after the code of the previous calls to `assemble_fragment`, for
+    /// incremental mode.
+    fn assemble_increment(&mut self, raw: &str) -> Result<Fragment, Vec<AssemblerError>> {
+        let mut program = parse(raw)?;
+        self.phase = AssemblerPhase::First;
+        if self.current_section.is_none() {
+            self.process_section_header("code");
+        }
+        let start = self.code_offset as usize;
+        self.rename_local_labels(&mut program, raw);
+        self.evaluate_expressions(&mut program, raw);
+        let labels = self.code_labels.len();
+        self.process_first_phase(&program, raw);
+        // The code of the earlier calls has already been placed, so nothing can go before it.
+        if !self.ro.is_empty() || !self.data.is_empty() || self.space_length > 0 {
+            self.errors.push(AssemblerError::IncrementalDeclaration);
+        }
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        }
+        // The new code is written where it goes in the whole program, so the addresses the
+        // second pass records are right, and then split off.
+        let mut code = vec![0; start];
+        self.process_second_phase(&program, &mut code, raw);
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        }
+        self.spans.clear();
+        self.relocations.clear();
+        let symbols = self.code_labels[labels..]
+            .iter()
+            .filter_map(|name| {
+                Some(FragmentSymbol {
+                    name: name.clone(),
+                    section: Section::Code,
+                    offset: self.symbols.symbol_value(name)? - start as u32,
+                    global: false,
+                })
+            })
+            .collect();
+        Ok(Fragment {
+            code: code.split_off(start),
+            symbols,
+            ..Fragment::default()
+        })
+    }
+
+    /// Turns incremental mode on or off, for interactive use such as the REPL. In incremental
+    /// mode, each call to `assemble_fragment` assembles code to be appended to that of the calls
+    /// before it, in a program with an empty read-only section. The labels and constants declared
+    /// by earlier calls can be used, so a label can be declared in one call and jumped to in a
+    /// later one. The code needs no sections and cannot declare data, and a call that fails
+    /// leaves the assembler as it was. The fragments hold only the new code, with each label
+    /// already resolved, and the labels the code declares.
+    pub fn set_incremental(&mut self, incremental: bool) {
+        self.incremental = incremental;
+    }
+
+    /// Leaves room for `length` bytes of code added to the program without the assembler, in
+    /// incremental mode, so the code assembled next, and the labels it declares, go after them.
+    pub fn skip_code(&mut self, length: usize) {
+        self.code_offset += length as u32;
     }
 }
 
+/// Parses `raw`, reporting each line that is not an instruction or directive.
+fn parse(raw: &str) -> Result<Program, Vec<AssemblerError>> {
+    match program(CompleteStr(raw)) {
+        Ok((_remainder, program)) => {
+            // Report every line the parser had to skip.
+            if !program.unparsed.is_empty() {
+                tracing::error!("Unable to parse {} lines", program.unparsed.len());
+                return Err(program
+                    .unparsed
+                    .iter()
+                    .map(|&offset| {
+                        AssemblerError::ParseError {
+                            error: "Not an instruction or directive".to_string(),
+                        }
+                        .at(raw, offset)
+                    })
+                    .collect());
+            }
+            Ok(program)
+        }
+        Err(e) => {
+            tracing::error!("There was an error assembling the code: {:?}", e);
+            Err(vec![AssemblerError::ParseError {
+                error: e.to_string(),
+            }
+            .at(raw, raw.len() - raw.trim_start().len())])
+        }
+    }
+}
+
+/// Writes the PIE header for a program with the read-only section `ro`, followed by `ro` itself.
+/// See `Assembler::write_pie_header`.
+pub(crate) fn write_pie_header(
+    program: &mut Vec<u8>,
+    ro: &[u8],
+    entry_point: Option<u32>,
+    space_length: u32,
+    data_length: u32,
+) {
+    let start = program.len();
+    program.extend_from_slice(&PIE_HEADER_PREFIX);
+    program.extend_from_slice(&(ro.len() as u32).to_le_bytes());
+    program.extend_from_slice(&entry_point.unwrap_or(0).to_le_bytes());
+    program.push(PIE_VERSION);
+    program.resize(start + PIE_HEADER_SPACE_OFFSET, 0);
+    program.extend_from_slice(&space_length.to_le_bytes());
+    program.resize(start + PIE_HEADER_DATA_OFFSET, 0);
+    program.extend_from_slice(&data_length.to_le_bytes());
+    program.resize(start + PIE_HEADER_LENGTH, 0);
+    program.extend_from_slice(ro);
+}
+
+/// Returns whether `name` is a local label, which is named with digits only.
+fn is_local_label(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Splits a reference to a local label, such as `1b` or `1f`, into the label and whether it refers
+/// forwards.
+fn local_reference(name: &str) -> Option<(&str, bool)> {
+    let (label, forward) = match name.strip_suffix('f') {
+        Some(label) => (label, true),
+        None => (name.strip_suffix('b')?, false),
+    };
+    if is_local_label(label) {
+        Some((label, forward))
+    } else {
+        None
+    }
+}
+
+/// Returns the name given to the `n`th declaration of a local label. Labels cannot contain a `.`,
+/// so it never clashes with another label.
+fn local_label_name(label: &str, n: usize) -> String {
+    format!("{}.{}", label, n)
+}
+
+/// Writes the CRC32 of the body of `program` into its header. Tools that patch an assembled
+/// program must call it again, or the VM will refuse to run the result.
+///
+/// # Panics
+///
+/// Panics if `program` is shorter than a header.
+pub fn write_checksum(program: &mut [u8]) {
+    let checksum = crc32fast::hash(&program[PIE_HEADER_LENGTH..]);
+    program[PIE_HEADER_CHECKSUM_OFFSET..PIE_HEADER_CHECKSUM_OFFSET + 4]
+        .copy_from_slice(&checksum.to_le_bytes());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::assembler_errors::SourceLocation;
     use crate::vm::VM;
 
+    #[test]
+    fn test_assemble_preallocates() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".data\n.code\nload $0 #100\nload $1 #1\nhlt")
+            .unwrap();
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 12);
+        assert_eq!(program.capacity(), program.len());
+    }
+
     #[test]
     fn test_assemble_program() {
         let mut asm = Assembler::new();
         let test_string =
-            ".data\n.code\nload $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\njmpe @test\nhlt";
+            ".data\n.code\nload $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\njeq @test\nhlt";
         let program = asm.assemble(test_string).unwrap();
         let mut vm = VM::new();
         assert_eq!(program.len(), 92);
@@ -346,18 +1167,80 @@ mod tests {
         assert_eq!(vm.program.len(), 92);
     }
 
+    #[test]
+    fn test_invalid_operand() {
+        let errors = Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\nload $1 'hi'")
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].location().unwrap().line, 4);
+        assert!(matches!(
+            errors[0].without_location(),
+            AssemblerError::InvalidOperand { .. }
+        ));
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let source = ".data\n.code\nload $0 #1\nlod $1 #2\nhlt";
+        let errors = Assembler::new().assemble(source).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].without_location(),
+            &AssemblerError::UnknownOpcode {
+                mnemonic: "lod".to_string(),
+                line: 4
+            }
+        );
+        assert_eq!(errors[0].location().unwrap().line, 4);
+        // Outside of strict mode, it is assembled as `IGL`.
+        let mut asm = Assembler::new();
+        asm.set_strict(false);
+        let program = asm.assemble(source).unwrap();
+        let code = PIE_HEADER_LENGTH + 4;
+        assert_eq!(&program[code..code + 4], &[Opcode::IGL as u8, 1, 0, 2]);
+    }
+
+    #[test]
+    fn test_code_label_offsets() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".data\n.code\nload $0 #1\nloop: add $0 $0 $0\njmp $0\nend: hlt")
+            .unwrap();
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 16);
+        assert_eq!(asm.symbols.symbol_value("loop"), Some(68));
+        assert_eq!(asm.symbols.symbol_value("end"), Some(76));
+    }
+
+    #[test]
+    fn test_jumps_to_labels_after_ro_data() {
+        let program = Assembler::new()
+            .assemble(
+                ".data\nhi: .asciiz 'Hello'\n.code\nload $0 #1\njmp @skip\nload $0 #2\n\
+                 skip: load $1 #3\nload $2 #1\neq $0 $2\njeq @done\nload $1 #4\ndone: hlt",
+            )
+            .unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        assert_eq!(
+            vm.run().last().unwrap().event,
+            crate::vm::VMEventType::GracefulStop { code: 0 }
+        );
+        assert_eq!(&vm.registers[..2], &[1, 3]);
+    }
+
     #[test]
     fn test_symbol_table() {
         let mut sym = SymbolTable::new();
         let new_symbol = Symbol::new_with_offset(String::from("test"), SymbolType::Label, 12);
         sym.add_symbol(new_symbol);
-        assert_eq!(sym.symbols.len(), 1);
+        assert_eq!(sym.symbols().len(), 1);
         let v = sym.symbol_value("test");
-        assert_eq!(true, v.is_some());
+        assert!(v.is_some());
         let v = v.unwrap();
         assert_eq!(v, 12);
         let v = sym.symbol_value("does_not_exist");
-        assert_eq!(v.is_some(), false);
+        assert!(v.is_none());
     }
 
     #[test]
@@ -365,7 +1248,472 @@ mod tests {
         let mut asm = Assembler::new();
         let test_string = ".data\ntest: .asciiz 'This is a test'\n.code\n";
         let program = asm.assemble(test_string);
-        assert_eq!(program.is_ok(), true);
+        assert!(program.is_ok());
+    }
+
+    #[test]
+    fn test_string_deduplication() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".data\na: .asciiz 'Hi'\nb: .ascii 'Hi'\nc: .asciiz 'Hi'\nd: .asciiz 'Ho'\n.code\nhlt\n")
+            .unwrap();
+        assert_eq!(asm.ro, b"Hi\0HiHo\0".to_vec());
+        assert_eq!(asm.symbols.symbol_value("a"), Some(0));
+        assert_eq!(asm.symbols.symbol_value("b"), Some(3));
+        assert_eq!(asm.symbols.symbol_value("c"), Some(0));
+        assert_eq!(asm.symbols.symbol_value("d"), Some(5));
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 8 + 4);
+    }
+
+    #[test]
+    fn test_ro_section_in_binary() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".data\nhi: .asciiz 'Hi'\n.code\nstart: prts @hi\njmp $0")
+            .unwrap();
+        assert_eq!(&program[4..8], &[3, 0, 0, 0]);
+        assert_eq!(&program[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 3], b"Hi\0");
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 3 + 8);
+        assert_eq!(asm.symbols.symbol_value("hi"), Some(0));
+        assert_eq!(asm.symbols.symbol_value("start"), Some(67));
+    }
+
+    #[test]
+    fn test_label_arithmetic() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".rodata\ntable: .byte 1, 2, 3, 4\n.code\nload $0 @table+2\nend: jmpl @end-4")
+            .unwrap();
+        let code = PIE_HEADER_LENGTH + 4;
+        assert_eq!(&program[code..code + 4], &[Opcode::LOAD as u8, 0, 0, 2]);
+        // `end` is at 72, so the jump goes back to the load at 68.
+        assert_eq!(
+            &program[code + 4..code + 8],
+            &[Opcode::JMPL as u8, 0, 0, 68]
+        );
+    }
+
+    #[test]
+    fn test_load_address() {
+        // Loading a label puts its value in the register: the offset of data in the read-only
+        // section, and the address of code.
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(
+                ".data
+hi: .asciiz 'Hi'
+bye: .asciiz 'Bye'
+.code
+load $0 @bye
+start: load $1 @start
+prts $0
+hlt",
+            )
+            .unwrap();
+        let code = PIE_HEADER_LENGTH + 7;
+        assert_eq!(&program[code..code + 4], &[Opcode::LOAD as u8, 0, 0, 3]);
+        assert_eq!(
+            &program[code + 4..code + 8],
+            &[Opcode::LOAD as u8, 1, 0, 75]
+        );
+        assert_eq!(
+            &program[code + 8..code + 12],
+            &[Opcode::PRTSR as u8, 0, 0, 0]
+        );
+        // The loads are relocated when the file is linked after another.
+        let fragment = Assembler::new()
+            .assemble_fragment(
+                ".data
+bye: .asciiz 'Bye'
+.code
+load $0 @bye
+prts $0
+hlt",
+            )
+            .unwrap();
+        let first = Assembler::new()
+            .assemble_fragment(
+                ".data
+hi: .asciiz 'Hi'
+.code
+hlt",
+            )
+            .unwrap();
+        let program = linker::link(&[first, fragment]).unwrap();
+        let code = PIE_HEADER_LENGTH + 7;
+        assert_eq!(&program[code + 4..code + 8], &[Opcode::LOAD as u8, 0, 0, 3]);
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".data\n.code\n1: jmpl @1f\n1: jmpl @1b\n2: jmpl @1f\n1: jmpl @2b\nhlt")
+            .unwrap();
+        let code = PIE_HEADER_LENGTH;
+        let targets: Vec<u8> = program[code..code + 16]
+            .chunks(4)
+            .map(|instruction| instruction[3])
+            .collect();
+        assert_eq!(targets, vec![68, 68, 76, 72]);
+        assert_eq!(asm.symbols.symbols_of_type(&SymbolType::Label).count(), 0);
+        assert_eq!(
+            asm.symbols.symbols_of_type(&SymbolType::LocalLabel).count(),
+            4
+        );
+
+        let errors = Assembler::new()
+            .assemble(".data\n.code\n1: jmpl @1b\njmpl @1f\nhlt")
+            .unwrap_err();
+        assert_eq!(
+            errors[0].without_location(),
+            &AssemblerError::UnknownLocalLabel {
+                label: "1f".to_string()
+            }
+        );
+        assert_eq!(errors[0].location().unwrap().line, 4);
+    }
+
+    #[test]
+    fn test_entry_directive() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(
+                ".data\nhi: .asciiz 'Hi'\n.code\n.entry main\nhelper: hlt\nmain: prts @hi\nhlt",
+            )
+            .unwrap();
+        assert_eq!(asm.symbols.symbol_value("main"), Some(71));
+        assert_eq!(
+            &program[PIE_HEADER_ENTRY_OFFSET..PIE_HEADER_ENTRY_OFFSET + 4],
+            &[71, 0, 0, 0]
+        );
+
+        // Without `.entry` the field is left as 0.
+        let program = Assembler::new().assemble(".data\n.code\nhlt").unwrap();
+        assert_eq!(
+            &program[PIE_HEADER_ENTRY_OFFSET..PIE_HEADER_ENTRY_OFFSET + 4],
+            &[0; 4]
+        );
+
+        let errors = Assembler::new()
+            .assemble(".data\nhi: .asciiz 'Hi'\n.code\n.entry hi\nhlt")
+            .unwrap_err();
+        assert_eq!(
+            errors[0].without_location(),
+            &AssemblerError::UnknownEntryLabel {
+                label: "hi".to_string()
+            }
+        );
+        assert_eq!(errors[0].location().unwrap().line, 4);
+        let errors = Assembler::new()
+            .assemble(".data\n.code\n.entry a\n.entry a\na: hlt")
+            .unwrap_err();
+        assert_eq!(
+            errors[0].without_location(),
+            &AssemblerError::EntryAlreadyDeclared
+        );
+    }
+
+    #[test]
+    fn test_ascii_directive() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".rodata\nname: .ascii \"Bob's\"\nlen: .byte 5\nhi: .asciiz 'Hi'\n.code\nhlt")
+            .unwrap();
+        assert_eq!(asm.symbols.symbol_value("len"), Some(5));
+        assert_eq!(asm.symbols.symbol_value("hi"), Some(6));
+        assert_eq!(
+            &program[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 9],
+            b"Bob's\x05Hi\0"
+        );
+    }
+
+    #[test]
+    fn test_byte_directive() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".rodata\nhi: .asciiz 'Hi'\ntable: .byte 1, 2, 3, 255\n.byte 4\n.code\nhlt")
+            .unwrap();
+        assert_eq!(asm.symbols.symbol_value("table"), Some(3));
+        assert_eq!(
+            &program[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 8],
+            &[b'H', b'i', 0, 1, 2, 3, 255, 4]
+        );
+        assert_eq!(&program[4..8], &[8, 0, 0, 0]);
+
+        let errors = Assembler::new()
+            .assemble(".data\ntable: .byte 1, 256\n.code\nhlt")
+            .unwrap_err();
+        assert_eq!(
+            errors[0].without_location(),
+            &AssemblerError::ByteOutOfRange { value: 256 }
+        );
+    }
+
+    #[test]
+    fn test_space_directive() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".data\nhi: .asciiz 'Hi'\nbuf: .space 10\nnext: .space 6\n.code\nhlt")
+            .unwrap();
+        assert_eq!(asm.symbols.symbol_value("buf"), Some(0));
+        assert_eq!(asm.symbols.symbol_value("next"), Some(10));
+        // The reserved bytes are not part of the program.
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 3 + 4);
+        assert_eq!(
+            &program[PIE_HEADER_SPACE_OFFSET..PIE_HEADER_SPACE_OFFSET + 4],
+            &[16, 0, 0, 0]
+        );
+        // Negative sizes, and reservations that add up to more than a `u32`, are rejected.
+        for source in &[
+            ".data\nbuf: .space -1\n.code\nhlt",
+            ".data\na: .space 2147483647\nb: .space 2147483647\nc: .space 2\n.code\nhlt",
+        ] {
+            let errors = Assembler::new().assemble(source).unwrap_err();
+            assert!(matches!(
+                errors[0].without_location(),
+                AssemblerError::SpaceOutOfRange { .. }
+            ));
+        }
+    }
+
+    #[test]
+    fn test_assemble_from_reader() {
+        let source = ".rodata\nhi: .asciiz 'Hi'\n.code\nprts @hi\nhlt\n";
+        let expected = Assembler::new().assemble(source).unwrap();
+        let program = Assembler::new()
+            .assemble_from_reader(source.as_bytes())
+            .unwrap();
+        assert_eq!(program, expected);
+        let mut output = vec![];
+        Assembler::new()
+            .assemble_to_writer(source.as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(output, expected);
+
+        // Errors in the source are reported as usual, and nothing is written.
+        let mut output = vec![];
+        let errors = Assembler::new()
+            .assemble_to_writer(".code\nhlt\n".as_bytes(), &mut output)
+            .unwrap_err();
+        assert_eq!(errors, vec![AssemblerError::InsufficientSections]);
+        assert!(output.is_empty());
+        let errors = Assembler::new()
+            .assemble_from_reader(&[0xff, 0xfe][..])
+            .unwrap_err();
+        assert!(matches!(errors[0], AssemblerError::IoFailed { .. }));
+    }
+
+    #[test]
+    fn test_incremental() {
+        let mut asm = Assembler::new();
+        asm.set_incremental(true);
+        let first = asm.assemble_fragment("load $0 #1\nloop: inc $0").unwrap();
+        assert_eq!(first.code.len(), 8);
+        assert_eq!(first.symbols[0].name, "loop");
+        assert_eq!(first.symbols[0].offset, 4);
+        // Later code follows the earlier code, and can jump back to its labels.
+        let second = asm.assemble_fragment("jmpl @loop").unwrap();
+        assert_eq!(second.code, vec![Opcode::JMPL as u8, 0, 0, 68]);
+        // A call that fails leaves nothing behind, not even the labels it declared.
+        assert!(asm.assemble_fragment("end: jmpl @missing").is_err());
+        let third = asm.assemble_fragment("end: hlt").unwrap();
+        assert_eq!(third.symbols[0].offset, 0);
+        assert_eq!(asm.symbols.symbol_value("end"), Some(76));
+        let errors = asm
+            .assemble_fragment(".data\nhi: .asciiz 'Hi'")
+            .unwrap_err();
+        assert_eq!(errors, vec![AssemblerError::IncrementalDeclaration]);
+    }
+
+    #[test]
+    fn test_relaxed() {
+        let source = "load $0 #2\nagain: djnz $0 @again\nhlt";
+        let errors = Assembler::new().assemble("load $0 #2\nhlt").unwrap_err();
+        assert_eq!(errors, vec![AssemblerError::InsufficientSections]);
+        let mut asm = Assembler::new();
+        asm.set_relaxed(true);
+        let expected = Assembler::new()
+            .assemble(&format!(".data\n.code\n{}", source))
+            .unwrap();
+        assert_eq!(asm.assemble(source).unwrap(), expected);
+        // Sections can still be declared.
+        let mut asm = Assembler::new();
+        asm.set_relaxed(true);
+        assert!(asm
+            .assemble(".rodata\nhi: .asciiz 'hi'\n.code\nprts @hi\nhlt")
+            .is_ok());
+    }
+
+    #[test]
+    fn test_data_sections() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(
+                ".data\ncount: .byte 5, 0, 0, 0\ngreeting: .asciiz 'Hi'\nbuf: .space 4\n.rodata\nlimit: .byte 9\n.code\nload $0 @count\nldw $1 $0\ninc $1\nstw $1 $0\nload $2 @buf\nprts @greeting\nhlt\n",
+            )
+            .unwrap();
+        // Strings are constants even in `.data`, and the writable data is addressed after the
+        // read-only section. `.space` is on the heap.
+        assert_eq!(asm.ro, b"Hi\0\x09".to_vec());
+        assert_eq!(asm.symbols.symbol_value("count"), Some(4));
+        assert_eq!(asm.symbols.symbol_value("greeting"), Some(0));
+        assert_eq!(asm.symbols.symbol_value("buf"), Some(0));
+        assert_eq!(asm.symbols.symbol_value("limit"), Some(3));
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 4 + 28 + 4);
+        assert_eq!(&program[program.len() - 4..], &[5, 0, 0, 0]);
+        assert_eq!(
+            &program[PIE_HEADER_DATA_OFFSET..PIE_HEADER_DATA_OFFSET + 4],
+            &[4, 0, 0, 0]
+        );
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.exit_code(), Some(0));
+        assert_eq!(vm.registers[1], 6);
+        assert_eq!(vm.data(), &[6, 0, 0, 0]);
+        assert_eq!(vm.heap(), &[0, 0, 0, 0]);
+
+        assert!(Assembler::new().assemble(".rodata\n.code\nhlt").is_ok());
+        assert!(Assembler::new().assemble(".code\nhlt").is_err());
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let source =
+            ".data\nhi: .asciiz 'Hi'\n.code\nload $0 #1\nstart: load $1 #0\ndiv $0 $1 $2\nhlt\n";
+        let lines = (1..=7)
+            .map(|line| SourceLine {
+                file: "crash.iasm".to_string(),
+                line,
+            })
+            .collect();
+        let mut asm = Assembler::new();
+        asm.emit_debug_info(lines);
+        let program = asm.assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(program.clone());
+        let events = vm.run();
+        let pc = match events.last().unwrap().event {
+            crate::vm::VMEventType::Crash { pc, .. } => pc,
+            ref other => panic!("Expected a crash, got {:?}", other),
+        };
+        assert_eq!(
+            vm.source_position(pc).unwrap().to_string(),
+            "crash.iasm:6 (label start)"
+        );
+
+        // Without debug info the program is just the header, read-only section and code.
+        let plain = Assembler::new().assemble(source).unwrap();
+        assert_eq!(plain.len(), PIE_HEADER_LENGTH + 3 + 16);
+        assert_eq!(
+            program[..PIE_HEADER_CHECKSUM_OFFSET],
+            plain[..PIE_HEADER_CHECKSUM_OFFSET]
+        );
+        assert_eq!(
+            program[PIE_HEADER_LENGTH..plain.len()],
+            plain[PIE_HEADER_LENGTH..]
+        );
+        let mut vm = VM::new();
+        vm.add_bytes(plain);
+        vm.run();
+        assert_eq!(vm.source_position(pc), None);
+
+        // A debug section longer than the body is rejected.
+        let mut program = program;
+        program[PIE_HEADER_DEBUG_OFFSET..PIE_HEADER_DEBUG_OFFSET + 4]
+            .copy_from_slice(&1000u32.to_le_bytes());
+        write_checksum(&mut program);
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        assert_eq!(
+            vm.verify_header(),
+            Err(crate::vm::HeaderError::DebugSectionPastEnd)
+        );
+    }
+
+    #[test]
+    fn test_constant_expressions() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".data\n.equ SIZE, 16\n.equ WIDE, SIZE*4096\nbuf: .space SIZE*2\n.code\nload $0 #(4*32+1)\nload $1 #SIZE-1\nload $2 #WIDE\nhlt\n")
+            .unwrap();
+        assert_eq!(
+            &program[PIE_HEADER_SPACE_OFFSET..PIE_HEADER_SPACE_OFFSET + 4],
+            &[32, 0, 0, 0]
+        );
+        // `WIDE` does not fit in 16 bits, so its load takes two instructions.
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 20);
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(&vm.registers[..3], &[129, 15, 65536]);
+
+        let errors = Assembler::new()
+            .assemble(".data\n.code\nload $0 #SIZE\n.equ SIZE, 1\n.equ SIZE, 2\nload $1 #1/0\n")
+            .unwrap_err();
+        let errors: Vec<(usize, &AssemblerError)> = errors
+            .iter()
+            .map(|e| (e.location().unwrap().line, e.without_location()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    3,
+                    &AssemblerError::UnknownConstant {
+                        name: "SIZE".to_string()
+                    }
+                ),
+                (5, &AssemblerError::SymbolAlreadyDeclared),
+                (
+                    6,
+                    &AssemblerError::InvalidExpression {
+                        error: "The result overflows or divides by zero".to_string()
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_locations() {
+        let errors = Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\n  hi: .asciiz 'Hi'\nhi: hlt\n")
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].location(),
+            Some(&SourceLocation {
+                line: 5,
+                column: 1,
+                source_line: "hi: hlt".to_string(),
+            })
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "Line 5, column 1: This symbol was previously declared.\n    hi: hlt"
+        );
+
+        // Every line the parser cannot make sense of is reported rather than dropped.
+        let errors = Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\n  %%% \nhlt\nload $0 #1 ???")
+            .unwrap_err();
+        assert_eq!(errors.len(), 2);
+        let location = errors[0].location().unwrap();
+        assert_eq!((location.line, location.column), (4, 3));
+        assert_eq!(location.source_line, "  %%%");
+        let location = errors[1].location().unwrap();
+        assert_eq!((location.line, location.column), (6, 12));
+        assert_eq!(location.source_line, "load $0 #1 ???");
+
+        // A register number too large for an operand is an error rather than a panic.
+        let errors = Assembler::new()
+            .assemble(".data\n.code\nload $999 #1\nhlt")
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        let location = errors[0].location().unwrap();
+        assert_eq!((location.line, location.column), (3, 6));
     }
 
     #[test]
@@ -373,7 +1721,7 @@ mod tests {
         let mut asm = Assembler::new();
         let test_string = ".code\ntest: .asciiz 'This is a test'\n.wrong\n";
         let program = asm.assemble(test_string);
-        assert_eq!(program.is_ok(), false);
+        assert!(program.is_err());
     }
 
     #[test]
@@ -381,9 +1729,9 @@ mod tests {
         let mut asm = Assembler::new();
         let test_string = "hello: .asciiz 'Fail'";
         let result = program(CompleteStr(test_string));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, p) = result.unwrap();
-        asm.process_first_phase(&p);
+        asm.process_first_phase(&p, test_string);
         assert_eq!(asm.errors.len(), 1);
     }
 
@@ -392,9 +1740,9 @@ mod tests {
         let mut asm = Assembler::new();
         let test_string = ".data\ntest: .asciiz 'Hello'";
         let result = program(CompleteStr(test_string));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, p) = result.unwrap();
-        asm.process_first_phase(&p);
+        asm.process_first_phase(&p, test_string);
         assert_eq!(asm.errors.len(), 0);
     }
 }
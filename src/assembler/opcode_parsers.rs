@@ -8,8 +8,9 @@ named!(pub opcode<CompleteStr, Token>,
     do_parse!(
         opcode: alpha1 >>
         (
-            {
-                Token::Op{code: Opcode::from(opcode)}
+            match Opcode::from(opcode) {
+                Opcode::IGL => Token::UnknownOp { mnemonic: opcode.to_string() },
+                code => Token::Op { code },
             }
         )
     )
@@ -22,12 +23,17 @@ mod tests {
     #[test]
     fn test_opcode() {
         let result = opcode(CompleteStr("load"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, token) = result.unwrap();
         assert_eq!(token, Token::Op { code: Opcode::LOAD });
         assert_eq!(rest, CompleteStr(""));
         let result = opcode(CompleteStr("aold"));
         let (_, token) = result.unwrap();
-        assert_eq!(token, Token::Op { code: Opcode::IGL });
+        assert_eq!(
+            token,
+            Token::UnknownOp {
+                mnemonic: "aold".to_string()
+            }
+        );
     }
 }
@@ -1,6 +1,6 @@
-use nom::digit;
 use nom::types::CompleteStr;
 
+use crate::assembler::expressions::expression;
 use crate::assembler::label_parsers::label_usage;
 use crate::assembler::register_parsers::register;
 use crate::assembler::Token;
@@ -14,27 +14,30 @@ named!(pub operand<CompleteStr, Token>,
     )
 );
 
-// Parser for integer numbers, which we preface with `#` in our assembly language.
-// Example: #100.
+// Parser for integer numbers, which we preface with `#` in our assembly language, or constant
+// expressions that evaluate to one.
+// Example: #100, #-5 or #(SIZE+1)*2.
 named!(pub integer_operand<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("#") >>
-            reg_num: digit >>
+            value: expression >>
             (
-                Token::IntegerOperand{value: reg_num.parse::<i32>().unwrap()}
+                value.into_operand()
             )
         )
     )
 );
 
 // Parser for string contstants in the form of `my_string .asciiz '<string>'`.
-// Strings are null-terminated (hence the MIPS `.asciiz` directive).
+// Strings are null-terminated (hence the MIPS `.asciiz` directive). They can also be written in
+// double quotes, so they can contain apostrophes: `.asciiz "It's"`.
 named!(irstring<CompleteStr, Token>,
     do_parse!(
-        tag!("'") >>
-        content: take_until!("'") >>
-        tag!("'") >>
+        content: alt!(
+            delimited!(tag!("'"), take_until!("'"), tag!("'")) |
+            delimited!(tag!("\""), take_until!("\""), tag!("\""))
+        ) >>
         (
             Token::IrString{ name: content.to_string() }
         )
@@ -48,18 +51,31 @@ mod tests {
     #[test]
     fn test_parse_integer_operand() {
         let result = integer_operand(CompleteStr("#10"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, value) = result.unwrap();
         assert_eq!(rest, CompleteStr(""));
         assert_eq!(value, Token::IntegerOperand { value: 10 });
 
         let result = integer_operand(CompleteStr("10"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+
+        let (_, value) = integer_operand(CompleteStr("#-5")).unwrap();
+        assert_eq!(value, Token::IntegerOperand { value: -5 });
     }
 
     #[test]
     fn test_parse_string_operand() {
         let result = irstring(CompleteStr("'This is a test'"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
+
+        let (rest, value) = irstring(CompleteStr("\"It's a test\"")).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(
+            value,
+            Token::IrString {
+                name: String::from("It's a test")
+            }
+        );
+        assert!(irstring(CompleteStr("\"Unterminated'")).is_err());
     }
 }
@@ -1,35 +1,78 @@
 use nom::types::CompleteStr;
+use nom::{ErrorKind, IResult};
+use serde::{Deserialize, Serialize};
 
+use crate::assembler::assembler_errors::AssemblerError;
 use crate::assembler::directive_parsers::directive;
 use crate::assembler::instruction_parsers::{instruction, AssemblerInstruction};
+use crate::assembler::spans::{line_starts, Span};
 use crate::assembler::SymbolTable;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Program {
     pub instructions: Vec<AssemblerInstruction>,
+    /// The byte offsets in the source of lines that could not be parsed. The parser skips to the
+    /// next line when it finds one, so that every broken line can be reported at once.
+    #[serde(default)]
+    pub unparsed: Vec<usize>,
 }
 
 impl Program {
-    /// Converts the program to a `Vec<u8>`.
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
-        let mut program = vec![];
+    /// Converts the program to a `Vec<u8>`, failing at the first instruction that cannot be
+    /// written.
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
+        let mut program =
+            Vec::with_capacity(self.instructions.iter().map(|i| i.byte_length()).sum());
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes(symbols));
+            instruction.write_bytes(symbols, &mut program)?;
         }
-        program
+        Ok(program)
     }
 }
 
-named!(pub program<CompleteStr, Program>,
-    do_parse!(
-        instructions: many1!(alt!(instruction | directive)) >>
-        (
-            Program {
-                instructions: instructions
+/// Parses one or more instructions and directives, recording where each one and each of its
+/// tokens were written. Each must end its line. Text that is not an instruction or directive, or
+/// that follows one on its line, is skipped up to the end of its line and recorded in `unparsed`.
+/// Fails only if the input holds nothing but whitespace.
+pub fn program(input: CompleteStr) -> IResult<CompleteStr, Program> {
+    let mut program = Program {
+        instructions: vec![],
+        unparsed: vec![],
+    };
+    let line_starts = line_starts(&input);
+    let mut rest = input;
+    while !rest.trim().is_empty() {
+        let start = CompleteStr(rest.trim_start());
+        let unparsed = match alt!(start, instruction | directive) {
+            Ok((remaining, mut instruction)) if remaining.len() < start.len() => {
+                instruction.spans = instruction.spans.resolve(&input, &line_starts);
+                instruction.spans.instruction =
+                    Span::remaining(start.len(), remaining.len()).resolve(&input, &line_starts);
+                program.instructions.push(instruction);
+                rest = remaining;
+                // The parsers may have consumed the whitespace after the instruction, line end
+                // included, or left it in `remaining`.
+                let consumed = &start[..start.len() - remaining.len()];
+                let consumed_line_end = consumed[consumed.trim_end().len()..].contains('\n');
+                let trailing = remaining.trim_start_matches([' ', '\t', '\r']);
+                if consumed_line_end || trailing.is_empty() || trailing.starts_with('\n') {
+                    continue;
+                }
+                trailing
             }
-        )
-    )
-);
+            _ => &start,
+        };
+        program.unparsed.push(input.len() - unparsed.len());
+        rest = match unparsed.find('\n') {
+            Some(end) => CompleteStr(&unparsed[end + 1..]),
+            None => CompleteStr(""),
+        };
+    }
+    if program.instructions.is_empty() && program.unparsed.is_empty() {
+        return Err(nom::Err::Error(error_position!(input, ErrorKind::Many1)));
+    }
+    Ok((rest, program))
+}
 
 #[cfg(test)]
 mod tests {
@@ -38,19 +81,57 @@ mod tests {
     #[test]
     fn test_parse_program() {
         let result = program(CompleteStr("load $0 #100\n"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (leftover, p) = result.unwrap();
         assert_eq!(leftover, CompleteStr(""));
         assert_eq!(1, p.instructions.len());
     }
 
+    #[test]
+    fn test_instruction_spans() {
+        let source = ".data\n  hi: .asciiz 'Hi'\n.code\nadd $0 $1 $2\n.equ SIZE, 4*2";
+        let (_, p) = program(CompleteStr(source)).unwrap();
+        let text = |span: Option<Span>| span.map(|s| &source[s.start..s.end]);
+        let starts: Vec<usize> = p
+            .instructions
+            .iter()
+            .map(|i| i.spans.instruction.start)
+            .collect();
+        assert_eq!(starts, vec![0, 8, 25, 31, 44]);
+        let string = &p.instructions[1].spans;
+        assert_eq!(text(Some(string.instruction)), Some("hi: .asciiz 'Hi'"));
+        assert_eq!(text(string.label), Some("hi:"));
+        assert_eq!(text(string.name), Some(".asciiz"));
+        assert_eq!(text(string.operands[0]), Some("'Hi'"));
+        let add = &p.instructions[3].spans;
+        assert_eq!(add.instruction.line, 4);
+        assert_eq!(text(add.name), Some("add"));
+        assert_eq!(text(add.operands[2]), Some("$2"));
+        let equ = &p.instructions[4].spans;
+        assert_eq!(equ.instruction.line, 5);
+        assert_eq!(text(equ.operands[0]), Some("SIZE"));
+        assert_eq!(text(equ.operands[1]), Some("4*2"));
+    }
+
+    #[test]
+    fn test_skip_unparsed_lines() {
+        let (_, p) = program(CompleteStr("load $0 #1\n%%%\nhlt\nload $0 #1 ???\n  !!")).unwrap();
+        assert_eq!(p.instructions.len(), 3);
+        assert_eq!(p.unparsed, vec![11, 30, 36]);
+        // Text after an instruction is reported where it starts, and the next line still parses.
+        let (_, p) = program(CompleteStr("load $0 #1 garbage\nhlt\nhlt")).unwrap();
+        assert_eq!(p.instructions.len(), 3);
+        assert_eq!(p.unparsed, vec![11]);
+        assert!(program(CompleteStr(" \n ")).is_err());
+    }
+
     #[test]
     fn test_program_to_bytes() {
         let result = program(CompleteStr("load $0 #100\n"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, program) = result.unwrap();
         let symbol_table = SymbolTable::new();
-        let bytecode = program.to_bytes(&symbol_table);
+        let bytecode = program.to_bytes(&symbol_table).unwrap();
         assert_eq!(bytecode.len(), 4);
     }
 
@@ -58,6 +139,6 @@ mod tests {
     fn test_complete_program() {
         let test_program = CompleteStr(".data\nhello: .asciiz 'Hello everyone!'\n.code\nhlt");
         let result = program(test_program);
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
     }
 }
@@ -11,11 +11,12 @@ named!(pub register <CompleteStr, Token>,
         do_parse!(
             // Use `tag!` too look for `$` and then pass the result.
             tag!("$") >>
-            // `digit` stores the result of `tag!` into `reg_num` and parses the number.
-            reg_num: digit >>
+            // `digit` stores the result of `tag!` into `reg_num`, and `map_res!` fails the parse
+            // if the number does not fit in a register operand.
+            reg_num: map_res!(digit, |d: CompleteStr| d.parse::<u8>()) >>
             (
                 Token::Register{
-                    reg_num: reg_num.parse::<u8>().unwrap()
+                    reg_num
                 }
             )
         )
@@ -29,10 +30,14 @@ mod tests {
     #[test]
     fn test_parse_register() {
         let result = register(CompleteStr("$0"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let result = register(CompleteStr("0"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
         let result = register(CompleteStr("$a"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+        let result = register(CompleteStr("$255"));
+        assert_eq!(result.unwrap().1, Token::Register { reg_num: 255 });
+        let result = register(CompleteStr("$999"));
+        assert!(result.is_err());
     }
 }
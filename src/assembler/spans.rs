@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+
+/// Where a token or instruction was written in the source: the range of bytes it covers, and the
+/// line it starts on, counted from 1.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+}
+
+impl Span {
+    /// Returns the span of what a parser consumed, given how much of the input was left before and
+    /// after it. The parsers only see the rest of the source, not where it starts, so this is how
+    /// they record spans; `program` then resolves them with `resolve`.
+    pub fn remaining(before: usize, after: usize) -> Span {
+        Span {
+            start: before,
+            end: after,
+            line: 0,
+        }
+    }
+
+    /// Returns the span in `source` of a span recorded with `remaining`, without the whitespace
+    /// the parser consumed on either side. `line_starts` holds the offset of each line of `source`.
+    pub fn resolve(self, source: &str, line_starts: &[usize]) -> Span {
+        let start = source.len() - self.start;
+        let end = source.len() - self.end;
+        let text = &source[start..end];
+        let start = start + (text.len() - text.trim_start().len());
+        let end = (start + text.trim().len()).max(start);
+        Span {
+            start,
+            end,
+            line: line_starts.partition_point(|&line_start| line_start <= start),
+        }
+    }
+}
+
+/// Returns the offset in `source` at which each of its lines starts.
+pub fn line_starts(source: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+        .collect()
+}
+
+/// Where an instruction or directive, and each of its tokens, were written in the source.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct InstructionSpans {
+    pub instruction: Span,
+    pub label: Option<Span>,
+    /// The span of the opcode, or of the directive's name.
+    pub name: Option<Span>,
+    pub operands: [Option<Span>; 3],
+}
+
+impl InstructionSpans {
+    /// Returns the spans with each one resolved by `Span::resolve`.
+    pub fn resolve(&self, source: &str, line_starts: &[usize]) -> InstructionSpans {
+        let resolve = |span: Option<Span>| span.map(|s| s.resolve(source, line_starts));
+        InstructionSpans {
+            instruction: self.instruction.resolve(source, line_starts),
+            label: resolve(self.label),
+            name: resolve(self.name),
+            operands: [
+                resolve(self.operands[0]),
+                resolve(self.operands[1]),
+                resolve(self.operands[2]),
+            ],
+        }
+    }
+}
+
+/// Runs a parser, returning what it parsed along with the `Span::remaining` it consumed, e.g.
+/// `spanned!(operand)`.
+macro_rules! spanned (
+    ($i:expr, $submac:ident!( $($args:tt)* )) => ({
+        let input = $i;
+        match $submac!(input, $($args)*) {
+            Ok((rest, value)) => Ok((
+                rest,
+                (value, $crate::assembler::spans::Span::remaining(input.len(), rest.len())),
+            )),
+            Err(e) => Err(e),
+        }
+    });
+    ($i:expr, $f:expr) => (
+        spanned!($i, call!($f))
+    );
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let source = "load $0 #1\n  hlt  \n";
+        let line_starts = line_starts(source);
+        assert_eq!(line_starts, vec![0, 11, 19]);
+        // `  hlt  ` leaves the final newline.
+        let span = Span::remaining(8, 1).resolve(source, &line_starts);
+        assert_eq!(
+            span,
+            Span {
+                start: 13,
+                end: 16,
+                line: 2
+            }
+        );
+        assert_eq!(&source[span.start..span.end], "hlt");
+        assert_eq!(
+            Span::remaining(19, 14).resolve(source, &line_starts).line,
+            1
+        );
+    }
+}
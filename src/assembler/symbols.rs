@@ -1,4 +1,46 @@
-#[derive(Debug)]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Identifies an interned symbol name. Comparing ids is much cheaper than comparing names.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub struct SymbolId(u32);
+
+/// Maps each distinct symbol name to a `SymbolId`, storing the name only once.
+#[derive(Clone, Debug, Default)]
+pub struct Interner {
+    ids: HashMap<String, SymbolId>,
+    names: Vec<String>,
+}
+
+impl Interner {
+    /// Returns a new, empty `Interner`.
+    pub fn new() -> Interner {
+        Interner::default()
+    }
+
+    /// Returns the id of `name`, allocating a new one the first time `name` is seen.
+    pub fn intern(&mut self, name: &str) -> SymbolId {
+        if let Some(id) = self.get(name) {
+            return id;
+        }
+        let id = SymbolId(self.names.len() as u32);
+        self.ids.insert(name.to_string(), id);
+        self.names.push(name.to_string());
+        id
+    }
+
+    /// Returns the id of `name` if it has been interned.
+    pub fn get(&self, name: &str) -> Option<SymbolId> {
+        self.ids.get(name).copied()
+    }
+
+    /// Returns the name an id was interned from.
+    pub fn resolve(&self, id: SymbolId) -> &str {
+        &self.names[id.0 as usize]
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Symbol {
     /// The name of the symbol.
     name: String,
@@ -26,83 +68,216 @@ impl Symbol {
             offset: Some(offset),
         }
     }
+
+    /// Returns the name of the symbol.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the byte offset of the symbol, if it has been set.
+    pub fn offset(&self) -> Option<u32> {
+        self.offset
+    }
+
+    /// Returns the type of the symbol.
+    pub fn symbol_type(&self) -> &SymbolType {
+        &self.symbol_type
+    }
 }
 
 /// The various types of symbols that can be parsed from a program.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum SymbolType {
     /// Labels that are used for naming specific instructions.
     /// E.g. `test1: LOAD $0 #100`.
     Label,
+    /// Numeric labels such as `1:`, which may be declared any number of times. Each declaration is
+    /// given a name of its own, such as `1.0`, that cannot clash with other labels.
+    LocalLabel,
     Integer,
     IrString,
 }
 
-/// A table for holding all symbols parsed from a program.
-///
-/// TODO: implement as HashMap.
-#[derive(Debug)]
+/// A table for holding all symbols parsed from a program. Symbols are looked up through an
+/// interner, so lookups by name hash the name once instead of comparing it against every symbol.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "SerializedSymbolTable")]
 pub struct SymbolTable {
-    pub symbols: Vec<Symbol>,
+    symbols: Vec<Symbol>,
+    #[serde(skip)]
+    interner: Interner,
+    /// The index in `symbols` of the symbol with each id.
+    #[serde(skip)]
+    indices: HashMap<SymbolId, usize>,
+}
+
+/// The serialized form of a `SymbolTable`, from which the interner is rebuilt.
+#[derive(Deserialize)]
+struct SerializedSymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl From<SerializedSymbolTable> for SymbolTable {
+    fn from(serialized: SerializedSymbolTable) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        for symbol in serialized.symbols {
+            table.add_symbol(symbol);
+        }
+        table
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SymbolTable {
     /// Returns a new `SymbolTable`.
     pub fn new() -> SymbolTable {
-        SymbolTable { symbols: vec![] }
+        SymbolTable {
+            symbols: vec![],
+            interner: Interner::new(),
+            indices: HashMap::new(),
+        }
+    }
+
+    /// Returns the symbols in the order they were added.
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// Returns the symbols of the given type in the order they were added.
+    pub fn symbols_of_type<'a>(
+        &'a self,
+        symbol_type: &'a SymbolType,
+    ) -> impl Iterator<Item = &'a Symbol> + 'a {
+        self.symbols
+            .iter()
+            .filter(move |s| s.symbol_type == *symbol_type)
+    }
+
+    /// Returns the id of `name`, interning it if needed.
+    pub fn intern(&mut self, name: &str) -> SymbolId {
+        self.interner.intern(name)
+    }
+
+    /// Returns the id of `name` if it has been interned.
+    pub fn id(&self, name: &str) -> Option<SymbolId> {
+        self.interner.get(name)
+    }
+
+    /// Returns the name an id was interned from.
+    pub fn name(&self, id: SymbolId) -> &str {
+        self.interner.resolve(id)
     }
 
-    /// Adds a symbol to the table.
+    /// Adds a symbol to the table, replacing any symbol with the same name.
     pub fn add_symbol(&mut self, s: Symbol) {
-        self.symbols.push(s);
+        let id = self.interner.intern(&s.name);
+        match self.indices.get(&id) {
+            Some(&index) => self.symbols[index] = s,
+            None => {
+                self.indices.insert(id, self.symbols.len());
+                self.symbols.push(s);
+            }
+        }
+    }
+
+    /// Returns the symbol with the given id.
+    pub fn symbol(&self, id: SymbolId) -> Option<&Symbol> {
+        self.indices.get(&id).map(|&index| &self.symbols[index])
     }
 
     /// Returns the byte offset value of a symbol if found within the table.
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
-        for symbol in &self.symbols {
-            if symbol.name == s {
-                return symbol.offset;
-            }
-        }
-        None
+        self.symbol(self.id(s)?)?.offset
     }
 
     pub fn has_symbol(&self, s: &str) -> bool {
-        for symbol in &self.symbols {
-            if symbol.name == s {
-                return true;
-            }
-        }
-        false
+        self.id(s).is_some_and(|id| self.indices.contains_key(&id))
     }
 
     pub fn set_symbol_offset(&mut self, s: &str, offset: u32) -> bool {
-        for symbol in &mut self.symbols {
-            if symbol.name == s {
-                symbol.offset = Some(offset);
-                return true;
+        match self.id(s).and_then(|id| self.indices.get(&id)) {
+            Some(&index) => {
+                self.symbols[index].offset = Some(offset);
+                true
             }
+            None => false,
         }
-        false
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::vm::VM;
 
     #[test]
     fn test_symbol_table() {
         let mut sym = SymbolTable::new();
         let new_symbol = Symbol::new_with_offset("test".to_string(), SymbolType::Label, 12);
         sym.add_symbol(new_symbol);
-        assert_eq!(sym.symbols.len(), 1);
+        assert_eq!(sym.symbols().len(), 1);
         let v = sym.symbol_value("test");
-        assert_eq!(v.is_some(), true);
+        assert!(v.is_some());
         let v = v.unwrap();
         assert_eq!(v, 12);
         let v = sym.symbol_value("does_not_exist");
-        assert_eq!(v.is_some(), false);
+        assert!(v.is_none());
+    }
+
+    #[test]
+    fn test_symbols_of_type() {
+        let mut sym = SymbolTable::new();
+        sym.add_symbol(Symbol::new_with_offset(
+            "a".to_string(),
+            SymbolType::Label,
+            0,
+        ));
+        sym.add_symbol(Symbol::new("b".to_string(), SymbolType::Integer));
+        sym.add_symbol(Symbol::new_with_offset(
+            "c".to_string(),
+            SymbolType::Label,
+            4,
+        ));
+        let labels: Vec<&str> = sym
+            .symbols_of_type(&SymbolType::Label)
+            .map(|s| s.name())
+            .collect();
+        assert_eq!(labels, vec!["a", "c"]);
+        assert_eq!(sym.symbols_of_type(&SymbolType::IrString).count(), 0);
+    }
+
+    #[test]
+    fn test_serialize_symbol_table() {
+        let mut sym = SymbolTable::new();
+        sym.add_symbol(Symbol::new_with_offset("test".to_string(), SymbolType::Label, 12));
+        let json = serde_json::to_string(&sym).unwrap();
+        assert_eq!(
+            json,
+            r#"{"symbols":[{"name":"test","offset":12,"symbol_type":"Label"}]}"#
+        );
+    }
+
+    #[test]
+    fn test_interner() {
+        let mut interner = Interner::new();
+        let id = interner.intern("loop");
+        assert_eq!(interner.intern("loop"), id);
+        assert_ne!(interner.intern("end"), id);
+        assert_eq!(interner.get("loop"), Some(id));
+        assert_eq!(interner.get("missing"), None);
+        assert_eq!(interner.resolve(id), "loop");
+    }
+
+    #[test]
+    fn test_deserialize_symbol_table() {
+        let json = r#"{"symbols":[{"name":"test","offset":12,"symbol_type":"Label"}]}"#;
+        let sym: SymbolTable = serde_json::from_str(json).unwrap();
+        assert_eq!(sym.symbol_value("test"), Some(12));
+        let id = sym.id("test").unwrap();
+        assert_eq!(sym.symbol(id).unwrap().name(), "test");
     }
 }
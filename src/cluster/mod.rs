@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::remote::{read_message, write_message, Request, Response};
+
+/// How often a node sends heartbeats to the members it knows about.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Members that haven't been heard from for this long are dropped from the membership list.
+pub const MEMBER_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// A node that is part of the cluster.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Member {
+    /// The human readable name the node was started with.
+    pub alias: String,
+    /// The address other nodes reach the node's server at.
+    pub addr: String,
+    /// The last time the node was heard from.
+    pub last_seen: DateTime<Utc>,
+}
+
+/// This node's view of the cluster.
+#[derive(Debug)]
+pub struct Cluster {
+    /// The alias of this node.
+    alias: String,
+    /// The address this node advertises to the others, which may differ from the one its
+    /// server is bound to, e.g. when listening on `0.0.0.0`.
+    addr: String,
+    /// Every other node this node knows about, keyed by alias.
+    members: Mutex<HashMap<String, Member>>,
+}
+
+impl Cluster {
+    /// Returns a new `Cluster` containing only this node.
+    pub fn new(alias: &str, addr: &str) -> Cluster {
+        Cluster {
+            alias: alias.to_string(),
+            addr: addr.to_string(),
+            members: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the alias of this node.
+    pub fn alias(&self) -> &str {
+        &self.alias
+    }
+
+    /// Returns this node as a `Member`.
+    pub fn local_member(&self) -> Member {
+        Member {
+            alias: self.alias.clone(),
+            addr: self.addr.clone(),
+            last_seen: Utc::now(),
+        }
+    }
+
+    /// Returns every known member, including this node, sorted by alias.
+    pub fn members(&self) -> Vec<Member> {
+        let mut members: Vec<Member> = self.members.lock().unwrap().values().cloned().collect();
+        members.push(self.local_member());
+        members.sort_by(|a, b| a.alias.cmp(&b.alias));
+        members
+    }
+
+    /// Records that a member was just heard from, adding it if it is new.
+    pub fn touch(&self, alias: &str, addr: &str) {
+        if alias == self.alias {
+            return;
+        }
+        self.members.lock().unwrap().insert(
+            alias.to_string(),
+            Member {
+                alias: alias.to_string(),
+                addr: addr.to_string(),
+                last_seen: Utc::now(),
+            },
+        );
+    }
+
+    /// Merges a membership list received from another node, keeping the most recent sighting of
+    /// each member.
+    pub fn merge(&self, members: Vec<Member>) {
+        let mut known = self.members.lock().unwrap();
+        for member in members {
+            if member.alias == self.alias {
+                continue;
+            }
+            let newer = known
+                .get(&member.alias)
+                .is_none_or(|m| m.last_seen < member.last_seen);
+            if newer {
+                known.insert(member.alias.clone(), member);
+            }
+        }
+    }
+
+    /// Drops members that haven't been heard from within `timeout`.
+    pub fn expire(&self, timeout: Duration) {
+        let cutoff = Utc::now() - chrono::Duration::from_std(timeout).unwrap();
+        self.members
+            .lock()
+            .unwrap()
+            .retain(|_, member| member.last_seen >= cutoff);
+    }
+
+    /// Joins the cluster the node at `addr` belongs to.
+    pub fn join<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        let request = Request::Join {
+            member: self.local_member(),
+        };
+        let members = self.exchange(addr, &request)?;
+        self.merge(members);
+        Ok(())
+    }
+
+    /// Sends a heartbeat to every known member, merging the lists they send back.
+    pub fn heartbeat(&self) {
+        let peers: Vec<Member> = self.members.lock().unwrap().values().cloned().collect();
+        for peer in peers {
+            let request = Request::Heartbeat {
+                member: self.local_member(),
+            };
+            if let Ok(members) = self.exchange(peer.addr.as_str(), &request) {
+                self.touch(&peer.alias, &peer.addr);
+                self.merge(members);
+            }
+        }
+        self.expire(MEMBER_TIMEOUT);
+    }
+
+    /// Starts a thread that sends heartbeats every `interval`.
+    pub fn start_heartbeat(cluster: Arc<Cluster>, interval: Duration) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            cluster.heartbeat();
+        })
+    }
+
+    /// Handles a membership request received by this node's server.
+    pub fn handle(&self, member: Member) -> Response {
+        self.touch(&member.alias, &member.addr);
+        Response::Members {
+            members: self.members(),
+        }
+    }
+
+    fn exchange<A: ToSocketAddrs>(&self, addr: A, request: &Request) -> io::Result<Vec<Member>> {
+        let mut stream = TcpStream::connect(addr)?;
+        write_message(&mut stream, request)?;
+        match read_message(&mut stream)? {
+            Response::Members { members } => Ok(members),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected a membership list, got {:?}", other),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::server::Server;
+
+    fn start_node(alias: &str) -> (Arc<Cluster>, String) {
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let cluster = Arc::new(Cluster::new(alias, &addr));
+        let server = server.with_cluster(cluster.clone());
+        thread::spawn(move || server.run());
+        (cluster, addr)
+    }
+
+    #[test]
+    fn test_join_cluster() {
+        let (first, first_addr) = start_node("first");
+        let (second, _) = start_node("second");
+        let (third, _) = start_node("third");
+        second.join(first_addr.as_str()).unwrap();
+        third.join(first_addr.as_str()).unwrap();
+        assert_eq!(first.members().len(), 3);
+        assert_eq!(third.members().len(), 3);
+        // `second` learns about `third` through its heartbeat to `first`.
+        assert_eq!(second.members().len(), 2);
+        second.heartbeat();
+        let aliases: Vec<String> = second.members().into_iter().map(|m| m.alias).collect();
+        assert_eq!(aliases, vec!["first", "second", "third"]);
+    }
+
+    #[test]
+    fn test_expire_members() {
+        let cluster = Cluster::new("local", "127.0.0.1:1");
+        cluster.merge(vec![Member {
+            alias: "stale".to_string(),
+            addr: "127.0.0.1:2".to_string(),
+            last_seen: Utc::now() - chrono::Duration::seconds(60),
+        }]);
+        assert_eq!(cluster.members().len(), 2);
+        cluster.expire(MEMBER_TIMEOUT);
+        assert_eq!(cluster.members().len(), 1);
+    }
+}
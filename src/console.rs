@@ -0,0 +1,185 @@
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+/// A destination for text produced by a running program. The default writes to stdout, but
+/// hosts without a terminal (e.g. a browser playground on wasm) can collect it instead.
+pub trait Output {
+    /// Writes `s` to the sink without appending a newline.
+    fn write_str(&mut self, s: &str);
+
+    /// Writes `s` followed by a newline.
+    fn write_line(&mut self, s: &str) {
+        self.write_str(s);
+        self.write_str("\n");
+    }
+}
+
+/// A source of text input for a running program.
+pub trait Input {
+    /// Reads a single line without the trailing newline. Returns `None` once input is exhausted.
+    fn read_line(&mut self) -> Option<String>;
+
+    /// Reads a line like `read_line`, once `prompt` has been written to `output`. Inputs that draw
+    /// their own prompt, such as `EditorInput`, override it.
+    fn read_line_after_prompt(&mut self, prompt: &str, output: &SharedOutput) -> Option<String> {
+        if let Ok(mut output) = output.lock() {
+            output.write_str(prompt);
+        }
+        self.read_line()
+    }
+}
+
+/// An `Output` that can be shared between a VM and its clones.
+pub type SharedOutput = Arc<Mutex<dyn Output + Send>>;
+/// An `Input` that can be shared between a VM and its clones.
+pub type SharedInput = Arc<Mutex<dyn Input + Send>>;
+
+/// Writes to the process's stdout.
+#[derive(Debug, Default)]
+pub struct StdoutOutput;
+
+impl Output for StdoutOutput {
+    fn write_str(&mut self, s: &str) {
+        print!("{}", s);
+        io::stdout().flush().ok();
+    }
+}
+
+/// Reads from the process's stdin.
+#[derive(Debug, Default)]
+pub struct StdinInput;
+
+impl Input for StdinInput {
+    fn read_line(&mut self) -> Option<String> {
+        let mut buffer = String::new();
+        match io::stdin().lock().read_line(&mut buffer) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(buffer.trim_end_matches(&['\r', '\n'][..]).to_string()),
+        }
+    }
+}
+
+/// Reads lines from the terminal through a line editor, so the line being typed can be edited with
+/// the arrow keys and shortcuts such as Ctrl-A and Ctrl-E, and earlier lines recalled with the up
+/// and down keys. `helper` adds to the editing, e.g. completing the word before the cursor with Tab.
+#[cfg(feature = "repl")]
+pub struct EditorInput<H: rustyline::Helper = ()> {
+    editor: rustyline::Editor<H, rustyline::history::DefaultHistory>,
+}
+
+#[cfg(feature = "repl")]
+impl<H: rustyline::Helper> EditorInput<H> {
+    /// Returns a new `EditorInput` using `helper`, or an error if the terminal cannot be set up.
+    pub fn new(helper: H) -> rustyline::Result<EditorInput<H>> {
+        let mut editor = rustyline::Editor::new()?;
+        editor.set_helper(Some(helper));
+        Ok(EditorInput { editor })
+    }
+}
+
+#[cfg(feature = "repl")]
+impl<H: rustyline::Helper> Input for EditorInput<H> {
+    fn read_line(&mut self) -> Option<String> {
+        self.read_line_after_prompt("", &stdout())
+    }
+
+    /// Reads a line with the editor, showing `prompt` before it. Ctrl-C discards the line being
+    /// typed, and Ctrl-D ends the input.
+    fn read_line_after_prompt(&mut self, prompt: &str, _: &SharedOutput) -> Option<String> {
+        match self.editor.readline(prompt) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    self.editor.add_history_entry(line.as_str()).ok();
+                }
+                Some(line)
+            }
+            Err(rustyline::error::ReadlineError::Interrupted) => Some(String::new()),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Collects everything written to it in memory.
+#[derive(Debug, Default)]
+pub struct BufferOutput {
+    pub buffer: String,
+}
+
+impl Output for BufferOutput {
+    fn write_str(&mut self, s: &str) {
+        self.buffer.push_str(s);
+    }
+}
+
+/// Serves lines from a fixed list, e.g. text a playground user typed ahead of time.
+#[derive(Debug, Default)]
+pub struct BufferInput {
+    pub lines: Vec<String>,
+}
+
+impl Input for BufferInput {
+    fn read_line(&mut self) -> Option<String> {
+        if self.lines.is_empty() {
+            None
+        } else {
+            Some(self.lines.remove(0))
+        }
+    }
+}
+
+/// Returns a shared handle to stdout.
+pub fn stdout() -> SharedOutput {
+    Arc::new(Mutex::new(StdoutOutput))
+}
+
+/// Returns a shared handle to stdin.
+pub fn stdin() -> SharedInput {
+    Arc::new(Mutex::new(StdinInput))
+}
+
+/// Returns a shared handle to a line editor on the terminal using `helper`, or to stdin if one
+/// cannot be set up.
+#[cfg(feature = "repl")]
+pub fn editor<H: rustyline::Helper + Send + 'static>(helper: H) -> SharedInput {
+    match EditorInput::new(helper) {
+        Ok(input) => Arc::new(Mutex::new(input)),
+        Err(_) => stdin(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_output() {
+        let mut output = BufferOutput::default();
+        output.write_str("Hello");
+        output.write_line(", world");
+        assert_eq!(output.buffer, "Hello, world\n");
+    }
+
+    #[test]
+    fn test_buffer_input() {
+        let mut input = BufferInput {
+            lines: vec!["1".to_string(), "2".to_string()],
+        };
+        assert_eq!(input.read_line(), Some("1".to_string()));
+        assert_eq!(input.read_line(), Some("2".to_string()));
+        assert_eq!(input.read_line(), None);
+    }
+
+    #[test]
+    fn test_read_line_after_prompt() {
+        let mut input = BufferInput {
+            lines: vec!["hlt".to_string()],
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let shared: SharedOutput = output.clone();
+        assert_eq!(
+            input.read_line_after_prompt(">>> ", &shared),
+            Some("hlt".to_string())
+        );
+        assert_eq!(output.lock().unwrap().buffer, ">>> ");
+    }
+}
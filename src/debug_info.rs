@@ -0,0 +1,97 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// The file and line a line of assembled source came from. Sources with includes are expanded
+/// into one, so each of its lines can come from a different file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SourceLine {
+    pub file: String,
+    /// The line in `file`, starting at 1.
+    pub line: u32,
+}
+
+/// Maps the code of a program back to its source. The assembler can write it into a section
+/// after the code, whose length is stored in the header, so crashes can be reported as a place in
+/// the source rather than a bare program counter.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DebugInfo {
+    /// The address of each instruction and the source line it was assembled from, sorted by
+    /// address.
+    pub lines: Vec<(u32, SourceLine)>,
+    /// The address of each label on an instruction, sorted by address.
+    pub labels: Vec<(u32, String)>,
+}
+
+/// Where an instruction was assembled from, and the closest label at or before it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourcePosition {
+    pub source: SourceLine,
+    pub label: Option<String>,
+}
+
+impl fmt::Display for SourcePosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.source.file, self.source.line)?;
+        if let Some(ref label) = self.label {
+            write!(f, " (label {})", label)?;
+        }
+        Ok(())
+    }
+}
+
+impl DebugInfo {
+    /// Encodes the debug info as the bytes of a debug section.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap_or_default()
+    }
+
+    /// Decodes a debug section, returning `None` if it is malformed.
+    pub fn from_bytes(bytes: &[u8]) -> Option<DebugInfo> {
+        bincode::deserialize(bytes).ok()
+    }
+
+    /// Returns where the instruction at `pc` was assembled from, if it is known.
+    pub fn position(&self, pc: usize) -> Option<SourcePosition> {
+        let pc = pc as u32;
+        let (_, source) = self.lines.iter().find(|(address, _)| *address == pc)?;
+        let label = self
+            .labels
+            .iter()
+            .rev()
+            .find(|(address, _)| *address <= pc)
+            .map(|(_, name)| name.clone());
+        Some(SourcePosition {
+            source: source.clone(),
+            label,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source_line(line: u32) -> SourceLine {
+        SourceLine {
+            file: "foo.iasm".to_string(),
+            line,
+        }
+    }
+
+    #[test]
+    fn test_position() {
+        let info = DebugInfo {
+            lines: vec![(64, source_line(3)), (68, source_line(5))],
+            labels: vec![(68, "loop_start".to_string())],
+        };
+        let info = DebugInfo::from_bytes(&info.to_bytes()).unwrap();
+        assert_eq!(info.position(64).unwrap().to_string(), "foo.iasm:3");
+        assert_eq!(
+            info.position(68).unwrap().to_string(),
+            "foo.iasm:5 (label loop_start)"
+        );
+        assert_eq!(info.position(66), None);
+        assert_eq!(DebugInfo::from_bytes(&[1, 2]), None);
+    }
+}
@@ -0,0 +1,261 @@
+//! C bindings for embedding the VM in C/C++ hosts. The matching declarations in
+//! `include/iridium.h` are generated from this module by cbindgen, configured by `cbindgen.toml`:
+//!
+//! ```text
+//! cbindgen --config cbindgen.toml --output include/iridium.h
+//! ```
+//!
+//! Every function taking a `*mut VM` expects a pointer previously returned by
+//! `iridium_vm_new` and not yet passed to `iridium_vm_free`.
+//!
+//! Unwinding across `extern "C"` is undefined behavior, so every function catches panics and
+//! reports them to the caller instead, as `IRIDIUM_ERR_PANIC` where it returns a status code.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use crate::vm::{VMEventType, VM};
+
+/// Returned by the FFI functions when they succeed.
+pub const IRIDIUM_OK: i32 = 0;
+/// Returned when a null pointer was passed in.
+pub const IRIDIUM_ERR_NULL: i32 = -1;
+/// Returned when a register index or memory range is out of bounds.
+pub const IRIDIUM_ERR_OUT_OF_BOUNDS: i32 = -2;
+/// Returned when the VM panicked. The VM may be left in an inconsistent state, and should only be
+/// freed.
+pub const IRIDIUM_ERR_PANIC: i32 = -3;
+/// Returned by `iridium_vm_run` when the program crashed, or its header was invalid.
+pub const IRIDIUM_CRASHED: i32 = 1;
+/// Returned by `iridium_vm_run` when the VM was stopped through its stop flag.
+pub const IRIDIUM_KILLED: i32 = 2;
+/// Returned by `iridium_vm_run` when the VM paused at a breakpoint.
+pub const IRIDIUM_PAUSED: i32 = 3;
+/// Returned by `iridium_vm_run` when the VM ran out of its instruction budget.
+pub const IRIDIUM_BUDGET_EXCEEDED: i32 = 4;
+
+/// Runs `f`, returning `fallback` if it panics rather than unwinding into the C caller.
+fn catch_panic<T>(fallback: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(fallback)
+}
+
+/// Allocates a new VM and returns an owning pointer to it, or null if it could not be created.
+#[no_mangle]
+pub extern "C" fn iridium_vm_new() -> *mut VM {
+    catch_panic(ptr::null_mut(), || Box::into_raw(Box::new(VM::new())))
+}
+
+/// Frees a VM created by `iridium_vm_new`. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `vm` must be null or a pointer returned by `iridium_vm_new` that has not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn iridium_vm_free(vm: *mut VM) {
+    if !vm.is_null() {
+        catch_panic((), || drop(Box::from_raw(vm)));
+    }
+}
+
+/// Appends `len` bytes of an assembled binary (header included) to the VM's program.
+///
+/// # Safety
+///
+/// `vm` must be a live VM pointer and `bytes` must point to at least `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn iridium_vm_load(vm: *mut VM, bytes: *const u8, len: usize) -> i32 {
+    if vm.is_null() || bytes.is_null() {
+        return IRIDIUM_ERR_NULL;
+    }
+    let bytes = slice::from_raw_parts(bytes, len);
+    catch_panic(IRIDIUM_ERR_PANIC, || {
+        (*vm).add_slice(bytes);
+        IRIDIUM_OK
+    })
+}
+
+/// Runs the loaded program and returns how it stopped: `IRIDIUM_OK` if it halted or exited,
+/// `IRIDIUM_CRASHED`, `IRIDIUM_KILLED`, `IRIDIUM_PAUSED` or `IRIDIUM_BUDGET_EXCEEDED` if it did
+/// not finish, or a negative error code. The program's exit code, or the code of the crash, is
+/// written to `exit_code` unless it is null; it is left alone if the program did not stop.
+///
+/// # Safety
+///
+/// `vm` must be a live VM pointer and `exit_code` must be null or valid for a write of one `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn iridium_vm_run(vm: *mut VM, exit_code: *mut i32) -> i32 {
+    if vm.is_null() {
+        return IRIDIUM_ERR_NULL;
+    }
+    catch_panic(IRIDIUM_ERR_PANIC, || {
+        let events = (*vm).run();
+        if !exit_code.is_null() {
+            if let Some(code) = (*vm).exit_code() {
+                *exit_code = code;
+            }
+        }
+        match events.last().map(|e| &e.event) {
+            Some(VMEventType::GracefulStop { .. }) => IRIDIUM_OK,
+            Some(VMEventType::Killed) => IRIDIUM_KILLED,
+            Some(VMEventType::Paused { .. }) => IRIDIUM_PAUSED,
+            Some(VMEventType::BudgetExceeded) => IRIDIUM_BUDGET_EXCEEDED,
+            _ => IRIDIUM_CRASHED,
+        }
+    })
+}
+
+/// Writes the value of register `index` into `out`.
+///
+/// # Safety
+///
+/// `vm` must be a live VM pointer and `out` must be valid for a write of one `i32`.
+#[no_mangle]
+pub unsafe extern "C" fn iridium_vm_register(vm: *const VM, index: usize, out: *mut i32) -> i32 {
+    if vm.is_null() || out.is_null() {
+        return IRIDIUM_ERR_NULL;
+    }
+    catch_panic(IRIDIUM_ERR_PANIC, || {
+        let registers = &(*vm).registers;
+        match registers.get(index) {
+            Some(value) => {
+                *out = *value;
+                IRIDIUM_OK
+            }
+            None => IRIDIUM_ERR_OUT_OF_BOUNDS,
+        }
+    })
+}
+
+/// Returns the current size of the VM's heap in bytes, or 0 if `vm` is null or the VM panicked.
+///
+/// # Safety
+///
+/// `vm` must be null or a live VM pointer.
+#[no_mangle]
+pub unsafe extern "C" fn iridium_vm_heap_len(vm: *const VM) -> usize {
+    if vm.is_null() {
+        return 0;
+    }
+    catch_panic(0, || (*vm).heap().len())
+}
+
+/// Copies `len` bytes of the heap starting at `offset` into `out`.
+///
+/// # Safety
+///
+/// `vm` must be a live VM pointer and `out` must be valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn iridium_vm_read_heap(
+    vm: *const VM,
+    offset: usize,
+    out: *mut u8,
+    len: usize,
+) -> i32 {
+    if vm.is_null() || out.is_null() {
+        return IRIDIUM_ERR_NULL;
+    }
+    catch_panic(IRIDIUM_ERR_PANIC, || {
+        let heap = (*vm).heap();
+        match offset.checked_add(len) {
+            Some(end) if end <= heap.len() => {
+                ptr::copy_nonoverlapping(heap[offset..end].as_ptr(), out, len);
+                IRIDIUM_OK
+            }
+            _ => IRIDIUM_ERR_OUT_OF_BOUNDS,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_vm_lifecycle() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble(".data\n.code\nload $0 #100\nhlt").unwrap();
+        unsafe {
+            let vm = iridium_vm_new();
+            assert_eq!(iridium_vm_load(vm, program.as_ptr(), program.len()), IRIDIUM_OK);
+            let mut code = 7;
+            assert_eq!(iridium_vm_run(vm, &mut code), IRIDIUM_OK);
+            assert_eq!(code, 0);
+            let mut value = 0;
+            assert_eq!(iridium_vm_register(vm, 0, &mut value), IRIDIUM_OK);
+            assert_eq!(value, 100);
+            assert_eq!(iridium_vm_register(vm, 32, &mut value), IRIDIUM_ERR_OUT_OF_BOUNDS);
+            iridium_vm_free(vm);
+        }
+    }
+
+    #[test]
+    fn test_run_status() {
+        let run = |source: &str, setup: &dyn Fn(&mut VM)| {
+            let program = Assembler::new().assemble(source).unwrap();
+            unsafe {
+                let vm = iridium_vm_new();
+                setup(&mut *vm);
+                iridium_vm_load(vm, program.as_ptr(), program.len());
+                let mut code = 7;
+                let status = iridium_vm_run(vm, &mut code);
+                iridium_vm_free(vm);
+                (status, code)
+            }
+        };
+        // Exit codes that look like error codes are still told apart from them.
+        let exit = ".data\n.code\nload $0 #-1\nexit $0";
+        assert_eq!(run(exit, &|_| {}), (IRIDIUM_OK, -1));
+        let crash = ".data\n.code\nload $0 #0\ndiv $0 $0 $1\nhlt";
+        assert_eq!(run(crash, &|_| {}), (IRIDIUM_CRASHED, 1));
+        let hlt = ".data\n.code\nload $0 #1\nhlt";
+        let kill = |vm: &mut VM| vm.stop_handle().store(true, Ordering::Relaxed);
+        assert_eq!(run(hlt, &kill), (IRIDIUM_KILLED, 7));
+        let pause = |vm: &mut VM| {
+            vm.set_breakpoint(68);
+        };
+        assert_eq!(run(hlt, &pause), (IRIDIUM_PAUSED, 7));
+        let budget = |vm: &mut VM| vm.set_instruction_budget(Some(1));
+        assert_eq!(run(hlt, &budget), (IRIDIUM_BUDGET_EXCEEDED, 7));
+    }
+
+    #[test]
+    fn test_panic_is_caught() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble(".data\n.code\ncallhost #0\nhlt").unwrap();
+        unsafe {
+            let vm = iridium_vm_new();
+            (*vm).register_host_function("boom", |_| panic!("boom"));
+            assert_eq!(iridium_vm_load(vm, program.as_ptr(), program.len()), IRIDIUM_OK);
+            assert_eq!(iridium_vm_run(vm, ptr::null_mut()), IRIDIUM_ERR_PANIC);
+            iridium_vm_free(vm);
+        }
+    }
+
+    #[test]
+    fn test_null_pointers() {
+        unsafe {
+            assert_eq!(
+                iridium_vm_run(ptr::null_mut(), ptr::null_mut()),
+                IRIDIUM_ERR_NULL
+            );
+            assert_eq!(iridium_vm_heap_len(ptr::null()), 0);
+            iridium_vm_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_read_heap_bounds() {
+        unsafe {
+            let vm = iridium_vm_new();
+            let mut buf = [0u8; 4];
+            assert_eq!(
+                iridium_vm_read_heap(vm, 0, buf.as_mut_ptr(), 4),
+                IRIDIUM_ERR_OUT_OF_BOUNDS
+            );
+            iridium_vm_free(vm);
+        }
+    }
+}
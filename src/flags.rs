@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+
+/// The condition flags set by `CMP`, the comparison instructions and the arithmetic instructions,
+/// and read by the conditional jumps. Kept as bits of a single byte so saving and restoring them is
+/// cheap.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Flags(u8);
+
+impl Flags {
+    /// Set when the result was zero.
+    pub const ZERO: u8 = 1;
+    /// Set when the result was negative as a signed value.
+    pub const NEGATIVE: u8 = 1 << 1;
+    /// Set when the operation carried (or borrowed) as unsigned arithmetic.
+    pub const CARRY: u8 = 1 << 2;
+    /// Set when the operation overflowed as signed arithmetic.
+    pub const OVERFLOW: u8 = 1 << 3;
+    /// Set when the condition the last comparison instruction tested held, e.g. when the registers
+    /// `EQ` compared were equal. Read by `JEQ` and `JNEQ`, and left alone by arithmetic.
+    pub const CONDITION: u8 = 1 << 4;
+
+    /// Returns the flags for an operation that produced `result`.
+    #[inline]
+    pub fn from_result(result: i32, carry: bool, overflow: bool) -> Flags {
+        Flags(
+            ((result == 0) as u8 * Flags::ZERO)
+                | ((result < 0) as u8 * Flags::NEGATIVE)
+                | (carry as u8 * Flags::CARRY)
+                | (overflow as u8 * Flags::OVERFLOW),
+        )
+    }
+
+    /// Returns the flags set by comparing `a` with `b`, i.e. by computing `a - b`.
+    pub fn compare(a: i32, b: i32) -> Flags {
+        let (result, overflow) = a.overflowing_sub(b);
+        Flags::from_result(result, (a as u32) < (b as u32), overflow)
+    }
+
+    /// Returns the flags with the condition flag set to `condition`.
+    #[inline]
+    pub fn with_condition(self, condition: bool) -> Flags {
+        Flags((self.0 & !Flags::CONDITION) | (condition as u8 * Flags::CONDITION))
+    }
+
+    /// Returns the flags as bits.
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn zero(self) -> bool {
+        self.0 & Flags::ZERO != 0
+    }
+
+    pub fn negative(self) -> bool {
+        self.0 & Flags::NEGATIVE != 0
+    }
+
+    pub fn carry(self) -> bool {
+        self.0 & Flags::CARRY != 0
+    }
+
+    pub fn overflow(self) -> bool {
+        self.0 & Flags::OVERFLOW != 0
+    }
+
+    pub fn condition(self) -> bool {
+        self.0 & Flags::CONDITION != 0
+    }
+
+    /// Returns whether a comparison found its first operand less than its second, as signed
+    /// values.
+    pub fn less(self) -> bool {
+        self.negative() != self.overflow()
+    }
+
+    /// Returns whether a comparison found its first operand greater than its second, as signed
+    /// values.
+    pub fn greater(self) -> bool {
+        !self.zero() && !self.less()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_result() {
+        let flags = Flags::from_result(0, true, false);
+        assert!(flags.zero() && flags.carry());
+        assert!(!flags.negative() && !flags.overflow());
+        assert_eq!(
+            Flags::from_result(-1, false, true).bits(),
+            Flags::NEGATIVE | Flags::OVERFLOW
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        assert!(Flags::compare(3, 3).zero());
+        assert!(Flags::compare(-5, 3).less());
+        assert!(Flags::compare(3, -5).greater());
+        // The overflow in `i32::MIN - 1` does not change the answer.
+        assert!(Flags::compare(i32::MIN, 1).less());
+        assert!(Flags::compare(i32::MAX, -1).greater());
+        // Unsigned, -1 is the largest value, so 3 borrows from it.
+        assert!(Flags::compare(3, -1).carry());
+    }
+
+    #[test]
+    fn test_with_condition() {
+        let flags = Flags::compare(3, 3).with_condition(true);
+        assert!(flags.zero() && flags.condition());
+        let flags = flags.with_condition(false);
+        assert!(flags.zero() && !flags.condition());
+    }
+}
@@ -0,0 +1,327 @@
+//! The gRPC VM control service defined in `proto/iridium.proto`.
+
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status};
+
+use crate::assembler::Assembler;
+use crate::remote::{remote_vm, DEFAULT_INSTRUCTION_BUDGET};
+use crate::scheduler::SharedScheduler;
+use crate::vm::{VMEvent, VMEventType};
+
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("iridium");
+}
+
+use proto::submit_program_request::Program;
+use proto::vm_control_server::{VmControl, VmControlServer};
+use proto::{
+    process_event, vm_event, KillProcessResponse, ProcessEvent, ProcessRequest, ProcessState,
+    SubmitProgramRequest, SubmitProgramResponse,
+};
+
+/// How often `StreamEvents` checks a process for new output.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Runs the programs submitted by gRPC clients.
+#[derive(Clone)]
+pub struct VmControlService {
+    scheduler: SharedScheduler,
+    /// The most instructions a submitted program runs. Unlimited when `None`.
+    budget: Option<u64>,
+}
+
+impl Default for VmControlService {
+    fn default() -> VmControlService {
+        VmControlService::with_scheduler(SharedScheduler::default())
+    }
+}
+
+impl VmControlService {
+    /// Returns a new `VmControlService` with no processes.
+    pub fn new() -> VmControlService {
+        VmControlService::default()
+    }
+
+    /// Returns a new `VmControlService` that spawns programs with `scheduler`, e.g. the node's.
+    pub fn with_scheduler(scheduler: SharedScheduler) -> VmControlService {
+        VmControlService {
+            scheduler,
+            budget: Some(DEFAULT_INSTRUCTION_BUDGET),
+        }
+    }
+
+    /// Makes submitted programs stop after `budget` instructions, or never when `None`, instead of
+    /// after `DEFAULT_INSTRUCTION_BUDGET`.
+    pub fn with_budget(mut self, budget: Option<u64>) -> VmControlService {
+        self.budget = budget;
+        self
+    }
+
+    /// Serves clients on `addr` until the listener fails, blocking the calling thread.
+    pub fn serve(self, addr: &str) -> io::Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            self.serve_on(listener).await
+        })
+    }
+
+    /// Serves clients on an already bound listener.
+    pub async fn serve_on(self, listener: tokio::net::TcpListener) -> io::Result<()> {
+        tonic::transport::Server::builder()
+            .add_service(VmControlServer::new(self))
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await
+            .map_err(io::Error::other)
+    }
+
+    fn not_found(pid: u32) -> Status {
+        Status::not_found(format!("No process with pid {}", pid))
+    }
+}
+
+#[tonic::async_trait]
+impl VmControl for VmControlService {
+    type StreamEventsStream = Pin<Box<dyn Stream<Item = Result<ProcessEvent, Status>> + Send>>;
+
+    async fn submit_program(
+        &self,
+        request: Request<SubmitProgramRequest>,
+    ) -> Result<Response<SubmitProgramResponse>, Status> {
+        let program = match request.into_inner().program {
+            Some(Program::Binary(program)) => program,
+            Some(Program::Assembly(source)) => {
+                Assembler::new().assemble(&source).map_err(|errors| {
+                    let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                    Status::invalid_argument(errors.join("\n"))
+                })?
+            }
+            None => return Err(Status::invalid_argument("No program given")),
+        };
+        let mut vm = remote_vm(self.budget);
+        vm.add_bytes(program);
+        match self.scheduler.lock().unwrap().spawn(vm) {
+            Some(pid) => Ok(Response::new(SubmitProgramResponse { pid })),
+            None => Err(Status::resource_exhausted("The process table is full")),
+        }
+    }
+
+    async fn stream_events(
+        &self,
+        request: Request<ProcessRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        let pid = request.into_inner().pid;
+        if self.scheduler.lock().unwrap().is_running(pid).is_none() {
+            return Err(VmControlService::not_found(pid));
+        }
+        let (sender, receiver) = mpsc::channel(16);
+        let scheduler = self.scheduler.clone();
+        tokio::spawn(async move {
+            let mut sent = 0;
+            loop {
+                // Check whether the process has stopped before reading its output, so the final
+                // output is always sent before its events.
+                let (running, output) = {
+                    let mut scheduler = scheduler.lock().unwrap();
+                    let running = scheduler.is_running(pid).unwrap_or(false);
+                    (running, scheduler.output(pid).unwrap_or_default())
+                };
+                if output.len() > sent {
+                    let event = process_event::Event::Output(output[sent..].to_string());
+                    sent = output.len();
+                    if sender
+                        .send(Ok(ProcessEvent { event: Some(event) }))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                if !running {
+                    break;
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            let events = scheduler.lock().unwrap().events(pid).unwrap_or_default();
+            for event in events {
+                let event = process_event::Event::VmEvent(to_proto(&event));
+                if sender
+                    .send(Ok(ProcessEvent { event: Some(event) }))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+
+    async fn kill_process(
+        &self,
+        request: Request<ProcessRequest>,
+    ) -> Result<Response<KillProcessResponse>, Status> {
+        let pid = request.into_inner().pid;
+        if self.scheduler.lock().unwrap().kill(pid) {
+            Ok(Response::new(KillProcessResponse {}))
+        } else {
+            Err(VmControlService::not_found(pid))
+        }
+    }
+
+    async fn get_state(
+        &self,
+        request: Request<ProcessRequest>,
+    ) -> Result<Response<ProcessState>, Status> {
+        let pid = request.into_inner().pid;
+        let mut scheduler = self.scheduler.lock().unwrap();
+        let running = scheduler
+            .is_running(pid)
+            .ok_or_else(|| VmControlService::not_found(pid))?;
+        Ok(Response::new(ProcessState {
+            pid,
+            running,
+            output: scheduler.output(pid).unwrap_or_default(),
+            registers: scheduler.registers(pid).unwrap_or_default(),
+            events: scheduler
+                .events(pid)
+                .unwrap_or_default()
+                .iter()
+                .map(to_proto)
+                .collect(),
+        }))
+    }
+}
+
+fn to_proto(event: &VMEvent) -> proto::VmEvent {
+    let (kind, code) = match event.event {
+        VMEventType::Start => (vm_event::Kind::Start, 0),
+        VMEventType::GracefulStop { code } => (vm_event::Kind::GracefulStop, code),
+        VMEventType::Crash { code, .. } => (vm_event::Kind::Crash, code),
+        VMEventType::Killed => (vm_event::Kind::Killed, 0),
+        VMEventType::BudgetExceeded => (vm_event::Kind::BudgetExceeded, 0),
+        VMEventType::Paused { .. } => (vm_event::Kind::Paused, 0),
+        VMEventType::InvalidHeader { .. } => (vm_event::Kind::InvalidHeader, 1),
+    };
+    let (reason, pc) = match event.event {
+        VMEventType::Crash { reason, pc, .. } => (reason.to_string(), pc as u64),
+        VMEventType::Paused { pc } => (String::new(), pc as u64),
+        VMEventType::InvalidHeader { error } => (error.to_string(), 0),
+        _ => (String::new(), 0),
+    };
+    proto::VmEvent {
+        kind: kind as i32,
+        code,
+        at: event.at.timestamp_millis(),
+        application_id: event.application_id.to_string(),
+        reason,
+        pc,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    #[test]
+    fn test_submit_and_stream() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let service = VmControlService::new();
+            let pid = service
+                .submit_program(Request::new(SubmitProgramRequest {
+                    program: Some(Program::Assembly(".data\n.code\nload $1 #9\nhlt".into())),
+                }))
+                .await
+                .unwrap()
+                .into_inner()
+                .pid;
+            let events: Vec<ProcessEvent> = service
+                .stream_events(Request::new(ProcessRequest { pid }))
+                .await
+                .unwrap()
+                .into_inner()
+                .map(Result::unwrap)
+                .collect()
+                .await;
+            assert_eq!(
+                events[0].event,
+                Some(process_event::Event::Output("HLT encountered\n".into()))
+            );
+            match events.last().unwrap().event {
+                Some(process_event::Event::VmEvent(ref event)) => {
+                    assert_eq!(event.kind, vm_event::Kind::GracefulStop as i32)
+                }
+                ref other => panic!("Expected a VM event, got {:?}", other),
+            }
+
+            let state = service
+                .get_state(Request::new(ProcessRequest { pid }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(!state.running);
+            assert_eq!(state.registers[1], 9);
+        });
+    }
+
+    #[test]
+    fn test_submitted_programs_are_sandboxed() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let service = VmControlService::new().with_budget(Some(100));
+            let mut last_events = vec![];
+            // Reading input gets EOF instead of waiting on the node's stdin, and a program that
+            // never stops is stopped once it runs out of instructions.
+            let sources = [".data\n.code\nreadi $0\nhlt", ".data\n.code\nloop: jmp @loop"];
+            for source in sources.iter() {
+                let pid = service
+                    .submit_program(Request::new(SubmitProgramRequest {
+                        program: Some(Program::Assembly(source.to_string())),
+                    }))
+                    .await
+                    .unwrap()
+                    .into_inner()
+                    .pid;
+                let mut scheduler = service.scheduler.lock().unwrap();
+                scheduler.wait(pid);
+                last_events.push(scheduler.events(pid).unwrap().pop().unwrap().event);
+            }
+            assert_eq!(
+                last_events,
+                vec![
+                    VMEventType::GracefulStop { code: 0 },
+                    VMEventType::BudgetExceeded
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_errors() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let service = VmControlService::new();
+            let status = service
+                .submit_program(Request::new(SubmitProgramRequest {
+                    program: Some(Program::Assembly("bogus".into())),
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            let status = service
+                .kill_process(Request::new(ProcessRequest { pid: 3 }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::NotFound);
+        });
+    }
+}
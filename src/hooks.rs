@@ -0,0 +1,122 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::instruction::DecodedInstruction;
+use crate::vm::{VMEvent, VM};
+
+/// A closure called around each instruction with the VM, the instruction's address and the
+/// instruction itself. Hooks that keep state, such as profilers, hold it behind a lock.
+pub type HookFn = Arc<dyn Fn(&VM, usize, DecodedInstruction) + Send + Sync>;
+/// A closure called with each event as the VM records it.
+pub type EventHookFn = Arc<dyn Fn(&VMEvent) + Send + Sync>;
+
+/// The hooks registered on a VM, called in registration order. Tracers, profilers and debuggers
+/// can be built on them without changing the VM.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    before: Vec<HookFn>,
+    after: Vec<HookFn>,
+    events: Vec<EventHookFn>,
+}
+
+impl Hooks {
+    /// Returns a table with no hooks.
+    pub fn new() -> Hooks {
+        Hooks::default()
+    }
+
+    /// Registers `f` to be called before each instruction executes.
+    pub fn before<F>(&mut self, f: F)
+    where
+        F: Fn(&VM, usize, DecodedInstruction) + Send + Sync + 'static,
+    {
+        self.before.push(Arc::new(f));
+    }
+
+    /// Registers `f` to be called after each instruction executes, including one that stopped
+    /// the VM.
+    pub fn after<F>(&mut self, f: F)
+    where
+        F: Fn(&VM, usize, DecodedInstruction) + Send + Sync + 'static,
+    {
+        self.after.push(Arc::new(f));
+    }
+
+    /// Registers `f` to be called with each event as the VM records it, e.g. to stream the events
+    /// of a program that is still running.
+    pub fn on_event<F>(&mut self, f: F)
+    where
+        F: Fn(&VMEvent) + Send + Sync + 'static,
+    {
+        self.events.push(Arc::new(f));
+    }
+
+    /// Returns whether no hooks are registered around instructions. Event hooks are not counted,
+    /// as they do not change how instructions are executed.
+    pub fn is_empty(&self) -> bool {
+        self.before.is_empty() && self.after.is_empty()
+    }
+
+    /// Calls the hooks registered with `before`.
+    pub fn call_before(&self, vm: &VM, pc: usize, instruction: DecodedInstruction) {
+        for hook in &self.before {
+            hook(vm, pc, instruction);
+        }
+    }
+
+    /// Calls the hooks registered with `after`.
+    pub fn call_after(&self, vm: &VM, pc: usize, instruction: DecodedInstruction) {
+        for hook in &self.after {
+            hook(vm, pc, instruction);
+        }
+    }
+
+    /// Calls the hooks registered with `on_event`.
+    pub fn call_event(&self, event: &VMEvent) {
+        for hook in &self.events {
+            hook(event);
+        }
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("before", &self.before.len())
+            .field("after", &self.after.len())
+            .field("events", &self.events.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Opcode;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_hooks_are_called_in_order() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let mut hooks = Hooks::new();
+        assert!(hooks.is_empty());
+        for name in ["first", "second"].iter() {
+            let calls = calls.clone();
+            hooks.before(move |_, pc, _| calls.lock().unwrap().push((*name, pc)));
+        }
+        let after = calls.clone();
+        hooks.after(move |_, pc, _| after.lock().unwrap().push(("after", pc)));
+
+        let vm = VM::new();
+        let instruction = DecodedInstruction {
+            opcode: Opcode::HLT,
+            operands: [0; 3],
+        };
+        hooks.call_before(&vm, 64, instruction);
+        hooks.call_after(&vm, 64, instruction);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![("first", 64), ("second", 64), ("after", 64)]
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// The view of the VM handed to a host function when guest code executes `CALLHOST`.
+///
+/// Calling convention: arguments are passed in registers `$1` through `$31`, the value the
+/// function returns is written back into `$0`. Buffers are passed as a heap offset and a length
+/// in two consecutive argument registers.
+pub struct HostCall<'a> {
+    pub registers: &'a mut [i32],
+    pub heap: &'a mut Vec<u8>,
+}
+
+impl<'a> HostCall<'a> {
+    /// Returns the `n`th argument (zero based), i.e. the contents of register `$n+1`.
+    pub fn arg(&self, n: usize) -> Option<i32> {
+        self.registers.get(n + 1).cloned()
+    }
+
+    /// Returns the heap buffer described by arguments `n` (offset) and `n + 1` (length).
+    pub fn buffer(&self, n: usize) -> Option<&[u8]> {
+        let (start, end) = self.buffer_range(n)?;
+        self.heap.get(start..end)
+    }
+
+    /// Returns the heap buffer described by arguments `n` and `n + 1` for writing.
+    pub fn buffer_mut(&mut self, n: usize) -> Option<&mut [u8]> {
+        let (start, end) = self.buffer_range(n)?;
+        self.heap.get_mut(start..end)
+    }
+
+    fn buffer_range(&self, n: usize) -> Option<(usize, usize)> {
+        let start = self.arg(n)?;
+        let len = self.arg(n + 1)?;
+        if start < 0 || len < 0 {
+            return None;
+        }
+        let start = start as usize;
+        Some((start, start.checked_add(len as usize)?))
+    }
+}
+
+/// A Rust closure that guest code can call.
+pub type HostFn = Arc<dyn Fn(&mut HostCall) -> i32 + Send + Sync>;
+
+/// The host functions registered on a VM. A function's id is its registration order, and is
+/// the immediate operand guest code passes to `CALLHOST`.
+#[derive(Clone, Default)]
+pub struct HostFunctions {
+    functions: Vec<(String, HostFn)>,
+}
+
+impl HostFunctions {
+    /// Returns an empty table.
+    pub fn new() -> HostFunctions {
+        HostFunctions { functions: vec![] }
+    }
+
+    /// Registers `f` under `name` and returns its id. Registering a name twice replaces the
+    /// previous function but keeps its id.
+    pub fn register<F>(&mut self, name: &str, f: F) -> u16
+    where
+        F: Fn(&mut HostCall) -> i32 + Send + Sync + 'static,
+    {
+        if let Some(id) = self.id(name) {
+            self.functions[id as usize].1 = Arc::new(f);
+            return id;
+        }
+        self.functions.push((name.to_string(), Arc::new(f)));
+        (self.functions.len() - 1) as u16
+    }
+
+    /// Returns the id of the function registered under `name`.
+    pub fn id(&self, name: &str) -> Option<u16> {
+        self.functions
+            .iter()
+            .position(|(n, _)| n == name)
+            .map(|id| id as u16)
+    }
+
+    /// Returns the function with the given id.
+    pub fn get(&self, id: u16) -> Option<HostFn> {
+        self.functions.get(id as usize).map(|(_, f)| f.clone())
+    }
+}
+
+impl fmt::Debug for HostFunctions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.functions.iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_host_function() {
+        let mut functions = HostFunctions::new();
+        let id = functions.register("double", |call| call.arg(0).unwrap() * 2);
+        assert_eq!(id, 0);
+        assert_eq!(functions.register("noop", |_| 0), 1);
+        assert_eq!(functions.register("double", |_| 0), 0);
+        assert_eq!(functions.id("noop"), Some(1));
+        assert!(functions.get(2).is_none());
+    }
+
+    #[test]
+    fn test_host_call_buffer() {
+        let mut registers = [0, 1, 3, 0];
+        let mut heap = vec![0, 10, 20, 30];
+        let call = HostCall {
+            registers: &mut registers,
+            heap: &mut heap,
+        };
+        assert_eq!(call.buffer(0), Some(&[10, 20, 30][..]));
+        assert_eq!(call.buffer(2), None);
+    }
+}
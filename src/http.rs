@@ -0,0 +1,464 @@
+//! A small JSON-over-HTTP API for driving a node from external services.
+//!
+//! | Method and path          | Action                                                   |
+//! |--------------------------|----------------------------------------------------------|
+//! | `POST /programs`         | Spawns a program. The body is assembly source, or an     |
+//! |                          | assembled binary when sent as `application/octet-stream` |
+//! | `GET /processes`         | Lists processes                                          |
+//! | `GET /processes/{pid}`   | Returns a process's output and events                    |
+//! | `DELETE /processes/{pid}`| Kills a process, or drops a finished one from the table  |
+//! | `GET /cluster`           | Lists the members of the node's cluster                  |
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::assembler::Assembler;
+use crate::cluster::Cluster;
+use crate::remote::{remote_vm, DEFAULT_INSTRUCTION_BUDGET};
+use crate::scheduler::SharedScheduler;
+use crate::vm::VMEvent;
+
+/// Requests with larger bodies are rejected.
+const MAX_BODY_LENGTH: usize = 16 * 1024 * 1024;
+
+/// A parsed HTTP request.
+#[derive(Debug, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// The status code and JSON body of a response.
+#[derive(Debug, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: serde_json::Value,
+}
+
+impl HttpResponse {
+    fn ok<T: Serialize>(body: T) -> HttpResponse {
+        HttpResponse::with_status(200, body)
+    }
+
+    fn with_status<T: Serialize>(status: u16, body: T) -> HttpResponse {
+        HttpResponse {
+            status,
+            body: serde_json::to_value(body).unwrap_or(serde_json::Value::Null),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> HttpResponse {
+        HttpResponse::with_status(status, json!({ "error": message }))
+    }
+}
+
+/// A process as listed by `GET /processes`.
+#[derive(Debug, Serialize)]
+struct ProcessSummary {
+    pid: u32,
+    running: bool,
+}
+
+/// A process as returned by `GET /processes/{pid}`.
+#[derive(Debug, Serialize)]
+struct ProcessDetails {
+    pid: u32,
+    running: bool,
+    output: String,
+    events: Vec<VMEvent>,
+}
+
+/// Runs and inspects processes through the node's scheduler.
+#[derive(Clone)]
+pub struct Api {
+    scheduler: SharedScheduler,
+    cluster: Option<Arc<Cluster>>,
+    /// The most instructions a submitted program runs. Unlimited when `None`.
+    budget: Option<u64>,
+}
+
+impl Api {
+    /// Returns a new `Api` that spawns programs with `scheduler`.
+    pub fn new(scheduler: SharedScheduler, cluster: Option<Arc<Cluster>>) -> Api {
+        Api {
+            scheduler,
+            cluster,
+            budget: Some(DEFAULT_INSTRUCTION_BUDGET),
+        }
+    }
+
+    /// Makes submitted programs stop after `budget` instructions, or never when `None`, instead of
+    /// after `DEFAULT_INSTRUCTION_BUDGET`.
+    pub fn with_budget(mut self, budget: Option<u64>) -> Api {
+        self.budget = budget;
+        self
+    }
+
+    /// Routes a request to its handler.
+    pub fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        let segments: Vec<&str> = request
+            .path
+            .split('?')
+            .next()
+            .unwrap_or("")
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("POST", ["programs"]) => self.submit(request),
+            ("GET", ["processes"]) => self.list(),
+            ("GET", ["processes", pid]) => match pid.parse() {
+                Ok(pid) => self.inspect(pid),
+                Err(_) => HttpResponse::error(400, "Invalid pid"),
+            },
+            ("DELETE", ["processes", pid]) => match pid.parse() {
+                Ok(pid) => self.kill(pid),
+                Err(_) => HttpResponse::error(400, "Invalid pid"),
+            },
+            ("GET", ["cluster"]) => match self.cluster {
+                Some(ref cluster) => HttpResponse::ok(cluster.members()),
+                None => HttpResponse::error(404, "This node is not part of a cluster"),
+            },
+            (_, ["programs"]) | (_, ["processes"]) | (_, ["processes", _]) | (_, ["cluster"]) => {
+                HttpResponse::error(405, "Method not allowed")
+            }
+            _ => HttpResponse::error(404, "Not found"),
+        }
+    }
+
+    fn submit(&self, request: &HttpRequest) -> HttpResponse {
+        let binary = request
+            .content_type
+            .as_ref()
+            .is_some_and(|t| t.starts_with("application/octet-stream"));
+        let program = if binary {
+            request.body.clone()
+        } else {
+            let source = match String::from_utf8(request.body.clone()) {
+                Ok(source) => source,
+                Err(_) => return HttpResponse::error(400, "Assembly source must be UTF-8"),
+            };
+            match Assembler::new().assemble(&source) {
+                Ok(program) => program,
+                Err(errors) => {
+                    let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                    return HttpResponse::with_status(400, json!({ "errors": errors }));
+                }
+            }
+        };
+
+        let mut vm = remote_vm(self.budget);
+        vm.add_bytes(program);
+        match self.scheduler.lock().unwrap().spawn(vm) {
+            Some(pid) => HttpResponse::with_status(201, json!({ "pid": pid })),
+            None => HttpResponse::error(503, "The process table is full"),
+        }
+    }
+
+    fn list(&self) -> HttpResponse {
+        let mut scheduler = self.scheduler.lock().unwrap();
+        let processes: Vec<ProcessSummary> = scheduler
+            .pids()
+            .into_iter()
+            .map(|pid| ProcessSummary {
+                pid,
+                running: scheduler.is_running(pid).unwrap_or(false),
+            })
+            .collect();
+        HttpResponse::ok(processes)
+    }
+
+    fn inspect(&self, pid: u32) -> HttpResponse {
+        let mut scheduler = self.scheduler.lock().unwrap();
+        let running = match scheduler.is_running(pid) {
+            Some(running) => running,
+            None => return HttpResponse::error(404, "No such process"),
+        };
+        HttpResponse::ok(ProcessDetails {
+            pid,
+            running,
+            output: scheduler.output(pid).unwrap_or_default(),
+            events: scheduler.events(pid).unwrap_or_default(),
+        })
+    }
+
+    fn kill(&self, pid: u32) -> HttpResponse {
+        let mut scheduler = self.scheduler.lock().unwrap();
+        match scheduler.is_running(pid) {
+            Some(true) => {
+                scheduler.kill(pid);
+                HttpResponse::ok(json!({ "pid": pid, "killed": true }))
+            }
+            Some(false) => {
+                scheduler.remove(pid);
+                HttpResponse::ok(json!({ "pid": pid, "removed": true }))
+            }
+            None => HttpResponse::error(404, "No such process"),
+        }
+    }
+}
+
+/// Serves the API over HTTP/1.1. Each connection handles a single request.
+pub struct HttpServer {
+    listener: TcpListener,
+    api: Arc<Api>,
+}
+
+impl HttpServer {
+    /// Binds a new `HttpServer` to the given address, spawning programs with `scheduler`.
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        scheduler: SharedScheduler,
+        cluster: Option<Arc<Cluster>>,
+    ) -> io::Result<HttpServer> {
+        Ok(HttpServer {
+            listener: TcpListener::bind(addr)?,
+            api: Arc::new(Api::new(scheduler, cluster)),
+        })
+    }
+
+    /// Makes submitted programs stop after `budget` instructions, or never when `None`.
+    pub fn with_budget(mut self, budget: Option<u64>) -> HttpServer {
+        self.api = Arc::new(Api::clone(&self.api).with_budget(budget));
+        self
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until the listener fails. Each connection is served on its own thread.
+    pub fn run(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let api = self.api.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, &api) {
+                    tracing::warn!("HTTP connection closed with an error: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn handle_connection(stream: TcpStream, api: &Api) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let response = match read_request(&mut reader) {
+        Ok(request) => api.handle(&request),
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+            HttpResponse::error(400, &e.to_string())
+        }
+        Err(e) => return Err(e),
+    };
+    write_response(stream, &response)
+}
+
+/// Reads a request line, headers, and a body of `Content-Length` bytes.
+pub fn read_request<R: BufRead>(reader: &mut R) -> io::Result<HttpRequest> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(invalid_data("Malformed request line")),
+    };
+
+    let mut content_type = None;
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed in headers",
+            ));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = match header.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => return Err(invalid_data("Malformed header")),
+        };
+        match name.as_str() {
+            "content-type" => content_type = Some(value.to_string()),
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| invalid_data("Invalid Content-Length"))?
+            }
+            _ => {}
+        }
+    }
+    if content_length > MAX_BODY_LENGTH {
+        return Err(invalid_data("Request body is too large"));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(HttpRequest {
+        method,
+        path,
+        content_type,
+        body,
+    })
+}
+
+fn write_response<W: Write>(mut writer: W, response: &HttpResponse) -> io::Result<()> {
+    let body = response.body.to_string();
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason(response.status),
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn request(method: &str, path: &str, body: &str) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            content_type: None,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_read_request() {
+        let raw =
+            "POST /programs HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 3\r\n\r\nhlt";
+        let request = read_request(&mut raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/programs");
+        assert_eq!(request.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(request.body, b"hlt");
+        assert!(read_request(&mut "garbage\r\n\r\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_submit_and_inspect() {
+        let api = Api::new(SharedScheduler::default(), None);
+        let response = api.handle(&request("POST", "/programs", ".data\n.code\nhlt"));
+        assert_eq!(response.status, 201);
+        let pid = response.body["pid"].as_u64().unwrap() as u32;
+        api.scheduler.lock().unwrap().wait(pid);
+
+        let response = api.handle(&request("GET", &format!("/processes/{}", pid), ""));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["running"], false);
+        assert_eq!(response.body["output"], "HLT encountered\n");
+        assert_eq!(
+            response.body["events"].as_array().unwrap().last().unwrap()["event"],
+            json!({ "GracefulStop": { "code": 0 } })
+        );
+
+        let response = api.handle(&request("GET", "/processes", ""));
+        assert_eq!(response.body, json!([{ "pid": pid, "running": false }]));
+
+        // Deleting a finished process drops it.
+        let path = format!("/processes/{}", pid);
+        assert_eq!(
+            api.handle(&request("DELETE", &path, "")).body["removed"],
+            true
+        );
+        assert_eq!(api.handle(&request("GET", &path, "")).status, 404);
+    }
+
+    #[test]
+    fn test_submitted_programs_are_sandboxed() {
+        let api = Api::new(SharedScheduler::default(), None).with_budget(Some(100));
+        let last_event = |source: &str| {
+            let pid = api.handle(&request("POST", "/programs", source)).body["pid"]
+                .as_u64()
+                .unwrap() as u32;
+            api.scheduler.lock().unwrap().wait(pid);
+            let response = api.handle(&request("GET", &format!("/processes/{}", pid), ""));
+            response.body["events"].as_array().unwrap().last().unwrap()["event"].clone()
+        };
+        // Reading input gets EOF instead of waiting on the node's stdin.
+        assert_eq!(
+            last_event(".data\n.code\nreadi $0\nhlt"),
+            json!({ "GracefulStop": { "code": 0 } })
+        );
+        assert_eq!(
+            last_event(".data\n.code\nloop: jmp @loop"),
+            json!("BudgetExceeded")
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let api = Api::new(SharedScheduler::default(), None);
+        assert_eq!(
+            api.handle(&request("POST", "/programs", "bogus")).status,
+            400
+        );
+        assert_eq!(api.handle(&request("GET", "/processes/7", "")).status, 404);
+        assert_eq!(
+            api.handle(&request("DELETE", "/processes/x", "")).status,
+            400
+        );
+        assert_eq!(api.handle(&request("PUT", "/processes", "")).status, 405);
+        assert_eq!(api.handle(&request("GET", "/cluster", "")).status, 404);
+        assert_eq!(api.handle(&request("GET", "/nowhere", "")).status, 404);
+    }
+
+    #[test]
+    fn test_kill_over_http() {
+        let server = HttpServer::bind("127.0.0.1:0", SharedScheduler::default(), None).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let send = |raw: String| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(raw.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let source = ".data\n.code\nload $0 #64\njmp $0";
+        let response = send(format!(
+            "POST /programs HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            source.len(),
+            source
+        ));
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(response.ends_with("{\"pid\":0}"));
+
+        let response = send("DELETE /processes/0 HTTP/1.1\r\n\r\n".to_string());
+        assert!(response.ends_with("{\"killed\":true,\"pid\":0}"));
+    }
+}
@@ -1,7 +1,10 @@
+use std::fmt;
+
 use nom::types::CompleteStr;
+use serde::{Deserialize, Serialize};
 
 /// Opcode encapsulates the various operation codes.
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum Opcode {
     HLT,
     LOAD,
@@ -24,11 +27,76 @@ pub enum Opcode {
     INC,
     DEC,
     PRTS,
+    CALLHOST,
+    AND,
+    OR,
+    XOR,
+    NOT,
+    SHL,
+    SHR,
+    ROL,
+    ROR,
+    MOD,
+    GETR,
+    PUSH,
+    POP,
+    CALL,
+    CALLR,
+    RET,
+    SYSCALL,
+    LUI,
+    JO,
+    JNO,
+    JC,
+    JNC,
+    JMPL,
+    JEQL,
+    JNEQL,
+    DJNZ,
+    EQR,
+    NEQR,
+    GTR,
+    LTR,
+    GTQR,
+    LTQR,
+    GTU,
+    LTU,
+    GTEU,
+    LTEU,
+    AADD,
+    CAS,
+    RAND,
+    SLEEP,
+    READI,
+    READS,
+    EXIT,
+    STRAP,
+    TRAP,
+    FREE,
+    CMP,
+    JZ,
+    JNZ,
+    JLT,
+    JGE,
+    JGT,
+    JLE,
+    PRTSR,
+    LDB,
+    STB,
+    LDW,
+    STW,
     IGL,
 }
 
 impl From<u8> for Opcode {
     fn from(v: u8) -> Self {
+        Opcode::from_byte(v)
+    }
+}
+
+impl Opcode {
+    /// Returns the opcode encoded by `v`, or `IGL` if there is none.
+    pub const fn from_byte(v: u8) -> Opcode {
         match v {
             0 => Opcode::HLT,
             1 => Opcode::LOAD,
@@ -51,9 +119,189 @@ impl From<u8> for Opcode {
             18 => Opcode::INC,
             19 => Opcode::DEC,
             20 => Opcode::PRTS,
+            21 => Opcode::CALLHOST,
+            22 => Opcode::AND,
+            23 => Opcode::OR,
+            24 => Opcode::XOR,
+            25 => Opcode::NOT,
+            26 => Opcode::SHL,
+            27 => Opcode::SHR,
+            28 => Opcode::ROL,
+            29 => Opcode::ROR,
+            30 => Opcode::MOD,
+            31 => Opcode::GETR,
+            32 => Opcode::PUSH,
+            33 => Opcode::POP,
+            34 => Opcode::CALL,
+            35 => Opcode::CALLR,
+            36 => Opcode::RET,
+            37 => Opcode::SYSCALL,
+            38 => Opcode::LUI,
+            39 => Opcode::JO,
+            40 => Opcode::JNO,
+            41 => Opcode::JC,
+            42 => Opcode::JNC,
+            43 => Opcode::JMPL,
+            44 => Opcode::JEQL,
+            45 => Opcode::JNEQL,
+            46 => Opcode::DJNZ,
+            47 => Opcode::EQR,
+            48 => Opcode::NEQR,
+            49 => Opcode::GTR,
+            50 => Opcode::LTR,
+            51 => Opcode::GTQR,
+            52 => Opcode::LTQR,
+            53 => Opcode::GTU,
+            54 => Opcode::LTU,
+            55 => Opcode::GTEU,
+            56 => Opcode::LTEU,
+            57 => Opcode::AADD,
+            58 => Opcode::CAS,
+            59 => Opcode::RAND,
+            60 => Opcode::SLEEP,
+            61 => Opcode::READI,
+            62 => Opcode::READS,
+            63 => Opcode::EXIT,
+            64 => Opcode::STRAP,
+            65 => Opcode::TRAP,
+            66 => Opcode::FREE,
+            67 => Opcode::CMP,
+            68 => Opcode::JZ,
+            69 => Opcode::JNZ,
+            70 => Opcode::JLT,
+            71 => Opcode::JGE,
+            72 => Opcode::JGT,
+            73 => Opcode::JLE,
+            74 => Opcode::PRTSR,
+            75 => Opcode::LDB,
+            76 => Opcode::STB,
+            77 => Opcode::LDW,
+            78 => Opcode::STW,
             _ => Opcode::IGL,
         }
     }
+
+    /// Returns how many of the instruction's leading operands name registers.
+    pub const fn register_operands(&self) -> usize {
+        match self {
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::SHL
+            | Opcode::SHR
+            | Opcode::ROL
+            | Opcode::ROR
+            | Opcode::MOD
+            | Opcode::EQR
+            | Opcode::NEQR
+            | Opcode::GTR
+            | Opcode::LTR
+            | Opcode::GTQR
+            | Opcode::LTQR
+            | Opcode::AADD
+            | Opcode::CAS
+            | Opcode::READS => 3,
+            Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GT
+            | Opcode::LT
+            | Opcode::GTQ
+            | Opcode::LTQ
+            | Opcode::NOT
+            | Opcode::GTU
+            | Opcode::LTU
+            | Opcode::GTEU
+            | Opcode::LTEU
+            | Opcode::CMP
+            | Opcode::LDB
+            | Opcode::STB
+            | Opcode::LDW
+            | Opcode::STW => 2,
+            Opcode::LOAD
+            | Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::JEQ
+            | Opcode::JNEQ
+            | Opcode::ALOC
+            | Opcode::INC
+            | Opcode::DEC
+            | Opcode::GETR
+            | Opcode::PUSH
+            | Opcode::POP
+            | Opcode::CALLR
+            | Opcode::SYSCALL
+            | Opcode::LUI
+            | Opcode::JO
+            | Opcode::JNO
+            | Opcode::JC
+            | Opcode::JNC
+            | Opcode::DJNZ
+            | Opcode::RAND
+            | Opcode::SLEEP
+            | Opcode::READI
+            | Opcode::EXIT
+            | Opcode::STRAP
+            | Opcode::TRAP
+            | Opcode::FREE
+            | Opcode::JZ
+            | Opcode::JNZ
+            | Opcode::JLT
+            | Opcode::JGE
+            | Opcode::JGT
+            | Opcode::JLE
+            | Opcode::PRTSR => 1,
+            _ => 0,
+        }
+    }
+
+    /// Returns whether the instruction's only operand is a 24-bit address spanning all three
+    /// operand bytes.
+    pub fn takes_address(&self) -> bool {
+        matches!(self, Opcode::JMPL | Opcode::JEQL | Opcode::JNEQL)
+    }
+
+    /// Returns the jump that takes an address in place of this jump's register, which is what
+    /// the assembler writes when the jump is given a label (e.g. `jmp @loop`).
+    pub fn address_form(&self) -> Option<Opcode> {
+        match self {
+            Opcode::JMP => Some(Opcode::JMPL),
+            Opcode::JEQ => Some(Opcode::JEQL),
+            Opcode::JNEQ => Some(Opcode::JNEQL),
+            _ => None,
+        }
+    }
+
+    /// Returns the instruction that takes a register in place of this one's immediate, which is
+    /// what the assembler writes when it is given a register (e.g. `prts $0`).
+    pub fn register_form(&self) -> Option<Opcode> {
+        match self {
+            Opcode::PRTS => Some(Opcode::PRTSR),
+            _ => None,
+        }
+    }
+
+    /// Returns the mnemonic the assembler reads as this opcode (e.g. `load` for `LOAD`), if it
+    /// has one.
+    pub fn mnemonic(&self) -> Option<String> {
+        let name = format!("{:?}", self).to_lowercase();
+        match Opcode::from(CompleteStr(&name)) {
+            Opcode::IGL => None,
+            opcode if opcode == *self => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Returns the mnemonic of every opcode that has one, in order of their bytes.
+    pub fn mnemonics() -> Vec<String> {
+        (0..=u8::MAX)
+            .filter_map(|byte| Opcode::from_byte(byte).mnemonic())
+            .collect()
+    }
 }
 
 impl<'a> From<CompleteStr<'a>> for Opcode {
@@ -77,7 +325,68 @@ impl<'a> From<CompleteStr<'a>> for Opcode {
             CompleteStr("ltq") => Opcode::LTQ,
             CompleteStr("jeq") => Opcode::JEQ,
             CompleteStr("jneq") => Opcode::JNEQ,
+            CompleteStr("aloc") => Opcode::ALOC,
+            CompleteStr("inc") => Opcode::INC,
+            CompleteStr("dec") => Opcode::DEC,
             CompleteStr("prts") => Opcode::PRTS,
+            CompleteStr("callhost") => Opcode::CALLHOST,
+            CompleteStr("and") => Opcode::AND,
+            CompleteStr("or") => Opcode::OR,
+            CompleteStr("xor") => Opcode::XOR,
+            CompleteStr("not") => Opcode::NOT,
+            CompleteStr("shl") => Opcode::SHL,
+            CompleteStr("shr") => Opcode::SHR,
+            CompleteStr("rol") => Opcode::ROL,
+            CompleteStr("ror") => Opcode::ROR,
+            CompleteStr("mod") => Opcode::MOD,
+            CompleteStr("getr") => Opcode::GETR,
+            CompleteStr("push") => Opcode::PUSH,
+            CompleteStr("pop") => Opcode::POP,
+            CompleteStr("call") => Opcode::CALL,
+            CompleteStr("callr") => Opcode::CALLR,
+            CompleteStr("ret") => Opcode::RET,
+            CompleteStr("syscall") => Opcode::SYSCALL,
+            CompleteStr("lui") => Opcode::LUI,
+            CompleteStr("jo") => Opcode::JO,
+            CompleteStr("jno") => Opcode::JNO,
+            CompleteStr("jc") => Opcode::JC,
+            CompleteStr("jnc") => Opcode::JNC,
+            CompleteStr("jmpl") => Opcode::JMPL,
+            CompleteStr("jeql") => Opcode::JEQL,
+            CompleteStr("jneql") => Opcode::JNEQL,
+            CompleteStr("djnz") => Opcode::DJNZ,
+            CompleteStr("eqr") => Opcode::EQR,
+            CompleteStr("neqr") => Opcode::NEQR,
+            CompleteStr("gtr") => Opcode::GTR,
+            CompleteStr("ltr") => Opcode::LTR,
+            CompleteStr("gtqr") => Opcode::GTQR,
+            CompleteStr("ltqr") => Opcode::LTQR,
+            CompleteStr("gtu") => Opcode::GTU,
+            CompleteStr("ltu") => Opcode::LTU,
+            CompleteStr("gteu") => Opcode::GTEU,
+            CompleteStr("lteu") => Opcode::LTEU,
+            CompleteStr("aadd") => Opcode::AADD,
+            CompleteStr("cas") => Opcode::CAS,
+            CompleteStr("rand") => Opcode::RAND,
+            CompleteStr("sleep") => Opcode::SLEEP,
+            CompleteStr("readi") => Opcode::READI,
+            CompleteStr("reads") => Opcode::READS,
+            CompleteStr("exit") => Opcode::EXIT,
+            CompleteStr("strap") => Opcode::STRAP,
+            CompleteStr("trap") => Opcode::TRAP,
+            CompleteStr("free") => Opcode::FREE,
+            CompleteStr("cmp") => Opcode::CMP,
+            CompleteStr("jz") => Opcode::JZ,
+            CompleteStr("jnz") => Opcode::JNZ,
+            CompleteStr("jlt") => Opcode::JLT,
+            CompleteStr("jge") => Opcode::JGE,
+            CompleteStr("jgt") => Opcode::JGT,
+            CompleteStr("jle") => Opcode::JLE,
+            CompleteStr("prtsr") => Opcode::PRTSR,
+            CompleteStr("ldb") => Opcode::LDB,
+            CompleteStr("stb") => Opcode::STB,
+            CompleteStr("ldw") => Opcode::LDW,
+            CompleteStr("stw") => Opcode::STW,
             _ => Opcode::IGL,
         }
     }
@@ -94,6 +403,141 @@ impl Instruction {
     }
 }
 
+/// Length in bytes of an encoded instruction: the opcode followed by three operand bytes.
+pub const INSTRUCTION_LENGTH: usize = 4;
+
+/// The number of registers a VM has unless it is built with a different number.
+pub const REGISTER_COUNT: usize = 32;
+
+/// The most registers a VM can have, since a register operand is a single byte.
+pub const MAX_REGISTER_COUNT: usize = 256;
+
+/// For each opcode, bit 8 of the 16 bit lane of each of its register operands. The register check
+/// spreads the operand bytes into 16 bit lanes and adds `256 - register_count` to every lane, which
+/// carries into bit 8 exactly when the operand is out of range, so every instruction is checked with
+/// one add and one `AND` whatever the register count.
+static REGISTER_OPERAND_LANES: [u64; 256] = {
+    let mut lanes = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let opcode = Opcode::from_byte(byte as u8);
+        let mut lane = 0;
+        let mut n = 0;
+        while n < opcode.register_operands() {
+            lane |= 0x100 << (16 * n);
+            n += 1;
+        }
+        lanes[opcode as usize] = lane;
+        byte += 1;
+    }
+    lanes
+};
+
+/// An instruction decoded from bytecode, ready to be executed without touching the program bytes.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct DecodedInstruction {
+    pub opcode: Opcode,
+    pub operands: [u8; 3],
+}
+
+impl DecodedInstruction {
+    /// Decodes the instruction starting at `offset`. Operand bytes past the end of `program` read
+    /// as zero.
+    pub fn decode(program: &[u8], offset: usize) -> DecodedInstruction {
+        let byte = |i: usize| program.get(offset + i).copied().unwrap_or(0);
+        DecodedInstruction {
+            opcode: Opcode::from(byte(0)),
+            operands: [byte(1), byte(2), byte(3)],
+        }
+    }
+
+    /// Decodes every instruction in `code`, in order.
+    pub fn decode_all(code: &[u8]) -> Vec<DecodedInstruction> {
+        (0..code.len())
+            .step_by(INSTRUCTION_LENGTH)
+            .map(|offset| DecodedInstruction::decode(code, offset))
+            .collect()
+    }
+
+    /// Decodes the instruction starting at `offset` without bounds checks.
+    ///
+    /// # Safety
+    ///
+    /// `offset + INSTRUCTION_LENGTH` must not exceed `program.len()`.
+    #[cfg(feature = "unchecked")]
+    pub unsafe fn decode_unchecked(program: &[u8], offset: usize) -> DecodedInstruction {
+        DecodedInstruction {
+            opcode: Opcode::from(*program.get_unchecked(offset)),
+            operands: [
+                *program.get_unchecked(offset + 1),
+                *program.get_unchecked(offset + 2),
+                *program.get_unchecked(offset + 3),
+            ],
+        }
+    }
+
+    /// Returns the first register operand that does not name one of the default 32 registers.
+    #[inline]
+    pub fn invalid_register(&self) -> Option<usize> {
+        self.invalid_register_for(REGISTER_COUNT)
+    }
+
+    /// Returns the first register operand that does not name one of `register_count` registers,
+    /// which must be between 1 and `MAX_REGISTER_COUNT`.
+    #[inline]
+    pub fn invalid_register_for(&self, register_count: usize) -> Option<usize> {
+        let [first, second, third] = self.operands;
+        let lanes = u64::from(first) | u64::from(second) << 16 | u64::from(third) << 32;
+        let bias = (MAX_REGISTER_COUNT - register_count) as u64 * 0x0000_0001_0001_0001;
+        let invalid = (lanes + bias) & REGISTER_OPERAND_LANES[self.opcode as usize];
+        if invalid == 0 {
+            None
+        } else {
+            Some(self.operands[invalid.trailing_zeros() as usize / 16] as usize)
+        }
+    }
+
+    /// Returns operand `n` as a register index.
+    pub fn register(&self, n: usize) -> usize {
+        self.operands[n] as usize
+    }
+
+    /// Returns the 16-bit immediate stored in operand bytes `n` and `n + 1`.
+    pub fn immediate(&self, n: usize) -> u16 {
+        ((self.operands[n] as u16) << 8) | self.operands[n + 1] as u16
+    }
+
+    /// Returns the 24-bit address stored in all three operand bytes.
+    pub fn address(&self) -> usize {
+        (self.operands[0] as usize) << 16
+            | (self.operands[1] as usize) << 8
+            | self.operands[2] as usize
+    }
+}
+
+impl fmt::Display for DecodedInstruction {
+    /// Writes the instruction as assembly, e.g. `load $0 #500`. Immediates of 0 are left out, as
+    /// most opcodes ignore their unused operand bytes.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mnemonic = self.opcode.mnemonic().unwrap_or_else(|| "igl".to_string());
+        f.write_str(&mnemonic)?;
+        let registers = self.opcode.register_operands();
+        for n in 0..registers {
+            write!(f, " ${}", self.register(n))?;
+        }
+        let immediate = match registers {
+            _ if self.opcode.takes_address() => self.address(),
+            0 | 1 => self.immediate(registers) as usize,
+            2 => self.operands[2] as usize,
+            _ => 0,
+        };
+        if immediate != 0 {
+            write!(f, " #{}", immediate)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -212,6 +656,233 @@ mod tests {
         assert_eq!(opcode, Opcode::JNEQ);
     }
 
+    #[test]
+    fn test_create_callhost() {
+        let opcode = Opcode::CALLHOST;
+        assert_eq!(opcode, Opcode::CALLHOST);
+    }
+
+    #[test]
+    fn test_bitwise_opcodes() {
+        assert_eq!(Opcode::from(22), Opcode::AND);
+        assert_eq!(Opcode::from(25), Opcode::NOT);
+        assert_eq!(Opcode::from(CompleteStr("xor")), Opcode::XOR);
+        assert_eq!(Opcode::from(CompleteStr("OR")), Opcode::OR);
+        assert_eq!(Opcode::AND as u8, 22);
+        assert_eq!(Opcode::NOT.register_operands(), 2);
+    }
+
+    #[test]
+    fn test_shift_opcodes() {
+        assert_eq!(Opcode::from(26), Opcode::SHL);
+        assert_eq!(Opcode::from(29), Opcode::ROR);
+        assert_eq!(Opcode::from(CompleteStr("shr")), Opcode::SHR);
+        assert_eq!(Opcode::from(CompleteStr("ROL")), Opcode::ROL);
+        assert_eq!(Opcode::SHR.register_operands(), 3);
+    }
+
+    #[test]
+    fn test_remainder_opcodes() {
+        assert_eq!(Opcode::from(30), Opcode::MOD);
+        assert_eq!(Opcode::from(31), Opcode::GETR);
+        assert_eq!(Opcode::from(CompleteStr("mod")), Opcode::MOD);
+        assert_eq!(Opcode::from(CompleteStr("getr")), Opcode::GETR);
+        assert_eq!(Opcode::GETR.register_operands(), 1);
+    }
+
+    #[test]
+    fn test_stack_opcodes() {
+        assert_eq!(Opcode::from(32), Opcode::PUSH);
+        assert_eq!(Opcode::from(33), Opcode::POP);
+        assert_eq!(Opcode::from(CompleteStr("push")), Opcode::PUSH);
+        assert_eq!(Opcode::from(CompleteStr("pop")), Opcode::POP);
+    }
+
+    #[test]
+    fn test_call_opcodes() {
+        assert_eq!(Opcode::from(34), Opcode::CALL);
+        assert_eq!(Opcode::from(36), Opcode::RET);
+        assert_eq!(Opcode::from(CompleteStr("callr")), Opcode::CALLR);
+        assert_eq!(Opcode::CALL.register_operands(), 0);
+        assert_eq!(Opcode::CALLR.register_operands(), 1);
+    }
+
+    #[test]
+    fn test_syscall_opcode() {
+        assert_eq!(Opcode::from(37), Opcode::SYSCALL);
+        assert_eq!(Opcode::from(CompleteStr("syscall")), Opcode::SYSCALL);
+        assert_eq!(Opcode::SYSCALL.register_operands(), 1);
+    }
+
+    #[test]
+    fn test_lui_opcode() {
+        assert_eq!(Opcode::from(38), Opcode::LUI);
+        assert_eq!(Opcode::from(CompleteStr("lui")), Opcode::LUI);
+        assert_eq!(Opcode::LUI.register_operands(), 1);
+    }
+
+    #[test]
+    fn test_flag_jump_opcodes() {
+        assert_eq!(Opcode::from(39), Opcode::JO);
+        assert_eq!(Opcode::from(42), Opcode::JNC);
+        assert_eq!(Opcode::from(CompleteStr("jno")), Opcode::JNO);
+        assert_eq!(Opcode::from(CompleteStr("jc")), Opcode::JC);
+        assert_eq!(Opcode::JO.register_operands(), 1);
+    }
+
+    #[test]
+    fn test_address_jump_opcodes() {
+        assert_eq!(Opcode::from(43), Opcode::JMPL);
+        assert_eq!(Opcode::from(45), Opcode::JNEQL);
+        assert_eq!(Opcode::from(CompleteStr("jeql")), Opcode::JEQL);
+        assert!(Opcode::JMPL.takes_address());
+        assert!(!Opcode::JMP.takes_address());
+        assert_eq!(Opcode::JNEQ.address_form(), Some(Opcode::JNEQL));
+        assert_eq!(Opcode::JMPF.address_form(), None);
+        let instruction = DecodedInstruction::decode(&[43, 1, 2, 3], 0);
+        assert_eq!(instruction.address(), 0x010203);
+    }
+
+    #[test]
+    fn test_djnz_opcode() {
+        assert_eq!(Opcode::from(46), Opcode::DJNZ);
+        assert_eq!(Opcode::from(CompleteStr("djnz")), Opcode::DJNZ);
+        assert_eq!(Opcode::DJNZ.register_operands(), 1);
+    }
+
+    #[test]
+    fn test_register_comparison_opcodes() {
+        assert_eq!(Opcode::from(47), Opcode::EQR);
+        assert_eq!(Opcode::from(52), Opcode::LTQR);
+        assert_eq!(Opcode::from(CompleteStr("gtqr")), Opcode::GTQR);
+        assert_eq!(Opcode::EQR.register_operands(), 3);
+    }
+
+    #[test]
+    fn test_unsigned_comparison_opcodes() {
+        assert_eq!(Opcode::from(53), Opcode::GTU);
+        assert_eq!(Opcode::from(56), Opcode::LTEU);
+        assert_eq!(Opcode::from(CompleteStr("gteu")), Opcode::GTEU);
+        assert_eq!(Opcode::LTU.register_operands(), 2);
+    }
+
+    #[test]
+    fn test_atomic_opcodes() {
+        assert_eq!(Opcode::from(57), Opcode::AADD);
+        assert_eq!(Opcode::from(58), Opcode::CAS);
+        assert_eq!(Opcode::from(CompleteStr("cas")), Opcode::CAS);
+        assert_eq!(Opcode::AADD.register_operands(), 3);
+    }
+
+    #[test]
+    fn test_rand_opcode() {
+        assert_eq!(Opcode::from(59), Opcode::RAND);
+        assert_eq!(Opcode::from(CompleteStr("rand")), Opcode::RAND);
+        assert_eq!(Opcode::RAND.register_operands(), 1);
+    }
+
+    #[test]
+    fn test_sleep_opcode() {
+        assert_eq!(Opcode::from(60), Opcode::SLEEP);
+        assert_eq!(Opcode::from(CompleteStr("sleep")), Opcode::SLEEP);
+        assert_eq!(Opcode::SLEEP.register_operands(), 1);
+    }
+
+    #[test]
+    fn test_input_opcodes() {
+        assert_eq!(Opcode::from(61), Opcode::READI);
+        assert_eq!(Opcode::from(62), Opcode::READS);
+        assert_eq!(Opcode::from(CompleteStr("reads")), Opcode::READS);
+        assert_eq!(Opcode::READI.register_operands(), 1);
+        assert_eq!(Opcode::READS.register_operands(), 3);
+    }
+
+    #[test]
+    fn test_exit_opcode() {
+        assert_eq!(Opcode::from(63), Opcode::EXIT);
+        assert_eq!(Opcode::from(CompleteStr("exit")), Opcode::EXIT);
+        assert_eq!(Opcode::EXIT.register_operands(), 1);
+    }
+
+    #[test]
+    fn test_trap_opcodes() {
+        assert_eq!(Opcode::from(64), Opcode::STRAP);
+        assert_eq!(Opcode::from(65), Opcode::TRAP);
+        assert_eq!(Opcode::from(CompleteStr("strap")), Opcode::STRAP);
+        assert_eq!(Opcode::TRAP.register_operands(), 1);
+    }
+
+    #[test]
+    fn test_free_opcode() {
+        assert_eq!(Opcode::from(66), Opcode::FREE);
+        assert_eq!(Opcode::from(CompleteStr("free")), Opcode::FREE);
+        assert_eq!(Opcode::from(CompleteStr("aloc")), Opcode::ALOC);
+        assert_eq!(Opcode::FREE.register_operands(), 1);
+    }
+
+    #[test]
+    fn test_cmp_and_condition_jumps() {
+        assert_eq!(Opcode::from(67), Opcode::CMP);
+        assert_eq!(Opcode::from(73), Opcode::JLE);
+        assert_eq!(Opcode::from(CompleteStr("jge")), Opcode::JGE);
+        assert_eq!(Opcode::CMP.register_operands(), 2);
+        assert_eq!(Opcode::JLT.register_operands(), 1);
+    }
+
+    #[test]
+    fn test_prtsr_opcode() {
+        assert_eq!(Opcode::from(74), Opcode::PRTSR);
+        assert_eq!(Opcode::from(CompleteStr("prtsr")), Opcode::PRTSR);
+        assert_eq!(Opcode::PRTSR.register_operands(), 1);
+        assert_eq!(Opcode::PRTS.register_operands(), 0);
+        assert_eq!(Opcode::PRTS.register_form(), Some(Opcode::PRTSR));
+    }
+
+    #[test]
+    fn test_load_store_opcodes() {
+        assert_eq!(Opcode::from(75), Opcode::LDB);
+        assert_eq!(Opcode::from(76), Opcode::STB);
+        assert_eq!(Opcode::from(77), Opcode::LDW);
+        assert_eq!(Opcode::from(78), Opcode::STW);
+        assert_eq!(Opcode::from(CompleteStr("ldb")), Opcode::LDB);
+        assert_eq!(Opcode::from(CompleteStr("stw")), Opcode::STW);
+        assert_eq!(Opcode::LDW.register_operands(), 2);
+        assert_eq!(Opcode::STB.register_operands(), 2);
+    }
+
+    #[test]
+    fn test_display_decoded_instruction() {
+        let display = |bytes: &[u8]| DecodedInstruction::decode(bytes, 0).to_string();
+        assert_eq!(display(&[1, 0, 1, 244]), "load $0 #500");
+        assert_eq!(display(&[2, 0, 1, 2]), "add $0 $1 $2");
+        assert_eq!(display(&[0, 0, 0, 0]), "hlt");
+        assert_eq!(display(&[43, 0, 1, 0]), "jmpl #256");
+        assert_eq!(display(&[20, 0, 3, 0]), "prts #3");
+        assert_eq!(display(&[255, 0, 0, 0]), "igl");
+    }
+
+    #[test]
+    fn test_mnemonics() {
+        assert_eq!(Opcode::LOAD.mnemonic(), Some("load".to_string()));
+        assert_eq!(Opcode::IGL.mnemonic(), None);
+        let mnemonics = Opcode::mnemonics();
+        assert_eq!(mnemonics[0], "hlt");
+        assert!(mnemonics.contains(&"prtsr".to_string()));
+        assert!(!mnemonics.contains(&"igl".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        // Only the register operands are checked, not the immediate.
+        let load = DecodedInstruction::decode(&[1, 31, 200, 200], 0);
+        assert_eq!(load.invalid_register(), None);
+        let add = DecodedInstruction::decode(&[2, 0, 32, 2], 0);
+        assert_eq!(add.invalid_register(), Some(32));
+        assert_eq!(add.invalid_register_for(64), None);
+        assert_eq!(add.invalid_register_for(3), Some(32));
+        assert_eq!(load.invalid_register_for(8), Some(31));
+    }
+
     #[test]
     fn test_str_to_opcode() {
         // Check lowercase.
@@ -223,4 +894,20 @@ mod tests {
         let opcode = Opcode::from(CompleteStr("illegal"));
         assert_eq!(opcode, Opcode::IGL);
     }
+
+    #[test]
+    fn test_decode_instructions() {
+        let decoded = DecodedInstruction::decode_all(&[1, 2, 1, 244, 0]);
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].opcode, Opcode::LOAD);
+        assert_eq!(decoded[0].register(0), 2);
+        assert_eq!(decoded[0].immediate(1), 500);
+        assert_eq!(
+            decoded[1],
+            DecodedInstruction {
+                opcode: Opcode::HLT,
+                operands: [0, 0, 0]
+            }
+        );
+    }
 }
@@ -0,0 +1,34 @@
+#[macro_use]
+extern crate nom;
+
+extern crate chrono;
+extern crate uuid;
+
+pub mod allocator;
+pub mod assembler;
+#[cfg(feature = "scheduler")]
+pub mod cluster;
+pub mod console;
+pub mod debug_info;
+pub mod ffi;
+pub mod flags;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hooks;
+pub mod host;
+#[cfg(feature = "scheduler")]
+pub mod http;
+pub mod instruction;
+pub mod loader;
+pub mod program;
+#[cfg(feature = "scheduler")]
+pub mod remote;
+#[cfg(feature = "repl")]
+pub mod repl;
+#[cfg(feature = "scheduler")]
+pub mod scheduler;
+pub mod shared;
+#[cfg(feature = "ssh")]
+pub mod ssh;
+pub mod syscall;
+pub mod vm;
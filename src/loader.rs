@@ -0,0 +1,415 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{Cursor, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::assembler::symbols::{Symbol, SymbolTable, SymbolType};
+use crate::vm::VM;
+
+/// Identifies a file as an Iridium container.
+pub const CONTAINER_MAGIC: [u8; 4] = *b"IRDC";
+/// The container version written by this crate. Containers with any other version are rejected.
+pub const CONTAINER_VERSION: u16 = 2;
+/// Length of the fixed part of the header: magic, version, and section count.
+const HEADER_LENGTH: usize = 8;
+/// Length of one entry in the section table: kind, offset, length, and CRC32.
+const SECTION_ENTRY_LENGTH: usize = 13;
+
+/// The kinds of sections a container can hold.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SectionKind {
+    /// Read-only data such as `.asciiz` constants.
+    ReadOnly,
+    /// Writable data.
+    Data,
+    /// Executable bytecode, exactly as the VM runs it (PIE header included).
+    Code,
+    /// Debug information. Opaque to the loader.
+    Debug,
+    /// The symbol table produced by the assembler.
+    Symbols,
+}
+
+impl SectionKind {
+    fn from_u8(v: u8) -> Option<SectionKind> {
+        match v {
+            0 => Some(SectionKind::ReadOnly),
+            1 => Some(SectionKind::Data),
+            2 => Some(SectionKind::Code),
+            3 => Some(SectionKind::Debug),
+            4 => Some(SectionKind::Symbols),
+            _ => None,
+        }
+    }
+}
+
+/// Errors that can occur while decoding or loading a container.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    BadMagic,
+    VersionMismatch { found: u16, expected: u16 },
+    Truncated,
+    UnknownSection { kind: u8 },
+    ChecksumMismatch { section: SectionKind },
+    MissingCodeSection,
+    MalformedSymbols,
+    TooManySections { count: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::BadMagic => f.write_str("The file is not an Iridium container"),
+            LoadError::VersionMismatch { found, expected } => f.write_str(&format!(
+                "Container version {} is not supported. Expected version {}",
+                found, expected
+            )),
+            LoadError::Truncated => f.write_str("The container ended unexpectedly"),
+            LoadError::UnknownSection { kind } => {
+                f.write_str(&format!("Unknown section kind found: {}", kind))
+            }
+            LoadError::ChecksumMismatch { section } => {
+                f.write_str(&format!("Checksum mismatch in the {:?} section", section))
+            }
+            LoadError::MissingCodeSection => f.write_str("The container has no code section"),
+            LoadError::MalformedSymbols => f.write_str("The symbol section could not be decoded"),
+            LoadError::TooManySections { count } => f.write_str(&format!(
+                "A container holds at most {} sections. Found {}",
+                u16::MAX,
+                count
+            )),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+/// A section of a container.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Section {
+    pub kind: SectionKind,
+    pub bytes: Vec<u8>,
+}
+
+/// A versioned container holding the sections of an assembled program.
+///
+/// Layout (all integers big endian):
+/// - magic (4 bytes), version (u16), section count (u16)
+/// - one table entry per section: kind (u8), offset (u32), length (u32), CRC32 (u32)
+/// - the section bytes, at the offsets given in the table
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Container {
+    pub sections: Vec<Section>,
+}
+
+impl Container {
+    /// Returns an empty `Container`.
+    pub fn new() -> Container {
+        Container { sections: vec![] }
+    }
+
+    /// Adds a section, replacing any existing section of the same kind.
+    pub fn set_section(&mut self, kind: SectionKind, bytes: Vec<u8>) {
+        self.sections.retain(|s| s.kind != kind);
+        self.sections.push(Section { kind, bytes });
+    }
+
+    /// Returns the bytes of the section of the given kind.
+    pub fn section(&self, kind: SectionKind) -> Option<&[u8]> {
+        self.sections
+            .iter()
+            .find(|s| s.kind == kind)
+            .map(|s| s.bytes.as_slice())
+    }
+
+    /// Encodes the container. Fails if it has more sections than the header can count.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, LoadError> {
+        if self.sections.len() > u16::MAX as usize {
+            return Err(LoadError::TooManySections {
+                count: self.sections.len(),
+            });
+        }
+        let body_len: usize = self.sections.iter().map(|s| s.bytes.len()).sum();
+        let table_len = self.sections.len() * SECTION_ENTRY_LENGTH;
+        let mut out = Vec::with_capacity(HEADER_LENGTH + table_len + body_len);
+        out.extend_from_slice(&CONTAINER_MAGIC);
+        out.write_u16::<BigEndian>(CONTAINER_VERSION).unwrap();
+        out.write_u16::<BigEndian>(self.sections.len() as u16).unwrap();
+        let mut offset = (HEADER_LENGTH + table_len) as u32;
+        for section in &self.sections {
+            out.push(section.kind as u8);
+            out.write_u32::<BigEndian>(offset).unwrap();
+            out.write_u32::<BigEndian>(section.bytes.len() as u32).unwrap();
+            out.write_u32::<BigEndian>(crc32fast::hash(&section.bytes))
+                .unwrap();
+            offset += section.bytes.len() as u32;
+        }
+        for section in &self.sections {
+            out.extend_from_slice(&section.bytes);
+        }
+        Ok(out)
+    }
+
+    /// Decodes and validates a container.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Container, LoadError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(LoadError::Truncated);
+        }
+        if bytes[0..4] != CONTAINER_MAGIC {
+            return Err(LoadError::BadMagic);
+        }
+        let mut reader = Cursor::new(&bytes[4..]);
+        let version = reader.read_u16::<BigEndian>().unwrap();
+        if version != CONTAINER_VERSION {
+            return Err(LoadError::VersionMismatch {
+                found: version,
+                expected: CONTAINER_VERSION,
+            });
+        }
+        let count = reader.read_u16::<BigEndian>().unwrap();
+        let mut container = Container::new();
+        for _ in 0..count {
+            let kind = reader.read_u8().map_err(|_| LoadError::Truncated)?;
+            let kind = SectionKind::from_u8(kind).ok_or(LoadError::UnknownSection { kind })?;
+            let offset = reader
+                .read_u32::<BigEndian>()
+                .map_err(|_| LoadError::Truncated)? as usize;
+            let len = reader
+                .read_u32::<BigEndian>()
+                .map_err(|_| LoadError::Truncated)? as usize;
+            let crc = reader
+                .read_u32::<BigEndian>()
+                .map_err(|_| LoadError::Truncated)?;
+            let section = offset
+                .checked_add(len)
+                .and_then(|end| bytes.get(offset..end))
+                .ok_or(LoadError::Truncated)?;
+            if crc32fast::hash(section) != crc {
+                return Err(LoadError::ChecksumMismatch { section: kind });
+            }
+            container.set_section(kind, section.to_vec());
+        }
+        Ok(container)
+    }
+}
+
+/// Returns the byte a symbol type is encoded as in the symbols section.
+fn symbol_type_to_u8(symbol_type: &SymbolType) -> u8 {
+    match symbol_type {
+        SymbolType::Label => 0,
+        SymbolType::LocalLabel => 1,
+        SymbolType::Integer => 2,
+        SymbolType::IrString => 3,
+    }
+}
+
+/// Returns the symbol type encoded as `v` in the symbols section.
+fn symbol_type_from_u8(v: u8) -> Option<SymbolType> {
+    match v {
+        0 => Some(SymbolType::Label),
+        1 => Some(SymbolType::LocalLabel),
+        2 => Some(SymbolType::Integer),
+        3 => Some(SymbolType::IrString),
+        _ => None,
+    }
+}
+
+/// Encodes a symbol table for the symbols section: for each symbol, the name length (u16), the
+/// name, the type (u8), and the offset (u32, `u32::MAX` when the symbol has none).
+pub fn encode_symbols(symbols: &SymbolTable) -> Vec<u8> {
+    let mut out = vec![];
+    for symbol in symbols.symbols() {
+        let name = symbol.name().as_bytes();
+        out.write_u16::<BigEndian>(name.len() as u16).unwrap();
+        out.write_all(name).unwrap();
+        out.push(symbol_type_to_u8(symbol.symbol_type()));
+        out.write_u32::<BigEndian>(symbol.offset().unwrap_or(u32::MAX))
+            .unwrap();
+    }
+    out
+}
+
+/// Decodes a symbols section written by `encode_symbols`.
+pub fn decode_symbols(bytes: &[u8]) -> Result<SymbolTable, LoadError> {
+    let mut table = SymbolTable::new();
+    let mut reader = Cursor::new(bytes);
+    while (reader.position() as usize) < bytes.len() {
+        let len = reader
+            .read_u16::<BigEndian>()
+            .map_err(|_| LoadError::MalformedSymbols)?;
+        let mut name = vec![0; len as usize];
+        reader
+            .read_exact(&mut name)
+            .map_err(|_| LoadError::MalformedSymbols)?;
+        let name = String::from_utf8(name).map_err(|_| LoadError::MalformedSymbols)?;
+        let symbol_type = reader
+            .read_u8()
+            .ok()
+            .and_then(symbol_type_from_u8)
+            .ok_or(LoadError::MalformedSymbols)?;
+        let offset = reader
+            .read_u32::<BigEndian>()
+            .map_err(|_| LoadError::MalformedSymbols)?;
+        let symbol = if offset == u32::MAX {
+            Symbol::new(name, symbol_type)
+        } else {
+            Symbol::new_with_offset(name, symbol_type, offset)
+        };
+        table.add_symbol(symbol);
+    }
+    Ok(table)
+}
+
+/// A container loaded into a VM.
+#[derive(Debug, Clone)]
+pub struct Loaded {
+    pub container: Container,
+    /// The symbols decoded from the symbols section. Empty if the container has none.
+    pub symbols: SymbolTable,
+}
+
+/// Validates a container and loads its code, read-only and data sections into the VM, returning
+/// it along with its symbols, e.g. for a debugger to name addresses with. Writable data in the
+/// code's own header takes precedence over a data section. The VM is reset with `reset_full`
+/// first, so the container replaces whatever program it held.
+pub fn load(vm: &mut VM, bytes: &[u8]) -> Result<Loaded, LoadError> {
+    let container = Container::from_bytes(bytes)?;
+    let code = container
+        .section(SectionKind::Code)
+        .ok_or(LoadError::MissingCodeSection)?;
+    let symbols = match container.section(SectionKind::Symbols) {
+        Some(symbols) => decode_symbols(symbols)?,
+        None => SymbolTable::new(),
+    };
+    vm.reset_full();
+    vm.set_ro_data(container.section(SectionKind::ReadOnly).unwrap_or(&[]).to_vec());
+    vm.set_data(container.section(SectionKind::Data).unwrap_or(&[]).to_vec());
+    vm.add_slice(code);
+    vm.load_done();
+    Ok(Loaded { container, symbols })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn test_container() -> Container {
+        let mut asm = Assembler::new();
+        let code = asm
+            .assemble(".data\nhello: .asciiz 'Hi'\n.code\nload $0 #7\nhlt")
+            .unwrap();
+        let mut container = Container::new();
+        container.set_section(SectionKind::ReadOnly, asm.ro.clone());
+        container.set_section(SectionKind::Code, code);
+        container.set_section(SectionKind::Symbols, encode_symbols(&asm.symbols));
+        container
+    }
+
+    #[test]
+    fn test_container_round_trip() {
+        let container = test_container();
+        let decoded = Container::from_bytes(&container.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded, container);
+        let symbols = decode_symbols(decoded.section(SectionKind::Symbols).unwrap()).unwrap();
+        assert_eq!(symbols.symbol_value("hello"), Some(0));
+        let hello = symbols.symbols().iter().find(|s| s.name() == "hello").unwrap();
+        assert_eq!(hello.symbol_type(), &SymbolType::Label);
+    }
+
+    #[test]
+    fn test_symbol_types_round_trip() {
+        let mut table = SymbolTable::new();
+        table.add_symbol(Symbol::new("SIZE".to_string(), SymbolType::Integer));
+        table.add_symbol(Symbol::new_with_offset("1.0".to_string(), SymbolType::LocalLabel, 4));
+        let decoded = decode_symbols(&encode_symbols(&table)).unwrap();
+        let types: Vec<(&str, &SymbolType)> =
+            decoded.symbols().iter().map(|s| (s.name(), s.symbol_type())).collect();
+        assert_eq!(
+            types,
+            vec![("SIZE", &SymbolType::Integer), ("1.0", &SymbolType::LocalLabel)]
+        );
+    }
+
+    #[test]
+    fn test_too_many_sections() {
+        let mut container = Container::new();
+        let section = Section {
+            kind: SectionKind::Debug,
+            bytes: vec![],
+        };
+        container.sections = vec![section; u16::MAX as usize + 1];
+        assert_eq!(
+            container.to_bytes(),
+            Err(LoadError::TooManySections {
+                count: u16::MAX as usize + 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let mut bytes = test_container().to_bytes().unwrap();
+        bytes[5] = 99;
+        assert_eq!(
+            Container::from_bytes(&bytes),
+            Err(LoadError::VersionMismatch {
+                found: 99,
+                expected: CONTAINER_VERSION
+            })
+        );
+    }
+
+    #[test]
+    fn test_bad_magic_and_checksum() {
+        let mut bytes = test_container().to_bytes().unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        assert_eq!(
+            Container::from_bytes(&bytes),
+            Err(LoadError::ChecksumMismatch {
+                section: SectionKind::Symbols
+            })
+        );
+        bytes[0] = 0;
+        assert_eq!(Container::from_bytes(&bytes), Err(LoadError::BadMagic));
+        assert_eq!(Container::from_bytes(&bytes[..3]), Err(LoadError::Truncated));
+    }
+
+    #[test]
+    fn test_load_into_vm() {
+        let bytes = test_container().to_bytes().unwrap();
+        let mut vm = VM::new();
+        let loaded = load(&mut vm, &bytes).unwrap();
+        assert_eq!(loaded.symbols.symbol_value("hello"), Some(0));
+        assert_eq!(vm.ro_data(), &b"Hi\0"[..]);
+        assert!(!vm.program.is_empty());
+
+        // The data section is the writable data of the program, addressed after the read-only
+        // section.
+        let mut container = test_container();
+        container.set_section(SectionKind::Data, vec![1, 2, 3]);
+        let mut vm = VM::new();
+        load(&mut vm, &container.to_bytes().unwrap()).unwrap();
+        vm.run();
+        assert_eq!(vm.data(), &[1, 2, 3]);
+        assert_eq!(vm.data_at(4), Some(&[2, 3][..]));
+
+        // Loading again replaces the program rather than appending to it.
+        let code = test_container().section(SectionKind::Code).unwrap().to_vec();
+        load(&mut vm, &bytes).unwrap();
+        assert_eq!(&vm.program[..], &code[..]);
+        assert!(vm.data().is_empty());
+        vm.run();
+        assert_eq!(vm.exit_code(), Some(0));
+        assert_eq!(vm.registers[0], 7);
+
+        let mut container = Container::new();
+        container.set_section(SectionKind::Debug, vec![]);
+        assert_eq!(
+            load(&mut VM::new(), &container.to_bytes().unwrap()).err(),
+            Some(LoadError::MissingCodeSection)
+        );
+    }
+}
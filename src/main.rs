@@ -1,75 +1,415 @@
-use std::io::prelude::*;
 use std::{fs::File, path::Path};
 
-#[macro_use]
-extern crate nom;
-
 #[macro_use]
 extern crate clap;
-use clap::App;
+use clap::{App, ArgMatches};
 
-extern crate chrono;
-extern crate uuid;
+extern crate iridium;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
 
-pub mod assembler;
-pub mod instruction;
-pub mod repl;
-pub mod scheduler;
-pub mod vm;
+use iridium::debug_info::SourceLine;
+use iridium::program::Program;
+use iridium::{assembler, cluster, console, http, remote, repl, scheduler, vm};
+use tracing::Level;
 
 fn main() {
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
-    let target_file = matches.value_of("INPUT_FILE");
-    match target_file {
-        Some(filename) => {
-            let program = read_file(filename);
-            let mut asm = assembler::Assembler::new();
-            let mut vm = vm::VM::new();
-            let program = asm.assemble(&program);
-            match program {
-                Ok(p) => {
-                    vm.add_bytes(p);
-                    let events = vm.run();
-                    println!("VM Events");
-                    println!("--------------------------");
-                    for event in &events {
-                        println!("{:#?}", event);
-                    }
-                    std::process::exit(0);
-                }
-                _ => {}
+    init_tracing(matches.occurrences_of("VERBOSE"));
+    if let Some(matches) = matches.subcommand_matches("node") {
+        start_node(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("run") {
+        let code = run_binary(matches.value_of("FILE").unwrap());
+        std::process::exit(code);
+    }
+    if let Some(script) = matches.value_of("SCRIPT") {
+        return run_script(script);
+    }
+    let files: Vec<&str> = match matches.values_of("INPUT_FILE") {
+        Some(files) => files.collect(),
+        None => return start_repl(),
+    };
+    if matches.is_present("COMPILE") {
+        return compile_file(&files, matches.value_of("OUTPUT").unwrap(), &matches);
+    }
+    let program = match files[..] {
+        [filename] if !is_object_file(filename) => assemble_file(filename, &matches),
+        _ => link_files(&files, &matches),
+    };
+    if let Some(output) = matches.value_of("OUTPUT") {
+        if let Err(e) = std::fs::write(output, &program) {
+            tracing::error!("Unable to write {}: {}", output, e);
+            std::process::exit(1);
+        }
+    }
+    if matches.is_present("LISTING") || matches.is_present("OUTPUT") {
+        return;
+    }
+    let mut vm = vm::VM::new();
+    vm.add_bytes(program);
+    let events = vm.run();
+    print_events(&vm, &events);
+    std::process::exit(vm.exit_code().unwrap_or(0));
+}
+
+/// Assembles a single file, printing its listing if one was asked for. Exits if the file cannot
+/// be assembled.
+fn assemble_file(filename: &str, matches: &ArgMatches) -> Vec<u8> {
+    let (source, lines) = read_file(filename);
+    let mut asm = new_assembler(matches);
+    if matches.is_present("DEBUG_INFO") {
+        asm.emit_debug_info(lines);
+    }
+    let program = match asm.assemble(&source) {
+        Ok(program) => program,
+        Err(errors) => exit_with_errors(filename, &errors),
+    };
+    if matches.is_present("LISTING") {
+        print!("{}", asm.listing(&source, &program));
+    }
+    program
+}
+
+/// Returns an assembler configured by the command line.
+fn new_assembler(matches: &ArgMatches) -> assembler::Assembler {
+    let mut asm = assembler::Assembler::new();
+    asm.set_strict(!matches.is_present("NO_STRICT"));
+    asm
+}
+
+/// Assembles a single file into an object file at `output`, to be linked with others later.
+fn compile_file(files: &[&str], output: &str, matches: &ArgMatches) {
+    let filename = match files {
+        [filename] => filename,
+        _ => {
+            tracing::error!("--compile takes a single file");
+            std::process::exit(1);
+        }
+    };
+    let (source, _) = read_file(filename);
+    let fragment = match new_assembler(matches).assemble_fragment(&source) {
+        Ok(fragment) => fragment,
+        Err(errors) => exit_with_errors(filename, &errors),
+    };
+    if let Err(e) = std::fs::write(output, fragment.to_bytes()) {
+        tracing::error!("Unable to write {}: {}", output, e);
+        std::process::exit(1);
+    }
+}
+
+/// Returns whether the file at `path` is an object file written with `--compile`.
+fn is_object_file(path: &str) -> bool {
+    std::fs::read(path).is_ok_and(|bytes| assembler::linker::is_object(&bytes))
+}
+
+/// Assembles each source file on its own, reads each object file, and links them into one
+/// program. Exits if any of them cannot be assembled or read, or they cannot be linked.
+fn link_files(files: &[&str], matches: &ArgMatches) -> Vec<u8> {
+    if matches.is_present("LISTING") || matches.is_present("DEBUG_INFO") {
+        tracing::error!("--listing and --debug-info can only be used with a single file");
+        std::process::exit(1);
+    }
+    let mut fragments = Vec::with_capacity(files.len());
+    for filename in files {
+        let fragment = match std::fs::read(filename) {
+            Ok(bytes) if assembler::linker::is_object(&bytes) => {
+                assembler::linker::Fragment::from_bytes(&bytes).map_err(|e| vec![e])
+            }
+            _ => new_assembler(matches).assemble_fragment(&read_file(filename).0),
+        };
+        match fragment {
+            Ok(fragment) => fragments.push(fragment),
+            Err(errors) => exit_with_errors(filename, &errors),
+        }
+    }
+    match assembler::linker::link(&fragments) {
+        Ok(program) => program,
+        Err(errors) => {
+            for error in errors {
+                tracing::error!("{}", error);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Logs the errors found assembling `filename` and exits.
+fn exit_with_errors(filename: &str, errors: &[assembler::assembler_errors::AssemblerError]) -> ! {
+    for error in errors {
+        tracing::error!("{}: {}", filename, error);
+    }
+    std::process::exit(1);
+}
+
+/// Runs an assembled binary, prints the events it produced, and returns its exit code.
+fn run_binary(path: &str) -> i32 {
+    let program = match load_binary(Path::new(path)) {
+        Ok(program) => program,
+        Err(e) => {
+            tracing::error!("Unable to load {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let mut vm = vm::VM::with_program(Arc::new(program));
+    let events = vm.run();
+    print_events(&vm, &events);
+    vm.exit_code().unwrap_or(0)
+}
+
+/// Maps the binary into memory, so the VM executes it in place. The binary must not be rewritten
+/// while it runs; builds without the `mmap` feature read it into memory instead.
+#[cfg(feature = "mmap")]
+fn load_binary(path: &Path) -> std::io::Result<Program> {
+    let file = File::open(path)?;
+    // SAFETY: `Program::map` requires that nothing modifies or truncates the file while it is
+    // mapped. This process never writes to it, but another one could, so this holds only as long
+    // as the binary is not rewritten while the VM runs it. Builds with the `mmap` feature, which
+    // is on by default, accept that contract; those that can't should disable it.
+    unsafe { Program::map(&file) }
+}
+
+#[cfg(not(feature = "mmap"))]
+fn load_binary(path: &Path) -> std::io::Result<Program> {
+    std::fs::read(path).map(Program::from)
+}
+
+/// Prints the events `vm` produced, and where in the source it crashed if its program has debug
+/// info.
+fn print_events(vm: &vm::VM, events: &[vm::VMEvent]) {
+    println!("VM Events");
+    println!("--------------------------");
+    for event in events {
+        println!("{:#?}", event);
+        if let vm::VMEventType::Crash { reason, pc, .. } = &event.event {
+            if let Some(position) = vm.source_position(*pc) {
+                println!("Crashed at {}: {}", position, reason);
             }
         }
-        None => start_repl(),
     }
 }
 
-/// Starts a REPL that will run until the user kills it.
+/// Logs to stderr at a level chosen by the number of `-v` flags given.
+fn init_tracing(verbosity: u64) {
+    let level = match verbosity {
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// Starts a REPL that will run until the user kills it, once it has run the startup script.
 fn start_repl() {
     let mut repl = repl::REPL::new();
-    repl.run();
-}
-
-/// Attempts to read a file and return the contents. Exits if unable to read the file for any reason.
-fn read_file(tmp: &str) -> String {
-    let filename = Path::new(tmp);
-    match File::open(Path::new(&filename)) {
-        Ok(mut fh) => {
-            let mut contents = String::new();
-            match fh.read_to_string(&mut contents) {
-                Ok(_) => {
-                    return contents;
-                }
-                Err(e) => {
-                    println!("There was an error reading the file: {:?}", e);
-                    std::process::exit(1);
-                }
-            }
+    if !repl.run_startup_script() {
+        repl.run();
+    }
+}
+
+/// Runs the REPL commands in the file at `path` without reading any from the user.
+fn run_script(path: &str) {
+    let script = match std::fs::read_to_string(path) {
+        Ok(script) => script,
+        Err(e) => {
+            tracing::error!("Unable to read {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let mut repl = repl::REPL::with_io(console::stdin(), console::stdout());
+    repl.run_script(&script);
+}
+
+/// Starts a node that runs programs submitted by remote clients until the process is killed.
+/// The node joins the cluster of the node at `--join`, if given, telling its members to reach it
+/// at `--advertise`, or `--bind` if not given. It optionally serves the HTTP and gRPC APIs, and
+/// optionally attaches a REPL locally and/or serves one over SSH.
+fn start_node(matches: &ArgMatches) {
+    let bind = matches.value_of("BIND").unwrap();
+    let advertise = matches.value_of("ADVERTISE").unwrap_or(bind);
+    let alias = matches.value_of("ALIAS");
+    let join = matches.value_of("JOIN");
+    let budget = match matches.value_of("INSTRUCTION_BUDGET").map(str::parse::<u64>) {
+        None => Some(remote::DEFAULT_INSTRUCTION_BUDGET),
+        Some(Ok(0)) => None,
+        Some(Ok(budget)) => Some(budget),
+        Some(Err(e)) => {
+            tracing::error!("Invalid instruction budget: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let attach_repl = matches.is_present("REPL");
+    let http_bind = matches.value_of("HTTP_BIND");
+    let grpc_bind = matches.value_of("GRPC_BIND");
+    let ssh_bind = matches.value_of("SSH_BIND");
+    let ssh_host_key = matches.value_of("SSH_HOST_KEY");
+    let authorized_keys = matches.value_of("AUTHORIZED_KEYS");
+    let server = match remote::server::Server::bind(bind) {
+        Ok(server) => server,
+        Err(e) => {
+            tracing::error!("Unable to bind to {}: {}", bind, e);
+            std::process::exit(1);
+        }
+    };
+    let unspecified = advertise
+        .parse::<SocketAddr>()
+        .map(|addr| addr.ip().is_unspecified())
+        .unwrap_or(false);
+    if unspecified {
+        tracing::warn!(
+            "Advertising {} to the cluster, which other nodes cannot connect to; set --advertise",
+            advertise
+        );
+    }
+    let alias = alias.unwrap_or(advertise);
+    let cluster = Arc::new(cluster::Cluster::new(alias, advertise));
+    if let Some(addr) = join {
+        if let Err(e) = cluster.join(addr) {
+            tracing::error!("Unable to join the cluster at {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    }
+    cluster::Cluster::start_heartbeat(cluster.clone(), cluster::HEARTBEAT_INTERVAL);
+    // Every front end runs programs with the same scheduler, so each sees the others' processes.
+    let scheduler = scheduler::SharedScheduler::default();
+    let server = server
+        .with_cluster(cluster.clone())
+        .with_scheduler(scheduler.clone())
+        .with_budget(budget);
+    tracing::info!("Node {} listening for programs on {}", alias, bind);
+    if let Some(http_bind) = http_bind {
+        start_http_server(http_bind, scheduler.clone(), cluster.clone(), budget);
+    }
+    if let Some(grpc_bind) = grpc_bind {
+        start_grpc_server(grpc_bind, scheduler.clone(), budget);
+    }
+    if let (Some(ssh_bind), Some(host_key), Some(authorized_keys)) =
+        (ssh_bind, ssh_host_key, authorized_keys)
+    {
+        start_ssh_server(
+            ssh_bind,
+            host_key,
+            authorized_keys,
+            scheduler.clone(),
+            cluster.clone(),
+        );
+    }
+    if attach_repl {
+        thread::spawn(move || server.run());
+        let mut repl = repl::REPL::new();
+        repl.set_scheduler(scheduler);
+        repl.set_cluster(cluster);
+        if !repl.run_startup_script() {
+            repl.run();
         }
+    } else if let Err(e) = server.run() {
+        tracing::error!("Node stopped: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Serves the HTTP API on a background thread.
+fn start_http_server(
+    bind: &str,
+    scheduler: scheduler::SharedScheduler,
+    cluster: Arc<cluster::Cluster>,
+    budget: Option<u64>,
+) {
+    let server = match http::HttpServer::bind(bind, scheduler, Some(cluster)) {
+        Ok(server) => server.with_budget(budget),
+        Err(e) => {
+            tracing::error!("Unable to bind the HTTP API to {}: {}", bind, e);
+            std::process::exit(1);
+        }
+    };
+    tracing::info!("Serving the HTTP API on {}", bind);
+    thread::spawn(move || {
+        if let Err(e) = server.run() {
+            tracing::error!("HTTP server stopped: {}", e);
+        }
+    });
+}
+
+/// Serves the gRPC VM control service on a background thread.
+#[cfg(feature = "grpc")]
+fn start_grpc_server(bind: &str, scheduler: scheduler::SharedScheduler, budget: Option<u64>) {
+    let bind = bind.to_string();
+    tracing::info!("Serving the gRPC API on {}", bind);
+    let service = iridium::grpc::VmControlService::with_scheduler(scheduler).with_budget(budget);
+    thread::spawn(move || {
+        if let Err(e) = service.serve(&bind) {
+            tracing::error!("gRPC server stopped: {}", e);
+        }
+    });
+}
+
+#[cfg(not(feature = "grpc"))]
+fn start_grpc_server(_: &str, _: scheduler::SharedScheduler, _: Option<u64>) {
+    tracing::error!(
+        "This build of iridium does not include gRPC support. Rebuild with `--features grpc`"
+    );
+    std::process::exit(1);
+}
+
+/// Serves the node's REPL over SSH on a background thread.
+#[cfg(feature = "ssh")]
+fn start_ssh_server(
+    bind: &str,
+    host_key: &str,
+    authorized_keys: &str,
+    scheduler: scheduler::SharedScheduler,
+    cluster: Arc<cluster::Cluster>,
+) {
+    let keys = match iridium::ssh::read_authorized_keys(Path::new(authorized_keys)) {
+        Ok(keys) => keys,
+        Err(e) => {
+            tracing::error!(
+                "Unable to read authorized keys from {}: {}",
+                authorized_keys,
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+    let server = iridium::ssh::SshServer::new(keys, scheduler, Some(cluster));
+    let bind = bind.to_string();
+    let host_key = host_key.to_string();
+    tracing::info!("Serving the REPL over SSH on {}", bind);
+    thread::spawn(move || {
+        if let Err(e) = server.serve(&bind, Path::new(&host_key)) {
+            tracing::error!("SSH server stopped: {}", e);
+        }
+    });
+}
+
+#[cfg(not(feature = "ssh"))]
+fn start_ssh_server(
+    _: &str,
+    _: &str,
+    _: &str,
+    _: scheduler::SharedScheduler,
+    _: Arc<cluster::Cluster>,
+) {
+    tracing::error!(
+        "This build of iridium does not include SSH support. Rebuild with `--features ssh`"
+    );
+    std::process::exit(1);
+}
+
+/// Reads an assembly file, expanding its includes, along with the origin of each line of the
+/// expanded source. Exits if unable to read any of them for any reason.
+fn read_file(tmp: &str) -> (String, Vec<SourceLine>) {
+    match assembler::include::read_source_lines(Path::new(tmp)) {
+        Ok(contents) => contents,
         Err(e) => {
-            println!("File not found: {:?}", e);
-            std::process::exit(1)
+            tracing::error!("There was an error reading the file: {}", e);
+            std::process::exit(1);
         }
     }
 }
@@ -0,0 +1,142 @@
+use std::fmt;
+use std::ops::Deref;
+
+#[cfg(feature = "mmap")]
+use std::{fs::File, io};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The bytecode a VM executes. It is either owned, or mapped straight from a file so large
+/// binaries run without being copied into memory first.
+pub struct Program(Bytes);
+
+enum Bytes {
+    Owned(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl Program {
+    /// Returns a new, empty `Program`.
+    pub fn new() -> Program {
+        Program(Bytes::Owned(vec![]))
+    }
+
+    /// Maps `file` into memory and returns a `Program` that reads from the mapping. The mapping
+    /// lives as long as the `Program`, so VMs sharing it can never observe it being unmapped.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while it is mapped, e.g. by another process.
+    #[cfg(feature = "mmap")]
+    pub unsafe fn map(file: &File) -> io::Result<Program> {
+        Ok(Program(Bytes::Mapped(memmap2::Mmap::map(file)?)))
+    }
+
+    /// Returns whether the program reads from a file mapping.
+    pub fn is_mapped(&self) -> bool {
+        match self.0 {
+            Bytes::Owned(_) => false,
+            #[cfg(feature = "mmap")]
+            Bytes::Mapped(_) => true,
+        }
+    }
+
+    /// Returns the bytes for modification, copying a mapped program into memory first.
+    pub fn to_mut(&mut self) -> &mut Vec<u8> {
+        if self.is_mapped() {
+            self.0 = Bytes::Owned(self.to_vec());
+        }
+        match self.0 {
+            Bytes::Owned(ref mut bytes) => bytes,
+            #[cfg(feature = "mmap")]
+            Bytes::Mapped(_) => unreachable!(),
+        }
+    }
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Program::new()
+    }
+}
+
+impl Deref for Program {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.0 {
+            Bytes::Owned(ref bytes) => bytes,
+            #[cfg(feature = "mmap")]
+            Bytes::Mapped(ref map) => map,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Program {
+    fn from(bytes: Vec<u8>) -> Program {
+        Program(Bytes::Owned(bytes))
+    }
+}
+
+/// Cloning always produces an owned copy.
+impl Clone for Program {
+    fn clone(&self) -> Program {
+        Program::from(self.to_vec())
+    }
+}
+
+impl PartialEq for Program {
+    fn eq(&self, other: &Program) -> bool {
+        **self == **other
+    }
+}
+
+impl fmt::Debug for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl Serialize for Program {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Program {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Program, D::Error> {
+        Vec::deserialize(deserializer).map(Program::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_owned_program() {
+        let mut program = Program::from(vec![1, 2]);
+        program.to_mut().push(3);
+        assert_eq!(&*program, &[1, 2, 3]);
+        assert!(!program.is_mapped());
+        assert_eq!(program.clone(), program);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mapped_program() {
+        let path = std::env::temp_dir().join("iridium_test_mapped_program");
+        std::fs::write(&path, [45, 50, 49, 45]).unwrap();
+        let file = File::open(&path).unwrap();
+        let mut program = unsafe { Program::map(&file) }.unwrap();
+        assert!(program.is_mapped());
+        assert_eq!(&*program, &[45, 50, 49, 45]);
+
+        // Modifying the program copies it out of the mapping.
+        program.to_mut().push(0);
+        assert!(!program.is_mapped());
+        std::fs::remove_file(&path).ok();
+        assert_eq!(program.len(), 5);
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::Member;
+use crate::console::BufferInput;
+use crate::vm::{VMEvent, VM};
+
+pub mod server;
+
+/// The default port a node listens on.
+pub const DEFAULT_PORT: u16 = 2244;
+/// Messages larger than this are rejected rather than buffered.
+pub const MAX_MESSAGE_LENGTH: u32 = 64 * 1024 * 1024;
+/// The most instructions a program submitted over the network runs, unless the node is started
+/// with another limit. A few seconds' worth, so a runaway loop cannot hold a thread forever.
+pub const DEFAULT_INSTRUCTION_BUDGET: u64 = 1_000_000_000;
+
+/// Returns a VM for a program submitted by a remote client. It reads from an empty input rather
+/// than the node's stdin, which belongs to the operator, and stops after `budget` instructions,
+/// if given.
+pub fn remote_vm(budget: Option<u64>) -> VM {
+    let mut vm = VM::new();
+    vm.set_input(Arc::new(Mutex::new(BufferInput::default())));
+    vm.set_instruction_budget(budget);
+    vm
+}
+
+/// A message sent by a client to a node.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum Request {
+    /// Runs an assembled, header-prefixed program.
+    Run { program: Vec<u8> },
+    /// Adds the sender to the node's cluster.
+    Join { member: Member },
+    /// Tells the node the sender is still alive.
+    Heartbeat { member: Member },
+}
+
+/// A message sent by a node back to a client.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum Response {
+    /// Text the program wrote to its output.
+    Output { text: String },
+    /// An event emitted by the VM running the program.
+    Event { event: VMEvent },
+    /// The program finished and no more messages will follow for this request.
+    Done,
+    /// The members of the node's cluster, sent in reply to `Join` and `Heartbeat`.
+    Members { members: Vec<Member> },
+    /// The request could not be processed.
+    Error { message: String },
+}
+
+/// Writes `message` as a big endian `u32` length followed by its bincode encoding.
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let bytes =
+        bincode::serialize(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    writer.write_u32::<BigEndian>(bytes.len() as u32)?;
+    writer.write_all(&bytes)?;
+    writer.flush()
+}
+
+/// Reads a message written by `write_message`.
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> io::Result<T> {
+    let len = reader.read_u32::<BigEndian>()?;
+    if len > MAX_MESSAGE_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Message of {} bytes exceeds the maximum length", len),
+        ));
+    }
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes)?;
+    bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut buffer = vec![];
+        let request = Request::Run {
+            program: vec![1, 2, 3],
+        };
+        write_message(&mut buffer, &request).unwrap();
+        assert_eq!(buffer[0..4], [0, 0, 0, 15]);
+        let decoded: Request = read_message(&mut &buffer[..]).unwrap();
+        assert_eq!(decoded, request);
+    }
+
+    #[test]
+    fn test_message_too_long() {
+        let buffer = [0xFF, 0xFF, 0xFF, 0xFF];
+        let result: io::Result<Request> = read_message(&mut &buffer[..]);
+        assert!(result.is_err());
+    }
+}
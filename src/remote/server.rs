@@ -0,0 +1,234 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::cluster::Cluster;
+use crate::console::Output;
+use crate::remote::{
+    read_message, remote_vm, write_message, Request, Response, DEFAULT_INSTRUCTION_BUDGET,
+};
+use crate::scheduler::{self, SharedScheduler};
+
+/// Accepts connections from clients and runs the programs they submit.
+pub struct Server {
+    listener: TcpListener,
+    /// The cluster this node belongs to, if any.
+    cluster: Option<Arc<Cluster>>,
+    /// Runs the programs clients submit.
+    scheduler: SharedScheduler,
+    /// The most instructions a submitted program runs. Unlimited when `None`.
+    budget: Option<u64>,
+}
+
+impl Server {
+    /// Binds a new `Server` to the given address.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Server> {
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            cluster: None,
+            scheduler: SharedScheduler::default(),
+            budget: Some(DEFAULT_INSTRUCTION_BUDGET),
+        })
+    }
+
+    /// Makes the server answer membership requests for `cluster`.
+    pub fn with_cluster(mut self, cluster: Arc<Cluster>) -> Server {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// Makes the server run programs with `scheduler`, e.g. the one the node's other front ends
+    /// use, instead of its own.
+    pub fn with_scheduler(mut self, scheduler: SharedScheduler) -> Server {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Makes submitted programs stop after `budget` instructions, or never when `None`, instead of
+    /// after `DEFAULT_INSTRUCTION_BUDGET`.
+    pub fn with_budget(mut self, budget: Option<u64>) -> Server {
+        self.budget = budget;
+        self
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until the listener fails. Each connection is served on its own thread.
+    pub fn run(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let cluster = self.cluster.clone();
+            let scheduler = self.scheduler.clone();
+            let budget = self.budget;
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, cluster, &scheduler, budget) {
+                    tracing::warn!("Connection closed with an error: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Forwards a program's output to the client as `Response::Output` messages.
+struct StreamOutput {
+    stream: Arc<Mutex<TcpStream>>,
+}
+
+impl Output for StreamOutput {
+    fn write_str(&mut self, s: &str) {
+        if let Ok(mut stream) = self.stream.lock() {
+            write_message(
+                &mut *stream,
+                &Response::Output {
+                    text: s.to_string(),
+                },
+            )
+            .ok();
+        }
+    }
+}
+
+/// Serves requests on a single connection until the client disconnects.
+fn handle_connection(
+    stream: TcpStream,
+    cluster: Option<Arc<Cluster>>,
+    scheduler: &SharedScheduler,
+    budget: Option<u64>,
+) -> io::Result<()> {
+    let mut reader = stream.try_clone()?;
+    let writer = Arc::new(Mutex::new(stream));
+    loop {
+        let request = match read_message(&mut reader) {
+            Ok(request) => request,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        match request {
+            Request::Run { program } => {
+                let mut vm = remote_vm(budget);
+                vm.set_output(Arc::new(Mutex::new(StreamOutput {
+                    stream: writer.clone(),
+                })));
+                // Events are sent as the VM records them, interleaved with the program's output.
+                let events = writer.clone();
+                vm.hooks_mut().on_event(move |event| {
+                    if let Ok(mut stream) = events.lock() {
+                        let event = Response::Event {
+                            event: event.clone(),
+                        };
+                        write_message(&mut *stream, &event).ok();
+                    }
+                });
+                vm.add_bytes(program);
+                let pid = scheduler.lock().unwrap().spawn_with_output(vm);
+                let error = match pid.and_then(|pid| scheduler::join(scheduler, pid)) {
+                    // A VM whose thread panicked has no events.
+                    Some(ref events) if events.is_empty() => Some("The VM thread panicked"),
+                    Some(_) => None,
+                    None => Some("The process table is full"),
+                };
+                let mut stream = writer.lock().unwrap();
+                if let Some(message) = error {
+                    let message = message.to_string();
+                    write_message(&mut *stream, &Response::Error { message })?;
+                }
+                write_message(&mut *stream, &Response::Done)?;
+            }
+            Request::Join { member } | Request::Heartbeat { member } => {
+                let response = match cluster {
+                    Some(ref cluster) => cluster.handle(member),
+                    None => Response::Error {
+                        message: "This node is not part of a cluster".to_string(),
+                    },
+                };
+                write_message(&mut *writer.lock().unwrap(), &response)?;
+            }
+        }
+    }
+}
+
+/// Submits a program to the node at `addr` and collects every response up to `Done`.
+pub fn run_remote<A: ToSocketAddrs>(addr: A, program: Vec<u8>) -> io::Result<Vec<Response>> {
+    let mut stream = TcpStream::connect(addr)?;
+    write_message(&mut stream, &Request::Run { program })?;
+    let mut responses = vec![];
+    loop {
+        match read_message(&mut stream)? {
+            Response::Done => return Ok(responses),
+            response => responses.push(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VMEventType;
+
+    #[test]
+    fn test_run_remote() {
+        let scheduler = SharedScheduler::default();
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .with_scheduler(scheduler.clone());
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let program = Assembler::new().assemble(".data\n.code\nhlt").unwrap();
+        let responses = run_remote(addr, program).unwrap();
+        // The program ran as a process of the scheduler the server was given.
+        assert_eq!(scheduler.lock().unwrap().pids(), vec![0]);
+        // Events are streamed in the order they happened, around the program's output.
+        let events: Vec<(usize, VMEventType)> = responses
+            .iter()
+            .enumerate()
+            .filter_map(|(i, response)| match response {
+                Response::Event { event } => Some((i, event.event.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (0, VMEventType::Start),
+                (responses.len() - 1, VMEventType::GracefulStop { code: 0 })
+            ]
+        );
+        assert_eq!(
+            responses[1],
+            Response::Output {
+                text: "HLT encountered".to_string()
+            }
+        );
+    }
+    #[test]
+    fn test_remote_programs_are_sandboxed() {
+        let server = Server::bind("127.0.0.1:0").unwrap().with_budget(Some(100));
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        let last_event = |source: &str| {
+            let program = Assembler::new().assemble(source).unwrap();
+            match run_remote(addr, program).unwrap().pop() {
+                Some(Response::Event { event }) => event.event,
+                other => panic!("Expected an event, got {:?}", other),
+            }
+        };
+
+        // Reading input gets EOF instead of waiting on the node's stdin.
+        assert_eq!(
+            last_event(".data\n.code\nreadi $0\nhlt"),
+            VMEventType::GracefulStop { code: 0 }
+        );
+        // A program that never stops is stopped once it runs out of instructions.
+        assert_eq!(
+            last_event(".data\n.code\nloop: jmp @loop"),
+            VMEventType::BudgetExceeded
+        );
+    }
+}
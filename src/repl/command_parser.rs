@@ -0,0 +1,78 @@
+use nom::types::CompleteStr;
+use nom::*;
+
+// Parser for the name of a command, such as `.load_file`.
+named!(command_name<CompleteStr, CompleteStr>,
+    recognize!(
+        preceded!(
+            tag!("."),
+            take_while1!(|c: char| c.is_alphanumeric() || c == '_')
+        )
+    )
+);
+
+// Parser for an argument in double quotes, which can hold spaces, e.g. a path such as
+// `"my programs/loop.iasm"`.
+named!(quoted_argument<CompleteStr, String>,
+    map!(
+        delimited!(tag!("\""), take_while!(|c: char| c != '"'), tag!("\"")),
+        |argument| argument.to_string()
+    )
+);
+
+// Parser for an argument that runs up to the next space.
+named!(bare_argument<CompleteStr, String>,
+    map!(is_not!(" \t"), |argument| argument.to_string())
+);
+
+named!(argument<CompleteStr, String>,
+    preceded!(space1, alt!(quoted_argument | bare_argument))
+);
+
+named!(command_line<CompleteStr, (CompleteStr, Vec<String>)>,
+    do_parse!(
+        name: command_name >>
+        args: many0!(argument) >>
+        space0 >>
+        eof!() >>
+        (name, args)
+    )
+);
+
+/// Splits a line such as `.breakpoint clear @loop` into the command's name and its arguments.
+/// Returns `None` if the line is not a command.
+pub fn parse_command(line: &str) -> Option<(String, Vec<String>)> {
+    match command_line(CompleteStr(line)) {
+        Ok((_, (name, args))) => Some((name.to_string(), args)),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command(".quit"), Some((".quit".to_string(), vec![])));
+        assert_eq!(
+            parse_command(".breakpoint clear  @loop "),
+            Some((
+                ".breakpoint".to_string(),
+                vec!["clear".to_string(), "@loop".to_string()]
+            ))
+        );
+        assert_eq!(
+            parse_command(".load_file \"my programs/loop.iasm\""),
+            Some((
+                ".load_file".to_string(),
+                vec!["my programs/loop.iasm".to_string()]
+            ))
+        );
+        assert_eq!(parse_command("load $0 #1"), None);
+        assert_eq!(
+            parse_command(".equ SIZE, 16").map(|(_, args)| args.len()),
+            Some(2)
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use crate::repl::REPL;
+
+/// What the REPL does once a command has run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// Reads the next command.
+    Done,
+    /// Tells the user how the command is used, as it was given the wrong arguments.
+    Usage,
+    /// Stops the REPL.
+    Quit,
+}
+
+/// A command the REPL understands, besides assembly.
+pub struct Command {
+    /// The name it is entered with, e.g. `.load_file`.
+    pub name: &'static str,
+    /// The arguments it takes, as shown in `.help` and usage messages, e.g. `[start len]`.
+    pub args: &'static str,
+    /// The fewest and most arguments it takes.
+    pub arity: (usize, usize),
+    /// What it does, as shown in `.help`.
+    pub help: &'static str,
+    handler: fn(&mut REPL, &[String]) -> Outcome,
+}
+
+impl Command {
+    /// Returns how the command is used, e.g. `.heap [start len]`.
+    pub fn usage(&self) -> String {
+        if self.args.is_empty() {
+            self.name.to_string()
+        } else {
+            format!("{} {}", self.name, self.args)
+        }
+    }
+
+    /// Runs the command with `args` in `repl`, or tells the user how it is used if they are not
+    /// the arguments it takes.
+    pub fn run(&self, repl: &mut REPL, args: &[String]) -> Outcome {
+        let (min, max) = self.arity;
+        let outcome = if args.len() < min || args.len() > max {
+            Outcome::Usage
+        } else {
+            (self.handler)(repl, args)
+        };
+        if outcome == Outcome::Usage {
+            repl.send_error(&format!("Usage: {}", self.usage()));
+            return Outcome::Done;
+        }
+        outcome
+    }
+}
+
+/// Returns the command named `name`, if there is one.
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// The commands the REPL understands, in the order `.help` lists them.
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: ".help",
+        args: "[command]",
+        arity: (0, 1),
+        help: "Lists the commands, or describes one",
+        handler: REPL::help,
+    },
+    Command {
+        name: ".quit",
+        args: "",
+        arity: (0, 0),
+        help: "Leaves the REPL",
+        handler: REPL::quit,
+    },
+    Command {
+        name: ".history",
+        args: "",
+        arity: (0, 0),
+        help: "Lists the lines entered so far",
+        handler: REPL::history,
+    },
+    Command {
+        name: ".program",
+        args: "",
+        arity: (0, 0),
+        help: "Lists the bytes of the VM's program",
+        handler: REPL::program,
+    },
+    Command {
+        name: ".registers",
+        args: "",
+        arity: (0, 0),
+        help: "Lists the registers, highlighting those the code last run changed",
+        handler: REPL::list_registers,
+    },
+    Command {
+        name: ".cluster",
+        args: "",
+        arity: (0, 0),
+        help: "Lists the members of the cluster this node belongs to",
+        handler: REPL::list_cluster,
+    },
+    Command {
+        name: ".clear_program",
+        args: "",
+        arity: (0, 0),
+        help: "Drops the program and the labels entered so far",
+        handler: REPL::clear_program,
+    },
+    Command {
+        name: ".reset",
+        args: "[all]",
+        arity: (0, 1),
+        help: "Resets the VM, keeping its program unless `all` is given",
+        handler: REPL::reset,
+    },
+    Command {
+        name: ".load_file",
+        args: "[path]",
+        arity: (0, 1),
+        help: "Assembles a file after the program without running it",
+        handler: REPL::load_file,
+    },
+    Command {
+        name: ".spawn",
+        args: "[path]",
+        arity: (0, 1),
+        help: "Runs a file in a new VM in the background and prints its pid",
+        handler: REPL::spawn,
+    },
+    Command {
+        name: ".ps",
+        args: "",
+        arity: (0, 0),
+        help: "Lists the spawned VMs with their state, start time and instructions executed",
+        handler: REPL::list_processes,
+    },
+    Command {
+        name: ".kill",
+        args: "<pid>",
+        arity: (1, 1),
+        help: "Stops a spawned VM and reports how it ended",
+        handler: REPL::kill,
+    },
+    Command {
+        name: ".join",
+        args: "<pid>",
+        arity: (1, 1),
+        help: "Waits for a spawned VM to stop and prints its events, registers and exit code",
+        handler: REPL::join,
+    },
+    Command {
+        name: ".hex",
+        args: "[bytes...]",
+        arity: (0, usize::MAX),
+        help: "Runs instructions given as hex bytes, or reads lines as hex bytes until given again",
+        handler: REPL::hex,
+    },
+    Command {
+        name: ".step",
+        args: "",
+        arity: (0, 0),
+        help: "Runs the next instruction",
+        handler: REPL::step,
+    },
+    Command {
+        name: ".continue",
+        args: "",
+        arity: (0, 0),
+        help: "Runs until the end of the program, a breakpoint or a watched register changing",
+        handler: REPL::continue_running,
+    },
+    Command {
+        name: ".breakpoint",
+        args: "[[clear] address]",
+        arity: (0, 2),
+        help: "Lists the breakpoints, or sets or clears one at an address or label",
+        handler: REPL::breakpoint,
+    },
+    Command {
+        name: ".watch",
+        args: "[[clear] register]",
+        arity: (0, 2),
+        help: "Lists the watched registers, or watches or stops watching one",
+        handler: REPL::watch,
+    },
+    Command {
+        name: ".disassemble",
+        args: "[start [end]]",
+        arity: (0, 2),
+        help: "Lists the instructions of the program's code or of an address range",
+        handler: REPL::disassemble,
+    },
+    Command {
+        name: ".heap",
+        args: "[start len]",
+        arity: (0, 2),
+        help: "Prints the heap as a hex dump",
+        handler: REPL::heap,
+    },
+    Command {
+        name: ".memory",
+        args: "[start len]",
+        arity: (0, 2),
+        help: "Lists the regions of the VM's memory, or prints an address range of it",
+        handler: REPL::memory,
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let command = find(".heap").unwrap();
+        assert_eq!(command.usage(), ".heap [start len]");
+        assert_eq!(find(".quit").unwrap().usage(), ".quit");
+        assert!(find(".data").is_none());
+    }
+}
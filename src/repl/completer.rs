@@ -0,0 +1,120 @@
+use std::sync::{Arc, Mutex};
+
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use crate::instruction::{Opcode, REGISTER_COUNT};
+use crate::repl::commands::COMMANDS;
+
+/// Completes the word before the cursor when Tab is pressed: a command at the start of the line,
+/// a register after `$`, a label after `@`, and a mnemonic otherwise.
+pub struct ReplHelper {
+    mnemonics: Vec<String>,
+    /// The labels declared so far, which the REPL adds to as lines are assembled.
+    labels: Arc<Mutex<Vec<String>>>,
+}
+
+impl ReplHelper {
+    /// Returns a helper that completes the labels in `labels`.
+    pub fn new(labels: Arc<Mutex<Vec<String>>>) -> ReplHelper {
+        ReplHelper {
+            mnemonics: Opcode::mnemonics(),
+            labels,
+        }
+    }
+
+    /// Returns where the word before `pos` in `line` starts, and the words it can be completed
+    /// to.
+    fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+        let words: Vec<String> = if word.starts_with('.') && start == 0 {
+            COMMANDS
+                .iter()
+                .map(|command| command.name.to_string())
+                .collect()
+        } else if word.starts_with('$') {
+            (0..REGISTER_COUNT).map(|r| format!("${}", r)).collect()
+        } else if word.starts_with('@') {
+            match self.labels.lock() {
+                Ok(labels) => labels.iter().map(|label| format!("@{}", label)).collect(),
+                Err(_) => vec![],
+            }
+        } else {
+            self.mnemonics.clone()
+        };
+        let lower = word.to_lowercase();
+        let candidates = words
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(&lower) || candidate.starts_with(word))
+            .collect();
+        (start, candidates)
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(line, pos))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustyline::history::DefaultHistory;
+
+    #[test]
+    fn test_complete() {
+        let labels = Arc::new(Mutex::new(vec!["loop".to_string(), "done".to_string()]));
+        let helper = ReplHelper::new(labels.clone());
+        let history = DefaultHistory::new();
+        let complete = |line: &str| {
+            helper
+                .complete(line, line.len(), &Context::new(&history))
+                .unwrap()
+        };
+        assert_eq!(
+            complete(".cl"),
+            (
+                0,
+                vec![".cluster".to_string(), ".clear_program".to_string()]
+            )
+        );
+        assert_eq!(
+            complete("prt"),
+            (0, vec!["prts".to_string(), "prtsr".to_string()])
+        );
+        assert_eq!(complete("LOA"), (0, vec!["load".to_string()]));
+        assert_eq!(
+            complete("inc $3"),
+            (
+                4,
+                vec!["$3".to_string(), "$30".to_string(), "$31".to_string()]
+            )
+        );
+        assert_eq!(complete("jmpl @l"), (5, vec!["@loop".to_string()]));
+        // Labels declared later are completed too.
+        labels.lock().unwrap().push("later".to_string());
+        assert_eq!(complete("jmpl @l").1.len(), 2);
+    }
+}
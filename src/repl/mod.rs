@@ -1,171 +1,846 @@
-use crate::assembler::Assembler;
-use crate::assembler::{program_parsers::program, symbols::SymbolTable};
-use crate::scheduler::Scheduler;
+pub mod command_parser;
+pub mod commands;
+pub mod completer;
+pub mod style;
+
+use crate::assembler::include::read_source;
+use crate::assembler::{write_checksum, write_pie_header, Assembler, PIE_HEADER_LENGTH};
+use crate::cluster::Cluster;
+use crate::console::{self, SharedInput, SharedOutput};
+use crate::instruction::{DecodedInstruction, Opcode, INSTRUCTION_LENGTH};
+use crate::program::Program;
+use crate::scheduler::{self, SharedScheduler};
+use crate::shared::SharedHeap;
 use crate::vm::VM;
-use nom::types::CompleteStr;
-use std;
-use std::io;
-use std::{fs::File, io::Read, io::Write, num::ParseIntError, path::Path};
+use std::collections::BTreeSet;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{env, num::ParseIntError};
+
+use command_parser::parse_command;
+use commands::{Command, Outcome};
+use completer::ReplHelper;
+use style::{paint, Style};
+
+/// The size in words of the heap segment shared by the REPL's VM and the VMs it spawns.
+const SHARED_HEAP_WORDS: usize = 1024;
+/// The most instructions the code entered on one line runs, so a loop that never ends does not
+/// hang the REPL.
+const STEP_LIMIT: usize = 1_000_000;
 
 /// The core structure of the Assembler REPL.
 pub struct REPL {
     command_buffer: Vec<String>,
     /// The VM the REPL uses to execute code.
     vm: VM,
+    /// Assembles each line entered incrementally, so lines can use the labels of earlier ones.
     asm: Assembler,
-    scheduler: Scheduler,
+    /// Runs the VMs spawned with `.spawn`, possibly alongside those of the node the REPL is
+    /// attached to.
+    scheduler: SharedScheduler,
+    /// The cluster of the node this REPL is attached to, if any.
+    cluster: Option<Arc<Cluster>>,
+    /// Where the REPL reads commands from.
+    input: SharedInput,
+    /// Where the REPL and its VM write their output.
+    output: SharedOutput,
+    /// The labels declared by the lines entered so far, for completion.
+    labels: Arc<Mutex<Vec<String>>>,
+    /// The registers watched with `.watch`, which pause the code running when they change.
+    watches: BTreeSet<usize>,
+    /// The registers as they were before the code last run, so `.registers` can highlight the
+    /// ones it changed.
+    previous_registers: Vec<i32>,
+    /// Whether the REPL's output is colored.
+    color: bool,
+    /// Whether lines that are not commands are read as instruction bytes in hex, toggled with
+    /// `.hex`.
+    hex_mode: bool,
+}
+
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl REPL {
-    /// Returns a new assembly REPL.
+    /// Returns a new assembly REPL attached to the terminal, reading commands through a line
+    /// editor that completes them with Tab, and to stdout.
     pub fn new() -> REPL {
+        let labels = Arc::new(Mutex::new(vec![]));
+        let input = console::editor(ReplHelper::new(labels.clone()));
+        let mut repl = REPL::with_io(input, console::stdout());
+        repl.labels = labels;
+        // Following https://no-color.org, `NO_COLOR` turns color off.
+        repl.color = io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none();
+        repl
+    }
+
+    /// Returns a new assembly REPL that reads commands from `input` and writes to `output`, e.g.
+    /// for a remote session. Its output is not colored unless `set_color` is called.
+    pub fn with_io(input: SharedInput, output: SharedOutput) -> REPL {
+        // The code entered follows an empty header, which is where the assembler places it.
+        let mut vm = VM::builder().entry_point(PIE_HEADER_LENGTH).build();
+        vm.set_output(output.clone());
+        vm.set_shared_heap(Arc::new(SharedHeap::new(SHARED_HEAP_WORDS)));
+        let previous_registers = vm.registers.clone();
         REPL {
-            vm: VM::new(),
+            vm,
             command_buffer: vec![],
-            asm: Assembler::new(),
-            scheduler: Scheduler::new(),
+            asm: incremental_assembler(),
+            scheduler: SharedScheduler::default(),
+            cluster: None,
+            input,
+            output,
+            labels: Arc::new(Mutex::new(vec![])),
+            watches: BTreeSet::new(),
+            previous_registers,
+            color: false,
+            hex_mode: false,
         }
     }
 
+    /// Sets whether the REPL colors its output with ANSI escape sequences, to tell errors apart
+    /// from what it reports and from the VM's own output.
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
+
+    /// Attaches the REPL to the cluster of the node it is running on.
+    pub fn set_cluster(&mut self, cluster: Arc<Cluster>) {
+        self.cluster = Some(cluster);
+    }
+
+    /// Makes the REPL spawn VMs with the scheduler of the node it is running on, so it sees the
+    /// processes submitted to the node and the node sees those spawned here.
+    pub fn set_scheduler(&mut self, scheduler: SharedScheduler) {
+        self.scheduler = scheduler;
+    }
+
+    /// Runs the REPL until the user quits or the input is exhausted.
     pub fn run(&mut self) {
-        println!("Welcome to Iridium! Let's be productive!");
-        loop {
-            let mut buffer = String::new();
-            let stdin = io::stdin();
-
-            print!(">>> ");
-            io::stdout().flush().expect("Unable to flush stdout");
-
-            stdin
-                .read_line(&mut buffer)
-                .expect("Unable to read line from user");
-            let buffer = buffer.trim();
-
-            // Store a copy of the command into the buffer.
-            self.command_buffer.push(buffer.to_string());
-
-            match buffer {
-                ".quit" => {
-                    println!("Farewell! Have a great day!");
-                    std::process::exit(0);
+        self.send_message("Welcome to Iridium! Let's be productive!");
+        while let Some(buffer) = self.read_command() {
+            if self.execute(&buffer) == Outcome::Quit {
+                return;
+            }
+        }
+    }
+
+    /// Runs each line of `script` as if it had been entered, stopping at `.quit`. Lines starting
+    /// with `#` are comments. Returns whether the script quit.
+    pub fn run_script(&mut self, script: &str) -> bool {
+        script
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .any(|line| self.execute(line) == Outcome::Quit)
+    }
+
+    /// Runs the startup script at `~/.iridiumrc`, if there is one, e.g. to load a standard library
+    /// with `.load_file`. Returns whether the script quit.
+    pub fn run_startup_script(&mut self) -> bool {
+        let path = match startup_script_path() {
+            Some(path) if path.is_file() => path,
+            _ => return false,
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(script) => self.run_script(&script),
+            Err(e) => {
+                self.send_error(&format!("Unable to read {}: {}", path.display(), e));
+                false
+            }
+        }
+    }
+
+    /// Runs a command, or assembles and runs a line of code.
+    fn execute(&mut self, buffer: &str) -> Outcome {
+        let buffer = buffer.trim();
+        // E.g. a line discarded with Ctrl-C.
+        if buffer.is_empty() {
+            return Outcome::Done;
+        }
+
+        // Store a copy of the command into the buffer.
+        self.command_buffer.push(buffer.to_string());
+
+        // Commands can take arguments after them, e.g. `.load_file examples/loop.iasm`. Lines
+        // that are not commands, including directives such as `.equ`, are assembled.
+        let command = parse_command(buffer)
+            .and_then(|(name, args)| commands::find(&name).map(|command| (command, args)));
+        match command {
+            Some((command, args)) => return command.run(self, &args),
+            None if self.hex_mode => self.append_hex(buffer),
+            None => {
+                if self.append_code(buffer) {
+                    self.run_entered_code();
                 }
-                ".history" => {
-                    for command in &self.command_buffer {
-                        println!("{}", command);
+            }
+        }
+        Outcome::Done
+    }
+
+    /// Lists the commands with what they do, or describes the one given as `.help <command>`.
+    fn help(&mut self, args: &[String]) -> Outcome {
+        let listed: Vec<&Command> = match args.first() {
+            None => commands::COMMANDS.iter().collect(),
+            Some(name) => {
+                let name = format!(".{}", name.trim_start_matches('.'));
+                match commands::find(&name) {
+                    Some(command) => vec![command],
+                    None => {
+                        self.send_error(&format!("Unknown command: {}", name));
+                        return Outcome::Done;
                     }
                 }
-                ".program" => {
-                    println!("Listing instructions currently in VM's program vector:");
-                    for instruction in &self.vm.program {
-                        println!("{}", instruction);
-                    }
-                    println!("End of Program Listing");
+            }
+        };
+        self.send_message("Listing commands:");
+        for command in listed {
+            self.send_message(&format!("{:<30} {}", command.usage(), command.help));
+        }
+        self.send_message("End of Command Listing");
+        Outcome::Done
+    }
+
+    fn quit(&mut self, _: &[String]) -> Outcome {
+        self.send_message("Farewell! Have a great day!");
+        Outcome::Quit
+    }
+
+    fn history(&mut self, _: &[String]) -> Outcome {
+        let history = self.command_buffer.join("\n");
+        self.send_message(&history);
+        Outcome::Done
+    }
+
+    fn program(&mut self, _: &[String]) -> Outcome {
+        self.send_message("Listing instructions currently in VM's program vector:");
+        let listing: Vec<String> = self.vm.program.iter().map(|b| b.to_string()).collect();
+        self.send_message(&listing.join("\n"));
+        self.send_message("End of Program Listing");
+        Outcome::Done
+    }
+
+    fn list_cluster(&mut self, _: &[String]) -> Outcome {
+        match self.cluster {
+            Some(ref cluster) => {
+                self.send_message("Listing members of the cluster:");
+                for member in cluster.members() {
+                    self.send_message(&format!(
+                        "{} {} (last seen {})",
+                        member.alias, member.addr, member.last_seen
+                    ));
                 }
-                ".registers" => {
-                    println!("Listing registers and all contents:");
-                    println!("{:#?}", self.vm.registers);
-                    println!("End of Register Listing");
+                self.send_message("End of Cluster Listing");
+            }
+            None => self.send_error("This REPL is not attached to a cluster node"),
+        }
+        Outcome::Done
+    }
+
+    fn clear_program(&mut self, _: &[String]) -> Outcome {
+        self.vm.program = Arc::new(Program::new());
+        self.vm.reset();
+        self.clear_assembler();
+        self.send_message("Program has been cleared!");
+        Outcome::Done
+    }
+
+    /// Assembles the file at the path given, or asked for, after the program. Its code is loaded
+    /// without being run, to be run with `.step` or `.continue`.
+    fn load_file(&mut self, args: &[String]) -> Outcome {
+        let contents = match self.get_data_from_load(args.first().map(String::as_str)) {
+            Some(contents) => contents,
+            None => return Outcome::Done,
+        };
+        let start = self.vm.program.len().max(PIE_HEADER_LENGTH);
+        if self.append_code(&contents) {
+            self.send_message(&format!(
+                "Loaded {} instructions at {}",
+                (self.vm.program.len() - start) / INSTRUCTION_LENGTH,
+                start
+            ));
+        }
+        Outcome::Done
+    }
+
+    /// Assembles the file at the path given, or asked for, and runs it in a new VM on its own
+    /// thread, tracked by the scheduler under the pid it reports.
+    fn spawn(&mut self, args: &[String]) -> Outcome {
+        let contents = match self.get_data_from_load(args.first().map(String::as_str)) {
+            Some(contents) => contents,
+            None => return Outcome::Done,
+        };
+        // A file can be a snippet of code without sections.
+        let mut asm = Assembler::new();
+        asm.set_relaxed(true);
+        match asm.assemble(&contents) {
+            Ok(assembled_program) => {
+                // Each spawned VM starts fresh, sharing only the program bytes and the shared
+                // heap.
+                let mut vm = VM::with_program(Arc::new(assembled_program.into()));
+                vm.set_output(self.output.clone());
+                vm.set_shared_heap(self.vm.shared_heap());
+                let pid = self.scheduler.lock().unwrap().spawn_with_output(vm);
+                match pid {
+                    Some(pid) => self.send_message(&format!("Spawned process {}", pid)),
+                    None => self.send_error("Unable to spawn: every pid is taken"),
                 }
-                ".clear_program" => {
-                    self.vm.program = vec![];
-                    println!("Program has been cleared!");
+            }
+            Err(errors) => {
+                for error in errors {
+                    self.send_error(&format!("Unable to parse input: {}", error));
                 }
-                ".load_file" => {
-                    let contents = self.get_data_from_load();
-                    if let Some(contents) = contents {
-                        let program = match program(CompleteStr(&contents)) {
-                            Ok((_remainder, program)) => program,
-                            Err(e) => {
-                                println!("Unable to parse input: {:?}", e);
-                                continue;
-                            }
-                        };
-                        // TODO fix
-                        let symbol_table = SymbolTable::new();
-                        self.vm.program.append(&mut program.to_bytes(&symbol_table));
-                    } else {
-                        continue;
-                    }
+            }
+        }
+        Outcome::Done
+    }
+
+    /// Assembles `source` after the code entered so far and adds it to the VM's program. Returns
+    /// whether it could be assembled, reporting why if not.
+    fn append_code(&mut self, source: &str) -> bool {
+        let fragment = match self.asm.assemble_fragment(source) {
+            Ok(fragment) => fragment,
+            Err(errors) => {
+                for error in errors {
+                    self.send_error(&format!("Unable to assemble input: {}", error));
                 }
-                ".spawn" => {
-                    let contents = self.get_data_from_load();
-                    if let Some(contents) = contents {
-                        match self.asm.assemble(&contents) {
-                            Ok(mut assembled_program) => {
-                                println!("Sending assembled program to VM");
-                                self.vm.program.append(&mut assembled_program);
-                                println!("{:#?}", self.vm.program);
-                                self.scheduler.get_thread(self.vm.clone());
-                            }
-                            Err(errors) => {
-                                for error in errors {
-                                    println!("Unable to parse input: {}", error);
-                                }
-                                continue;
-                            }
-                        }
-                    } else {
-                        continue;
-                    }
+                return false;
+            }
+        };
+        self.add_header();
+        if let Ok(mut labels) = self.labels.lock() {
+            labels.extend(fragment.symbols.iter().map(|s| s.name.clone()));
+        }
+        self.vm.add_slice(&fragment.code);
+        true
+    }
+
+    /// Starts the program with an empty header if it has none yet, as the code entered follows
+    /// it.
+    fn add_header(&mut self) {
+        if self.vm.program.is_empty() {
+            let mut header = vec![];
+            write_pie_header(&mut header, &[], None, 0, 0);
+            write_checksum(&mut header);
+            self.vm.add_bytes(header);
+        }
+    }
+
+    /// Runs the instructions given as hex bytes, e.g. `.hex 01 00 03 E8`, or without them, turns
+    /// hex mode on or off.
+    fn hex(&mut self, args: &[String]) -> Outcome {
+        if !args.is_empty() {
+            self.append_hex(&args.join(" "));
+        } else if self.hex_mode {
+            self.hex_mode = false;
+            self.send_message("Leaving hex mode");
+        } else {
+            self.hex_mode = true;
+            self.send_message("Entering hex mode: lines are read as instruction bytes until .hex");
+        }
+        Outcome::Done
+    }
+
+    /// Adds the instructions whose bytes `line` gives in hex to the program and runs them.
+    fn append_hex(&mut self, line: &str) {
+        let bytes = match self.parse_hex(line) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                self.send_error(&format!("Unable to parse hex bytes: {}", e));
+                return;
+            }
+        };
+        if bytes.len() % INSTRUCTION_LENGTH != 0 {
+            self.send_error(&format!(
+                "Instructions are {} bytes long, but {} bytes were given",
+                INSTRUCTION_LENGTH,
+                bytes.len()
+            ));
+            return;
+        }
+        self.add_header();
+        self.asm.skip_code(bytes.len());
+        self.vm.add_slice(&bytes);
+        self.run_entered_code();
+    }
+
+    /// Lists the VMs spawned with `.spawn`.
+    fn list_processes(&mut self, _: &[String]) -> Outcome {
+        self.send_message("Listing processes:");
+        self.send_message(&format!(
+            "{:>5}  {:<8}  {:<19}  {:>12}",
+            "PID", "STATE", "STARTED", "INSTRUCTIONS"
+        ));
+        let processes = self.scheduler.lock().unwrap().processes();
+        for process in processes {
+            self.send_message(&format!(
+                "{:>5}  {:<8}  {:<19}  {:>12}",
+                process.pid,
+                process.state.to_string(),
+                process.started.format("%Y-%m-%d %H:%M:%S").to_string(),
+                process.instructions
+            ));
+        }
+        self.send_message("End of Process Listing");
+        Outcome::Done
+    }
+
+    /// Stops the spawned VM given as `.kill <pid>`, waiting for it to stop to report its final
+    /// event.
+    fn kill(&mut self, args: &[String]) -> Outcome {
+        let pid = match args[0].parse::<u32>() {
+            Ok(pid) => pid,
+            Err(_) => return Outcome::Usage,
+        };
+        let running = self.scheduler.lock().unwrap().is_running(pid);
+        match running {
+            None => self.send_error(&format!("There is no process {}", pid)),
+            Some(false) => self.send_error(&format!("Process {} has already stopped", pid)),
+            Some(true) => {
+                self.scheduler.lock().unwrap().kill(pid);
+                let events = scheduler::join(&self.scheduler, pid).unwrap_or_default();
+                if let Some(event) = events.last() {
+                    self.send_message(&format!("Process {}: {}", pid, event.event));
                 }
-                _ => {
-                    let parsed_program = program(CompleteStr(buffer));
-                    if !parsed_program.is_ok() {
-                        println!("Unable to parse input");
-                        continue;
-                    }
-                    let (_, result) = parsed_program.unwrap();
-                    // TODO fix
-                    let symbol_table = SymbolTable::new();
-                    let bytecode = result.to_bytes(&symbol_table);
+            }
+        }
+        Outcome::Done
+    }
 
-                    for byte in bytecode {
-                        self.vm.add_byte(byte);
-                    }
-                    self.vm.run_once();
+    /// Waits for the spawned VM given as `.join <pid>` to stop, and prints its events, final
+    /// registers and exit code.
+    fn join(&mut self, args: &[String]) -> Outcome {
+        let pid = match args[0].parse::<u32>() {
+            Ok(pid) => pid,
+            Err(_) => return Outcome::Usage,
+        };
+        let events = match scheduler::join(&self.scheduler, pid) {
+            Some(events) => events,
+            None => {
+                self.send_error(&format!("There is no process {}", pid));
+                return Outcome::Done;
+            }
+        };
+        self.send_message(&format!("Listing events of process {}:", pid));
+        for event in events {
+            self.send_message(&format!(
+                "{} {}",
+                event.at.format("%Y-%m-%d %H:%M:%S%.3f"),
+                event.event
+            ));
+        }
+        self.send_message("Listing its registers:");
+        let (registers, exit_code) = {
+            let mut scheduler = self.scheduler.lock().unwrap();
+            (scheduler.registers(pid), scheduler.exit_code(pid))
+        };
+        for (register, value) in registers.unwrap_or_default().iter().enumerate() {
+            self.send_message(&format!("${}: {}", register, value));
+        }
+        match exit_code {
+            Some(code) => self.send_message(&format!("Exit code: {}", code)),
+            None => self.send_message("Exit code: none, as it did not stop by itself"),
+        }
+        self.send_message("End of Process Listing");
+        Outcome::Done
+    }
+
+    /// Resets the VM's registers, program counter, heap and stacks, keeping its program, or with
+    /// `.reset all` also drops the program, its breakpoints and the labels entered so far.
+    fn reset(&mut self, args: &[String]) -> Outcome {
+        match args.first().map(String::as_str) {
+            None => {
+                self.vm.reset();
+                self.previous_registers = self.vm.registers.clone();
+                self.send_message("VM has been reset, keeping its program!");
+            }
+            Some("all") => {
+                self.vm.reset_full();
+                self.previous_registers = self.vm.registers.clone();
+                self.clear_assembler();
+                self.send_message("VM has been reset, along with its program!");
+            }
+            Some(_) => return Outcome::Usage,
+        }
+        Outcome::Done
+    }
+
+    /// Starts assembling the lines entered afresh, forgetting the labels declared so far.
+    fn clear_assembler(&mut self) {
+        self.asm = incremental_assembler();
+        if let Ok(mut labels) = self.labels.lock() {
+            labels.clear();
+        }
+    }
+
+    /// Lists the registers, highlighting the ones the code last run changed along with the values
+    /// they had before.
+    fn list_registers(&mut self, _: &[String]) -> Outcome {
+        self.send_message("Listing registers and all contents:");
+        for (register, value) in self.vm.registers.iter().enumerate() {
+            let line = format!("${}: {}", register, value);
+            match self.previous_registers.get(register) {
+                Some(old) if old != value => self.write_line(&format!(
+                    "{} {}",
+                    paint(&line, Style::Changed, self.color),
+                    paint(&format!("(was {})", old), Style::Info, self.color)
+                )),
+                _ => self.send_message(&line),
+            }
+        }
+        self.send_message("End of Register Listing");
+        Outcome::Done
+    }
+
+    /// Runs the instruction at the program counter, printing it and the registers it changed.
+    fn step(&mut self, _: &[String]) -> Outcome {
+        let pc = self.vm.pc();
+        if pc >= self.vm.program.len() {
+            self.send_error("There are no instructions left to run");
+            return Outcome::Done;
+        }
+        let instruction = DecodedInstruction::decode(&self.vm.program, pc);
+        let before = self.vm.registers.clone();
+        self.previous_registers = before.clone();
+        self.vm.run_once();
+        self.send_message(&format!("{}: {}", pc, instruction));
+        for (register, (old, new)) in before.iter().zip(&self.vm.registers).enumerate() {
+            if old != new {
+                self.send_message(&format!("${}: {} -> {}", register, old, new));
+            }
+        }
+        self.report_watches(&before);
+        Outcome::Done
+    }
+
+    /// Reports each watched register whose value differs from the one in `before`, returning
+    /// whether there were any.
+    fn report_watches(&self, before: &[i32]) -> bool {
+        let mut changed = false;
+        for &register in &self.watches {
+            let (old, new) = (before[register], self.vm.registers[register]);
+            if old != new {
+                self.send_message(&format!(
+                    "Watched ${} changed: {} -> {}",
+                    register, old, new
+                ));
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Carries on running the code entered, e.g. after `.step`.
+    fn continue_running(&mut self, _: &[String]) -> Outcome {
+        self.run_entered_code();
+        Outcome::Done
+    }
+
+    /// Runs the code entered until the program counter reaches the end of the program, a
+    /// breakpoint or a change to a watched register.
+    fn run_entered_code(&mut self) {
+        let breakpoints: BTreeSet<usize> = self.vm.breakpoints().collect();
+        self.previous_registers = self.vm.registers.clone();
+        for step in 0..STEP_LIMIT {
+            let pc = self.vm.pc();
+            if pc >= self.vm.program.len() {
+                return;
+            }
+            // The instruction the VM is at is always run, so `.continue` carries on from a
+            // breakpoint.
+            if step > 0 && breakpoints.contains(&pc) {
+                self.send_message(&format!(
+                    "Paused at breakpoint {}",
+                    self.describe_address(pc)
+                ));
+                return;
+            }
+            if self.watches.is_empty() {
+                self.vm.run_once();
+                continue;
+            }
+            let before = self.vm.registers.clone();
+            self.vm.run_once();
+            if self.report_watches(&before) {
+                self.send_message(&format!(
+                    "Paused at {}",
+                    self.describe_address(self.vm.pc())
+                ));
+                return;
+            }
+        }
+        self.send_error("Stopped after running too many instructions");
+    }
+
+    /// Lists the breakpoints, or sets or clears one at an address or label, e.g. `.breakpoint @loop`
+    /// or `.breakpoint clear 72`.
+    fn breakpoint(&mut self, args: &[String]) -> Outcome {
+        let (clear, target) = match args {
+            [] => {
+                self.send_message("Listing breakpoints:");
+                let breakpoints: Vec<usize> = self.vm.breakpoints().collect();
+                for address in breakpoints {
+                    self.send_message(&self.describe_address(address));
+                }
+                self.send_message("End of Breakpoint Listing");
+                return Outcome::Done;
+            }
+            [target] => (false, target),
+            [clear, target] if clear == "clear" => (true, target),
+            _ => return Outcome::Usage,
+        };
+        let address = match self.resolve_address(target) {
+            Some(address) => address,
+            None => {
+                self.send_error(&format!("Unknown address or label: {}", target));
+                return Outcome::Done;
+            }
+        };
+        let described = self.describe_address(address);
+        if !clear {
+            self.vm.set_breakpoint(address);
+            self.send_message(&format!("Breakpoint set at {}", described));
+        } else if self.vm.clear_breakpoint(address) {
+            self.send_message(&format!("Breakpoint cleared at {}", described));
+        } else {
+            self.send_error(&format!("There is no breakpoint at {}", described));
+        }
+        Outcome::Done
+    }
+
+    /// Lists the watched registers, or watches or stops watching one, e.g. `.watch $3` or
+    /// `.watch clear $3`.
+    fn watch(&mut self, args: &[String]) -> Outcome {
+        let (clear, target) = match args {
+            [] => {
+                self.send_message("Listing watched registers:");
+                for register in &self.watches {
+                    self.send_message(&format!("${}: {}", register, self.vm.registers[*register]));
                 }
+                self.send_message("End of Watch Listing");
+                return Outcome::Done;
+            }
+            [target] => (false, target),
+            [clear, target] if clear == "clear" => (true, target),
+            _ => return Outcome::Usage,
+        };
+        let register = match target
+            .strip_prefix('$')
+            .and_then(|r| r.parse::<usize>().ok())
+        {
+            Some(register) if register < self.vm.registers.len() => register,
+            _ => {
+                self.send_error(&format!("Not a register: {}", target));
+                return Outcome::Done;
             }
+        };
+        if !clear {
+            self.watches.insert(register);
+            self.send_message(&format!("Watching ${}", register));
+        } else if self.watches.remove(&register) {
+            self.send_message(&format!("Stopped watching ${}", register));
+        } else {
+            self.send_error(&format!("${} is not watched", register));
         }
+        Outcome::Done
     }
 
-    fn get_data_from_load(&mut self) -> Option<String> {
-        let stdin = io::stdin();
-        println!("Please enter the path to the file you wish to load: ");
-        io::stdout().flush().expect("Unable to flush stdout");
-        let mut tmp = String::new();
+    /// Lists the instructions of the program's code, or of the addresses from `start` up to `end`
+    /// given as `.disassemble <start> [end]`, with their bytes and the labels declared at them and
+    /// jumped to.
+    fn disassemble(&mut self, args: &[String]) -> Outcome {
+        let code = self.vm.code_range().unwrap_or(0..0);
+        let start = args
+            .first()
+            .map(|target| (target, self.resolve_address(target)));
+        let end = args
+            .get(1)
+            .map(|target| (target, self.resolve_address(target)));
+        let range = match (start, end) {
+            (None, _) => code,
+            (Some((_, Some(start))), None) => start..code.end,
+            (Some((_, Some(start))), Some((_, Some(end)))) => start..end,
+            (Some((target, None)), _) | (_, Some((target, None))) => {
+                self.send_error(&format!("Unknown address or label: {}", target));
+                return Outcome::Done;
+            }
+        };
+        let end = range.end.min(self.vm.program.len());
+        self.send_message("Disassembling instructions in VM's program:");
+        for address in (range.start..end).step_by(INSTRUCTION_LENGTH) {
+            if let Some(label) = self.label_at(address) {
+                self.send_message(&format!("{}:", label));
+            }
+            let bytes = &self.vm.program[address..(address + INSTRUCTION_LENGTH).min(end)];
+            let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let instruction = DecodedInstruction::decode(&self.vm.program, address);
+            let mut line = format!("{:>6}  {:<11}  {}", address, bytes.join(" "), instruction);
+            if let Some(label) = jump_target(&instruction).and_then(|a| self.label_at(a)) {
+                line.push_str(&format!(" ; @{}", label));
+            }
+            self.send_message(&line);
+        }
+        self.send_message("End of Disassembly");
+        Outcome::Done
+    }
 
-        stdin
-            .read_line(&mut tmp)
-            .expect("Unable to read line from user");
-        println!("Attempting to load program from file...");
+    /// Prints the heap, or the `len` bytes of it from `start` given as `.heap <start> <len>`, as
+    /// rows of hex bytes followed by the characters they spell.
+    fn heap(&mut self, args: &[String]) -> Outcome {
+        let heap = self.vm.heap();
+        let bounds: Vec<Option<usize>> = args.iter().map(|bound| bound.parse().ok()).collect();
+        let range = match bounds[..] {
+            [] => 0..heap.len(),
+            [Some(start), Some(len)] => start..start.saturating_add(len),
+            _ => return Outcome::Usage,
+        };
+        if range.end > heap.len() {
+            self.send_error(&format!("The heap is only {} bytes long", heap.len()));
+            return Outcome::Done;
+        }
+        self.send_message("Listing heap contents:");
+        for row in hexdump(range.start, &heap[range]) {
+            self.send_message(&row);
+        }
+        self.send_message("End of Heap Listing");
+        Outcome::Done
+    }
 
-        let tmp = tmp.trim();
-        let filename = Path::new(&tmp);
-        let mut f = match File::open(&filename) {
-            Ok(f) => f,
-            Err(e) => {
-                println!("There was an error opening that file: {:?}", e);
-                return None;
+    /// Lists the regions of the VM's memory, or prints the `len` bytes from `start` given as
+    /// `.memory <start> <len>` as a hex dump of each region they fall in. The heap's addresses
+    /// follow the program's.
+    fn memory(&mut self, args: &[String]) -> Outcome {
+        let regions = self.vm.memory_regions();
+        let range = match args {
+            [] => {
+                self.send_message("Listing regions of the VM's memory:");
+                for (region, range) in regions {
+                    self.send_message(&format!("{:>6}..{:<6}  {}", range.start, range.end, region));
+                }
+                self.send_message("End of Memory Region Listing");
+                return Outcome::Done;
+            }
+            [start, len] => match (self.resolve_address(start), len.parse::<usize>()) {
+                (Some(start), Ok(len)) => start..start.saturating_add(len),
+                _ => return Outcome::Usage,
+            },
+            _ => return Outcome::Usage,
+        };
+        let size = regions.last().map_or(0, |(_, range)| range.end);
+        if range.end > size {
+            self.send_error(&format!("The VM's memory is only {} bytes long", size));
+            return Outcome::Done;
+        }
+        self.send_message("Listing memory contents:");
+        for (region, bounds) in regions {
+            let start = range.start.max(bounds.start);
+            let end = range.end.min(bounds.end);
+            if start >= end {
+                continue;
+            }
+            self.send_message(&format!("{} ({}..{}):", region, start, end));
+            for row in hexdump(start, self.vm.memory(start..end).unwrap_or_default()) {
+                self.send_message(&row);
+            }
+        }
+        self.send_message("End of Memory Listing");
+        Outcome::Done
+    }
+
+    /// Returns the address `target` names: a label declared in the REPL, such as `@loop`, or a
+    /// number.
+    fn resolve_address(&self, target: &str) -> Option<usize> {
+        match target.strip_prefix('@') {
+            Some(label) => self
+                .asm
+                .symbols
+                .symbol_value(label)
+                .map(|address| address as usize),
+            None => target.parse().ok(),
+        }
+    }
+
+    /// Returns `address` along with the label declared there, if any, e.g. `72 (@loop)`.
+    fn describe_address(&self, address: usize) -> String {
+        match self.label_at(address) {
+            Some(label) => format!("{} (@{})", address, label),
+            None => address.to_string(),
+        }
+    }
+
+    /// Returns the label declared in the REPL at `address`, if any.
+    fn label_at(&self, address: usize) -> Option<String> {
+        self.labels.lock().ok().and_then(|labels| {
+            labels
+                .iter()
+                .find(|label| self.asm.symbols.symbol_value(label) == Some(address as u32))
+                .cloned()
+        })
+    }
+
+    /// Reads the file at `path`, or at a path the user is asked for if the command was not given
+    /// one.
+    fn get_data_from_load(&mut self, path: Option<&str>) -> Option<String> {
+        let tmp = match path {
+            Some(path) => path.to_string(),
+            None => {
+                self.send_message("Please enter the path to the file you wish to load: ");
+                self.read_line()?
             }
         };
-        let mut contents = String::new();
-        match f.read_to_string(&mut contents) {
-            Ok(_bytes_read) => Some(contents),
+        self.send_message("Attempting to load program from file...");
+
+        let tmp = tmp.trim();
+        match read_source(Path::new(&tmp)) {
+            Ok(contents) => Some(contents),
             Err(e) => {
-                println!("there was an error reading that file: {:?}", e);
+                self.send_error(&format!("There was an error loading that file: {}", e));
                 None
             }
         }
     }
 
+    /// Writes a line of output to the user.
+    fn send_message(&self, msg: &str) {
+        self.write_line(&paint(msg, Style::Info, self.color));
+    }
+
+    /// Writes a line telling the user something went wrong.
+    fn send_error(&self, msg: &str) {
+        self.write_line(&paint(msg, Style::Error, self.color));
+    }
+
+    /// Writes a line of output that is already styled.
+    fn write_line(&self, line: &str) {
+        if let Ok(mut output) = self.output.lock() {
+            output.write_line(line);
+        }
+    }
+
+    /// Shows the prompt and reads the user's next command. Returns `None` once input is
+    /// exhausted.
+    fn read_command(&self) -> Option<String> {
+        self.input
+            .lock()
+            .ok()?
+            .read_line_after_prompt(if self.hex_mode { "hex> " } else { ">>> " }, &self.output)
+    }
+
+    /// Reads a line of input from the user. Returns `None` once input is exhausted.
+    fn read_line(&self) -> Option<String> {
+        self.input.lock().ok()?.read_line()
+    }
+
     /// Accepts a hexadecimal string *without* a leading `0x` and returns a `Vec<u8>`.
     /// Example for a LOAD command: `01 01 03 E8`.
-    #[allow(dead_code)]
-    fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
-        let split = i.split(" ").collect::<Vec<&str>>();
+    fn parse_hex(&self, i: &str) -> Result<Vec<u8>, ParseIntError> {
+        let split = i.split_whitespace().collect::<Vec<&str>>();
         let mut results: Vec<u8> = vec![];
         for hex_string in split {
-            let byte = u8::from_str_radix(&hex_string, 16);
+            let byte = u8::from_str_radix(hex_string, 16);
             match byte {
                 Ok(result) => {
                     results.push(result);
@@ -178,3 +853,605 @@ impl REPL {
         Ok(results)
     }
 }
+
+/// Returns where the startup script is: `.iridiumrc` in the user's home directory.
+fn startup_script_path() -> Option<PathBuf> {
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    Some(Path::new(&home).join(".iridiumrc"))
+}
+
+/// Returns an assembler for the lines entered at the REPL.
+fn incremental_assembler() -> Assembler {
+    let mut asm = Assembler::new();
+    asm.set_incremental(true);
+    asm
+}
+
+/// Returns the address an instruction jumps to, if it is a jump to an address rather than to a
+/// register.
+fn jump_target(instruction: &DecodedInstruction) -> Option<usize> {
+    match instruction.opcode {
+        opcode if opcode.takes_address() => Some(instruction.address()),
+        Opcode::DJNZ => Some(instruction.immediate(1) as usize),
+        _ => None,
+    }
+}
+
+/// The number of bytes in each row of a hex dump.
+const HEXDUMP_WIDTH: usize = 16;
+
+/// Formats `bytes`, which start at address `start`, as rows of each row's address, its bytes in
+/// hex and the characters they spell, with `.` for those that are not printable.
+fn hexdump(start: usize, bytes: &[u8]) -> Vec<String> {
+    bytes
+        .chunks(HEXDUMP_WIDTH)
+        .enumerate()
+        .map(|(row, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let text: String = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!(
+                "{:>6}  {:<width$}  |{}|",
+                start + row * HEXDUMP_WIDTH,
+                hex.join(" "),
+                text,
+                width = HEXDUMP_WIDTH * 3 - 1
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::{BufferInput, BufferOutput};
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_run_with_io() {
+        let input = BufferInput {
+            lines: vec![".history".to_string(), ".quit".to_string()],
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        let output = output.lock().unwrap();
+        assert!(output.buffer.contains(">>> .history\n"));
+        assert!(output.buffer.ends_with("Farewell! Have a great day!\n"));
+    }
+
+    #[test]
+    fn test_command_arguments() {
+        let path = std::env::temp_dir().join("iridium_test_repl_load_file.iasm");
+        std::fs::write(&path, "load $2 #9\n").unwrap();
+        let path = path.to_str().unwrap();
+        let lines = [
+            format!(".load_file {}", path),
+            ".load_file".to_string(),
+            path.to_string(),
+            ".quit".to_string(),
+        ];
+        let input = BufferInput {
+            lines: lines.to_vec(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        // The path is only asked for when the command is not given one.
+        assert_eq!(
+            &repl.vm.program[PIE_HEADER_LENGTH..],
+            &[1, 2, 0, 9, 1, 2, 0, 9]
+        );
+        let output = output.lock().unwrap();
+        assert_eq!(output.buffer.matches("Please enter the path").count(), 1);
+    }
+
+    #[test]
+    fn test_step_and_continue() {
+        let path = std::env::temp_dir().join("iridium_test_repl_step.iasm");
+        std::fs::write(&path, "load $0 #7\nload $1 #2\nadd $0 $1 $2\nhlt\n").unwrap();
+        let lines = [
+            format!(".load_file {}", path.to_str().unwrap()),
+            ".step".to_string(),
+            ".step".to_string(),
+            ".continue".to_string(),
+            ".step".to_string(),
+            ".quit".to_string(),
+        ];
+        let input = BufferInput {
+            lines: lines.to_vec(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        assert_eq!(repl.vm.registers[2], 9);
+        let output = output.lock().unwrap();
+        assert!(output.buffer.contains("Loaded 4 instructions at 64\n"));
+        assert!(output.buffer.contains("64: load $0 #7\n$0: 0 -> 7\n"));
+        assert!(output.buffer.contains("68: load $1 #2\n$1: 0 -> 2\n"));
+        // Nothing is printed for the instructions `.continue` runs.
+        assert!(!output.buffer.contains("72: add"));
+        assert!(output
+            .buffer
+            .contains("There are no instructions left to run"));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let path = std::env::temp_dir().join("iridium_test_repl_breakpoint.iasm");
+        std::fs::write(
+            &path,
+            "load $0 #0\nload $1 #3\nagain: inc $0\ndjnz $1 @again\nhlt\n",
+        )
+        .unwrap();
+        let lines = [
+            format!(".load_file {}", path.to_str().unwrap()),
+            ".breakpoint @again".to_string(),
+            ".breakpoint @missing".to_string(),
+            ".breakpoint".to_string(),
+            ".continue".to_string(),
+            ".continue".to_string(),
+            ".breakpoint clear 72".to_string(),
+            ".continue".to_string(),
+            ".quit".to_string(),
+        ];
+        let input = BufferInput {
+            lines: lines.to_vec(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        assert_eq!(repl.vm.registers[0], 3);
+        assert_eq!(repl.vm.breakpoints().count(), 0);
+        let output = output.lock().unwrap();
+        assert!(output.buffer.contains("Breakpoint set at 72 (@again)\n"));
+        assert!(output
+            .buffer
+            .contains("Unknown address or label: @missing\n"));
+        assert!(output
+            .buffer
+            .contains("Listing breakpoints:\n72 (@again)\nEnd of Breakpoint Listing\n"));
+        // The first `.continue` stops before the loop, and the second after one pass of it.
+        assert_eq!(
+            output
+                .buffer
+                .matches("Paused at breakpoint 72 (@again)")
+                .count(),
+            2
+        );
+        assert!(output
+            .buffer
+            .contains("Breakpoint cleared at 72 (@again)\n"));
+    }
+
+    #[test]
+    fn test_watch() {
+        let lines = [
+            ".watch $0",
+            ".watch $99",
+            ".watch",
+            "load $1 #2",
+            "load $0 #0\nagain: inc $0\ndjnz $1 @again\nload $2 #7",
+            ".continue",
+            ".watch clear $0",
+            ".continue",
+            ".quit",
+        ];
+        let input = BufferInput {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        assert_eq!(repl.vm.registers[0], 2);
+        assert_eq!(repl.vm.registers[2], 7);
+        let output = output.lock().unwrap();
+        assert!(output.buffer.contains("Not a register: $99\n"));
+        assert!(output
+            .buffer
+            .contains("Listing watched registers:\n$0: 0\nEnd of Watch Listing\n"));
+        // Loading 0 leaves $0 as it was, so the first change is the increment.
+        assert!(output
+            .buffer
+            .contains("Watched $0 changed: 0 -> 1\nPaused at 76\n"));
+        assert!(output
+            .buffer
+            .contains("Watched $0 changed: 1 -> 2\nPaused at 76\n"));
+        assert!(output.buffer.contains("Stopped watching $0\n"));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let lines = [
+            "load $1 #2",
+            "again: inc $0\ndjnz $1 @again",
+            ".disassemble",
+            ".disassemble @again 72",
+            ".disassemble @nowhere",
+            ".quit",
+        ];
+        let input = BufferInput {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        let output = output.lock().unwrap();
+        let listing = "Disassembling instructions in VM's program:\n    \
+                       64  01 01 00 02  load $1 #2\n\
+                       again:\n    \
+                       68  12 00 00 00  inc $0\n    \
+                       72  2e 01 00 44  djnz $1 #68 ; @again\n\
+                       End of Disassembly\n";
+        assert!(output.buffer.contains(listing));
+        assert!(output.buffer.contains(
+            "Disassembling instructions in VM's program:\nagain:\n    \
+             68  12 00 00 00  inc $0\nEnd of Disassembly\n"
+        ));
+        assert!(output
+            .buffer
+            .contains("Unknown address or label: @nowhere\n"));
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let mut bytes = b"Hello, world!\n".to_vec();
+        bytes.extend_from_slice(&[0, 1, 0xff, b'x']);
+        assert_eq!(
+            hexdump(32, &bytes),
+            vec![
+                "    32  48 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 0a 00 01  |Hello, world!...|",
+                "    48  ff 78                                            |.x|",
+            ]
+        );
+        assert!(hexdump(0, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_heap() {
+        let lines = [
+            "load $0 #20",
+            "aloc $0",
+            ".heap",
+            ".heap 16 4",
+            ".heap 16 8",
+            ".heap 16",
+            ".quit",
+        ];
+        let input = BufferInput {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        let output = output.lock().unwrap();
+        let zeros = "00 ".repeat(16);
+        assert!(output.buffer.contains(&format!(
+            "Listing heap contents:\n     0  {} |{}|\n",
+            zeros,
+            ".".repeat(16)
+        )));
+        assert!(output.buffer.contains(&format!(
+            "Listing heap contents:\n    16  00 00 00 00{}  |....|\nEnd of Heap Listing\n",
+            " ".repeat(36)
+        )));
+        assert!(output.buffer.contains("The heap is only 20 bytes long\n"));
+        assert!(output.buffer.contains("Usage: .heap [start len]\n"));
+    }
+
+    #[test]
+    fn test_memory() {
+        let lines = [
+            "load $0 #4",
+            "aloc $0",
+            ".memory",
+            ".memory 68 8",
+            ".memory 70 8",
+            ".memory 70",
+            ".quit",
+        ];
+        let input = BufferInput {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        let output = output.lock().unwrap();
+        assert!(output.buffer.contains(
+            "Listing regions of the VM's memory:\n     \
+             0..64      header\n    \
+             64..72      code\n    \
+             72..76      heap\n\
+             End of Memory Region Listing\n"
+        ));
+        assert!(output
+            .buffer
+            .contains("Listing memory contents:\ncode (68..72):\n    68  11 00 00 00"));
+        assert!(output
+            .buffer
+            .contains("|....|\nheap (72..76):\n    72  00 00 00 00"));
+        assert!(output
+            .buffer
+            .contains("The VM's memory is only 76 bytes long\n"));
+        assert!(output.buffer.contains("Usage: .memory [start len]\n"));
+    }
+
+    #[test]
+    fn test_reset() {
+        let lines = [
+            "load $0 #4",
+            "again: aloc $0",
+            ".breakpoint @again",
+            ".reset",
+            ".reset some",
+            ".step",
+            ".reset all",
+            "load $1 #2",
+            ".quit",
+        ];
+        let input = BufferInput {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        // The program was run again from the start after `.reset`, then dropped by `.reset all`.
+        assert_eq!(repl.vm.registers[0], 0);
+        assert_eq!(repl.vm.registers[1], 2);
+        assert_eq!(repl.vm.heap().len(), 0);
+        assert_eq!(repl.vm.breakpoints().count(), 0);
+        assert_eq!(&repl.vm.program[PIE_HEADER_LENGTH..], &[1, 1, 0, 2]);
+        assert!(repl.labels.lock().unwrap().is_empty());
+        let output = output.lock().unwrap();
+        assert!(output.buffer.contains("64: load $0 #4\n$0: 0 -> 4\n"));
+        assert!(output.buffer.contains("Usage: .reset [all]\n"));
+        assert!(output
+            .buffer
+            .contains("VM has been reset, along with its program!\n"));
+    }
+
+    #[test]
+    fn test_registers_and_color() {
+        let lines = [
+            "load $0 #4\nload $1 #2",
+            "load $1 #3",
+            ".registers",
+            "jmpl @missing",
+            ".quit",
+        ];
+        let input = BufferInput {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.set_color(true);
+        repl.run();
+        let output = output.lock().unwrap();
+        // Only the register the last line changed is highlighted.
+        assert!(output.buffer.contains(&format!(
+            "{}\n{} {}\n{}\n",
+            paint("$0: 4", Style::Info, true),
+            paint("$1: 3", Style::Changed, true),
+            paint("(was 2)", Style::Info, true),
+            paint("$2: 0", Style::Info, true)
+        )));
+        assert!(output.buffer.contains("\x1b[1;31mUnable to assemble input"));
+    }
+
+    #[test]
+    fn test_help_and_usage() {
+        let lines = [
+            ".help",
+            ".help heap",
+            ".help .nothing",
+            ".step now",
+            ".quit",
+        ];
+        let input = BufferInput {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        let output = output.lock().unwrap();
+        for command in commands::COMMANDS {
+            assert!(output
+                .buffer
+                .contains(&format!("{:<30} {}\n", command.usage(), command.help)));
+        }
+        assert!(output.buffer.contains(&format!(
+            "Listing commands:\n{:<30} Prints the heap as a hex dump\nEnd of Command Listing\n",
+            ".heap [start len]"
+        )));
+        assert!(output.buffer.contains("Unknown command: .nothing\n"));
+        assert!(output.buffer.contains("Usage: .step\n"));
+    }
+
+    #[test]
+    fn test_spawn() {
+        let path = std::env::temp_dir().join("iridium_test_repl_spawn.iasm");
+        std::fs::write(&path, ".data\n.code\nload $0 #5\nhlt\n").unwrap();
+        let snippet = std::env::temp_dir().join("iridium_test_repl_spawn_snippet.iasm");
+        std::fs::write(&snippet, "load $0 #5\nhlt\n").unwrap();
+        let lines = [
+            format!(".spawn {}", path.to_str().unwrap()),
+            format!(".spawn {}", snippet.to_str().unwrap()),
+            ".quit".to_string(),
+        ];
+        let input = BufferInput {
+            lines: lines.to_vec(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        assert_eq!(repl.scheduler.lock().unwrap().pids(), vec![0, 1]);
+        repl.scheduler.lock().unwrap().wait(1).unwrap();
+        assert_eq!(repl.scheduler.lock().unwrap().registers(1).unwrap()[0], 5);
+        let output = output.lock().unwrap();
+        assert!(output.buffer.contains("Spawned process 0\n"));
+        assert!(output.buffer.contains("Spawned process 1\n"));
+    }
+
+    #[test]
+    fn test_ps() {
+        let path = std::env::temp_dir().join("iridium_test_repl_ps.iasm");
+        std::fs::write(&path, ".data\n.code\nload $0 #5\nhlt\n").unwrap();
+        let input = BufferInput {
+            lines: vec![format!(".spawn {}", path.to_str().unwrap())],
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        repl.scheduler.lock().unwrap().wait(0).unwrap();
+        repl.list_processes(&[]);
+        let output = output.lock().unwrap();
+        let started = repl.scheduler.lock().unwrap().processes()[0].started;
+        assert!(output.buffer.contains(&format!(
+            "Listing processes:\n  PID  STATE     STARTED              INSTRUCTIONS\n    \
+             0  finished  {}             2\nEnd of Process Listing\n",
+            started.format("%Y-%m-%d %H:%M:%S")
+        )));
+    }
+
+    #[test]
+    fn test_kill() {
+        let path = std::env::temp_dir().join("iridium_test_repl_kill.iasm");
+        std::fs::write(&path, ".data\n.code\nload $0 #64\njmp $0\n").unwrap();
+        let lines = [
+            format!(".spawn {}", path.to_str().unwrap()),
+            ".kill 0".to_string(),
+            ".kill 0".to_string(),
+            ".kill 7".to_string(),
+            ".kill zero".to_string(),
+        ];
+        let input = BufferInput {
+            lines: lines.to_vec(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        let output = output.lock().unwrap();
+        assert!(output.buffer.contains("Process 0: killed\n"));
+        assert!(output.buffer.contains("Process 0 has already stopped\n"));
+        assert!(output.buffer.contains("There is no process 7\n"));
+        assert!(output.buffer.contains("Usage: .kill <pid>\n"));
+    }
+
+    #[test]
+    fn test_join() {
+        let path = std::env::temp_dir().join("iridium_test_repl_join.iasm");
+        std::fs::write(&path, ".data\n.code\nload $1 #5\nload $2 #3\nexit $2\n").unwrap();
+        let lines = [
+            format!(".spawn {}", path.to_str().unwrap()),
+            ".join 0".to_string(),
+            ".join 7".to_string(),
+        ];
+        let input = BufferInput {
+            lines: lines.to_vec(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        let output = output.lock().unwrap();
+        assert!(output.buffer.contains("Listing events of process 0:\n"));
+        assert!(output.buffer.contains(" started\n"));
+        assert!(output.buffer.contains(" stopped with exit code 3\n"));
+        assert!(output
+            .buffer
+            .contains("Listing its registers:\n$0: 0\n$1: 5\n$2: 3\n"));
+        assert!(output
+            .buffer
+            .contains("$31: 0\nExit code: 3\nEnd of Process Listing\n"));
+        assert!(output.buffer.contains("There is no process 7\n"));
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        let repl = REPL::with_io(
+            Arc::new(Mutex::new(BufferInput::default())),
+            Arc::new(Mutex::new(BufferOutput::default())),
+        );
+        assert_eq!(repl.parse_hex("01 01  03 E8"), Ok(vec![1, 1, 3, 0xe8]));
+        assert!(repl.parse_hex("01 0G").is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        let lines = [
+            ".hex 01 00 00 07",
+            ".hex",
+            "01 01 00 02",
+            "again: inc $0",
+            "01 02",
+            ".hex",
+            "again: inc $0",
+            "djnz $1 @again",
+            ".quit",
+        ];
+        let input = BufferInput {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        // The label after the bytes entered in hex is placed after them.
+        assert_eq!(repl.vm.registers[0], 9);
+        assert_eq!(repl.asm.symbols.symbol_value("again"), Some(72));
+        let output = output.lock().unwrap();
+        assert!(output.buffer.contains("hex> "));
+        assert!(output
+            .buffer
+            .contains("Unable to parse hex bytes: invalid digit found in string\n"));
+        assert!(output
+            .buffer
+            .contains("Instructions are 4 bytes long, but 2 bytes were given\n"));
+        assert!(output.buffer.contains("Leaving hex mode\n"));
+    }
+
+    #[test]
+    fn test_run_script() {
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(BufferInput::default())), output.clone());
+        let script =
+            "# Counts to 2\nload $1 #2\n\nagain: inc $0\ndjnz $1 @again\n.quit\nload $2 #1\n";
+        assert!(repl.run_script(script));
+        assert_eq!(repl.vm.registers[0], 2);
+        // Nothing after `.quit` is run.
+        assert_eq!(repl.vm.registers[2], 0);
+        assert!(!repl.run_script(".registers"));
+        assert!(output.lock().unwrap().buffer.contains("$0: 2 (was 1)\n"));
+    }
+
+    #[test]
+    fn test_labels_across_lines() {
+        let lines = [
+            "load $1 #3",
+            "load $0 #0",
+            "again: inc $0",
+            "djnz $1 @again",
+            "jmpl @missing",
+            ".registers",
+            ".quit",
+        ];
+        let input = BufferInput {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        };
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut repl = REPL::with_io(Arc::new(Mutex::new(input)), output.clone());
+        repl.run();
+        assert_eq!(repl.vm.registers[0], 3);
+        assert_eq!(repl.vm.registers[1], 0);
+        let output = output.lock().unwrap();
+        assert!(output
+            .buffer
+            .contains("A label is used but never declared. Label was: missing"));
+    }
+}
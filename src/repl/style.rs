@@ -0,0 +1,46 @@
+/// How a piece of the REPL's output is shown on a terminal that supports color. The VM's own
+/// output is left as it is, so it stands apart from what the REPL says about it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Style {
+    /// What the REPL reports, such as listings and the results of commands.
+    Info,
+    /// Commands or code that could not be run, and why.
+    Error,
+    /// Values that changed, e.g. registers written by the last instruction run.
+    Changed,
+}
+
+impl Style {
+    /// Returns the ANSI escape sequence that starts the style.
+    fn code(self) -> &'static str {
+        match self {
+            Style::Info => "\x1b[36m",
+            Style::Error => "\x1b[1;31m",
+            Style::Changed => "\x1b[1;33m",
+        }
+    }
+}
+
+/// The ANSI escape sequence that ends a style.
+const RESET: &str = "\x1b[0m";
+
+/// Returns `text` in `style` if `color` is set, and as it is otherwise.
+pub fn paint(text: &str, style: Style, color: bool) -> String {
+    if color && !text.is_empty() {
+        format!("{}{}{}", style.code(), text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paint() {
+        assert_eq!(paint("oops", Style::Error, true), "\x1b[1;31moops\x1b[0m");
+        assert_eq!(paint("oops", Style::Error, false), "oops");
+        assert_eq!(paint("", Style::Info, true), "");
+    }
+}
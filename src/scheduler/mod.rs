@@ -1,10 +1,117 @@
-use crate::vm::{VMEvent, VM};
+use crate::console::BufferOutput;
+use crate::vm::{VMEvent, VMEventType, VM};
+use chrono::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
+
+/// How many finished processes a scheduler keeps for inspection unless told otherwise. Older ones
+/// are dropped, VM and all, as new ones are spawned.
+pub const DEFAULT_RETAINED_PROCESSES: usize = 1000;
+
+/// How often `join` checks whether a process has finished.
+const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A scheduler shared by the parts of a node that run programs, such as its REPL and its APIs, so
+/// each of them sees the processes spawned through the others.
+pub type SharedScheduler = Arc<Mutex<Scheduler>>;
+
+/// What a spawned VM is doing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProcessState {
+    Running,
+    /// The program stopped by itself, with `HLT` or `EXIT`.
+    Finished,
+    /// The program crashed, or its header was invalid.
+    Crashed,
+    /// The VM was stopped with `kill`, or ran out of instructions.
+    Killed,
+}
+
+impl fmt::Display for ProcessState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProcessState::Running => f.write_str("running"),
+            ProcessState::Finished => f.write_str("finished"),
+            ProcessState::Crashed => f.write_str("crashed"),
+            ProcessState::Killed => f.write_str("killed"),
+        }
+    }
+}
+
+/// A row of the process table, as returned by `Scheduler::processes`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub state: ProcessState,
+    /// When the process was spawned.
+    pub started: DateTime<Utc>,
+    /// How many instructions it has executed so far.
+    pub instructions: u64,
+}
+
+/// A VM spawned by the scheduler.
+struct Process {
+    /// Stops the VM when set.
+    stop: Arc<AtomicBool>,
+    /// Everything the VM has printed so far.
+    output: Arc<Mutex<BufferOutput>>,
+    /// When the VM was spawned.
+    started: DateTime<Utc>,
+    /// How many instructions the VM has executed.
+    executed: Arc<AtomicU64>,
+    /// The VM's thread, until it has been joined.
+    handle: Option<thread::JoinHandle<(VM, Vec<VMEvent>)>>,
+    /// The VM and the events it produced, once its thread has been joined.
+    finished: Option<(VM, Vec<VMEvent>)>,
+}
+
+impl Process {
+    /// Collects the VM if its thread has finished.
+    fn reap(&mut self) {
+        if self.handle.as_ref().is_some_and(|h| h.is_finished()) {
+            self.join();
+        }
+    }
+
+    /// Waits for the VM's thread to finish.
+    fn join(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.finished = handle.join().ok();
+        }
+    }
+
+    /// Returns what the VM is doing, collecting it first if it has finished.
+    fn state(&mut self) -> ProcessState {
+        self.reap();
+        if self.handle.is_some() {
+            return ProcessState::Running;
+        }
+        let last = self.finished.as_ref().and_then(|(_, events)| events.last());
+        match last.map(|event| &event.event) {
+            Some(VMEventType::GracefulStop { .. }) => ProcessState::Finished,
+            Some(VMEventType::Killed) | Some(VMEventType::BudgetExceeded) => ProcessState::Killed,
+            // A thread that panicked has no events.
+            _ => ProcessState::Crashed,
+        }
+    }
+}
 
-#[derive(Default)]
 pub struct Scheduler {
     next_pid: u32,
     max_pid: u32,
+    /// The most finished processes kept in the table.
+    retained: usize,
+    processes: BTreeMap<u32, Process>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Scheduler {
@@ -12,10 +119,313 @@ impl Scheduler {
         Self {
             next_pid: 0,
             max_pid: 50000,
+            retained: DEFAULT_RETAINED_PROCESSES,
+            processes: BTreeMap::new(),
         }
     }
 
+    /// Sets how many finished processes are kept in the table, `DEFAULT_RETAINED_PROCESSES` unless
+    /// set. When another is spawned, the oldest of the finished ones are dropped.
+    pub fn set_retained(&mut self, retained: usize) {
+        self.retained = retained;
+    }
+
     pub fn get_thread(&self, mut vm: VM) -> thread::JoinHandle<Vec<VMEvent>> {
         thread::spawn(move || vm.run())
     }
+
+    /// Runs `vm` on its own thread and tracks it in the process table, capturing its output.
+    /// Returns the new process's pid, or `None` if every pid up to `max_pid` is taken.
+    pub fn spawn(&mut self, vm: VM) -> Option<u32> {
+        self.start(vm, true)
+    }
+
+    /// Runs `vm` like `spawn`, but leaves it writing to the output it was given instead of
+    /// capturing it, e.g. so a REPL user sees what it prints as it runs.
+    pub fn spawn_with_output(&mut self, vm: VM) -> Option<u32> {
+        self.start(vm, false)
+    }
+
+    fn start(&mut self, mut vm: VM, capture: bool) -> Option<u32> {
+        self.evict();
+        if self.processes.len() as u32 >= self.max_pid {
+            return None;
+        }
+        while self.processes.contains_key(&self.next_pid) {
+            self.next_pid = (self.next_pid + 1) % self.max_pid;
+        }
+        let pid = self.next_pid;
+        self.next_pid = (self.next_pid + 1) % self.max_pid;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        vm.set_stop_handle(stop.clone());
+        let executed = vm.instruction_counter();
+        if capture {
+            vm.set_output(output.clone());
+        }
+        let handle = thread::spawn(move || {
+            let _entered = tracing::info_span!("process", pid).entered();
+            let events = vm.run();
+            (vm, events)
+        });
+        self.processes.insert(
+            pid,
+            Process {
+                stop,
+                output,
+                started: Utc::now(),
+                executed,
+                handle: Some(handle),
+                finished: None,
+            },
+        );
+        Some(pid)
+    }
+
+    /// Drops the oldest finished processes, so at most `retained` of them are kept.
+    fn evict(&mut self) {
+        let mut finished: Vec<(DateTime<Utc>, u32)> = self
+            .processes
+            .iter_mut()
+            .filter_map(|(&pid, process)| {
+                process.reap();
+                match process.handle {
+                    Some(_) => None,
+                    None => Some((process.started, pid)),
+                }
+            })
+            .collect();
+        finished.sort();
+        let excess = finished.len().saturating_sub(self.retained);
+        for (_, pid) in &finished[..excess] {
+            self.processes.remove(pid);
+        }
+    }
+
+    /// Drops a finished process from the table, with its VM, output and events. Returns `false`
+    /// if it is still running or there is no such process.
+    pub fn remove(&mut self, pid: u32) -> bool {
+        match self.is_running(pid) {
+            Some(false) => self.processes.remove(&pid).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Returns the pids of all tracked processes, in ascending order.
+    pub fn pids(&self) -> Vec<u32> {
+        self.processes.keys().copied().collect()
+    }
+
+    /// Returns the process table: each tracked process with its state, start time and the number
+    /// of instructions it has executed, in ascending order of pid.
+    pub fn processes(&mut self) -> Vec<ProcessInfo> {
+        self.processes
+            .iter_mut()
+            .map(|(&pid, process)| ProcessInfo {
+                pid,
+                state: process.state(),
+                started: process.started,
+                instructions: process.executed.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Returns whether the process is still running, or `None` if there is no such process.
+    pub fn is_running(&mut self, pid: u32) -> Option<bool> {
+        let process = self.processes.get_mut(&pid)?;
+        process.reap();
+        Some(process.handle.is_some())
+    }
+
+    /// Returns the events of a finished process. They are empty while it is still running.
+    pub fn events(&mut self, pid: u32) -> Option<Vec<VMEvent>> {
+        let process = self.processes.get_mut(&pid)?;
+        process.reap();
+        Some(events_of(process))
+    }
+
+    /// Returns everything the process has printed so far, or nothing if it was spawned with
+    /// `spawn_with_output`.
+    pub fn output(&self, pid: u32) -> Option<String> {
+        let process = self.processes.get(&pid)?;
+        let output = process.output.lock().unwrap();
+        Some(output.buffer.clone())
+    }
+
+    /// Returns the registers of a finished process, or `None` if it is still running or there is
+    /// no such process.
+    pub fn registers(&mut self, pid: u32) -> Option<Vec<i32>> {
+        let process = self.processes.get_mut(&pid)?;
+        process.reap();
+        process
+            .finished
+            .as_ref()
+            .map(|(vm, _)| vm.registers.clone())
+    }
+
+    /// Returns the exit code of a finished process, or `None` if it is still running, was killed,
+    /// or there is no such process.
+    pub fn exit_code(&mut self, pid: u32) -> Option<i32> {
+        let process = self.processes.get_mut(&pid)?;
+        process.reap();
+        process.finished.as_ref()?.0.exit_code()
+    }
+
+    /// Asks a process to stop before its next instruction. Returns `false` if there is no such
+    /// process.
+    pub fn kill(&self, pid: u32) -> bool {
+        match self.processes.get(&pid) {
+            Some(process) => {
+                process.stop.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Waits for a process to finish and returns its events.
+    pub fn wait(&mut self, pid: u32) -> Option<Vec<VMEvent>> {
+        let process = self.processes.get_mut(&pid)?;
+        process.join();
+        Some(events_of(process))
+    }
+}
+
+/// Waits for a process to finish and returns its events like `Scheduler::wait`, but only locks the
+/// scheduler to check on the process, so the others sharing it are not blocked in the meantime.
+pub fn join(scheduler: &Mutex<Scheduler>, pid: u32) -> Option<Vec<VMEvent>> {
+    while scheduler.lock().unwrap().is_running(pid)? {
+        thread::sleep(JOIN_POLL_INTERVAL);
+    }
+    scheduler.lock().unwrap().wait(pid)
+}
+
+fn events_of(process: &Process) -> Vec<VMEvent> {
+    process
+        .finished
+        .as_ref()
+        .map(|(_, events)| events.clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VMEventType;
+
+    fn program(source: &str) -> VM {
+        let mut vm = VM::new();
+        vm.add_bytes(Assembler::new().assemble(source).unwrap());
+        vm
+    }
+
+    #[test]
+    fn test_spawn_and_wait() {
+        let mut scheduler = Scheduler::new();
+        let pid = scheduler.spawn(program(".data\n.code\nhlt")).unwrap();
+        assert_eq!(scheduler.pids(), vec![pid]);
+        let events = scheduler.wait(pid).unwrap();
+        assert_eq!(
+            events.last().unwrap().event,
+            VMEventType::GracefulStop { code: 0 }
+        );
+        assert_eq!(scheduler.is_running(pid), Some(false));
+        assert_eq!(scheduler.output(pid).unwrap(), "HLT encountered\n");
+        assert!(scheduler.registers(pid).is_some());
+        assert_eq!(scheduler.exit_code(pid), Some(0));
+        assert_eq!(scheduler.events(pid + 1), None);
+    }
+
+    #[test]
+    fn test_spawn_with_output() {
+        let mut scheduler = Scheduler::new();
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut vm = program(".data\n.code\nhlt");
+        vm.set_output(output.clone());
+        let pid = scheduler.spawn_with_output(vm).unwrap();
+        scheduler.wait(pid).unwrap();
+        assert_eq!(output.lock().unwrap().buffer, "HLT encountered\n");
+        assert_eq!(scheduler.output(pid).unwrap(), "");
+    }
+
+    #[test]
+    fn test_processes() {
+        let mut scheduler = Scheduler::new();
+        let finished = scheduler
+            .spawn(program(".data\n.code\nload $0 #1\nhlt"))
+            .unwrap();
+        let crashed = scheduler
+            .spawn(program(".data\n.code\nload $0 #0\ndiv $1 $0 $2"))
+            .unwrap();
+        let running = scheduler
+            .spawn(program(".data\n.code\nload $0 #64\njmp $0"))
+            .unwrap();
+        scheduler.wait(finished);
+        scheduler.wait(crashed);
+        let processes = scheduler.processes();
+        assert_eq!(processes.len(), 3);
+        assert_eq!(processes[0].state, ProcessState::Finished);
+        assert_eq!(processes[0].instructions, 2);
+        assert_eq!(processes[1].state, ProcessState::Crashed);
+        assert_eq!(processes[2].state, ProcessState::Running);
+        assert!(processes[0].started <= processes[2].started);
+
+        scheduler.kill(running);
+        scheduler.wait(running);
+        assert_eq!(scheduler.processes()[2].state, ProcessState::Killed);
+    }
+
+    #[test]
+    fn test_finished_processes_are_dropped() {
+        let mut scheduler = Scheduler::default();
+        scheduler.set_retained(2);
+        let running = scheduler
+            .spawn(program(".data\n.code\nload $0 #64\njmp $0"))
+            .unwrap();
+        for _ in 0..4 {
+            let pid = scheduler.spawn(program(".data\n.code\nhlt")).unwrap();
+            scheduler.wait(pid);
+        }
+        // Spawning the fifth drops the oldest finished ones, but not the running one.
+        let pid = scheduler.spawn(program(".data\n.code\nhlt")).unwrap();
+        assert_eq!(scheduler.pids(), vec![running, 3, 4, pid]);
+        assert!(!scheduler.remove(running));
+        scheduler.wait(pid);
+        assert!(scheduler.remove(pid));
+        assert_eq!(scheduler.events(pid), None);
+        scheduler.kill(running);
+        scheduler.wait(running);
+    }
+
+    #[test]
+    fn test_join() {
+        let scheduler = Mutex::new(Scheduler::new());
+        let pid = scheduler
+            .lock()
+            .unwrap()
+            .spawn(program(
+                ".data\n.code\nload $0 #1000\nloop: djnz $0 @loop\nhlt",
+            ))
+            .unwrap();
+        let events = join(&scheduler, pid).unwrap();
+        assert_eq!(
+            events.last().unwrap().event,
+            VMEventType::GracefulStop { code: 0 }
+        );
+        assert_eq!(join(&scheduler, pid + 1), None);
+    }
+
+    #[test]
+    fn test_kill() {
+        let mut scheduler = Scheduler::new();
+        let pid = scheduler
+            .spawn(program(".data\n.code\nload $0 #64\njmp $0"))
+            .unwrap();
+        assert!(scheduler.kill(pid));
+        let events = scheduler.wait(pid).unwrap();
+        assert_eq!(events.last().unwrap().event, VMEventType::Killed);
+        assert!(!scheduler.kill(pid + 1));
+    }
 }
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// A segment of heap memory that several VMs can share, e.g. the VMs spawned from one REPL. It is
+/// addressed by word rather than by byte, and is only accessed with atomic operations so that VMs
+/// running on different threads can coordinate through it.
+pub struct SharedHeap {
+    words: Vec<AtomicI32>,
+}
+
+impl SharedHeap {
+    /// Returns a segment of `words` words, all zero.
+    pub fn new(words: usize) -> SharedHeap {
+        SharedHeap {
+            words: (0..words).map(|_| AtomicI32::new(0)).collect(),
+        }
+    }
+
+    /// Returns the number of words in the segment.
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    /// Returns whether the segment has no words.
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Returns the word at `index`.
+    pub fn load(&self, index: usize) -> Option<i32> {
+        self.words
+            .get(index)
+            .map(|word| word.load(Ordering::SeqCst))
+    }
+
+    /// Adds `value` to the word at `index`, wrapping on overflow, and returns its previous value.
+    pub fn fetch_add(&self, index: usize, value: i32) -> Option<i32> {
+        self.words
+            .get(index)
+            .map(|word| word.fetch_add(value, Ordering::SeqCst))
+    }
+
+    /// Stores `new` in the word at `index` if it holds `current`. Returns the previous value,
+    /// which equals `current` if and only if the swap happened.
+    pub fn compare_and_swap(&self, index: usize, current: i32, new: i32) -> Option<i32> {
+        self.words.get(index).map(|word| {
+            match word.compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(previous) | Err(previous) => previous,
+            }
+        })
+    }
+}
+
+impl Default for SharedHeap {
+    fn default() -> Self {
+        SharedHeap::new(0)
+    }
+}
+
+impl fmt::Debug for SharedHeap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedHeap")
+            .field("words", &self.words.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_atomic_operations() {
+        let heap = SharedHeap::new(2);
+        assert_eq!(heap.fetch_add(0, 5), Some(0));
+        assert_eq!(heap.compare_and_swap(0, 4, 9), Some(5));
+        assert_eq!(heap.compare_and_swap(0, 5, 9), Some(5));
+        assert_eq!(heap.load(0), Some(9));
+        assert_eq!(heap.fetch_add(2, 1), None);
+    }
+
+    #[test]
+    fn test_concurrent_fetch_add() {
+        let heap = Arc::new(SharedHeap::new(1));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let heap = heap.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        heap.fetch_add(0, 1);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(heap.load(0), Some(4000));
+    }
+}
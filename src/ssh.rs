@@ -0,0 +1,260 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use russh::keys::{self, PublicKey};
+use russh::server::{Auth, Config, Msg, Server as _, Session};
+use russh::{Channel, ChannelId, CryptoVec, Pty};
+use tokio::sync::mpsc as async_mpsc;
+
+use crate::cluster::Cluster;
+use crate::console::{Input, Output};
+use crate::repl::REPL;
+use crate::scheduler::SharedScheduler;
+
+/// Feeds a REPL the lines an SSH client types.
+struct ChannelInput {
+    lines: mpsc::Receiver<String>,
+}
+
+impl Input for ChannelInput {
+    fn read_line(&mut self) -> Option<String> {
+        self.lines.recv().ok()
+    }
+}
+
+/// Forwards REPL output to an SSH client.
+struct ChannelOutput {
+    sender: async_mpsc::UnboundedSender<String>,
+}
+
+impl Output for ChannelOutput {
+    fn write_str(&mut self, s: &str) {
+        // The client's terminal is in raw mode, so bare newlines need a carriage return.
+        self.sender.send(s.replace('\n', "\r\n")).ok();
+    }
+}
+
+/// Serves REPL sessions to SSH clients holding one of the authorized keys.
+#[derive(Clone)]
+pub struct SshServer {
+    authorized_keys: Arc<Vec<PublicKey>>,
+    scheduler: SharedScheduler,
+    cluster: Option<Arc<Cluster>>,
+}
+
+/// The state of a single client connection.
+pub struct SshSession {
+    authorized_keys: Arc<Vec<PublicKey>>,
+    /// The node's scheduler, which the session's REPL spawns VMs with.
+    scheduler: SharedScheduler,
+    cluster: Option<Arc<Cluster>>,
+    /// The characters typed since the last newline.
+    line: Vec<u8>,
+    /// Sends complete lines to the session's REPL, once a shell has been requested.
+    lines: Option<mpsc::Sender<String>>,
+}
+
+impl SshServer {
+    /// Returns a new `SshServer` that accepts the given keys, and whose REPLs spawn VMs with
+    /// `scheduler`.
+    pub fn new(
+        authorized_keys: Vec<PublicKey>,
+        scheduler: SharedScheduler,
+        cluster: Option<Arc<Cluster>>,
+    ) -> SshServer {
+        SshServer {
+            authorized_keys: Arc::new(authorized_keys),
+            scheduler,
+            cluster,
+        }
+    }
+
+    /// Serves clients on `addr` until the listener fails, blocking the calling thread.
+    pub fn serve(mut self, addr: &str, host_key: &Path) -> io::Result<()> {
+        let host_key = keys::load_secret_key(host_key, None).map_err(to_io_error)?;
+        let config = Arc::new(Config {
+            keys: vec![host_key],
+            ..Default::default()
+        });
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(self.run_on_address(config, addr))
+    }
+}
+
+impl russh::server::Server for SshServer {
+    type Handler = SshSession;
+
+    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> SshSession {
+        SshSession {
+            authorized_keys: self.authorized_keys.clone(),
+            scheduler: self.scheduler.clone(),
+            cluster: self.cluster.clone(),
+            line: vec![],
+            lines: None,
+        }
+    }
+}
+
+impl SshSession {
+    fn auth(&self, key: &PublicKey) -> Auth {
+        if is_authorized(&self.authorized_keys, key) {
+            Auth::Accept
+        } else {
+            Auth::reject()
+        }
+    }
+}
+
+impl russh::server::Handler for SshSession {
+    type Error = russh::Error;
+
+    async fn auth_publickey_offered(
+        &mut self,
+        _user: &str,
+        key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        Ok(self.auth(key))
+    }
+
+    async fn auth_publickey(&mut self, _user: &str, key: &PublicKey) -> Result<Auth, Self::Error> {
+        Ok(self.auth(key))
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        _channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    async fn pty_request(
+        &mut self,
+        channel: ChannelId,
+        _term: &str,
+        _col_width: u32,
+        _row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _modes: &[(Pty, u32)],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.channel_success(channel)
+    }
+
+    async fn shell_request(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let (line_sender, line_receiver) = mpsc::channel();
+        let (output_sender, mut output_receiver) = async_mpsc::unbounded_channel();
+        self.lines = Some(line_sender);
+
+        let handle = session.handle();
+        tokio::spawn(async move {
+            while let Some(text) = output_receiver.recv().await {
+                if handle.data(channel, CryptoVec::from(text)).await.is_err() {
+                    break;
+                }
+            }
+            handle.close(channel).await.ok();
+        });
+
+        let scheduler = self.scheduler.clone();
+        let cluster = self.cluster.clone();
+        thread::spawn(move || {
+            let input = Arc::new(Mutex::new(ChannelInput {
+                lines: line_receiver,
+            }));
+            let output = Arc::new(Mutex::new(ChannelOutput {
+                sender: output_sender,
+            }));
+            let mut repl = REPL::with_io(input, output);
+            repl.set_scheduler(scheduler);
+            if let Some(cluster) = cluster {
+                repl.set_cluster(cluster);
+            }
+            repl.run();
+        });
+        session.channel_success(channel)
+    }
+
+    async fn data(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        for byte in data {
+            match byte {
+                b'\r' | b'\n' => {
+                    session.data(channel, CryptoVec::from("\r\n"))?;
+                    let line = String::from_utf8_lossy(&self.line).to_string();
+                    self.line.clear();
+                    if let Some(ref lines) = self.lines {
+                        lines.send(line).ok();
+                    }
+                }
+                // Backspace and delete.
+                8 | 127 => {
+                    if self.line.pop().is_some() {
+                        session.data(channel, CryptoVec::from("\x08 \x08"))?;
+                    }
+                }
+                // Ctrl-C and Ctrl-D end the session.
+                3 | 4 => {
+                    self.lines = None;
+                }
+                _ => {
+                    self.line.push(*byte);
+                    session.data(channel, CryptoVec::from_slice(&[*byte]))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads an OpenSSH `authorized_keys` style file, skipping blank lines and comments.
+pub fn read_authorized_keys(path: &Path) -> io::Result<Vec<PublicKey>> {
+    let contents = fs::read_to_string(path)?;
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| PublicKey::from_openssh(line).map_err(to_io_error))
+        .collect()
+}
+
+/// Checks whether `key` is one of the authorized keys.
+fn is_authorized(authorized_keys: &[PublicKey], key: &PublicKey) -> bool {
+    authorized_keys
+        .iter()
+        .any(|k| k.key_data() == key.key_data())
+}
+
+fn to_io_error<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAICNoyEYJzvlobok2L3WmA2CsxdWbejLhYKiHKrz3Ss/A test@iridium";
+
+    #[test]
+    fn test_read_authorized_keys() {
+        let path = std::env::temp_dir().join("iridium_test_authorized_keys");
+        fs::write(&path, format!("# operators\n\n{}\n", TEST_KEY)).unwrap();
+        let keys = read_authorized_keys(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(keys.len(), 1);
+        assert!(is_authorized(&keys, &keys[0]));
+        assert!(!is_authorized(&[], &keys[0]));
+    }
+}
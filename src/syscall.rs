@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::console::{SharedInput, SharedOutput};
+
+/// Writes the heap buffer in `$1` (offset) and `$2` (length) to the VM's output as text. Returns
+/// the number of bytes written.
+pub const SYS_PRINT: u32 = 1;
+/// Reads a line from the VM's input into the heap buffer in `$1` (offset) and `$2` (capacity),
+/// truncating it to fit. Returns the number of bytes read, or 0 at the end of the input.
+pub const SYS_READ: u32 = 2;
+/// Returns the current time in seconds since the Unix epoch.
+pub const SYS_TIME: u32 = 3;
+
+/// The view of the VM handed to a syscall handler when guest code executes `SYSCALL`.
+///
+/// Calling convention: the syscall number is in the register named by the instruction, arguments
+/// are passed in registers `$1` through `$31`, and the value the handler returns is written back
+/// into `$0`. Handlers return -1 to signal an error.
+pub struct Syscall<'a> {
+    pub registers: &'a mut [i32],
+    pub heap: &'a mut Vec<u8>,
+    pub output: &'a SharedOutput,
+    pub input: &'a SharedInput,
+}
+
+impl<'a> Syscall<'a> {
+    /// Returns the `n`th argument (zero based), i.e. the contents of register `$n+1`.
+    pub fn arg(&self, n: usize) -> Option<i32> {
+        self.registers.get(n + 1).cloned()
+    }
+
+    /// Returns the heap range described by arguments `n` (offset) and `n + 1` (length), if it lies
+    /// within the heap.
+    fn buffer_range(&self, n: usize) -> Option<(usize, usize)> {
+        let start = self.arg(n)?;
+        let len = self.arg(n + 1)?;
+        if start < 0 || len < 0 {
+            return None;
+        }
+        let start = start as usize;
+        let end = start.checked_add(len as usize)?;
+        if end > self.heap.len() {
+            return None;
+        }
+        Some((start, end))
+    }
+}
+
+/// A syscall handler.
+pub type SyscallFn = Arc<dyn Fn(&mut Syscall) -> i32 + Send + Sync>;
+
+/// The syscalls a VM can make, by number. A new table has the standard syscalls (`SYS_PRINT`,
+/// `SYS_READ` and `SYS_TIME`) registered; embedders can replace them or add their own.
+#[derive(Clone)]
+pub struct SyscallTable {
+    handlers: BTreeMap<u32, SyscallFn>,
+}
+
+impl SyscallTable {
+    /// Returns a table with the standard syscalls registered.
+    pub fn new() -> SyscallTable {
+        let mut table = SyscallTable::empty();
+        table.register(SYS_PRINT, sys_print);
+        table.register(SYS_READ, sys_read);
+        table.register(SYS_TIME, sys_time);
+        table
+    }
+
+    /// Returns a table with no syscalls registered.
+    pub fn empty() -> SyscallTable {
+        SyscallTable {
+            handlers: BTreeMap::new(),
+        }
+    }
+
+    /// Registers `f` as syscall `number`, replacing any handler already registered for it.
+    pub fn register<F>(&mut self, number: u32, f: F)
+    where
+        F: Fn(&mut Syscall) -> i32 + Send + Sync + 'static,
+    {
+        self.handlers.insert(number, Arc::new(f));
+    }
+
+    /// Removes syscall `number`, returning whether it was registered.
+    pub fn unregister(&mut self, number: u32) -> bool {
+        self.handlers.remove(&number).is_some()
+    }
+
+    /// Returns the handler for syscall `number`.
+    pub fn get(&self, number: u32) -> Option<SyscallFn> {
+        self.handlers.get(&number).cloned()
+    }
+}
+
+impl Default for SyscallTable {
+    fn default() -> Self {
+        SyscallTable::new()
+    }
+}
+
+impl fmt::Debug for SyscallTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.handlers.keys()).finish()
+    }
+}
+
+fn sys_print(call: &mut Syscall) -> i32 {
+    let (start, end) = match call.buffer_range(0) {
+        Some(range) => range,
+        None => return -1,
+    };
+    let text = String::from_utf8_lossy(&call.heap[start..end]);
+    match call.output.lock() {
+        Ok(mut output) => {
+            output.write_str(&text);
+            (end - start) as i32
+        }
+        Err(_) => -1,
+    }
+}
+
+fn sys_read(call: &mut Syscall) -> i32 {
+    let (start, end) = match call.buffer_range(0) {
+        Some(range) => range,
+        None => return -1,
+    };
+    let line = match call.input.lock() {
+        Ok(mut input) => input.read_line(),
+        Err(_) => return -1,
+    };
+    match line {
+        Some(line) => {
+            let len = line.len().min(end - start);
+            call.heap[start..start + len].copy_from_slice(&line.as_bytes()[..len]);
+            len as i32
+        }
+        None => 0,
+    }
+}
+
+fn sys_time(_: &mut Syscall) -> i32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i32)
+        .unwrap_or(-1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::{BufferInput, BufferOutput};
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_register_syscall() {
+        let mut table = SyscallTable::empty();
+        assert!(table.get(SYS_PRINT).is_none());
+        table.register(40, |call| call.arg(0).unwrap() + 1);
+        assert!(table.get(40).is_some());
+        assert!(table.unregister(40));
+        assert!(!table.unregister(40));
+        assert!(SyscallTable::new().get(SYS_TIME).is_some());
+    }
+
+    #[test]
+    fn test_standard_syscalls() {
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let input = Arc::new(Mutex::new(BufferInput::default()));
+        input.lock().unwrap().lines.push("hello".into());
+        let shared_output: SharedOutput = output.clone();
+        let shared_input: SharedInput = input;
+        let mut registers = [0, 0, 3];
+        let mut heap = vec![0; 4];
+        let mut call = Syscall {
+            registers: &mut registers,
+            heap: &mut heap,
+            output: &shared_output,
+            input: &shared_input,
+        };
+        assert_eq!(sys_read(&mut call), 3);
+        assert_eq!(sys_print(&mut call), 3);
+        assert_eq!(sys_read(&mut call), 0);
+        assert_eq!(output.lock().unwrap().buffer, "hel");
+        assert!(sys_time(&mut call) > 0);
+
+        call.registers[2] = 5;
+        assert_eq!(sys_print(&mut call), -1);
+    }
+}
@@ -1,373 +1,3829 @@
-use crate::assembler::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
-use crate::instruction::Opcode;
+use crate::allocator::Allocator;
+use crate::assembler::{
+    PIE_HEADER_CHECKSUM_OFFSET, PIE_HEADER_DATA_OFFSET, PIE_HEADER_DEBUG_OFFSET,
+    PIE_HEADER_ENTRY_OFFSET, PIE_HEADER_LENGTH, PIE_HEADER_PREFIX, PIE_HEADER_RO_LENGTH_OFFSET,
+    PIE_HEADER_SPACE_OFFSET, PIE_HEADER_VERSION_OFFSET, PIE_VERSION,
+};
+use crate::console::{self, SharedInput, SharedOutput};
+use crate::debug_info::{DebugInfo, SourcePosition};
+use crate::flags::Flags;
+use crate::hooks::Hooks;
+use crate::host::{HostCall, HostFunctions};
+use crate::instruction::{
+    DecodedInstruction, Opcode, INSTRUCTION_LENGTH, MAX_REGISTER_COUNT, REGISTER_COUNT,
+};
+use crate::program::Program;
+use crate::shared::SharedHeap;
+use crate::syscall::{Syscall, SyscallTable};
 use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 /// The type of VM event that occured.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum VMEventType {
     Start,
-    GracefulStop { code: u32 },
-    Crash { code: u32 },
+    GracefulStop {
+        code: u32,
+    },
+    Crash {
+        code: u32,
+        reason: CrashReason,
+        /// The address of the instruction that crashed, or 0 if the VM never started.
+        pc: usize,
+    },
+    /// The VM was asked to stop through its stop flag.
+    Killed,
+    /// The VM executed as many instructions as its budget allows and was stopped.
+    BudgetExceeded,
+    /// The VM reached a breakpoint and stopped before executing the instruction at `pc`. `resume`
+    /// continues from there.
+    Paused {
+        pc: usize,
+    },
+    /// The program's header failed `verify_header`, so none of it was executed.
+    InvalidHeader {
+        error: HeaderError,
+    },
+}
+
+impl fmt::Display for VMEventType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VMEventType::Start => f.write_str("started"),
+            VMEventType::GracefulStop { code } => {
+                f.write_str(&format!("stopped with exit code {}", code))
+            }
+            VMEventType::Crash { code, reason, pc } => f.write_str(&format!(
+                "crashed at {} with exit code {}: {}",
+                pc, code, reason
+            )),
+            VMEventType::Killed => f.write_str("killed"),
+            VMEventType::BudgetExceeded => f.write_str("ran out of instructions"),
+            VMEventType::Paused { pc } => f.write_str(&format!("paused at {}", pc)),
+            VMEventType::InvalidHeader { error } => {
+                f.write_str(&format!("refused to start: {}", error))
+            }
+        }
+    }
+}
+
+/// Why a VM crashed.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CrashReason {
+    /// The read-only section runs past the end of the program.
+    BadReadOnlySection,
+    /// `run_unchecked` was given a program that fails `validate`.
+    InvalidProgram,
+    /// The program counter ran past the end of the program.
+    PcOutOfBounds,
+    IllegalOpcode,
+    CallStackOverflow,
+    /// `RET` was executed outside of a subroutine.
+    ReturnOutsideSubroutine,
+    /// `DIV` or `MOD` divided by zero with no handler registered for `TRAP_DIVIDE_BY_ZERO`.
+    DivideByZero,
+    /// A trap was raised with no handler registered for it.
+    UnhandledTrap {
+        vector: usize,
+    },
+    /// A register operand named a register the VM does not have.
+    InvalidRegister {
+        register: usize,
+    },
+    /// `STRAP` or `TRAP` named a vector outside the trap table.
+    InvalidTrapVector,
+    /// A jump or call targeted an address outside the code section, or one that is not the start
+    /// of an instruction.
+    InvalidJump {
+        target: usize,
+    },
+    MissingHostFunction,
+    MissingSyscall,
+    /// An instruction accessed the heap, shared heap, or data out of bounds.
+    MemoryOutOfBounds,
+    /// `STB` or `STW` stored to an address in the read-only section.
+    WriteToReadOnly {
+        address: usize,
+    },
+    /// `ALOC` was asked for a negative number of bytes.
+    InvalidAllocation {
+        size: i32,
+    },
+    /// `FREE` was given a pointer that is not the start of an allocated block.
+    InvalidFree {
+        pointer: i32,
+    },
+    /// The program's `.space` reservations need more heap than the VM allows.
+    HeapLimitExceeded {
+        size: usize,
+    },
+    /// `SLEEP` was asked to wait on a target with no clock or threads to wait with, such as
+    /// wasm32-unknown-unknown.
+    SleepUnsupported,
+}
+
+impl fmt::Display for CrashReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CrashReason::BadReadOnlySection => {
+                f.write_str("The read-only section runs past the end of the program")
+            }
+            CrashReason::InvalidProgram => f.write_str("The program failed validation"),
+            CrashReason::PcOutOfBounds => {
+                f.write_str("The program counter ran past the end of the program")
+            }
+            CrashReason::IllegalOpcode => f.write_str("Unrecognized opcode"),
+            CrashReason::CallStackOverflow => f.write_str("Call stack overflow"),
+            CrashReason::ReturnOutsideSubroutine => {
+                f.write_str("RET executed outside of a subroutine")
+            }
+            CrashReason::DivideByZero => f.write_str("Division by zero"),
+            CrashReason::UnhandledTrap { vector } => {
+                f.write_str(&format!("No handler registered for trap {}", vector))
+            }
+            CrashReason::InvalidRegister { register } => {
+                f.write_str(&format!("Register ${} does not exist", register))
+            }
+            CrashReason::InvalidTrapVector => f.write_str("Trap vector is outside the trap table"),
+            CrashReason::InvalidJump { target } => {
+                f.write_str(&format!("Jump to {} is not an instruction", target))
+            }
+            CrashReason::MissingHostFunction => f.write_str("No such host function"),
+            CrashReason::MissingSyscall => f.write_str("No such syscall"),
+            CrashReason::MemoryOutOfBounds => f.write_str("Memory access out of bounds"),
+            CrashReason::WriteToReadOnly { address } => f.write_str(&format!(
+                "Cannot store to address {}, which is in the read-only section",
+                address
+            )),
+            CrashReason::InvalidAllocation { size } => {
+                f.write_str(&format!("Cannot allocate {} bytes", size))
+            }
+            CrashReason::InvalidFree { pointer } => {
+                f.write_str(&format!("{} is not an allocated block", pointer))
+            }
+            CrashReason::HeapLimitExceeded { size } => f.write_str(&format!(
+                "The program needs {} bytes of heap, more than the VM allows",
+                size
+            )),
+            CrashReason::SleepUnsupported => f.write_str("SLEEP is not supported on this target"),
+        }
+    }
+}
+
+/// An event in the VM.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VMEvent {
+    /// The type of event that occured.
+    pub event: VMEventType,
+    /// The time at which the event occured.
+    pub at: DateTime<Utc>,
+    /// The id of the VM the event occured in.
+    pub application_id: Uuid,
+}
+
+/// Why `VM::verify_header` rejected a program's header.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HeaderError {
+    /// The program is shorter than a header.
+    Truncated,
+    /// The program does not start with the header prefix.
+    BadPrefix,
+    /// The program was assembled for a version of the bytecode format this VM cannot run.
+    UnsupportedVersion { version: u8 },
+    /// The read-only section runs past the end of the program.
+    ReadOnlySectionPastEnd,
+    /// The body does not match the checksum in the header, so the program is corrupt or
+    /// truncated.
+    ChecksumMismatch { expected: u32, found: u32 },
+    /// The debug section is longer than what follows the read-only section.
+    DebugSectionPastEnd,
+    /// The writable data is longer than what follows the read-only section.
+    DataSectionPastEnd,
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeaderError::Truncated => f.write_str("The program is shorter than its header"),
+            HeaderError::BadPrefix => f.write_str("The program does not start with a header"),
+            HeaderError::UnsupportedVersion { version } => f.write_str(&format!(
+                "The program is bytecode version {}, but this VM runs version {}",
+                version, PIE_VERSION
+            )),
+            HeaderError::ReadOnlySectionPastEnd => {
+                f.write_str("The read-only section runs past the end of the program")
+            }
+            HeaderError::ChecksumMismatch { expected, found } => f.write_str(&format!(
+                "The program is corrupt: its checksum is {:08x}, but the header says {:08x}",
+                found, expected
+            )),
+            HeaderError::DebugSectionPastEnd => {
+                f.write_str("The debug section runs past the end of the program")
+            }
+            HeaderError::DataSectionPastEnd => {
+                f.write_str("The writable data runs past the end of the program")
+            }
+        }
+    }
+}
+
+impl Error for HeaderError {}
+
+/// Problems `VM::validate` can find in a program.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidationError {
+    BadHeader { error: HeaderError },
+    TruncatedInstruction { offset: usize },
+    InvalidRegister { offset: usize, register: usize },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationError::BadHeader { error } => {
+                f.write_str(&format!("The program header is invalid: {}", error))
+            }
+            ValidationError::TruncatedInstruction { offset } => f.write_str(&format!(
+                "The instruction at offset {} is truncated",
+                offset
+            )),
+            ValidationError::InvalidRegister { offset, register } => f.write_str(&format!(
+                "The instruction at offset {} uses register {}, which does not exist",
+                offset, register
+            )),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+/// The parts of a VM's memory, as laid out by `VM::memory_regions`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryRegion {
+    Header,
+    ReadOnly,
+    Code,
+    Data,
+    Debug,
+    Heap,
+}
+
+impl fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryRegion::Header => f.write_str("header"),
+            MemoryRegion::ReadOnly => f.write_str("read-only data"),
+            MemoryRegion::Code => f.write_str("code"),
+            MemoryRegion::Data => f.write_str("writable data"),
+            MemoryRegion::Debug => f.write_str("debug info"),
+            MemoryRegion::Heap => f.write_str("heap"),
+        }
+    }
+}
+
+/// The most values the stack can hold. Pushing past it crashes the VM.
+pub const MAX_STACK_DEPTH: usize = 1024;
+
+/// The deepest subroutine calls can nest. Calling past it crashes the VM.
+pub const MAX_CALL_DEPTH: usize = 1024;
+
+/// The most bytes of heap a VM lets a program reserve with `.space` unless built with a different
+/// limit. Starting a program that reserves more crashes the VM.
+pub const DEFAULT_HEAP_LIMIT: usize = 64 * 1024 * 1024;
+
+/// The number of entries in the VM's trap table.
+pub const TRAP_VECTORS: usize = 16;
+
+/// Raised by `DIV` and `MOD` when dividing by zero.
+pub const TRAP_DIVIDE_BY_ZERO: usize = 0;
+
+/// Raised by `PUSH` on a full stack and by `POP` on an empty one.
+pub const TRAP_STACK_FAULT: usize = 1;
+
+/// The longest `SLEEP` waits between checks of the stop flag, so a sleeping VM can still be stopped.
+#[cfg(not(target_arch = "wasm32"))]
+const SLEEP_SLICE: Duration = Duration::from_millis(10);
+
+/// The I/O handles and host functions are not part of the serialized state; a deserialized VM
+/// starts out attached to stdin/stdout with no host functions registered.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VM {
+    /// The registers of the VM. There are `REGISTER_COUNT` of them unless the VM was built with
+    /// `VM::builder`.
+    pub registers: Vec<i32>,
+    /// Program counter that is used to track which byte is executing.
+    pc: usize,
+    /// Where `run` starts executing. The start of the code section when `None`.
+    #[serde(default)]
+    entry_point: Option<usize>,
+    /// The address of the code section, below which jumps are rejected. Set when the VM starts.
+    #[serde(skip, default = "default_code_start")]
+    code_start: usize,
+    /// Bytecode of the program. VMs spawned from the same program share it, and it is copied
+    /// only when one of them adds bytes.
+    pub program: Arc<Program>,
+    /// Used for heap memory.
+    heap: Vec<u8>,
+    /// How many zeroed bytes of heap the VM starts with, and has again after `reset`.
+    #[serde(default)]
+    heap_size: usize,
+    /// The most bytes of heap the program's `.space` reservations can take.
+    #[serde(default = "default_heap_limit")]
+    heap_limit: usize,
+    /// Tracks which blocks of `heap` are allocated with `ALOC` and released with `FREE`.
+    #[serde(default)]
+    allocator: Allocator,
+    /// Values saved with `PUSH`. Its length is the stack pointer.
+    #[serde(default)]
+    stack: Vec<i32>,
+    /// Return addresses saved by `CALL`, kept apart from `stack` so pushes cannot clobber them.
+    #[serde(default)]
+    call_stack: Vec<usize>,
+    /// The remainder of a division operation.
+    remainder: u32,
+    /// The flags set by the last comparison or arithmetic instruction.
+    #[serde(default)]
+    flags: Flags,
+    /// The handler address for each trap vector, set with `STRAP` or `set_trap_handler`.
+    #[serde(default)]
+    traps: [Option<usize>; TRAP_VECTORS],
+    /// State of the generator `RAND` draws from. Set it with `seed_rng` for reproducible runs.
+    #[serde(default)]
+    rng_state: u64,
+    /// Contains the read-only section of data.
+    ro_data: Vec<u8>,
+    /// The length of the debug section at the end of the program, which is not executed.
+    #[serde(default)]
+    debug_length: usize,
+    /// The length of the writable data between the code and the debug section.
+    #[serde(default)]
+    data_length: usize,
+    /// The writable data, loaded from the program when it starts. Its addresses follow those of
+    /// the read-only section; see `data_at`.
+    #[serde(default)]
+    data: Vec<u8>,
+    /// Is a unique, randomly generated UUID for identifying a VM.
+    id: Uuid,
+    /// Events that have occured in the VM.
+    events: Vec<VMEvent>,
+    /// Why, and at which instruction, the running program crashed. Set by the handler that
+    /// crashed it and turned into a `Crash` event once the VM stops.
+    #[serde(skip)]
+    crash: Option<(CrashReason, usize)>,
+    /// Where text produced by the program is written.
+    #[serde(skip, default = "console::stdout")]
+    output: SharedOutput,
+    /// Where the program reads text input from.
+    #[serde(skip, default = "console::stdin")]
+    input: SharedInput,
+    /// Functions registered by the embedder that guest code can invoke with `CALLHOST`.
+    #[serde(skip)]
+    host_functions: HostFunctions,
+    /// The handlers guest code can invoke with `SYSCALL`.
+    #[serde(skip)]
+    syscalls: SyscallTable,
+    /// Closures called before and after each instruction, e.g. by tracers.
+    #[serde(skip)]
+    hooks: Hooks,
+    /// Addresses `run` pauses at before executing the instruction there.
+    #[serde(default)]
+    breakpoints: BTreeSet<usize>,
+    /// Whether the VM stopped at a breakpoint, so `resume` can continue where it left off.
+    #[serde(skip)]
+    paused: bool,
+    /// The most instructions `run` executes before stopping the VM. Unlimited when `None`.
+    #[serde(default)]
+    instruction_budget: Option<u64>,
+    /// Set from another thread to make a running VM stop before its next instruction.
+    #[serde(skip)]
+    stop: Arc<AtomicBool>,
+    /// How many instructions `run` has executed since the program started, readable from other
+    /// threads while it runs.
+    #[serde(skip)]
+    executed: Arc<AtomicU64>,
+    /// Memory shared with other VMs, accessed with `AADD` and `CAS`. Clones share it.
+    #[serde(skip)]
+    shared_heap: Arc<SharedHeap>,
+    /// The code section decoded by `predecode`, if it has been called.
+    #[serde(skip)]
+    decoded: Option<DecodedProgram>,
+}
+
+/// A pre-decoded code section.
+#[derive(Clone, Debug)]
+struct DecodedProgram {
+    /// The address of the first decoded instruction.
+    base: usize,
+    /// The program length the cache was built for. A cache for a different length is ignored.
+    program_len: usize,
+    instructions: Vec<DecodedInstruction>,
+    /// Filled in by `fuse_instructions`: for each instruction, the handler that executes it
+    /// together with the next one, if the pair can be fused. Empty until then.
+    fused: Vec<Option<FusedHandler>>,
+}
+
+impl DecodedProgram {
+    /// Returns the index of the cached instruction at `pc`, if there is one.
+    fn index(&self, pc: usize) -> Option<usize> {
+        let offset = pc.checked_sub(self.base)?;
+        if offset.is_multiple_of(INSTRUCTION_LENGTH)
+            && offset / INSTRUCTION_LENGTH < self.instructions.len()
+        {
+            Some(offset / INSTRUCTION_LENGTH)
+        } else {
+            None
+        }
+    }
 }
 
-/// An event in the VM.
-#[derive(Clone, Debug)]
-pub struct VMEvent {
-    /// The type of event that occured.
-    event: VMEventType,
-    /// The time at which the event occured.
-    at: DateTime<Utc>,
-    application_id: Uuid,
-}
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    /// Returns a new `VM` instance.
+    pub fn new() -> VM {
+        VM {
+            registers: vec![0; REGISTER_COUNT],
+            program: Arc::new(Program::new()),
+            heap: vec![],
+            heap_size: 0,
+            heap_limit: DEFAULT_HEAP_LIMIT,
+            allocator: Allocator::new(),
+            stack: Vec::with_capacity(MAX_STACK_DEPTH),
+            call_stack: vec![],
+            pc: INITIAL_PC,
+            entry_point: None,
+            code_start: PIE_HEADER_LENGTH,
+            remainder: 0,
+            flags: Flags::default(),
+            traps: [None; TRAP_VECTORS],
+            rng_state: rand_seed(),
+            ro_data: vec![],
+            debug_length: 0,
+            data_length: 0,
+            data: vec![],
+            id: new_id(),
+            events: vec![],
+            crash: None,
+            output: console::stdout(),
+            input: console::stdin(),
+            host_functions: HostFunctions::new(),
+            syscalls: SyscallTable::new(),
+            hooks: Hooks::new(),
+            breakpoints: BTreeSet::new(),
+            paused: false,
+            instruction_budget: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            executed: Arc::new(AtomicU64::new(0)),
+            shared_heap: Arc::new(SharedHeap::default()),
+            decoded: None,
+        }
+    }
+
+    /// Returns a builder for a `VM` with a different number of registers, heap size or entry
+    /// point than `VM::new` gives it.
+    pub fn builder() -> VMBuilder {
+        VMBuilder::default()
+    }
+
+    pub fn run(&mut self) -> Vec<VMEvent> {
+        self.dispatch(false)
+    }
+
+    /// Continues a VM that paused at a breakpoint, starting with the instruction it paused
+    /// before. Runs the program from the start like `run` if the VM is not paused.
+    pub fn resume(&mut self) -> Vec<VMEvent> {
+        let paused = self.paused;
+        self.dispatch(paused)
+    }
+
+    /// Runs or resumes the program with the fastest way of executing instructions the VM has set
+    /// up.
+    fn dispatch(&mut self, resume: bool) -> Vec<VMEvent> {
+        // Hooks see every instruction, so fused pairs are executed one at a time while any are
+        // registered.
+        if !self.hooks.is_empty() {
+            return self.run_with(VM::execute_hooked_instruction, resume);
+        }
+        // Likewise, a breakpoint can be on the second instruction of a pair.
+        match self.decoded {
+            Some(ref decoded) if !decoded.fused.is_empty() && self.breakpoints.is_empty() => {
+                self.run_with(VM::execute_fused_instruction, resume)
+            }
+            _ => self.run_with(VM::execute_instruction, resume),
+        }
+    }
+
+    /// Runs the program like `run`, but stops it with a `BudgetExceeded` event after `budget`
+    /// instructions. The VM's own budget, if any, is left as it was.
+    pub fn run_with_budget(&mut self, budget: u64) -> Vec<VMEvent> {
+        let previous = self.instruction_budget.replace(budget);
+        let events = self.run();
+        self.instruction_budget = previous;
+        events
+    }
+
+    /// Runs the program like `run`, but fetches instructions without bounds checks. The program
+    /// is checked with `validate` first, and is not run if that fails.
+    ///
+    /// # Safety
+    ///
+    /// The program must never run past its last instruction without stopping. Jumps are checked
+    /// at run time, but falling off the end of the code section is not, so this should only be
+    /// used on trusted bytecode.
+    #[cfg(feature = "unchecked")]
+    pub unsafe fn run_unchecked(&mut self) -> Vec<VMEvent> {
+        if let Err(e) = self.validate() {
+            tracing::error!("Refusing to run an invalid program unchecked: {}", e);
+            self.push_event(VMEventType::Crash {
+                code: 1,
+                reason: CrashReason::InvalidProgram,
+                pc: 0,
+            });
+            return self.events.clone();
+        }
+        self.run_with(VM::execute_unchecked, false)
+    }
+
+    /// Runs the program, executing each instruction with `step`. When resuming, execution
+    /// continues from the program counter, and a breakpoint there does not pause the VM again.
+    fn run_with<F: Fn(&mut VM) -> Option<u32>>(&mut self, step: F, resume: bool) -> Vec<VMEvent> {
+        let span = tracing::info_span!("vm", id = %self.id);
+        let _entered = span.enter();
+        self.crash = None;
+        self.paused = false;
+        if !resume {
+            if let Some(events) = self.start() {
+                return events;
+            }
+        }
+        let mut remaining = self.instruction_budget;
+        // Nothing the program does can change the breakpoints, so there is no need to look them up
+        // when there are none.
+        let has_breakpoints = !self.breakpoints.is_empty();
+        let mut skip_breakpoint = resume;
+        let mut executed = self.executed.load(Ordering::Relaxed);
+        let mut is_done = None;
+        while is_done.is_none() {
+            if has_breakpoints && !skip_breakpoint && self.breakpoints.contains(&self.pc) {
+                tracing::info!(pc = self.pc, "VM paused at a breakpoint");
+                self.paused = true;
+                self.push_event(VMEventType::Paused { pc: self.pc });
+                return self.events.clone();
+            }
+            skip_breakpoint = false;
+            if let Some(ref mut remaining) = remaining {
+                if *remaining == 0 {
+                    tracing::warn!(pc = self.pc, "VM exceeded its instruction budget");
+                    self.push_event(VMEventType::BudgetExceeded);
+                    return self.events.clone();
+                }
+                *remaining -= 1;
+            }
+            if self.stop.load(Ordering::Relaxed) {
+                tracing::info!(pc = self.pc, "VM killed");
+                self.push_event(VMEventType::Killed);
+                return self.events.clone();
+            }
+            is_done = step(self);
+            executed += 1;
+            self.executed.store(executed, Ordering::Relaxed);
+        }
+        tracing::debug!(pc = self.pc, code = is_done.unwrap(), "VM stopped");
+        self.record_stop(is_done.unwrap());
+        self.events.clone()
+    }
+
+    /// Records the `Start` event, checks the header and moves the program counter to the entry
+    /// point. Returns the events if the program cannot be started.
+    fn start(&mut self) -> Option<Vec<VMEvent>> {
+        self.push_event(VMEventType::Start);
+        self.executed.store(0, Ordering::Relaxed);
+        if let Err(error) = self.verify_header() {
+            self.push_event(VMEventType::InvalidHeader { error });
+            tracing::error!("Header was incorrect: {}", error);
+            return Some(self.events.clone());
+        }
+        let code_start = match self.code_start() {
+            Some(code_start) => code_start,
+            None => {
+                self.push_event(VMEventType::Crash {
+                    code: 1,
+                    reason: CrashReason::BadReadOnlySection,
+                    pc: 0,
+                });
+                tracing::error!("Read-only section runs past the end of the program");
+                return Some(self.events.clone());
+            }
+        };
+        // Bytes added with `add_slice` are only split once the program runs, and the program may
+        // have been replaced since, so split it here.
+        self.split_sections();
+        tracing::debug!(program_len = self.program.len(), code_start, "Starting VM");
+        // If the header is valid, the code starts after it and the read-only section.
+        self.code_start = code_start;
+        // The heap starts with the bytes the program reserved with `.space`, and `ALOC` allocates
+        // after them.
+        let reserved = self.header_field(PIE_HEADER_SPACE_OFFSET) as usize;
+        if reserved > self.heap_limit {
+            tracing::error!(
+                reserved,
+                limit = self.heap_limit,
+                "Program reserves more heap than the VM allows"
+            );
+            self.push_event(VMEventType::Crash {
+                code: 1,
+                reason: CrashReason::HeapLimitExceeded { size: reserved },
+                pc: 0,
+            });
+            return Some(self.events.clone());
+        }
+        if self.heap.len() < reserved {
+            self.heap.resize(reserved, 0);
+        }
+        // An entry point set by the embedder overrides the one in the header.
+        let entry_point = self.entry_point.or_else(|| self.header_entry_point());
+        self.pc = match entry_point {
+            Some(entry_point) if !self.is_jump_target(entry_point, code_start) => {
+                tracing::error!(
+                    entry_point,
+                    "Entry point is not an instruction in the code section"
+                );
+                self.push_event(VMEventType::Crash {
+                    code: 1,
+                    reason: CrashReason::PcOutOfBounds,
+                    pc: entry_point,
+                });
+                return Some(self.events.clone());
+            }
+            Some(entry_point) => entry_point,
+            None => code_start,
+        };
+        None
+    }
+
+    /// Records an event that occured now.
+    fn push_event(&mut self, event: VMEventType) {
+        let event = VMEvent {
+            event,
+            at: Utc::now(),
+            application_id: self.id,
+        };
+        self.hooks.call_event(&event);
+        self.events.push(event);
+    }
+
+    /// Records that the VM stopped with `code`: as a crash if a handler crashed it, and as a
+    /// graceful stop otherwise.
+    fn record_stop(&mut self, code: u32) {
+        let event = match self.crash.take() {
+            Some((reason, pc)) => VMEventType::Crash { code, reason, pc },
+            None => VMEventType::GracefulStop { code },
+        };
+        self.push_event(event);
+    }
+
+    /// Marks the running program as crashed by the instruction at `pc`, and returns the code that
+    /// stops the VM.
+    fn crash(&mut self, reason: CrashReason, pc: usize) -> Option<u32> {
+        self.crash = Some((reason, pc));
+        Some(1)
+    }
+
+    /// Returns the exit code of the last run: the operand of `EXIT`, 0 for `HLT`, or the code of
+    /// the crash that stopped it, with 1 for a program whose header is invalid. Returns `None` if
+    /// the VM has not stopped yet or was killed.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self.events.last().map(|e| &e.event) {
+            Some(VMEventType::GracefulStop { code }) | Some(VMEventType::Crash { code, .. }) => {
+                Some(*code as i32)
+            }
+            Some(VMEventType::InvalidHeader { .. }) => Some(1),
+            _ => None,
+        }
+    }
+
+    /// Executes one instruction. Meant to allow for more controlled execution of the VM. Fused
+    /// instructions are still executed one at a time.
+    /// Records an event if the instruction stopped the VM.
+    pub fn run_once(&mut self) {
+        let stop = if self.hooks.is_empty() {
+            self.execute_instruction()
+        } else {
+            self.execute_hooked_instruction()
+        };
+        if let Some(code) = stop {
+            self.paused = false;
+            self.record_stop(code);
+        }
+    }
+
+    #[inline(always)]
+    fn execute_instruction(&mut self) -> Option<u32> {
+        match self.fetch() {
+            Ok(instruction) => self.execute_decoded(instruction),
+            Err(stop) => stop,
+        }
+    }
+
+    /// Executes the instruction at the program counter, calling the hooks before and after it.
+    fn execute_hooked_instruction(&mut self) -> Option<u32> {
+        let pc = self.pc;
+        let instruction = match self.fetch() {
+            Ok(instruction) => instruction,
+            Err(stop) => return stop,
+        };
+        self.hooks.call_before(self, pc, instruction);
+        let stop = self.execute_decoded(instruction);
+        self.hooks.call_after(self, pc, instruction);
+        stop
+    }
+
+    /// Returns the instruction at the program counter, or crashes the VM if there is no valid
+    /// instruction there.
+    #[inline(always)]
+    fn fetch(&mut self) -> Result<DecodedInstruction, Option<u32>> {
+        // If our program counter has exceeded the length of the program itself,
+        // something has gone awry.
+        if self.pc >= self.code_end() {
+            tracing::error!(
+                pc = self.pc,
+                "Program counter ran past the end of the program"
+            );
+            return Err(self.crash(CrashReason::PcOutOfBounds, self.pc));
+        }
+        match self.cached() {
+            Some(instruction) => Ok(instruction),
+            None => {
+                let instruction = DecodedInstruction::decode(&self.program, self.pc);
+                if let Some(register) = instruction.invalid_register_for(self.registers.len()) {
+                    return Err(self.invalid_register(register, self.pc));
+                }
+                Ok(instruction)
+            }
+        }
+    }
+
+    /// Executes the instruction at the program counter, or the fused pair starting there.
+    fn execute_fused_instruction(&mut self) -> Option<u32> {
+        if let Some(ref decoded) = self.decoded {
+            if decoded.program_len == self.program.len() {
+                if let Some(i) = decoded.index(self.pc) {
+                    let first = decoded.instructions[i];
+                    return match decoded.fused[i] {
+                        Some(handler) => {
+                            let second = decoded.instructions[i + 1];
+                            self.execute_fused(handler, first, second)
+                        }
+                        None => self.execute_decoded(first),
+                    };
+                }
+            }
+        }
+        self.execute_instruction()
+    }
+
+    /// Executes the instruction at the program counter without checking it is in bounds. Only
+    /// reachable through `run_unchecked`, whose caller guarantees that.
+    #[cfg(feature = "unchecked")]
+    fn execute_unchecked(&mut self) -> Option<u32> {
+        // SAFETY: `run_unchecked` validated that every instruction lies within the program, jumps
+        // are checked to land on instructions, and the caller guarantees that execution does not
+        // run off the end of the program.
+        let instruction = unsafe { DecodedInstruction::decode_unchecked(&self.program, self.pc) };
+        self.execute_decoded(instruction)
+    }
+
+    /// Checks that the program has a valid header, that its code section is made of whole
+    /// instructions, and that every register operand names one of the VM's registers.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        self.verify_header()
+            .map_err(|error| ValidationError::BadHeader { error })?;
+        let code_start = self.code_start().ok_or(ValidationError::BadHeader {
+            error: HeaderError::ReadOnlySectionPastEnd,
+        })?;
+        let code_end = self.program.len() - self.header_debug_length() - self.header_data_length();
+        let code = &self.program[code_start..code_end];
+        if !code.len().is_multiple_of(INSTRUCTION_LENGTH) {
+            return Err(ValidationError::TruncatedInstruction {
+                offset: code_start + code.len() / INSTRUCTION_LENGTH * INSTRUCTION_LENGTH,
+            });
+        }
+        for (i, instruction) in DecodedInstruction::decode_all(code).iter().enumerate() {
+            let offset = code_start + i * INSTRUCTION_LENGTH;
+            if let Some(register) = instruction.invalid_register_for(self.registers.len()) {
+                return Err(ValidationError::InvalidRegister { offset, register });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the instruction at the program counter if the pre-decoded cache covers that
+    /// address. Cached instructions have already been checked for invalid registers.
+    #[inline(always)]
+    fn cached(&self) -> Option<DecodedInstruction> {
+        if let Some(ref decoded) = self.decoded {
+            if decoded.program_len == self.program.len() && self.pc >= decoded.base {
+                let offset = self.pc - decoded.base;
+                if offset.is_multiple_of(INSTRUCTION_LENGTH) {
+                    return decoded
+                        .instructions
+                        .get(offset / INSTRUCTION_LENGTH)
+                        .copied();
+                }
+            }
+        }
+        None
+    }
+
+    /// Executes an instruction located at the program counter. Inlined into each step function so
+    /// dispatch stays a single indirect call.
+    #[inline(always)]
+    fn execute_decoded(&mut self, instruction: DecodedInstruction) -> Option<u32> {
+        let pc = self.pc;
+        tracing::trace!(pc, opcode = ?instruction.opcode, "Executing instruction");
+        self.pc += INSTRUCTION_LENGTH;
+        HANDLERS[instruction.opcode as usize](self, instruction, pc)
+    }
+
+    /// Crashes the VM on an instruction that names a register it does not have. Kept out of line
+    /// so the check costs the dispatch loop as little as possible.
+    #[cold]
+    #[inline(never)]
+    fn invalid_register(&mut self, register: usize, pc: usize) -> Option<u32> {
+        tracing::error!(
+            pc,
+            register,
+            "Instruction names a register that does not exist"
+        );
+        self.crash(CrashReason::InvalidRegister { register }, pc)
+    }
+
+    /// Executes a fused pair of instructions located at the program counter.
+    fn execute_fused(
+        &mut self,
+        handler: FusedHandler,
+        first: DecodedInstruction,
+        second: DecodedInstruction,
+    ) -> Option<u32> {
+        let pc = self.pc;
+        tracing::trace!(
+            pc,
+            first = ?first.opcode,
+            second = ?second.opcode,
+            "Executing fused instructions"
+        );
+        self.pc += 2 * INSTRUCTION_LENGTH;
+        handler(self, first, second, pc)
+    }
+
+    /// Jumps to the subroutine at `target`, saving the address of the next instruction for `RET`.
+    fn call_subroutine(&mut self, target: usize, pc: usize) -> Option<u32> {
+        let depth = self.call_stack.len();
+        if depth >= MAX_CALL_DEPTH {
+            tracing::error!(pc, depth, "Call stack overflow");
+            return self.crash(CrashReason::CallStackOverflow, pc);
+        }
+        if !self.is_jump_target(target, pc) {
+            return self.invalid_jump(target, pc);
+        }
+        tracing::trace!(pc, depth = depth + 1, "Calling {}", target);
+        self.call_stack.push(self.pc);
+        self.pc = target;
+        None
+    }
+
+    /// Jumps to `target` if the instruction at `pc` may jump there, and crashes the VM otherwise.
+    #[inline]
+    fn jump(&mut self, target: usize, pc: usize) -> Option<u32> {
+        if !self.is_jump_target(target, pc) {
+            return self.invalid_jump(target, pc);
+        }
+        self.pc = target;
+        None
+    }
+
+    /// Returns whether `target` lies within the code section and a whole number of instructions
+    /// away from `pc`, the instruction jumping to it.
+    #[inline]
+    fn is_jump_target(&self, target: usize, pc: usize) -> bool {
+        target >= self.code_start
+            && target < self.code_end()
+            && target.wrapping_sub(pc).is_multiple_of(INSTRUCTION_LENGTH)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn invalid_jump(&mut self, target: usize, pc: usize) -> Option<u32> {
+        tracing::error!(pc, target, "Jump target is not an instruction");
+        self.crash(CrashReason::InvalidJump { target }, pc)
+    }
+
+    /// Raises trap `vector`: calls its handler, which can return past the instruction that raised it
+    /// with `RET`. Crashes the VM if no handler is registered.
+    fn raise_trap(&mut self, vector: usize, pc: usize) -> Option<u32> {
+        match self.traps.get(vector).cloned().flatten() {
+            Some(handler) => {
+                tracing::debug!(pc, vector, "Trap raised, jumping to {}", handler);
+                self.call_subroutine(handler, pc)
+            }
+            None => {
+                tracing::error!(pc, vector, "No handler registered for trap {}", vector);
+                let reason = match vector {
+                    TRAP_DIVIDE_BY_ZERO => CrashReason::DivideByZero,
+                    _ => CrashReason::UnhandledTrap { vector },
+                };
+                self.crash(reason, pc)
+            }
+        }
+    }
+
+    /// Returns the values of the registers named by the first two operands.
+    fn operand_registers(&self, instruction: &DecodedInstruction) -> (i32, i32) {
+        (
+            self.registers[instruction.register(0)],
+            self.registers[instruction.register(1)],
+        )
+    }
+
+    /// Decodes the code section once up front, so `run` executes instructions without decoding
+    /// them byte by byte. The cache is dropped whenever bytes are added to the program; callers
+    /// that modify `program` directly should call `clear_decoded` or `predecode` again.
+    pub fn predecode(&mut self) {
+        let base = self
+            .code_start()
+            .unwrap_or(PIE_HEADER_LENGTH)
+            .min(self.code_end());
+        let mut instructions = DecodedInstruction::decode_all(&self.program[base..self.code_end()]);
+        // Only the instructions before the first one naming an invalid register are cached, so
+        // cached instructions never need checking. That one crashes the VM when it is decoded.
+        let register_count = self.registers.len();
+        if let Some(invalid) = instructions
+            .iter()
+            .position(|instruction| instruction.invalid_register_for(register_count).is_some())
+        {
+            instructions.truncate(invalid);
+        }
+        self.decoded = Some(DecodedProgram {
+            base,
+            program_len: self.program.len(),
+            instructions,
+            fused: vec![],
+        });
+    }
+
+    /// Fuses common pairs of adjacent instructions in the pre-decoded cache, such as a comparison
+    /// followed by a conditional jump, so `run` dispatches them as one. Decodes the program first
+    /// if `predecode` has not been called. Jumps to the second instruction of a pair still
+    /// execute it on its own.
+    pub fn fuse_instructions(&mut self) {
+        if self.decoded.is_none() {
+            self.predecode();
+        }
+        let decoded = self.decoded.as_mut().unwrap();
+        let mut fused: Vec<Option<FusedHandler>> = decoded
+            .instructions
+            .windows(2)
+            .map(|pair| fused_handler(pair[0].opcode, pair[1].opcode))
+            .collect();
+        fused.push(None);
+        decoded.fused = fused;
+    }
+
+    /// Drops the pre-decoded instruction cache.
+    pub fn clear_decoded(&mut self) {
+        self.decoded = None;
+    }
+
+    /// Returns a new `VM` that runs `program`, sharing its bytes with every other VM running it.
+    pub fn with_program(program: Arc<Program>) -> VM {
+        let mut vm = VM {
+            program,
+            ..VM::new()
+        };
+        vm.split_ro_data();
+        vm
+    }
+
+    /// Returns a handle to the program's bytes, for starting more VMs with `with_program`.
+    pub fn shared_program(&self) -> Arc<Program> {
+        self.program.clone()
+    }
+
+    /// Adds a byte to the program.
+    pub fn add_byte(&mut self, byte: u8) {
+        self.decoded = None;
+        Arc::make_mut(&mut self.program).to_mut().push(byte);
+    }
+
+    /// Adds multiple bytes to the program. An empty program takes ownership of `bytes` without
+    /// copying them, and if they pass `verify_header`, its read-only section is loaded into the
+    /// VM. Otherwise they are appended like `add_slice`. `run` refuses to execute a program that
+    /// does not pass `verify_header`.
+    pub fn add_bytes(&mut self, bytes: Vec<u8>) {
+        if self.program.is_empty() {
+            self.decoded = None;
+            self.program = Arc::new(bytes.into());
+            self.split_ro_data();
+        } else {
+            self.add_slice(&bytes);
+        }
+    }
+
+    /// Copies `bytes` onto the end of the program, growing it at most once. The header is not
+    /// checked and the read-only section is not loaded until the program runs, so a program can be
+    /// added in many pieces cheaply; call `load_done` after the last one to load it sooner.
+    pub fn add_slice(&mut self, bytes: &[u8]) {
+        self.decoded = None;
+        Arc::make_mut(&mut self.program)
+            .to_mut()
+            .extend_from_slice(bytes);
+    }
+
+    /// Loads the read-only section and writable data of a program added with `add_slice`, if it
+    /// passes `verify_header`, e.g. to read them before it runs.
+    pub fn load_done(&mut self) {
+        self.split_ro_data();
+    }
+
+    /// Copies the read-only section that follows the header into `ro_data`, and the writable data
+    /// that follows the code into `data`, if the program has a valid header and the sections are
+    /// complete. Programs without a read-only section or writable data of their own keep the ones
+    /// they were given, e.g. by the loader.
+    fn split_ro_data(&mut self) {
+        if self.verify_header().is_err() {
+            self.debug_length = 0;
+            self.data_length = 0;
+            return;
+        }
+        self.split_sections();
+    }
+
+    /// Splits the sections like `split_ro_data`, for a program that already passed
+    /// `verify_header`.
+    fn split_sections(&mut self) {
+        self.debug_length = self.header_debug_length();
+        self.data_length = self.header_data_length();
+        if let Some(code_start) = self.code_start() {
+            if code_start > PIE_HEADER_LENGTH {
+                self.ro_data = self.program[PIE_HEADER_LENGTH..code_start].to_vec();
+            }
+        }
+        self.load_data();
+    }
+
+    /// Copies the writable data that follows the code into `data`, if the program has any.
+    fn load_data(&mut self) {
+        if self.data_length > 0 {
+            let data_start = self.code_end();
+            self.data = self.program[data_start..data_start + self.data_length].to_vec();
+        }
+    }
+
+    /// Sets where text produced by the program is written.
+    pub fn set_output(&mut self, output: SharedOutput) {
+        self.output = output;
+    }
+
+    /// Sets where the program reads text input from.
+    pub fn set_input(&mut self, input: SharedInput) {
+        self.input = input;
+    }
+
+    /// Registers a Rust closure that guest code can call with `CALLHOST #<id>`, and returns its
+    /// id. See `HostCall` for the calling convention.
+    pub fn register_host_function<F>(&mut self, name: &str, f: F) -> u16
+    where
+        F: Fn(&mut HostCall) -> i32 + Send + Sync + 'static,
+    {
+        self.host_functions.register(name, f)
+    }
+
+    /// Returns the id of the host function registered under `name`.
+    pub fn host_function_id(&self, name: &str) -> Option<u16> {
+        self.host_functions.id(name)
+    }
+
+    /// Clears the registers, heap, stacks, flags and trap handlers and moves the program counter
+    /// back to where it started, keeping the program so it can be run again from a clean slate.
+    /// The stop flag and instruction counter are cleared too, and the writable data is loaded from
+    /// the program again.
+    pub fn reset(&mut self) {
+        self.registers.iter_mut().for_each(|register| *register = 0);
+        self.pc = self.entry_point.unwrap_or(INITIAL_PC);
+        self.code_start = PIE_HEADER_LENGTH;
+        self.heap = vec![0; self.heap_size];
+        self.allocator = Allocator::new();
+        self.stack.clear();
+        self.call_stack.clear();
+        self.remainder = 0;
+        self.flags = Flags::default();
+        self.traps = [None; TRAP_VECTORS];
+        self.crash = None;
+        self.paused = false;
+        self.stop.store(false, Ordering::Relaxed);
+        self.executed.store(0, Ordering::Relaxed);
+        self.load_data();
+    }
+
+    /// Resets the VM like `reset`, and also drops its program, read-only data, events and
+    /// breakpoints. What the embedder set up, such as the I/O handles, host functions, syscalls,
+    /// hooks and shared heap, is kept.
+    pub fn reset_full(&mut self) {
+        self.reset();
+        self.program = Arc::new(Program::new());
+        self.decoded = None;
+        self.ro_data.clear();
+        self.debug_length = 0;
+        self.data_length = 0;
+        self.data.clear();
+        self.events.clear();
+        self.breakpoints.clear();
+    }
+
+    /// Captures the VM's execution state, e.g. to checkpoint a long computation. The program is
+    /// shared with the VM rather than copied.
+    pub fn snapshot(&self) -> VmSnapshot {
+        VmSnapshot {
+            registers: self.registers.clone(),
+            pc: self.pc,
+            code_start: self.code_start,
+            entry_point: self.entry_point,
+            paused: self.paused,
+            program: self.program.clone(),
+            heap: self.heap.clone(),
+            allocator: self.allocator.clone(),
+            stack: self.stack.clone(),
+            call_stack: self.call_stack.clone(),
+            remainder: self.remainder,
+            flags: self.flags,
+            traps: self.traps,
+            rng_state: self.rng_state,
+            ro_data: self.ro_data.clone(),
+            debug_length: self.debug_length,
+            data_length: self.data_length,
+            data: self.data.clone(),
+        }
+    }
+
+    /// Puts the VM back in the state captured by `snapshot`. A VM that was paused when the
+    /// snapshot was taken can be continued with `resume`.
+    pub fn restore(&mut self, snapshot: &VmSnapshot) {
+        if !Arc::ptr_eq(&self.program, &snapshot.program) {
+            self.decoded = None;
+        }
+        self.registers = snapshot.registers.clone();
+        self.pc = snapshot.pc;
+        self.code_start = snapshot.code_start;
+        self.entry_point = snapshot.entry_point;
+        self.paused = snapshot.paused;
+        self.program = snapshot.program.clone();
+        self.heap = snapshot.heap.clone();
+        self.allocator = snapshot.allocator.clone();
+        self.stack = snapshot.stack.clone();
+        self.call_stack = snapshot.call_stack.clone();
+        self.remainder = snapshot.remainder;
+        self.flags = snapshot.flags;
+        self.traps = snapshot.traps;
+        self.rng_state = snapshot.rng_state;
+        self.ro_data = snapshot.ro_data.clone();
+        self.debug_length = snapshot.debug_length;
+        self.data_length = snapshot.data_length;
+        self.data = snapshot.data.clone();
+        self.crash = None;
+    }
+
+    /// Returns the hooks called around each instruction.
+    pub fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    /// Returns the hooks for registering tracers, profilers and the like. They are called by
+    /// `run` and `run_once`, but not by `run_unchecked`.
+    pub fn hooks_mut(&mut self) -> &mut Hooks {
+        &mut self.hooks
+    }
+
+    /// Makes `run` pause with a `Paused` event before executing the instruction at `address`.
+    /// Returns false if there already was a breakpoint there.
+    pub fn set_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    /// Removes the breakpoint at `address`, returning whether there was one.
+    pub fn clear_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    /// Returns the addresses of the breakpoints, in order.
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().cloned()
+    }
+
+    /// Returns whether the VM is paused at a breakpoint.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Returns the syscalls guest code can make with `SYSCALL`.
+    pub fn syscalls(&self) -> &SyscallTable {
+        &self.syscalls
+    }
+
+    /// Returns the syscall table for registering or removing handlers. See `Syscall` for the
+    /// calling convention.
+    pub fn syscalls_mut(&mut self) -> &mut SyscallTable {
+        &mut self.syscalls
+    }
+
+    /// Replaces the syscall table, e.g. with `SyscallTable::empty()` to sandbox a program.
+    pub fn set_syscalls(&mut self, syscalls: SyscallTable) {
+        self.syscalls = syscalls;
+    }
+
+    /// Writes a line to the VM's output.
+    fn print_line(&self, s: &str) {
+        if let Ok(mut output) = self.output.lock() {
+            output.write_line(s);
+        }
+    }
+
+    /// Reads a line from the VM's input. Returns `None` once the input is exhausted.
+    fn read_line(&self) -> Option<String> {
+        self.input.lock().ok()?.read_line()
+    }
+
+    /// Returns the most instructions `run` executes before stopping the VM. A fused pair of
+    /// instructions counts as one.
+    pub fn instruction_budget(&self) -> Option<u64> {
+        self.instruction_budget
+    }
+
+    /// Limits how many instructions `run` executes, so a program stuck in a loop cannot hang its
+    /// thread. `None` removes the limit.
+    pub fn set_instruction_budget(&mut self, budget: Option<u64>) {
+        self.instruction_budget = budget;
+    }
+
+    /// Returns the flag that stops the VM when set, e.g. from the thread that spawned it.
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// Replaces the VM's stop flag, so a clone can be stopped independently of the original.
+    pub fn set_stop_handle(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = stop;
+    }
+
+    /// Returns the counter of the instructions `run` has executed since the program started, e.g.
+    /// to watch a VM's progress from the thread that spawned it. A fused pair of instructions
+    /// counts as one.
+    pub fn instruction_counter(&self) -> Arc<AtomicU64> {
+        self.executed.clone()
+    }
+
+    /// Returns the memory this VM shares with other VMs.
+    pub fn shared_heap(&self) -> Arc<SharedHeap> {
+        self.shared_heap.clone()
+    }
+
+    /// Attaches the VM to a shared heap segment, e.g. the one of the VM that spawned it.
+    pub fn set_shared_heap(&mut self, shared_heap: Arc<SharedHeap>) {
+        self.shared_heap = shared_heap;
+    }
+
+    /// Returns the handler address registered for trap `vector`.
+    pub fn trap_handler(&self, vector: usize) -> Option<usize> {
+        self.traps.get(vector).cloned().flatten()
+    }
+
+    /// Registers `handler` as the address to jump to when trap `vector` is raised, or removes the
+    /// handler when it is `None`. Returns false if there is no such vector.
+    pub fn set_trap_handler(&mut self, vector: usize, handler: Option<usize>) -> bool {
+        match self.traps.get_mut(vector) {
+            Some(entry) => {
+                *entry = handler;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the read-only data section.
+    pub fn ro_data(&self) -> &[u8] {
+        &self.ro_data
+    }
+
+    /// Replaces the read-only data section.
+    pub fn set_ro_data(&mut self, ro_data: Vec<u8>) {
+        self.ro_data = ro_data;
+    }
+
+    /// Returns the writable data.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Replaces the writable data.
+    pub fn set_data(&mut self, data: Vec<u8>) {
+        self.data = data;
+    }
+
+    /// Returns the bytes from `address` to the end of its section, in the addresses `LDB`, `LDW`
+    /// and `PRTS` read: the read-only section starts at 0, and the writable data follows it.
+    pub fn data_at(&self, address: usize) -> Option<&[u8]> {
+        match address.checked_sub(self.ro_data.len()) {
+            None => self.ro_data.get(address..),
+            Some(offset) => self.data.get(offset..),
+        }
+    }
+
+    /// Returns the `len` writable bytes at `address`, in the addresses `data_at` reads, for `STB`
+    /// and `STW` to store to.
+    fn data_at_mut(&mut self, address: usize, len: usize) -> Result<&mut [u8], CrashReason> {
+        let offset = match address.checked_sub(self.ro_data.len()) {
+            Some(offset) => offset,
+            None => return Err(CrashReason::WriteToReadOnly { address }),
+        };
+        offset
+            .checked_add(len)
+            .and_then(move |end| self.data.get_mut(offset..end))
+            .ok_or(CrashReason::MemoryOutOfBounds)
+    }
+
+    /// Returns the flags set by the last `CMP` or arithmetic instruction.
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    /// Returns whether the last arithmetic instruction overflowed as signed arithmetic.
+    pub fn overflow_flag(&self) -> bool {
+        self.flags.overflow()
+    }
+
+    /// Returns whether the last arithmetic instruction carried as unsigned arithmetic.
+    pub fn carry_flag(&self) -> bool {
+        self.flags.carry()
+    }
+
+    /// Sets the flags for an arithmetic instruction that produced `result`. The condition flag is
+    /// left alone, so a comparison can still be tested after arithmetic.
+    #[inline]
+    fn set_result_flags(&mut self, result: i32, carry: bool, overflow: bool) {
+        self.flags =
+            Flags::from_result(result, carry, overflow).with_condition(self.flags.condition());
+    }
+
+    /// Seeds the generator `RAND` draws from, so the numbers a program gets are reproducible.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng_state = seed;
+    }
+
+    /// Returns the next number from the VM's pseudo-random generator (SplitMix64).
+    fn next_random(&mut self) -> i32 {
+        self.rng_state = self.rng_state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.rng_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        ((z ^ (z >> 31)) >> 32) as i32
+    }
+
+    /// Returns the values on the stack, oldest first.
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    /// Returns the stack pointer: the number of values on the stack.
+    pub fn sp(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Returns how many subroutine calls have not yet returned.
+    pub fn call_depth(&self) -> usize {
+        self.call_stack.len()
+    }
+
+    /// Returns the program counter: the address of the next instruction to execute.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Returns the contents of the heap.
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    /// Returns where the code starts: after the header and the read-only section that follows it.
+    /// Returns `None` if the header claims a read-only section longer than the program.
+    fn code_start(&self) -> Option<usize> {
+        let length = self.header_field(PIE_HEADER_RO_LENGTH_OFFSET) as usize;
+        let code_start = PIE_HEADER_LENGTH.checked_add(length)?;
+        if length > 0 && code_start > self.program.len() {
+            return None;
+        }
+        Some(code_start)
+    }
+
+    /// Returns the entry point recorded in the header by `.entry`, if there is one.
+    fn header_entry_point(&self) -> Option<usize> {
+        match self.header_field(PIE_HEADER_ENTRY_OFFSET) {
+            0 => None,
+            entry_point => Some(entry_point as usize),
+        }
+    }
+
+    /// Returns the addresses of the code section: from the end of the read-only section to the
+    /// start of the writable data or debug section, or the end of the program. Returns `None` if
+    /// the header claims a read-only section longer than the program.
+    pub fn code_range(&self) -> Option<Range<usize>> {
+        Some(self.code_start()?..self.code_end())
+    }
+
+    /// Returns the little endian `u32` at `offset` in the header, or 0 if the program is too short
+    /// to have it.
+    fn header_field(&self, offset: usize) -> u32 {
+        match self.program.get(offset..offset + 4) {
+            Some(bytes) => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            None => 0,
+        }
+    }
+
+    /// Checks that the program starts with a complete header for a version of the bytecode format
+    /// this VM can run, and that the body matches the header's checksum.
+    pub fn verify_header(&self) -> Result<(), HeaderError> {
+        if self.program.len() < PIE_HEADER_LENGTH {
+            return Err(HeaderError::Truncated);
+        }
+        if self.program[0..4] != PIE_HEADER_PREFIX {
+            return Err(HeaderError::BadPrefix);
+        }
+        let version = self.program[PIE_HEADER_VERSION_OFFSET];
+        if version != PIE_VERSION {
+            return Err(HeaderError::UnsupportedVersion { version });
+        }
+        let expected = self.header_field(PIE_HEADER_CHECKSUM_OFFSET);
+        let found = crc32fast::hash(&self.program[PIE_HEADER_LENGTH..]);
+        if found != expected {
+            return Err(HeaderError::ChecksumMismatch { expected, found });
+        }
+        let sections = PIE_HEADER_LENGTH
+            + self.header_field(PIE_HEADER_RO_LENGTH_OFFSET) as usize
+            + self.header_debug_length();
+        if self.header_debug_length() > 0 && sections > self.program.len() {
+            return Err(HeaderError::DebugSectionPastEnd);
+        }
+        if self.header_data_length() > 0
+            && sections + self.header_data_length() > self.program.len()
+        {
+            return Err(HeaderError::DataSectionPastEnd);
+        }
+        Ok(())
+    }
+
+    /// Returns the length of the debug section recorded in the header.
+    fn header_debug_length(&self) -> usize {
+        self.header_field(PIE_HEADER_DEBUG_OFFSET) as usize
+    }
+
+    /// Returns the length of the writable data recorded in the header.
+    fn header_data_length(&self) -> usize {
+        self.header_field(PIE_HEADER_DATA_OFFSET) as usize
+    }
+
+    /// Returns the end of the code section: the end of the program, or the start of its writable
+    /// data or debug section if it has them.
+    #[inline]
+    fn code_end(&self) -> usize {
+        self.program
+            .len()
+            .saturating_sub(self.debug_length + self.data_length)
+    }
+
+    /// Returns the debug info the program was assembled with, if it has any.
+    pub fn debug_info(&self) -> Option<DebugInfo> {
+        if self.debug_length == 0 {
+            return None;
+        }
+        DebugInfo::from_bytes(&self.program[self.program.len() - self.debug_length..])
+    }
+
+    /// Returns where in the source the instruction at `pc` was assembled from, if the program was
+    /// assembled with debug info, e.g. to report where it crashed.
+    pub fn source_position(&self, pc: usize) -> Option<SourcePosition> {
+        self.debug_info()?.position(pc)
+    }
+
+    /// Returns the non-empty regions of the VM's memory in address order. The program's sections
+    /// are at their offsets in the program, and the heap follows the end of the program. The data
+    /// region holds the writable data as the program has changed it.
+    pub fn memory_regions(&self) -> Vec<(MemoryRegion, Range<usize>)> {
+        let len = self.program.len();
+        let header_end = PIE_HEADER_LENGTH.min(len);
+        let code_start = self.code_start().unwrap_or(len).clamp(header_end, len);
+        let code_end = self.code_end().max(code_start);
+        let data_end = (code_end + self.data_length).min(len);
+        let mut regions = vec![
+            (MemoryRegion::Header, 0..header_end),
+            (MemoryRegion::ReadOnly, header_end..code_start),
+            (MemoryRegion::Code, code_start..code_end),
+            (MemoryRegion::Data, code_end..data_end),
+            (MemoryRegion::Debug, data_end..len),
+            (MemoryRegion::Heap, len..len + self.heap.len()),
+        ];
+        regions.retain(|(_, range)| !range.is_empty());
+        regions
+    }
+
+    /// Returns the bytes at `range` of the addresses laid out by `memory_regions`, or `None` if it
+    /// is not all in the program or all in the heap.
+    pub fn memory(&self, range: Range<usize>) -> Option<&[u8]> {
+        let len = self.program.len();
+        let code_end = self.code_end();
+        if range.start >= code_end && range.end <= code_end + self.data_length {
+            self.data.get(range.start - code_end..range.end - code_end)
+        } else if range.end <= len {
+            self.program.get(range)
+        } else if range.start >= len {
+            self.heap.get(range.start - len..range.end - len)
+        } else {
+            None
+        }
+    }
+}
+
+/// The execution state of a VM, taken with `VM::snapshot` and put back with `VM::restore`. The
+/// VM's id, events, I/O handles, host functions, syscalls, hooks and breakpoints are not part of
+/// it, so restoring a snapshot leaves them as they are.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VmSnapshot {
+    registers: Vec<i32>,
+    pc: usize,
+    code_start: usize,
+    entry_point: Option<usize>,
+    paused: bool,
+    program: Arc<Program>,
+    heap: Vec<u8>,
+    allocator: Allocator,
+    stack: Vec<i32>,
+    call_stack: Vec<usize>,
+    remainder: u32,
+    flags: Flags,
+    traps: [Option<usize>; TRAP_VECTORS],
+    rng_state: u64,
+    ro_data: Vec<u8>,
+    #[serde(default)]
+    debug_length: usize,
+    #[serde(default)]
+    data_length: usize,
+    #[serde(default)]
+    data: Vec<u8>,
+}
+
+impl VmSnapshot {
+    /// Returns the registers at the time of the snapshot.
+    pub fn registers(&self) -> &[i32] {
+        &self.registers
+    }
+
+    /// Returns the program counter at the time of the snapshot.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+}
+
+/// Builds a `VM` whose register count, initial heap or entry point differ from the defaults.
+#[derive(Clone, Debug)]
+pub struct VMBuilder {
+    registers: usize,
+    heap_size: usize,
+    heap_limit: usize,
+    entry_point: Option<usize>,
+}
+
+impl Default for VMBuilder {
+    fn default() -> Self {
+        VMBuilder {
+            registers: REGISTER_COUNT,
+            heap_size: 0,
+            heap_limit: DEFAULT_HEAP_LIMIT,
+            entry_point: None,
+        }
+    }
+}
+
+impl VMBuilder {
+    /// Sets the number of registers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is zero, since `CALLHOST` and `SYSCALL` return into `$0`, or more than
+    /// `MAX_REGISTER_COUNT`, since register operands could not name the rest.
+    pub fn registers(mut self, count: usize) -> VMBuilder {
+        assert!(
+            count > 0 && count <= MAX_REGISTER_COUNT,
+            "A VM needs between 1 and {} registers, not {}",
+            MAX_REGISTER_COUNT,
+            count
+        );
+        self.registers = count;
+        self
+    }
+
+    /// Sets how many zeroed bytes of heap the VM starts with. `ALOC` allocates after them.
+    pub fn heap_size(mut self, bytes: usize) -> VMBuilder {
+        self.heap_size = bytes;
+        self
+    }
+
+    /// Sets the most bytes of heap the program's `.space` reservations can take,
+    /// `DEFAULT_HEAP_LIMIT` unless set. The VM crashes when it starts a program that needs more.
+    pub fn heap_limit(mut self, bytes: usize) -> VMBuilder {
+        self.heap_limit = bytes;
+        self
+    }
+
+    /// Sets the address `run` starts executing at, instead of the start of the code section. The
+    /// VM crashes when it starts if this is not an instruction in the code section.
+    pub fn entry_point(mut self, pc: usize) -> VMBuilder {
+        self.entry_point = Some(pc);
+        self
+    }
+
+    /// Returns the new `VM`.
+    pub fn build(self) -> VM {
+        let mut vm = VM::new();
+        vm.registers = vec![0; self.registers];
+        vm.heap = vec![0; self.heap_size];
+        vm.heap_size = self.heap_size;
+        vm.heap_limit = self.heap_limit;
+        if let Some(entry_point) = self.entry_point {
+            vm.pc = entry_point;
+            vm.entry_point = Some(entry_point);
+        }
+        vm
+    }
+}
+
+/// Executes one instruction. Receives the VM with its program counter already moved past the
+/// instruction, the instruction, and the address it was fetched from. Returns an exit code to stop
+/// the VM.
+type Handler = fn(&mut VM, DecodedInstruction, usize) -> Option<u32>;
+
+/// Where the program counter of a new or reset VM points until `run` moves it to the entry point.
+const INITIAL_PC: usize = 65;
+
+fn default_code_start() -> usize {
+    PIE_HEADER_LENGTH
+}
+
+fn default_heap_limit() -> usize {
+    DEFAULT_HEAP_LIMIT
+}
+
+/// Returns a random id for a new VM.
+#[cfg(not(target_arch = "wasm32"))]
+fn new_id() -> Uuid {
+    Uuid::new_v4()
+}
+
+/// Returns an id for a new VM. There is no source of randomness on wasm32-unknown-unknown, so
+/// VMs are numbered in the order they are created instead.
+#[cfg(target_arch = "wasm32")]
+fn new_id() -> Uuid {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut bytes = [0; 16];
+    // The first half seeds the VM's random number generator, so it is scrambled.
+    bytes[..8].copy_from_slice(&id.wrapping_mul(0x9E37_79B9_7F4A_7C15).to_be_bytes());
+    bytes[8..].copy_from_slice(&id.to_be_bytes());
+    Uuid::from_bytes(bytes)
+}
+
+/// Returns a seed for a new VM's random number generator, different for every VM.
+fn rand_seed() -> u64 {
+    let id = new_id();
+    let mut seed = 0;
+    for byte in &id.as_bytes()[..8] {
+        seed = (seed << 8) | u64::from(*byte);
+    }
+    seed
+}
+
+/// The handler for every opcode byte. Bytes with no opcode crash the VM.
+static HANDLERS: [Handler; 256] = {
+    let mut table = [illegal as Handler; 256];
+    table[Opcode::HLT as usize] = hlt;
+    table[Opcode::LOAD as usize] = load;
+    table[Opcode::ADD as usize] = add;
+    table[Opcode::SUB as usize] = sub;
+    table[Opcode::MUL as usize] = mul;
+    table[Opcode::DIV as usize] = div;
+    table[Opcode::JMP as usize] = jmp;
+    table[Opcode::JMPF as usize] = jmpf;
+    table[Opcode::JMPB as usize] = jmpb;
+    table[Opcode::EQ as usize] = eq;
+    table[Opcode::NEQ as usize] = neq;
+    table[Opcode::GT as usize] = gt;
+    table[Opcode::LT as usize] = lt;
+    table[Opcode::GTQ as usize] = gtq;
+    table[Opcode::LTQ as usize] = ltq;
+    table[Opcode::JEQ as usize] = jeq;
+    table[Opcode::JNEQ as usize] = jneq;
+    table[Opcode::ALOC as usize] = aloc;
+    table[Opcode::INC as usize] = inc;
+    table[Opcode::DEC as usize] = dec;
+    table[Opcode::CALLHOST as usize] = callhost;
+    table[Opcode::AND as usize] = and;
+    table[Opcode::OR as usize] = or;
+    table[Opcode::XOR as usize] = xor;
+    table[Opcode::NOT as usize] = not;
+    table[Opcode::SHL as usize] = shl;
+    table[Opcode::SHR as usize] = shr;
+    table[Opcode::ROL as usize] = rol;
+    table[Opcode::ROR as usize] = ror;
+    table[Opcode::MOD as usize] = modulo;
+    table[Opcode::GETR as usize] = getr;
+    table[Opcode::PUSH as usize] = push;
+    table[Opcode::POP as usize] = pop;
+    table[Opcode::CALL as usize] = call;
+    table[Opcode::CALLR as usize] = callr;
+    table[Opcode::RET as usize] = ret;
+    table[Opcode::SYSCALL as usize] = syscall;
+    table[Opcode::PRTS as usize] = prts;
+    table[Opcode::LUI as usize] = lui;
+    table[Opcode::JO as usize] = jo;
+    table[Opcode::JNO as usize] = jno;
+    table[Opcode::JC as usize] = jc;
+    table[Opcode::JNC as usize] = jnc;
+    table[Opcode::JMPL as usize] = jmpl;
+    table[Opcode::JEQL as usize] = jeql;
+    table[Opcode::JNEQL as usize] = jneql;
+    table[Opcode::DJNZ as usize] = djnz;
+    table[Opcode::EQR as usize] = eqr;
+    table[Opcode::NEQR as usize] = neqr;
+    table[Opcode::GTR as usize] = gtr;
+    table[Opcode::LTR as usize] = ltr;
+    table[Opcode::GTQR as usize] = gtqr;
+    table[Opcode::LTQR as usize] = ltqr;
+    table[Opcode::GTU as usize] = gtu;
+    table[Opcode::LTU as usize] = ltu;
+    table[Opcode::GTEU as usize] = gteu;
+    table[Opcode::LTEU as usize] = lteu;
+    table[Opcode::AADD as usize] = aadd;
+    table[Opcode::CAS as usize] = cas;
+    table[Opcode::RAND as usize] = rand;
+    table[Opcode::SLEEP as usize] = sleep;
+    table[Opcode::READI as usize] = readi;
+    table[Opcode::READS as usize] = reads;
+    table[Opcode::EXIT as usize] = exit;
+    table[Opcode::STRAP as usize] = strap;
+    table[Opcode::TRAP as usize] = trap;
+    table[Opcode::FREE as usize] = free;
+    table[Opcode::CMP as usize] = cmp;
+    table[Opcode::JZ as usize] = jz;
+    table[Opcode::JNZ as usize] = jnz;
+    table[Opcode::JLT as usize] = jlt;
+    table[Opcode::JGE as usize] = jge;
+    table[Opcode::JGT as usize] = jgt;
+    table[Opcode::JLE as usize] = jle;
+    table[Opcode::PRTSR as usize] = prtsr;
+    table[Opcode::LDB as usize] = ldb;
+    table[Opcode::STB as usize] = stb;
+    table[Opcode::LDW as usize] = ldw;
+    table[Opcode::STW as usize] = stw;
+    table
+};
+
+/// Executes two adjacent instructions. Receives the VM with its program counter already moved past
+/// both, the instructions, and the address of the first.
+type FusedHandler = fn(&mut VM, DecodedInstruction, DecodedInstruction, usize) -> Option<u32>;
+
+/// Defines a fused handler for each pair of handlers, and `fused_handler` to look them up by
+/// opcode. The first handler of a pair must never stop the VM or move the program counter.
+macro_rules! fused_handlers {
+    ($($name:ident: $first:ident + $second:ident => $first_op:ident, $second_op:ident;)*) => {
+        $(
+            fn $name(
+                vm: &mut VM,
+                first: DecodedInstruction,
+                second: DecodedInstruction,
+                pc: usize,
+            ) -> Option<u32> {
+                $first(vm, first, pc);
+                $second(vm, second, pc + INSTRUCTION_LENGTH)
+            }
+        )*
+
+        /// Returns the handler that executes `first` and `second` together, if they can be fused.
+        fn fused_handler(first: Opcode, second: Opcode) -> Option<FusedHandler> {
+            match (first, second) {
+                $((Opcode::$first_op, Opcode::$second_op) => Some($name),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+fused_handlers! {
+    eq_jeq: eq + jeq => EQ, JEQ;
+    neq_jeq: neq + jeq => NEQ, JEQ;
+    gt_jeq: gt + jeq => GT, JEQ;
+    lt_jeq: lt + jeq => LT, JEQ;
+    gtq_jeq: gtq + jeq => GTQ, JEQ;
+    ltq_jeq: ltq + jeq => LTQ, JEQ;
+    eq_jneq: eq + jneq => EQ, JNEQ;
+    neq_jneq: neq + jneq => NEQ, JNEQ;
+    gt_jneq: gt + jneq => GT, JNEQ;
+    lt_jneq: lt + jneq => LT, JNEQ;
+    gtq_jneq: gtq + jneq => GTQ, JNEQ;
+    ltq_jneq: ltq + jneq => LTQ, JNEQ;
+    inc_eq: inc + eq => INC, EQ;
+    inc_neq: inc + neq => INC, NEQ;
+    inc_gt: inc + gt => INC, GT;
+    inc_lt: inc + lt => INC, LT;
+    inc_gtq: inc + gtq => INC, GTQ;
+    inc_ltq: inc + ltq => INC, LTQ;
+    dec_eq: dec + eq => DEC, EQ;
+    dec_neq: dec + neq => DEC, NEQ;
+    dec_gt: dec + gt => DEC, GT;
+    dec_lt: dec + lt => DEC, LT;
+    dec_gtq: dec + gtq => DEC, GTQ;
+    dec_ltq: dec + ltq => DEC, LTQ;
+    load_add: load + add => LOAD, ADD;
+    load_sub: load + sub => LOAD, SUB;
+    load_mul: load + mul => LOAD, MUL;
+}
+
+fn hlt(vm: &mut VM, _: DecodedInstruction, pc: usize) -> Option<u32> {
+    vm.pc = pc + 1;
+    vm.print_line("HLT encountered");
+    Some(0)
+}
+
+/// Registers the address given by the immediate operand as the handler for the trap vector in the
+/// register operand.
+fn strap(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let vector = vm.registers[instruction.register(0)];
+    let handler = instruction.immediate(1) as usize;
+    if vector < 0 || !vm.set_trap_handler(vector as usize, Some(handler)) {
+        tracing::error!(pc, vector, "STRAP vector is outside the trap table");
+        return vm.crash(CrashReason::InvalidTrapVector, pc);
+    }
+    None
+}
+
+/// Raises the trap vector in the register operand.
+fn trap(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let vector = vm.registers[instruction.register(0)];
+    if vector < 0 || vector as usize >= TRAP_VECTORS {
+        tracing::error!(pc, vector, "TRAP vector is outside the trap table");
+        return vm.crash(CrashReason::InvalidTrapVector, pc);
+    }
+    vm.raise_trap(vector as usize, pc)
+}
+
+/// Stops the VM with the value of the register operand as its exit code.
+fn exit(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    vm.pc = pc + INSTRUCTION_LENGTH;
+    Some(vm.registers[instruction.register(0)] as u32)
+}
+
+fn load(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+    // Our registers are i32s, so the 16 bit immediate is sign-extended.
+    vm.registers[instruction.register(0)] = instruction.immediate(1) as i16 as i32;
+    None
+}
+
+/// Replaces the upper 16 bits of a register with the immediate operand, keeping the lower 16.
+fn lui(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+    let register = &mut vm.registers[instruction.register(0)];
+    *register = ((instruction.immediate(1) as u32) << 16 | (*register as u32 & 0xFFFF)) as i32;
+    None
+}
+
+/// Defines a handler that stores the wrapped result of an arithmetic operation on the registers
+/// named by the first two operands in the register named by the third, and sets the overflow and
+/// carry flags.
+macro_rules! checked_arithmetic_handler {
+    ($name:ident, $op:ident) => {
+        fn $name(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+            let (register1, register2) = vm.operand_registers(&instruction);
+            let (result, overflow) = register1.$op(register2);
+            let carry = (register1 as u32).$op(register2 as u32).1;
+            vm.set_result_flags(result, carry, overflow);
+            vm.registers[instruction.register(2)] = result;
+            None
+        }
+    };
+}
+
+checked_arithmetic_handler!(add, overflowing_add);
+checked_arithmetic_handler!(sub, overflowing_sub);
+checked_arithmetic_handler!(mul, overflowing_mul);
+
+/// Defines a handler that stores the result of an arithmetic operation on the registers named by
+/// the first two operands in the register named by the third.
+macro_rules! arithmetic_handler {
+    ($name:ident, $op:tt) => {
+        fn $name(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+            let (register1, register2) = vm.operand_registers(&instruction);
+            vm.registers[instruction.register(2)] = register1 $op register2;
+            None
+        }
+    };
+}
+
+arithmetic_handler!(and, &);
+arithmetic_handler!(or, |);
+arithmetic_handler!(xor, ^);
+
+/// Defines a handler that shifts or rotates the bits of the first register operand by the number
+/// of places in the second, storing the result in the third. Only the low five bits of the amount
+/// are used, so every shift stays within the register.
+macro_rules! shift_handler {
+    ($name:ident, $shift:ident) => {
+        fn $name(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+            let (value, amount) = vm.operand_registers(&instruction);
+            vm.registers[instruction.register(2)] = (value as u32).$shift(amount as u32) as i32;
+            None
+        }
+    };
+}
+
+shift_handler!(shl, wrapping_shl);
+// Shifts right logically, filling with zeros rather than the sign bit.
+shift_handler!(shr, wrapping_shr);
+shift_handler!(rol, rotate_left);
+shift_handler!(ror, rotate_right);
+
+/// Stores the bitwise complement of the first register operand in the second.
+fn not(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+    vm.registers[instruction.register(1)] = !vm.registers[instruction.register(0)];
+    None
+}
+
+fn div(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let (register1, register2) = vm.operand_registers(&instruction);
+    if register2 == 0 {
+        return vm.raise_trap(TRAP_DIVIDE_BY_ZERO, pc);
+    }
+    // Only `i32::MIN / -1` overflows, and wraps back to `i32::MIN`.
+    let (result, overflow) = register1.overflowing_div(register2);
+    vm.set_result_flags(result, false, overflow);
+    vm.registers[instruction.register(2)] = result;
+    vm.remainder = register1.wrapping_rem(register2) as u32;
+    None
+}
+
+fn modulo(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let (register1, register2) = vm.operand_registers(&instruction);
+    if register2 == 0 {
+        return vm.raise_trap(TRAP_DIVIDE_BY_ZERO, pc);
+    }
+    vm.registers[instruction.register(2)] = register1.wrapping_rem(register2);
+    None
+}
+
+/// Copies the remainder left by the last `DIV` into a register.
+fn getr(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+    vm.registers[instruction.register(0)] = vm.remainder as i32;
+    None
+}
+
+fn jmp(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let target = vm.registers[instruction.register(0)] as usize;
+    vm.jump(target, pc)
+}
+
+// Relative jumps count from the byte after the register operand. Targets before address 0 wrap
+// around to addresses past the end of the program, which `jump` rejects.
+fn jmpf(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let value = vm.registers[instruction.register(0)];
+    vm.jump((pc as i64 + 2 + i64::from(value)) as usize, pc)
+}
+
+fn jmpb(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let value = vm.registers[instruction.register(0)];
+    vm.jump((pc as i64 + 2 - i64::from(value)) as usize, pc)
+}
+
+/// Defines a handler that compares the registers named by the first two operands like `CMP`, and
+/// sets the condition flag to the result of the comparison, as signed values unless another type is
+/// given.
+macro_rules! comparison_handler {
+    ($name:ident, $op:tt) => {
+        comparison_handler!($name, $op, i32);
+    };
+    ($name:ident, $op:tt, $type:ty) => {
+        fn $name(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+            let (register1, register2) = vm.operand_registers(&instruction);
+            let condition = (register1 as $type) $op (register2 as $type);
+            vm.flags = Flags::compare(register1, register2).with_condition(condition);
+            None
+        }
+    };
+}
+
+comparison_handler!(eq, ==);
+comparison_handler!(neq, !=);
+comparison_handler!(gt, >);
+comparison_handler!(lt, <);
+comparison_handler!(gtq, >=);
+comparison_handler!(ltq, <=);
+comparison_handler!(gtu, >, u32);
+comparison_handler!(ltu, <, u32);
+comparison_handler!(gteu, >=, u32);
+comparison_handler!(lteu, <=, u32);
+
+/// Defines a handler that writes 1 to the register named by the third operand if comparing the
+/// registers named by the first two is true, and 0 otherwise. The flags are left alone.
+macro_rules! register_comparison_handler {
+    ($name:ident, $op:tt) => {
+        fn $name(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+            let (register1, register2) = vm.operand_registers(&instruction);
+            vm.registers[instruction.register(2)] = (register1 $op register2) as i32;
+            None
+        }
+    };
+}
+
+register_comparison_handler!(eqr, ==);
+register_comparison_handler!(neqr, !=);
+register_comparison_handler!(gtr, >);
+register_comparison_handler!(ltr, <);
+register_comparison_handler!(gtqr, >=);
+register_comparison_handler!(ltqr, <=);
+
+/// Defines a handler that jumps to the address in the register operand when a condition on the
+/// VM's flags holds.
+macro_rules! flag_jump_handler {
+    ($name:ident, |$vm:ident| $condition:expr) => {
+        fn $name($vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+            if $condition {
+                return $vm.jump($vm.registers[instruction.register(0)] as usize, pc);
+            }
+            None
+        }
+    };
+}
+
+flag_jump_handler!(jeq, |vm| vm.flags.condition());
+flag_jump_handler!(jneq, |vm| !vm.flags.condition());
+flag_jump_handler!(jo, |vm| vm.flags.overflow());
+flag_jump_handler!(jno, |vm| !vm.flags.overflow());
+flag_jump_handler!(jc, |vm| vm.flags.carry());
+flag_jump_handler!(jnc, |vm| !vm.flags.carry());
+flag_jump_handler!(jz, |vm| vm.flags.zero());
+flag_jump_handler!(jnz, |vm| !vm.flags.zero());
+flag_jump_handler!(jlt, |vm| vm.flags.less());
+flag_jump_handler!(jge, |vm| !vm.flags.less());
+flag_jump_handler!(jgt, |vm| vm.flags.greater());
+flag_jump_handler!(jle, |vm| !vm.flags.greater());
+
+/// Compares the register operands, setting the flags as `SUB` would without storing the result,
+/// and the condition flag to whether they are equal.
+fn cmp(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+    let (register1, register2) = vm.operand_registers(&instruction);
+    vm.flags = Flags::compare(register1, register2).with_condition(register1 == register2);
+    None
+}
+
+fn jmpl(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    vm.jump(instruction.address(), pc)
+}
+
+fn jeql(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    if vm.flags.condition() {
+        return vm.jump(instruction.address(), pc);
+    }
+    None
+}
+
+fn jneql(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    if !vm.flags.condition() {
+        return vm.jump(instruction.address(), pc);
+    }
+    None
+}
+
+/// Decrements the register operand and, unless it reached zero, jumps to the address given by the
+/// immediate operand.
+fn djnz(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let register = &mut vm.registers[instruction.register(0)];
+    *register = register.wrapping_sub(1);
+    if *register != 0 {
+        return vm.jump(instruction.immediate(1) as usize, pc);
+    }
+    None
+}
+
+/// Fills the register operand with a pseudo-random number.
+fn rand(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+    vm.registers[instruction.register(0)] = vm.next_random();
+    None
+}
+
+/// Suspends the VM for the number of milliseconds in the register operand. Negative durations do
+/// not sleep at all.
+#[cfg(not(target_arch = "wasm32"))]
+fn sleep(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+    let millis = vm.registers[instruction.register(0)].max(0) as u64;
+    let deadline = Instant::now() + Duration::from_millis(millis);
+    loop {
+        let now = Instant::now();
+        if now >= deadline || vm.stop.load(Ordering::Relaxed) {
+            return None;
+        }
+        thread::sleep((deadline - now).min(SLEEP_SLICE));
+    }
+}
+
+/// Crashes the VM rather than waiting, as wasm32 has no clock or threads to wait with, and
+/// calling them panics. Durations that would not wait at all still do nothing.
+#[cfg(target_arch = "wasm32")]
+fn sleep(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    if vm.registers[instruction.register(0)] <= 0 {
+        return None;
+    }
+    tracing::error!(pc, "SLEEP is not supported on this target");
+    vm.crash(CrashReason::SleepUnsupported, pc)
+}
+
+/// Allocates a zeroed heap block of the size in the register operand, and replaces the size with
+/// the block's offset.
+fn aloc(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let size = vm.registers[instruction.register(0)];
+    if size < 0 {
+        tracing::error!(pc, size, "ALOC size is negative");
+        return vm.crash(CrashReason::InvalidAllocation { size }, pc);
+    }
+    let pointer = vm.allocator.alloc(&mut vm.heap, size as usize);
+    vm.registers[instruction.register(0)] = pointer as i32;
+    None
+}
+
+/// Frees the heap block whose offset is in the register operand.
+fn free(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let pointer = vm.registers[instruction.register(0)];
+    if pointer < 0 || !vm.allocator.free(&mut vm.heap, pointer as usize) {
+        tracing::error!(pc, pointer, "FREE pointer is not an allocated block");
+        return vm.crash(CrashReason::InvalidFree { pointer }, pc);
+    }
+    None
+}
+
+/// Defines a handler that adds or subtracts 1 from the register operand, wrapping on overflow, and
+/// sets the flags as `ADD` or `SUB` would.
+macro_rules! step_handler {
+    ($name:ident, $op:ident) => {
+        fn $name(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+            let register = vm.registers[instruction.register(0)];
+            let (result, overflow) = register.$op(1);
+            let carry = (register as u32).$op(1).1;
+            vm.set_result_flags(result, carry, overflow);
+            vm.registers[instruction.register(0)] = result;
+            None
+        }
+    };
+}
+
+step_handler!(inc, overflowing_add);
+step_handler!(dec, overflowing_sub);
+
+fn push(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    if vm.stack.len() >= MAX_STACK_DEPTH {
+        tracing::warn!(pc, "Stack overflow");
+        return vm.raise_trap(TRAP_STACK_FAULT, pc);
+    }
+    vm.stack.push(vm.registers[instruction.register(0)]);
+    None
+}
+
+fn pop(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    match vm.stack.pop() {
+        Some(value) => {
+            vm.registers[instruction.register(0)] = value;
+            None
+        }
+        None => {
+            tracing::warn!(pc, "Stack underflow");
+            vm.raise_trap(TRAP_STACK_FAULT, pc)
+        }
+    }
+}
+
+fn call(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    vm.call_subroutine(instruction.immediate(0) as usize, pc)
+}
+
+fn callr(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let target = vm.registers[instruction.register(0)] as usize;
+    vm.call_subroutine(target, pc)
+}
+
+fn ret(vm: &mut VM, _: DecodedInstruction, pc: usize) -> Option<u32> {
+    match vm.call_stack.pop() {
+        Some(address) => {
+            tracing::trace!(pc, depth = vm.call_stack.len(), "Returning to {}", address);
+            vm.pc = address;
+            None
+        }
+        None => {
+            tracing::error!(pc, "RET executed outside of a subroutine");
+            vm.crash(CrashReason::ReturnOutsideSubroutine, pc)
+        }
+    }
+}
+
+fn callhost(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let id = instruction.immediate(0);
+    let function = match vm.host_functions.get(id) {
+        Some(function) => function,
+        None => {
+            tracing::error!(pc, id, "No host function registered with id {}", id);
+            return vm.crash(CrashReason::MissingHostFunction, pc);
+        }
+    };
+    let mut call = HostCall {
+        registers: &mut vm.registers,
+        heap: &mut vm.heap,
+    };
+    vm.registers[0] = function(&mut call);
+    None
+}
+
+fn syscall(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let number = vm.registers[instruction.register(0)] as u32;
+    let handler = match vm.syscalls.get(number) {
+        Some(handler) => handler,
+        None => {
+            tracing::error!(pc, number, "No syscall registered with number {}", number);
+            return vm.crash(CrashReason::MissingSyscall, pc);
+        }
+    };
+    let mut call = Syscall {
+        registers: &mut vm.registers,
+        heap: &mut vm.heap,
+        output: &vm.output,
+        input: &vm.input,
+    };
+    vm.registers[0] = handler(&mut call);
+    None
+}
+
+/// Atomically adds the second register operand to the shared heap word indexed by the first, and
+/// writes the word's previous value to the third.
+fn aadd(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let index = vm.registers[instruction.register(0)] as usize;
+    let value = vm.registers[instruction.register(1)];
+    match vm.shared_heap.fetch_add(index, value) {
+        Some(previous) => {
+            vm.registers[instruction.register(2)] = previous;
+            None
+        }
+        None => {
+            tracing::error!(pc, index, "AADD index is outside the shared heap");
+            vm.crash(CrashReason::MemoryOutOfBounds, pc)
+        }
+    }
+}
+
+/// Atomically stores the third register operand in the shared heap word indexed by the first, if
+/// the word equals the second. The word's previous value is written to the second register, and
+/// the flags are set as `CMP` would set them comparing it with the expected value.
+fn cas(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let index = vm.registers[instruction.register(0)] as usize;
+    let current = vm.registers[instruction.register(1)];
+    let new = vm.registers[instruction.register(2)];
+    match vm.shared_heap.compare_and_swap(index, current, new) {
+        Some(previous) => {
+            vm.flags = Flags::compare(previous, current).with_condition(previous == current);
+            vm.registers[instruction.register(1)] = previous;
+            None
+        }
+        None => {
+            tracing::error!(pc, index, "CAS index is outside the shared heap");
+            vm.crash(CrashReason::MemoryOutOfBounds, pc)
+        }
+    }
+}
+
+/// Reads a line holding an integer into the register operand. The condition flag is set if one was
+/// read; at the end of the input, or if the line is not an integer, it is cleared and the register
+/// is left alone.
+fn readi(vm: &mut VM, instruction: DecodedInstruction, _: usize) -> Option<u32> {
+    match vm.read_line().and_then(|line| line.trim().parse().ok()) {
+        Some(value) => {
+            vm.registers[instruction.register(0)] = value;
+            vm.flags = vm.flags.with_condition(true);
+        }
+        None => vm.flags = vm.flags.with_condition(false),
+    }
+    None
+}
+
+/// Reads a line into the heap buffer at the offset in the first register operand, truncating it to
+/// the capacity in the second. The number of bytes read, 0 at the end of the input, is written to
+/// the third.
+fn reads(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let start = vm.registers[instruction.register(0)];
+    let capacity = vm.registers[instruction.register(1)];
+    let end = start.checked_add(capacity);
+    let (start, end) = match end {
+        Some(end) if start >= 0 && capacity >= 0 && end as usize <= vm.heap.len() => {
+            (start as usize, end as usize)
+        }
+        _ => {
+            tracing::error!(pc, start, capacity, "READS buffer is outside the heap");
+            return vm.crash(CrashReason::MemoryOutOfBounds, pc);
+        }
+    };
+    let line = vm.read_line().unwrap_or_default();
+    let len = line.len().min(end - start);
+    vm.heap[start..start + len].copy_from_slice(&line.as_bytes()[..len]);
+    vm.registers[instruction.register(2)] = len as i32;
+    None
+}
+
+/// Loads the byte at the data address in the second register operand into the first.
+fn ldb(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let address = vm.registers[instruction.register(1)] as u32 as usize;
+    match vm.data_at(address).and_then(|bytes| bytes.first()) {
+        Some(&byte) => {
+            vm.registers[instruction.register(0)] = i32::from(byte);
+            None
+        }
+        None => {
+            tracing::error!(pc, address, "LDB address is outside the data");
+            vm.crash(CrashReason::MemoryOutOfBounds, pc)
+        }
+    }
+}
+
+/// Stores the low byte of the first register operand at the data address in the second.
+fn stb(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let value = vm.registers[instruction.register(0)];
+    let address = vm.registers[instruction.register(1)] as u32 as usize;
+    match vm.data_at_mut(address, 1) {
+        Ok(bytes) => {
+            bytes[0] = value as u8;
+            None
+        }
+        Err(reason) => {
+            tracing::error!(pc, address, "STB address is not writable data");
+            vm.crash(reason, pc)
+        }
+    }
+}
+
+/// Loads the little endian word at the data address in the second register operand into the
+/// first.
+fn ldw(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let address = vm.registers[instruction.register(1)] as u32 as usize;
+    match vm.data_at(address).and_then(|bytes| bytes.get(..4)) {
+        Some(bytes) => {
+            vm.registers[instruction.register(0)] =
+                i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            None
+        }
+        None => {
+            tracing::error!(pc, address, "LDW address is outside the data");
+            vm.crash(CrashReason::MemoryOutOfBounds, pc)
+        }
+    }
+}
+
+/// Stores the first register operand as a little endian word at the data address in the second.
+fn stw(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let value = vm.registers[instruction.register(0)];
+    let address = vm.registers[instruction.register(1)] as u32 as usize;
+    match vm.data_at_mut(address, 4) {
+        Ok(bytes) => {
+            bytes.copy_from_slice(&value.to_le_bytes());
+            None
+        }
+        Err(reason) => {
+            tracing::error!(pc, address, "STW address is not writable data");
+            vm.crash(reason, pc)
+        }
+    }
+}
+
+/// Prints the null-terminated string at the data address given by the immediate operand.
+fn prts(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    print_string(vm, instruction.immediate(0) as usize, pc)
+}
+
+/// Prints the null-terminated string at the data address in the register named by the first
+/// operand, e.g. one loaded with `load $0 @msg`.
+fn prtsr(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    let address = vm.registers[instruction.register(0)];
+    print_string(vm, address as u32 as usize, pc)
+}
+
+fn print_string(vm: &mut VM, address: usize, pc: usize) -> Option<u32> {
+    let bytes = match vm.data_at(address) {
+        Some(bytes) => bytes,
+        None => {
+            tracing::error!(pc, address, "PRTS address is outside the data");
+            return vm.crash(CrashReason::MemoryOutOfBounds, pc);
+        }
+    };
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    let text = String::from_utf8_lossy(&bytes[..end]);
+    if let Ok(mut output) = vm.output.lock() {
+        output.write_str(&text);
+    }
+    None
+}
+
+fn illegal(vm: &mut VM, instruction: DecodedInstruction, pc: usize) -> Option<u32> {
+    vm.pc = pc + 1;
+    tracing::error!(pc, opcode = ?instruction.opcode, "Unrecognized opcode found! Terminating");
+    vm.crash(CrashReason::IllegalOpcode, pc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::PIE_HEADER_LENGTH;
+    use crate::console::{BufferInput, BufferOutput};
+    use std::sync::{Arc, Mutex};
+
+    fn get_test_vm() -> VM {
+        VM::new()
+    }
+
+    fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
+        let mut prepension = vec![];
+        for byte in PIE_HEADER_PREFIX.iter() {
+            prepension.push(*byte);
+        }
+        while prepension.len() <= PIE_HEADER_LENGTH {
+            prepension.push(0);
+        }
+        prepension[PIE_HEADER_VERSION_OFFSET] = PIE_VERSION;
+        prepension.append(&mut b);
+        crate::assembler::write_checksum(&mut prepension);
+        prepension
+    }
+
+    /// Updates the checksum of a program the test has added bytes to.
+    fn update_checksum(vm: &mut VM) {
+        crate::assembler::write_checksum(Arc::make_mut(&mut vm.program).to_mut());
+    }
+
+    #[test]
+    fn test_create_vm() {
+        let test_vm = get_test_vm();
+        assert_eq!(test_vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_opcode_hlt() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(vec![0, 0, 0, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 66);
+    }
+
+    #[test]
+    fn test_output_sink() {
+        let mut test_vm = get_test_vm();
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        test_vm.set_output(output.clone());
+        test_vm.program = Arc::new(prepend_header(vec![0, 0, 0, 0]).into());
+        test_vm.run_once();
+        assert_eq!(output.lock().unwrap().buffer, "HLT encountered\n");
+    }
+
+    #[test]
+    fn test_opcode_igl() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(vec![200, 0, 0, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 66);
+    }
+
+    #[test]
+    fn test_load_opcode() {
+        let mut test_vm = get_test_vm();
+        // Remember, this is how we represent 500 using two u8s in little endian format.
+        test_vm.program = Arc::new(vec![1, 0, 1, 244].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 500);
+    }
+
+    #[test]
+    fn test_add_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(vec![2, 8, 5, 2].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
+        test_vm.registers[5] = 3;
+        test_vm.registers[8] = 7;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], 10);
+    }
+
+    #[test]
+    fn test_sub_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(vec![3, 8, 5, 2].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
+        test_vm.registers[5] = 3;
+        test_vm.registers[8] = 7;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], 4);
+    }
+
+    #[test]
+    fn test_mul_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(vec![4, 8, 5, 2].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
+        test_vm.registers[5] = 3;
+        test_vm.registers[8] = 7;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], 21);
+    }
+
+    #[test]
+    fn test_bitwise_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(
+            prepend_header(vec![22, 8, 5, 2, 23, 8, 5, 3, 24, 8, 5, 4, 25, 8, 6, 0]).into(),
+        );
+        test_vm.registers[5] = 0b1100;
+        test_vm.registers[8] = 0b1010;
+        for _ in 0..4 {
+            test_vm.run_once();
+        }
+        assert_eq!(test_vm.registers[2], 0b1000);
+        assert_eq!(test_vm.registers[3], 0b1110);
+        assert_eq!(test_vm.registers[4], 0b0110);
+        assert_eq!(test_vm.registers[6], !0b1010);
+    }
+
+    #[test]
+    fn test_shift_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(
+            prepend_header(vec![26, 8, 5, 2, 27, 9, 5, 3, 28, 9, 5, 4, 29, 8, 5, 6]).into(),
+        );
+        test_vm.registers[5] = 4;
+        test_vm.registers[8] = 3;
+        test_vm.registers[9] = -8;
+        for _ in 0..4 {
+            test_vm.run_once();
+        }
+        assert_eq!(test_vm.registers[2], 48);
+        assert_eq!(test_vm.registers[3], 0x0FFF_FFFF);
+        assert_eq!(test_vm.registers[4], -113);
+        assert_eq!(test_vm.registers[6], 0x3000_0000);
+
+        // Shift amounts wrap instead of overflowing.
+        test_vm.program = Arc::new(prepend_header(vec![26, 8, 5, 2]).into());
+        test_vm.registers[5] = 33;
+        test_vm.pc = 65;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], 6);
+    }
+
+    #[test]
+    fn test_push_and_pop_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(
+            prepend_header(vec![32, 0, 0, 0, 32, 1, 0, 0, 33, 0, 0, 0, 33, 1, 0, 0]).into(),
+        );
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 9;
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_eq!(test_vm.stack(), &[5, 9]);
+        assert_eq!(test_vm.sp(), 2);
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 9);
+        assert_eq!(test_vm.registers[1], 5);
+        assert_eq!(test_vm.sp(), 0);
+    }
+
+    #[test]
+    fn test_stack_overflow_and_underflow() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(prepend_header(vec![33, 0, 0, 0]).into());
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+
+        test_vm.program = Arc::new(prepend_header(vec![32, 0, 0, 0]).into());
+        test_vm.stack = vec![0; MAX_STACK_DEPTH];
+        test_vm.pc = 65;
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+        assert_eq!(test_vm.sp(), MAX_STACK_DEPTH);
+    }
+
+    #[test]
+    fn test_call_and_ret() {
+        // Calls a subroutine that doubles $0, then halts.
+        let program = crate::assembler::Assembler::new()
+            .assemble(
+                ".data\n.code\nload $0 #21\ncall @double\nload $1 #1\nhlt\ndouble: add $0 $0 $0\nret",
+            )
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        let events = test_vm.run();
+        assert_eq!(
+            events.last().unwrap().event,
+            VMEventType::GracefulStop { code: 0 }
+        );
+        assert_eq!(test_vm.registers[0], 42);
+        assert_eq!(test_vm.registers[1], 1);
+        assert_eq!(test_vm.call_depth(), 0);
+    }
+
+    #[test]
+    fn test_callr_and_call_depth() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(prepend_header(vec![35, 0, 0, 0, 36, 0, 0, 0]).into());
+        test_vm.registers[0] = 69;
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 69);
+        assert_eq!(test_vm.call_depth(), 1);
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 69);
+        assert_eq!(test_vm.call_depth(), 0);
+        // Returning with nothing on the call stack crashes the VM.
+        test_vm.pc = 69;
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+    }
+
+    #[test]
+    fn test_syscall_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(prepend_header(vec![37, 4, 0, 0, 37, 5, 0, 0]).into());
+        test_vm.registers[1] = 20;
+        test_vm.registers[4] = 100;
+        test_vm.registers[5] = 101;
+        test_vm
+            .syscalls_mut()
+            .register(100, |call| call.arg(0).unwrap() * 2);
+        assert_eq!(test_vm.execute_instruction(), None);
+        assert_eq!(test_vm.registers[0], 40);
+        // Unregistered syscalls crash the VM.
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+    }
+
+    #[test]
+    fn test_print_syscall() {
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut test_vm = get_test_vm();
+        test_vm.set_output(output.clone());
+        test_vm.heap = b"hi!".to_vec();
+        test_vm.program = Arc::new(prepend_header(vec![37, 4, 0, 0]).into());
+        test_vm.registers[2] = 3;
+        test_vm.registers[4] = crate::syscall::SYS_PRINT as i32;
+        test_vm.run_once();
+        assert_eq!(output.lock().unwrap().buffer, "hi!");
+        assert_eq!(test_vm.registers[0], 3);
+    }
+
+    #[test]
+    fn test_prts_opcode() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(
+                ".data\nhello: .asciiz 'Hello'\nworld: .asciiz ', world!'\n.code\nprts @hello\nprts @world\nload $0 #1\nhlt",
+            )
+            .unwrap();
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut test_vm = get_test_vm();
+        test_vm.set_output(output.clone());
+        test_vm.add_bytes(program);
+        // The read-only section is loaded as soon as the program is.
+        assert_eq!(test_vm.ro_data(), &b"Hello\0, world!\0"[..]);
+        assert_eq!(test_vm.validate(), Ok(()));
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 1);
+        assert_eq!(
+            output.lock().unwrap().buffer,
+            "Hello, world!HLT encountered\n"
+        );
+
+        // Offsets past the read-only section crash the VM.
+        test_vm.program = Arc::new(prepend_header(vec![20, 0, 40, 0]).into());
+        test_vm.pc = 65;
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+    }
+
+    #[test]
+    fn test_add_slice_in_pieces() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\nhi: .asciiz 'Hi'\nn: .byte 7\n.code\nprts @hi\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        for chunk in program.chunks(4) {
+            test_vm.add_slice(chunk);
+        }
+        // The sections are only loaded once the last piece is added.
+        assert!(test_vm.ro_data().is_empty());
+        assert!(test_vm.data().is_empty());
+        test_vm.load_done();
+        assert_eq!(test_vm.ro_data(), &b"Hi\0"[..]);
+        assert_eq!(test_vm.data(), &[7]);
+
+        // Running the program loads them too.
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut test_vm = get_test_vm();
+        test_vm.set_output(output.clone());
+        for chunk in program.chunks(4) {
+            test_vm.add_slice(chunk);
+        }
+        test_vm.run();
+        assert_eq!(test_vm.exit_code(), Some(0));
+        assert_eq!(output.lock().unwrap().buffer, "HiHLT encountered\n");
+        assert_eq!(test_vm.data(), &[7]);
+    }
+
+    #[test]
+    fn test_prtsr_opcode() {
+        // The address of a string is loaded into a register, and printed from there.
+        let program = crate::assembler::Assembler::new()
+            .assemble(
+                ".data
+hello: .asciiz 'Hello'
+world: .asciiz ', world!'
+.code
+load $0 @hello
+prts $0
+load $1 @world
+prtsr $1
+load $2 @world+2
+prts $2
+hlt",
+            )
+            .unwrap();
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut test_vm = get_test_vm();
+        test_vm.set_output(output.clone());
+        test_vm.add_bytes(program);
+        assert_eq!(test_vm.validate(), Ok(()));
+        test_vm.run();
+        assert_eq!(
+            output.lock().unwrap().buffer,
+            "Hello, world!world!HLT encountered\n"
+        );
+
+        // Offsets outside the read-only section crash the VM.
+        test_vm.registers[3] = -1;
+        test_vm.program = Arc::new(prepend_header(vec![74, 3, 0, 0]).into());
+        test_vm.pc = 65;
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+    }
+
+    #[test]
+    fn test_load_store_opcodes() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(
+                ".rodata
+limit: .byte 9, 0, 0, 0
+.data
+count: .byte 1, 0, 0, 0
+name: .byte 72, 105, 0
+.code
+load $0 @count
+ldw $1 $0
+load $2 @limit
+ldw $3 $2
+add $1 $3 $1
+stw $1 $0
+load $4 @name
+prts $4
+inc $4
+load $5 #367
+stb $5 $4
+ldb $6 $4
+prts @name
+hlt",
+            )
+            .unwrap();
+        let output = Arc::new(Mutex::new(BufferOutput::default()));
+        let mut test_vm = get_test_vm();
+        test_vm.set_output(output.clone());
+        test_vm.add_bytes(program);
+        test_vm.run();
+        assert_eq!(test_vm.exit_code(), Some(0));
+        assert_eq!(test_vm.registers[1], 10);
+        assert_eq!(test_vm.registers[6], 111);
+        assert_eq!(test_vm.data(), &[10, 0, 0, 0, b'H', b'o', 0]);
+        assert_eq!(output.lock().unwrap().buffer, "HiHoHLT encountered\n");
+
+        // Stores into the read-only section, and accesses past the writable data, crash the VM.
+        for (code, reason) in [
+            (
+                "load $0 @limit\nstb $0 $0",
+                CrashReason::WriteToReadOnly { address: 0 },
+            ),
+            (
+                "load $0 @limit\nstw $0 $0",
+                CrashReason::WriteToReadOnly { address: 0 },
+            ),
+            ("load $0 @count\nldw $1 $0", CrashReason::MemoryOutOfBounds),
+            ("load $0 @count\nstw $0 $0", CrashReason::MemoryOutOfBounds),
+            ("load $0 #2\nldb $1 $0", CrashReason::MemoryOutOfBounds),
+        ] {
+            let program = crate::assembler::Assembler::new()
+                .assemble(&format!(
+                    ".rodata\nlimit: .byte 9\n.data\ncount: .byte 1\n.code\n{}\nhlt",
+                    code
+                ))
+                .unwrap();
+            let mut test_vm = get_test_vm();
+            test_vm.add_bytes(program);
+            let events = test_vm.run();
+            assert_eq!(
+                events.last().unwrap().event,
+                VMEventType::Crash {
+                    code: 1,
+                    reason,
+                    pc: 64 + 1 + 4,
+                }
+            );
+            assert_eq!(test_vm.data(), &[1]);
+        }
+    }
+
+    #[test]
+    fn test_verify_header() {
+        let mut program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nhlt")
+            .unwrap();
+        assert_eq!(program[PIE_HEADER_VERSION_OFFSET], PIE_VERSION);
+        let mut test_vm = get_test_vm();
+        test_vm.add_slice(&program[..PIE_HEADER_LENGTH - 1]);
+        assert_eq!(test_vm.verify_header(), Err(HeaderError::Truncated));
+
+        // A binary from an assembler that writes a different version is not run.
+        program[PIE_HEADER_VERSION_OFFSET] = PIE_VERSION + 1;
+        let mut test_vm = get_test_vm();
+        test_vm.add_slice(&program);
+        let error = HeaderError::UnsupportedVersion {
+            version: PIE_VERSION + 1,
+        };
+        assert_eq!(test_vm.verify_header(), Err(error));
+        let events = test_vm.run();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].event, VMEventType::InvalidHeader { error });
+        assert_eq!(test_vm.exit_code(), Some(1));
+
+        program[0] = 0;
+        let mut test_vm = get_test_vm();
+        test_vm.add_slice(&program);
+        assert_eq!(test_vm.verify_header(), Err(HeaderError::BadPrefix));
+    }
+
+    #[test]
+    fn test_space_is_reserved_on_the_heap() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\nbuf: .space 10\n.code\nload $0 #4\naloc $0\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        test_vm.run();
+        // 10 bytes are reserved, so the allocation starts after them.
+        assert_eq!(test_vm.registers[0], 10);
+        assert_eq!(test_vm.heap().len(), 14);
+    }
+
+    #[test]
+    fn test_space_past_heap_limit() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\nbuf: .space 10\n.code\nhlt")
+            .unwrap();
+        let mut test_vm = VM::builder().heap_limit(8).build();
+        test_vm.add_bytes(program);
+        let events = test_vm.run();
+        assert!(matches!(
+            events.last().unwrap().event,
+            VMEventType::Crash {
+                reason: CrashReason::HeapLimitExceeded { size: 10 },
+                ..
+            }
+        ));
+        assert!(test_vm.heap().is_empty());
+    }
+
+    #[test]
+    fn test_memory_regions() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(
+                ".rodata\nhi: .asciiz 'hi'\n.data\nn: .byte 7\n.code\nload $0 #4\nload $1 @n\nstb $0 $1\naloc $0\nhlt",
+            )
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        test_vm.run();
+        assert_eq!(
+            test_vm.memory_regions(),
+            vec![
+                (MemoryRegion::Header, 0..64),
+                (MemoryRegion::ReadOnly, 64..67),
+                (MemoryRegion::Code, 67..87),
+                (MemoryRegion::Data, 87..88),
+                (MemoryRegion::Heap, 88..92),
+            ]
+        );
+        assert_eq!(test_vm.memory(64..67), Some(&b"hi\0"[..]));
+        // The data region shows what the program stored there.
+        assert_eq!(test_vm.memory(87..88), Some(&[4][..]));
+        assert_eq!(test_vm.memory(88..90), Some(&[0, 0][..]));
+        assert_eq!(test_vm.memory(87..89), None);
+        assert_eq!(test_vm.memory(91..93), None);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\nhlt")
+            .unwrap();
+        // A corrupted byte and a truncated body are both caught.
+        let mut corrupted = program.clone();
+        corrupted[PIE_HEADER_LENGTH + 3] = 2;
+        for bytes in [&corrupted[..], &program[..program.len() - 4]].iter() {
+            let mut test_vm = get_test_vm();
+            test_vm.add_slice(bytes);
+            match test_vm.run().last().unwrap().event {
+                VMEventType::InvalidHeader {
+                    error: HeaderError::ChecksumMismatch { expected, found },
+                } => assert_ne!(expected, found),
+                ref other => panic!("Expected a checksum mismatch, got {:?}", other),
+            }
+            assert_eq!(test_vm.registers[0], 0);
+        }
+    }
+
+    #[test]
+    fn test_ro_section_past_end() {
+        let mut test_vm = get_test_vm();
+        let mut program = prepend_header(vec![0, 0, 0, 0]);
+        program[PIE_HEADER_RO_LENGTH_OFFSET] = 200;
+        test_vm.program = Arc::new(program.into());
+        assert_eq!(
+            test_vm.validate(),
+            Err(ValidationError::BadHeader {
+                error: HeaderError::ReadOnlySectionPastEnd
+            })
+        );
+        let events = test_vm.run();
+        assert_eq!(events[0].event, VMEventType::Start);
+        assert_eq!(
+            events[1].event,
+            VMEventType::Crash {
+                code: 1,
+                reason: CrashReason::BadReadOnlySection,
+                pc: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_lui_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(prepend_header(vec![38, 0, 0x12, 0x34]).into());
+        test_vm.registers[0] = 0x7777_5678;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 0x1234_5678);
+    }
+
+    #[test]
+    fn test_load_negative_constant() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #-5\nload $1 #-100000\nload $2 #40000\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], -5);
+        assert_eq!(test_vm.registers[1], -100_000);
+        assert_eq!(test_vm.registers[2], 40_000);
+    }
+
+    #[test]
+    fn test_load_32_bit_constant() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #305419896\nload $1 #70000\nload $2 #5\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 305_419_896);
+        assert_eq!(test_vm.registers[1], 70_000);
+        assert_eq!(test_vm.registers[2], 5);
+    }
+
+    #[test]
+    fn test_arithmetic_flags() {
+        let mut test_vm = get_test_vm();
+        test_vm.program =
+            Arc::new(prepend_header(vec![2, 0, 1, 2, 2, 0, 3, 2, 3, 4, 3, 2, 4, 0, 0, 2]).into());
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 1;
+        test_vm.registers[3] = -1;
+        test_vm.registers[4] = 0;
+        // i32::MAX + 1 overflows, but does not carry as unsigned arithmetic.
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert!(test_vm.overflow_flag() && !test_vm.carry_flag());
+        // i32::MAX + -1 carries, but does not overflow.
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], i32::MAX - 1);
+        assert!(!test_vm.overflow_flag() && test_vm.carry_flag());
+        // 0 - -1 borrows.
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], 1);
+        assert!(!test_vm.overflow_flag() && test_vm.carry_flag());
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], 1);
+        assert!(test_vm.overflow_flag());
+    }
+
+    #[test]
+    fn test_jo_and_jno_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(prepend_header(vec![39, 0, 0, 0, 40, 0, 0, 0]).into());
+        test_vm.registers[0] = 69;
+        test_vm.flags = Flags::from_result(0, false, true);
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 69);
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 73);
+    }
+
+    #[test]
+    fn test_address_jumps() {
+        // Counts $0 up to 10 without loading the loop's address into a register.
+        let program = crate::assembler::Assembler::new()
+            .assemble(
+                ".data\n.code\nload $1 #10\nload $2 #1\nloop: add $0 $2 $0\nneq $0 $1\njeql @loop\njmpl @end\nload $0 #99\nend: hlt",
+            )
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 10);
+
+        test_vm.program = Arc::new(prepend_header(vec![45, 0, 0, 69, 0, 0, 0, 0]).into());
+        test_vm.pc = 65;
+        test_vm.flags = Flags::default();
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 69);
+    }
+
+    #[test]
+    fn test_djnz_opcode() {
+        // Adds 3 to $1 five times.
+        let program = crate::assembler::Assembler::new()
+            .assemble(
+                ".data\n.code\nload $0 #5\nload $2 #3\nloop: add $1 $2 $1\ndjnz $0 @loop\nhlt",
+            )
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 0);
+        assert_eq!(test_vm.registers[1], 15);
+    }
+
+    #[test]
+    fn test_register_comparison_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(
+            prepend_header(vec![
+                47, 0, 1, 2, 48, 0, 1, 3, 49, 0, 1, 4, 50, 0, 1, 5, 51, 0, 0, 6, 52, 1, 0, 7,
+            ])
+            .into(),
+        );
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 9;
+        test_vm.registers[2] = 42;
+        for _ in 0..6 {
+            test_vm.run_once();
+        }
+        assert_eq!(&test_vm.registers[2..8], &[0, 1, 0, 1, 1, 0]);
+        assert!(!test_vm.flags.condition());
+    }
+
+    #[test]
+    fn test_unsigned_comparison_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(
+            prepend_header(vec![53, 0, 1, 0, 54, 0, 1, 0, 55, 0, 0, 0, 56, 0, 1, 0]).into(),
+        );
+        // -1 is the largest unsigned value.
+        test_vm.registers[0] = -1;
+        test_vm.registers[1] = 1;
+        test_vm.run_once();
+        assert!(test_vm.flags.condition());
+        test_vm.run_once();
+        assert!(!test_vm.flags.condition());
+        test_vm.run_once();
+        assert!(test_vm.flags.condition());
+        test_vm.run_once();
+        assert!(!test_vm.flags.condition());
+    }
+
+    #[test]
+    fn test_atomic_opcodes() {
+        let mut test_vm = get_test_vm();
+        let shared_heap = Arc::new(SharedHeap::new(1));
+        test_vm.set_shared_heap(shared_heap.clone());
+        test_vm.program = Arc::new(
+            prepend_header(vec![57, 0, 1, 2, 58, 0, 3, 1, 58, 0, 3, 1, 57, 4, 1, 2]).into(),
+        );
+        test_vm.registers[0] = 0;
+        test_vm.registers[1] = 5;
+        test_vm.registers[3] = 5;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], 0);
+        assert_eq!(shared_heap.load(0), Some(5));
+        // The word holds 5, so this CAS succeeds.
+        test_vm.run_once();
+        assert!(test_vm.flags.condition());
+        assert_eq!(test_vm.registers[3], 5);
+        // Now it does not: the word is left alone and its value is returned in $3.
+        test_vm.registers[1] = 7;
+        test_vm.registers[3] = 4;
+        test_vm.run_once();
+        assert!(!test_vm.flags.condition());
+        assert_eq!(test_vm.registers[3], 5);
+        assert_eq!(shared_heap.load(0), Some(5));
+        // Index 4 is outside the segment.
+        test_vm.registers[4] = 4;
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+    }
+
+    #[test]
+    fn test_rand_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(prepend_header(vec![59, 0, 0, 0, 59, 1, 0, 0]).into());
+        test_vm.seed_rng(42);
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_ne!(test_vm.registers[0], test_vm.registers[1]);
+
+        // The same seed produces the same numbers.
+        let first = test_vm.registers[0];
+        test_vm.seed_rng(42);
+        test_vm.pc = 65;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], first);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn test_sleep_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(prepend_header(vec![60, 0, 0, 0, 60, 1, 0, 0]).into());
+        test_vm.registers[0] = 20;
+        let start = Instant::now();
+        test_vm.run_once();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // A stopped VM wakes up early.
+        test_vm.registers[1] = 60_000;
+        test_vm.stop_handle().store(true, Ordering::Relaxed);
+        let start = Instant::now();
+        test_vm.run_once();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    #[test]
+    fn test_sleep_crashes_on_wasm() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(prepend_header(vec![60, 0, 0, 0, 60, 1, 0, 0]).into());
+        // Durations that would not wait still do nothing.
+        test_vm.registers[1] = 20;
+        test_vm.run_once();
+        assert!(test_vm.events.is_empty());
+        test_vm.run_once();
+        assert_eq!(
+            test_vm.events.last().unwrap().event,
+            VMEventType::Crash {
+                code: 1,
+                reason: CrashReason::SleepUnsupported,
+                pc: 69
+            }
+        );
+    }
 
-#[derive(Clone)]
-pub struct VM {
-    // Since we know the number of registers at compile time, we use an array instead
-    // of a vector.
-    /// The registers of the VM.
-    pub registers: [i32; 32],
-    /// Program counter that is used to track which byte is executing.
-    pc: usize,
-    /// Bytecode of the program.
-    pub program: Vec<u8>,
-    /// Used for heap memory.
-    heap: Vec<u8>,
-    /// The remainder of a division operation.
-    remainder: u32,
-    /// Contains the result of the last comparison operation.
-    equal_flag: bool,
-    /// Contains the read-only section of data.
-    ro_data: Vec<u8>,
-    /// Is a unique, randomly generated UUID for identifying a VM.
-    id: Uuid,
-    /// Events that have occured in the VM.
-    events: Vec<VMEvent>,
-}
+    #[test]
+    fn test_input_opcodes() {
+        let mut test_vm = get_test_vm();
+        let input = Arc::new(Mutex::new(BufferInput::default()));
+        input.lock().unwrap().lines = vec!["42".into(), "nope".into(), "hello".into()];
+        test_vm.set_input(input);
+        test_vm.heap = vec![0; 4];
+        test_vm.program = Arc::new(
+            prepend_header(vec![
+                61, 0, 0, 0, 61, 0, 0, 0, 62, 1, 2, 3, 62, 1, 2, 3, 62, 1, 4, 3,
+            ])
+            .into(),
+        );
+        test_vm.registers[1] = 1;
+        test_vm.registers[2] = 3;
+        test_vm.registers[4] = 9;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 42);
+        assert!(test_vm.flags.condition());
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 42);
+        assert!(!test_vm.flags.condition());
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[3], 3);
+        assert_eq!(test_vm.heap, b"\0hel");
+        // At the end of the input nothing is read.
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[3], 0);
+        // The buffer must fit in the heap.
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+    }
 
-impl VM {
-    /// Returns a new `VM` instance.
-    pub fn new() -> VM {
-        VM {
-            registers: [0; 32],
-            program: vec![],
-            heap: vec![],
-            pc: 65,
-            remainder: 0,
-            equal_flag: false,
-            ro_data: vec![],
-            id: Uuid::new_v4(),
-            events: vec![],
-        }
+    #[test]
+    fn test_exit_opcode() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #-3\nexit $0")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        assert_eq!(test_vm.exit_code(), None);
+        test_vm.add_bytes(program);
+        test_vm.run();
+        assert_eq!(test_vm.exit_code(), Some(-3));
     }
 
-    pub fn run(&mut self) -> Vec<VMEvent> {
-        if !self.verify_header() {
-            self.events.push(VMEvent {
-                event: VMEventType::Crash { code: 1 },
-                at: Utc::now(),
-                application_id: self.id.clone(),
-            });
-            println!("Header was incorrect");
-            return self.events.clone();
-        }
-        // If the header is valid, we need to change the PC to be at bit 65.
-        self.pc = 64;
-        let mut is_done = None;
-        while is_done.is_none() {
-            is_done = self.execute_instruction();
-        }
-        self.events.push(VMEvent {
-            event: VMEventType::GracefulStop {
-                code: is_done.unwrap(),
-            },
-            at: Utc::now(),
-            application_id: self.id.clone(),
-        });
-        self.events.clone()
+    #[test]
+    fn test_trap_opcodes() {
+        // Registers a handler for vector 2 and raises it; the handler sets $1 and returns.
+        let program = crate::assembler::Assembler::new()
+            .assemble(
+                ".data\n.code\nload $0 #2\nstrap $0 @handler\ntrap $0\nexit $1\nhandler: load $1 #9\nret",
+            )
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        test_vm.run();
+        assert_eq!(test_vm.exit_code(), Some(9));
+        assert!(test_vm.trap_handler(2).is_some());
+        assert!(!test_vm.set_trap_handler(TRAP_VECTORS, None));
     }
 
-    /// Executes one instruction. Meant to allow for more controlled execution of the VM.
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    #[test]
+    fn test_divide_by_zero_trap() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(prepend_header(vec![5, 0, 1, 2, 30, 0, 1, 2]).into());
+        test_vm.registers[0] = 7;
+        // Without a handler the VM crashes.
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+
+        // With one, it calls the handler and returns past the faulting instruction.
+        test_vm.set_trap_handler(TRAP_DIVIDE_BY_ZERO, Some(69));
+        test_vm.pc = 65;
+        assert_eq!(test_vm.execute_instruction(), None);
+        assert_eq!(test_vm.pc, 69);
+        assert_eq!(test_vm.call_stack, vec![69]);
+        assert_eq!(test_vm.execute_instruction(), None);
+        assert_eq!(test_vm.pc, 69);
+        assert_eq!(test_vm.call_stack, vec![69, 73]);
     }
 
-    fn execute_instruction(&mut self) -> Option<u32> {
-        // If our program counter has exceeded the length of the program itself,
-        // something has gone awry.
-        if self.pc >= self.program.len() {
-            return Some(1);
-        }
-        match self.decode_opcode() {
-            Opcode::HLT => {
-                println!("HLT encountered");
-                return Some(0);
-            }
-            Opcode::LOAD => {
-                // We cast to usize so we can use it as an index into the array.
-                let register = self.next_8_bits() as usize;
-                let number = self.next_16_bits() as u16;
-                // Our registers are i32s, so we need to cast it.
-                self.registers[register] = number as i32;
-            }
-            Opcode::ADD => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
-            }
-            Opcode::SUB => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
-            }
-            Opcode::MUL => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
-            }
-            Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.remainder = (register1 % register2) as u32;
-            }
-            Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
-            }
-            Opcode::JMPF => {
-                let value = self.registers[self.next_8_bits() as usize];
-                self.pc += value as usize;
-            }
-            Opcode::JMPB => {
-                let value = self.registers[self.next_8_bits() as usize];
-                self.pc -= value as usize;
-            }
-            Opcode::EQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = register1 == register2;
-                self.next_8_bits();
-            }
-            Opcode::NEQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = register1 != register2;
-                self.next_8_bits();
-            }
-            Opcode::GT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = register1 > register2;
-                self.next_8_bits();
-            }
-            Opcode::LT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = register1 < register2;
-                self.next_8_bits();
-            }
-            Opcode::GTQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = register1 >= register2;
-                self.next_8_bits();
-            }
-            Opcode::LTQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = register1 <= register2;
-                self.next_8_bits();
-            }
-            Opcode::JEQ => {
-                let target = self.registers[self.next_8_bits() as usize];
-                if self.equal_flag {
-                    self.pc = target as usize;
-                }
-            }
-            Opcode::JNEQ => {
-                let target = self.registers[self.next_8_bits() as usize];
-                if !self.equal_flag {
-                    self.pc = target as usize;
-                }
-            }
-            Opcode::ALOC => {
-                let register = self.next_8_bits() as usize;
-                let bytes = self.registers[register];
-                let new_end = self.heap.len() as i32 + bytes;
-                self.heap.resize(new_end as usize, 0);
-            }
-            Opcode::INC => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] += 1;
-            }
-            Opcode::DEC => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] -= 1;
+    #[test]
+    fn test_instruction_counter() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #3\nagain: djnz $0 @again\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        let counter = test_vm.instruction_counter();
+        test_vm.run();
+        assert_eq!(counter.load(Ordering::Relaxed), 5);
+        // Each run counts from the start again.
+        test_vm.run();
+        assert_eq!(counter.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn test_instruction_budget() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 @loop\nloop: jmp $0")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        let events = test_vm.run_with_budget(100);
+        assert_eq!(events.last().unwrap().event, VMEventType::BudgetExceeded);
+        assert_eq!(test_vm.instruction_budget(), None);
+
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        test_vm.set_instruction_budget(Some(2));
+        assert_eq!(
+            test_vm.run().last().unwrap().event,
+            VMEventType::GracefulStop { code: 0 }
+        );
+    }
+
+    #[test]
+    fn test_crash_events() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #7\nret")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        let events = test_vm.run();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, VMEventType::Start);
+        assert_eq!(
+            events[1].event,
+            VMEventType::Crash {
+                code: 1,
+                reason: CrashReason::ReturnOutsideSubroutine,
+                pc: 68
             }
-            _ => {
-                println!("Unrecognized opcode found! Terminating");
-                return Some(1);
+        );
+
+        // A program that exits with code 1 stops gracefully.
+        let mut test_vm = get_test_vm();
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\nexit $0")
+            .unwrap();
+        test_vm.add_bytes(program);
+        assert_eq!(
+            test_vm.run().last().unwrap().event,
+            VMEventType::GracefulStop { code: 1 }
+        );
+    }
+
+    #[test]
+    fn test_run_once_records_events() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(prepend_header(vec![0, 0, 0, 0]).into());
+        test_vm.run_once();
+        assert_eq!(
+            test_vm.events.last().unwrap().event,
+            VMEventType::GracefulStop { code: 0 }
+        );
+    }
+
+    #[test]
+    fn test_invalid_register_crashes() {
+        let mut program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\nhlt")
+            .unwrap();
+        // Turn `hlt` into `add $0 $40 $0`, which the assembler would reject.
+        let last = program.len() - INSTRUCTION_LENGTH;
+        program[last..].copy_from_slice(&[2, 0, 40, 0]);
+        crate::assembler::write_checksum(&mut program);
+        for predecode in [false, true].iter() {
+            let mut test_vm = get_test_vm();
+            test_vm.add_slice(&program);
+            if *predecode {
+                test_vm.fuse_instructions();
             }
+            assert_eq!(
+                test_vm.run().last().unwrap().event,
+                VMEventType::Crash {
+                    code: 1,
+                    reason: CrashReason::InvalidRegister { register: 40 },
+                    pc: last
+                }
+            );
+            assert_eq!(test_vm.registers[0], 1);
         }
-        Some(0)
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
-        opcode
+    #[test]
+    fn test_hooks() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\nload $1 #1\neq $0 $1\nhlt")
+            .unwrap();
+        let trace = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut test_vm = get_test_vm();
+        test_vm.add_slice(&program);
+        // Hooks see both instructions of a fused pair.
+        test_vm.fuse_instructions();
+        let before = trace.clone();
+        test_vm.hooks_mut().before(move |vm, pc, instruction| {
+            before
+                .lock()
+                .unwrap()
+                .push((pc, instruction.opcode, vm.registers[0]))
+        });
+        let after = trace.clone();
+        test_vm.hooks_mut().after(move |vm, pc, _| {
+            after
+                .lock()
+                .unwrap()
+                .push((pc, Opcode::IGL, vm.registers[0]))
+        });
+        test_vm.run();
+        let trace = trace.lock().unwrap();
+        assert_eq!(trace.len(), 8);
+        assert_eq!(trace[0], (64, Opcode::LOAD, 0));
+        assert_eq!(trace[1], (64, Opcode::IGL, 1));
+        assert_eq!(trace[4], (72, Opcode::EQ, 1));
+        assert_eq!(trace[6], (76, Opcode::HLT, 1));
     }
 
-    /// Reads the next 8 bits of the program.
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
-        self.pc += 1;
-        result
+    #[test]
+    fn test_event_hooks() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nhlt")
+            .unwrap();
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut test_vm = get_test_vm();
+        test_vm.add_slice(&program);
+        let events = seen.clone();
+        test_vm
+            .hooks_mut()
+            .on_event(move |event| events.lock().unwrap().push(event.clone()));
+        // Event hooks do not take instructions off the fast path.
+        assert!(test_vm.hooks().is_empty());
+        let events = test_vm.run();
+        assert_eq!(*seen.lock().unwrap(), events);
     }
 
-    /// Reads the next 16 bits of the program.
-    fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8) | (self.program[self.pc + 1] as u16);
-        self.pc += 2;
-        result
+    #[test]
+    fn test_breakpoints() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\nload $1 #2\nload $2 #3\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_slice(&program);
+        assert!(test_vm.set_breakpoint(68));
+        assert!(test_vm.set_breakpoint(72));
+        assert!(!test_vm.set_breakpoint(72));
+        assert_eq!(test_vm.breakpoints().collect::<Vec<_>>(), vec![68, 72]);
+
+        assert_eq!(
+            test_vm.run().last().unwrap().event,
+            VMEventType::Paused { pc: 68 }
+        );
+        assert!(test_vm.is_paused());
+        assert_eq!(test_vm.registers[..3], [1, 0, 0]);
+        assert_eq!(
+            test_vm.resume().last().unwrap().event,
+            VMEventType::Paused { pc: 72 }
+        );
+        assert_eq!(test_vm.registers[..3], [1, 2, 0]);
+        assert!(test_vm.clear_breakpoint(68));
+        assert!(!test_vm.clear_breakpoint(68));
+        assert_eq!(
+            test_vm.resume().last().unwrap().event,
+            VMEventType::GracefulStop { code: 0 }
+        );
+        assert!(!test_vm.is_paused());
+        assert_eq!(test_vm.registers[..3], [1, 2, 3]);
+
+        // Running again starts over, and pauses at the breakpoint that is left.
+        assert_eq!(
+            test_vm.run().last().unwrap().event,
+            VMEventType::Paused { pc: 72 }
+        );
     }
 
-    /// Adds a byte to the program.
-    pub fn add_byte(&mut self, byte: u8) {
-        self.program.push(byte);
+    #[test]
+    fn test_snapshot_and_restore() {
+        // Counts $0 up to 10, drawing a random number each time round.
+        let program = crate::assembler::Assembler::new()
+            .assemble(
+                ".data\n.code\nload $1 #10\nload $2 #1\nload $3 #76\nadd $0 $2 $0\nrand $4\nneq $0 $1\njeq $3\nhlt",
+            )
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_slice(&program);
+        test_vm.set_breakpoint(80);
+        test_vm.run();
+        let snapshot = test_vm.snapshot();
+        assert_eq!(snapshot.pc(), 80);
+        assert_eq!(snapshot.registers()[0], 1);
+
+        test_vm.clear_breakpoint(80);
+        test_vm.resume();
+        let finished = test_vm.registers.clone();
+        assert_eq!(finished[0], 10);
+
+        // Resuming from the snapshot, even after a round trip through JSON, ends the same way.
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let snapshot: VmSnapshot = serde_json::from_str(&json).unwrap();
+        test_vm.restore(&snapshot);
+        assert_eq!(test_vm.registers[0], 1);
+        assert!(test_vm.is_paused());
+        assert_eq!(
+            test_vm.resume().last().unwrap().event,
+            VMEventType::GracefulStop { code: 0 }
+        );
+        assert_eq!(test_vm.registers, finished);
     }
 
-    /// Adds multiple bytes to the program.
-    pub fn add_bytes(&mut self, bytes: Vec<u8>) {
-        for byte in bytes {
-            self.add_byte(byte);
-        }
+    #[test]
+    fn test_reset() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #8\naloc $0\nload $1 #4\npush $1\nhlt")
+            .unwrap();
+        let mut test_vm = VM::builder().heap_size(4).build();
+        test_vm.add_slice(&program);
+        test_vm.run();
+        assert_eq!(test_vm.heap().len(), 12);
+        assert_eq!(test_vm.sp(), 1);
+
+        test_vm.reset();
+        assert_eq!(test_vm.registers, vec![0; REGISTER_COUNT]);
+        assert_eq!(test_vm.heap(), &[0; 4][..]);
+        assert_eq!(test_vm.sp(), 0);
+        assert_eq!(test_vm.pc, INITIAL_PC);
+        assert_eq!(test_vm.program.len(), program.len());
+        assert_eq!(test_vm.events.len(), 2);
+        // The program runs the same way again.
+        assert_eq!(
+            test_vm.run().last().unwrap().event,
+            VMEventType::GracefulStop { code: 0 }
+        );
+        assert_eq!(test_vm.heap().len(), 12);
+
+        test_vm.set_breakpoint(68);
+        test_vm.reset_full();
+        assert!(test_vm.program.is_empty());
+        assert!(test_vm.events.is_empty());
+        assert_eq!(test_vm.breakpoints().count(), 0);
+        assert_eq!(test_vm.heap(), &[0; 4][..]);
     }
 
-    /// Processes the header of bytecode the VM wants to execute.
-    fn verify_header(&self) -> bool {
-        if self.program[0..4] != PIE_HEADER_PREFIX {
-            return false;
-        }
-        true
+    #[test]
+    fn test_reset_after_kill() {
+        // A killed VM runs again once it is reset, and its writable data is loaded from the
+        // program again.
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\nn: .byte 1\n.code\nload $0 @n\nload $1 #9\nstb $1 $0\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_slice(&program);
+        test_vm.run();
+        assert_eq!(test_vm.data(), &[9]);
+        test_vm.reset();
+        assert_eq!(test_vm.instruction_counter().load(Ordering::Relaxed), 0);
+        assert_eq!(test_vm.data(), &[1]);
+
+        test_vm.stop_handle().store(true, Ordering::Relaxed);
+        assert_eq!(test_vm.run().last().unwrap().event, VMEventType::Killed);
+        test_vm.reset();
+        assert!(!test_vm.stop_handle().load(Ordering::Relaxed));
+        assert_eq!(
+            test_vm.run().last().unwrap().event,
+            VMEventType::GracefulStop { code: 0 }
+        );
+        assert_eq!(test_vm.data(), &[9]);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_entry_point_from_header() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\n.entry main\ndouble: add $0 $0 $0\nhlt\nmain: load $0 #4\nload $1 #64\njmp $1")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_slice(&program);
+        test_vm.run();
+        // `main` ran first, then jumped back to the helper before it.
+        assert_eq!(test_vm.registers[0], 8);
 
-    fn get_test_vm() -> VM {
-        VM::new()
+        // The builder's entry point takes precedence.
+        let mut test_vm = VM::builder().entry_point(64).build();
+        test_vm.add_slice(&program);
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 0);
     }
 
-    fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
-        let mut prepension = vec![];
-        for byte in PIE_HEADER_PREFIX.into_iter() {
-            prepension.push(byte.clone());
-        }
-        while prepension.len() <= PIE_HEADER_LENGTH {
-            prepension.push(0);
+    #[test]
+    fn test_builder() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $1 #1\nload $2 #2\nload $3 #3\nhlt")
+            .unwrap();
+        let mut test_vm = VM::builder()
+            .registers(4)
+            .heap_size(16)
+            .entry_point(PIE_HEADER_LENGTH + INSTRUCTION_LENGTH)
+            .build();
+        assert_eq!(test_vm.registers.len(), 4);
+        assert_eq!(test_vm.heap().len(), 16);
+        test_vm.add_slice(&program);
+        test_vm.run();
+        // The first instruction was skipped.
+        assert_eq!(test_vm.registers, vec![0, 0, 2, 3]);
+
+        // With three registers, `$3` is out of range.
+        let mut test_vm = VM::builder().registers(3).build();
+        test_vm.add_slice(&program);
+        assert_eq!(
+            test_vm.validate(),
+            Err(ValidationError::InvalidRegister {
+                offset: PIE_HEADER_LENGTH + 2 * INSTRUCTION_LENGTH,
+                register: 3
+            })
+        );
+        assert_eq!(
+            test_vm.run().last().unwrap().event,
+            VMEventType::Crash {
+                code: 1,
+                reason: CrashReason::InvalidRegister { register: 3 },
+                pc: PIE_HEADER_LENGTH + 2 * INSTRUCTION_LENGTH
+            }
+        );
+    }
+
+    #[test]
+    fn test_builder_bad_entry_point() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nhlt")
+            .unwrap();
+        for entry_point in [1, PIE_HEADER_LENGTH + 2, PIE_HEADER_LENGTH + 4].iter() {
+            let mut test_vm = VM::builder().entry_point(*entry_point).build();
+            test_vm.add_slice(&program);
+            assert_eq!(
+                test_vm.run().last().unwrap().event,
+                VMEventType::Crash {
+                    code: 1,
+                    reason: CrashReason::PcOutOfBounds,
+                    pc: *entry_point
+                }
+            );
         }
-        prepension.append(&mut b);
-        prepension
     }
 
     #[test]
-    fn test_create_vm() {
-        let test_vm = get_test_vm();
-        assert_eq!(test_vm.registers[0], 0);
+    #[should_panic]
+    fn test_builder_too_many_registers() {
+        VM::builder().registers(MAX_REGISTER_COUNT + 1);
     }
 
     #[test]
-    fn test_opcode_hlt() {
-        let mut test_vm = get_test_vm();
-        test_vm.program = vec![0, 0, 0, 0];
-        test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, 66);
+    fn test_divide_by_zero_crash() {
+        for op in ["div", "mod"].iter() {
+            let program = crate::assembler::Assembler::new()
+                .assemble(&format!(".data\n.code\nload $0 #7\n{} $0 $1 $2\nhlt", op))
+                .unwrap();
+            let mut test_vm = get_test_vm();
+            test_vm.add_bytes(program);
+            assert_eq!(
+                test_vm.run().last().unwrap().event,
+                VMEventType::Crash {
+                    code: 1,
+                    reason: CrashReason::DivideByZero,
+                    pc: 68
+                }
+            );
+        }
     }
 
     #[test]
-    fn test_opcode_igl() {
-        let mut test_vm = get_test_vm();
-        test_vm.program = vec![200, 0, 0, 0];
-        test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, 66);
+    fn test_cmp_and_condition_jumps() {
+        // Computes the sign of $0 into $1: -1, 0 or 1.
+        let source = ".data\n.code\ncmp $0 $2\nload $3 @negative\njlt $3\nload $3 @zero\njz $3\nload $1 #1\nhlt\nnegative: load $1 #-1\nhlt\nzero: load $1 #0\nhlt";
+        let program = crate::assembler::Assembler::new().assemble(source).unwrap();
+        for (value, sign) in [(-7, -1), (0, 0), (i32::MAX, 1), (i32::MIN, -1)].iter() {
+            let mut test_vm = get_test_vm();
+            test_vm.add_slice(&program);
+            test_vm.registers[0] = *value;
+            test_vm.registers[1] = 99;
+            test_vm.run();
+            assert_eq!(test_vm.registers[1], *sign);
+        }
     }
 
     #[test]
-    fn test_load_opcode() {
+    fn test_comparisons_set_flags() {
+        // `EQ` sets the flags like `CMP`, so `JZ` jumps when the registers are equal.
+        let source = ".data\n.code\nload $3 @equal\neq $0 $2\njz $3\nload $1 #1\nhlt\nequal: load $1 #2\nhlt";
+        let program = crate::assembler::Assembler::new().assemble(source).unwrap();
+        for (value, result) in [(0, 2), (5, 1)].iter() {
+            let mut test_vm = get_test_vm();
+            test_vm.add_slice(&program);
+            test_vm.registers[0] = *value;
+            test_vm.run();
+            assert_eq!(test_vm.registers[1], *result);
+        }
+        // `GT` sets the condition flag for `JEQ`, which arithmetic leaves alone.
         let mut test_vm = get_test_vm();
-        // Remember, this is how we represent 500 using two u8s in little endian format.
-        test_vm.program = vec![1, 0, 1, 244];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(prepend_header(vec![11, 0, 1, 0, 2, 0, 2, 4]).into());
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 3;
         test_vm.run_once();
-        assert_eq!(test_vm.registers[0], 500);
+        assert!(test_vm.flags().condition() && test_vm.flags().greater());
+        test_vm.run_once();
+        assert!(test_vm.flags().condition());
+        assert_eq!(test_vm.registers[4], 5);
     }
 
     #[test]
-    fn test_add_opcode() {
+    fn test_arithmetic_sets_zero_and_negative() {
         let mut test_vm = get_test_vm();
-        test_vm.program = vec![2, 8, 5, 2];
-        test_vm.program = prepend_header(test_vm.program);
-        test_vm.registers[5] = 3;
-        test_vm.registers[8] = 7;
+        test_vm.program = Arc::new(prepend_header(vec![3, 0, 0, 1, 3, 1, 0, 1]).into());
+        test_vm.registers[0] = 5;
         test_vm.run_once();
-        assert_eq!(test_vm.registers[2], 10);
+        assert!(test_vm.flags().zero());
+        test_vm.run_once();
+        assert!(test_vm.flags().negative() && !test_vm.flags().zero());
     }
 
     #[test]
-    fn test_sub_opcode() {
+    fn test_div_opcode() {
         let mut test_vm = get_test_vm();
-        test_vm.program = vec![3, 8, 5, 2];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(vec![5, 8, 5, 2].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.registers[5] = 3;
         test_vm.registers[8] = 7;
         test_vm.run_once();
-        assert_eq!(test_vm.registers[2], 4);
+        assert_eq!(test_vm.registers[2], 2);
+        assert_eq!(test_vm.remainder, 1);
     }
 
     #[test]
-    fn test_mul_opcode() {
+    fn test_mod_opcode() {
         let mut test_vm = get_test_vm();
-        test_vm.program = vec![4, 8, 5, 2];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(prepend_header(vec![30, 8, 5, 2]).into());
         test_vm.registers[5] = 3;
         test_vm.registers[8] = 7;
         test_vm.run_once();
-        assert_eq!(test_vm.registers[2], 21);
+        assert_eq!(test_vm.registers[2], 1);
     }
 
     #[test]
-    fn test_div_opcode() {
+    fn test_getr_opcode() {
         let mut test_vm = get_test_vm();
-        test_vm.program = vec![5, 8, 5, 2];
-        test_vm.program = prepend_header(test_vm.program);
-        test_vm.registers[5] = 3;
-        test_vm.registers[8] = 7;
+        test_vm.program = Arc::new(prepend_header(vec![5, 8, 5, 2, 31, 3, 0, 0]).into());
+        test_vm.registers[5] = 4;
+        test_vm.registers[8] = 11;
         test_vm.run_once();
-        assert_eq!(test_vm.registers[2], 2);
-        assert_eq!(test_vm.remainder, 1);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[3], 3);
     }
 
     #[test]
     fn test_jmp_opcode() {
         let mut test_vm = get_test_vm();
-        test_vm.program = vec![6, 0, 0, 0];
-        test_vm.program = prepend_header(test_vm.program);
-        test_vm.registers[0] = 1;
+        test_vm.program = Arc::new(vec![6, 0, 0, 0, 6, 1, 0, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
+        test_vm.registers[0] = 69;
         test_vm.run_once();
-        assert_eq!(test_vm.pc, 1);
+        assert_eq!(test_vm.pc, 69);
+    }
+
+    #[test]
+    fn test_invalid_jumps() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(prepend_header(vec![6, 0, 0, 0, 8, 1, 0, 0]).into());
+        // Into the header, mid-instruction, and past the end of the program.
+        for target in [1, 67, 73, -1].iter() {
+            test_vm.pc = 65;
+            test_vm.registers[0] = *target;
+            assert_eq!(test_vm.execute_instruction(), Some(1));
+            assert_eq!(
+                test_vm.crash,
+                Some((
+                    CrashReason::InvalidJump {
+                        target: *target as usize
+                    },
+                    65
+                ))
+            );
+        }
+        // JMPB cannot jump back past address 0.
+        test_vm.pc = 69;
+        test_vm.registers[1] = 100;
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+        assert_eq!(test_vm.pc, 73);
     }
 
     #[test]
     fn test_jmpf_opcode() {
         let mut test_vm = get_test_vm();
-        test_vm.program = vec![7, 0, 0, 0, 3, 0, 0, 2];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(vec![7, 0, 0, 0, 3, 0, 0, 2].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.registers[0] = 2;
         test_vm.run_once();
         assert_eq!(test_vm.pc, 69);
@@ -376,8 +3832,8 @@ mod tests {
     #[test]
     fn test_jmpb_opcode() {
         let mut test_vm = get_test_vm();
-        test_vm.program = vec![8, 0, 0, 0, 3, 0, 0, 2];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(vec![8, 0, 0, 0, 3, 0, 0, 2].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.registers[0] = 2;
         test_vm.run_once();
         assert_eq!(test_vm.pc, 65);
@@ -388,13 +3844,13 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
-        test_vm.program = vec![9, 0, 1, 0, 9, 0, 1, 0];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(vec![9, 0, 1, 0, 9, 0, 1, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.condition());
         test_vm.registers[1] = 20;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.flags.condition());
     }
 
     #[test]
@@ -402,13 +3858,13 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 11;
-        test_vm.program = vec![10, 0, 1, 0, 10, 0, 1, 0];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(vec![10, 0, 1, 0, 10, 0, 1, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.condition());
         test_vm.registers[1] = 10;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.flags.condition());
     }
 
     #[test]
@@ -416,13 +3872,13 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 11;
         test_vm.registers[1] = 10;
-        test_vm.program = vec![11, 0, 1, 0, 11, 0, 1, 0];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(vec![11, 0, 1, 0, 11, 0, 1, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.condition());
         test_vm.registers[1] = 11;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.flags.condition());
     }
 
     #[test]
@@ -430,13 +3886,13 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 9;
         test_vm.registers[1] = 10;
-        test_vm.program = vec![12, 0, 1, 0, 12, 0, 1, 0];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(vec![12, 0, 1, 0, 12, 0, 1, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.condition());
         test_vm.registers[1] = 9;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.flags.condition());
     }
 
     #[test]
@@ -444,16 +3900,16 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 11;
         test_vm.registers[1] = 10;
-        test_vm.program = vec![13, 0, 1, 0, 13, 0, 1, 0, 13, 0, 1, 0];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(vec![13, 0, 1, 0, 13, 0, 1, 0, 13, 0, 1, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.condition());
         test_vm.registers[1] = 11;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.condition());
         test_vm.registers[1] = 12;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.flags.condition());
     }
 
     #[test]
@@ -461,56 +3917,96 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 11;
         test_vm.registers[1] = 12;
-        test_vm.program = vec![14, 0, 1, 0, 14, 0, 1, 0, 14, 0, 1, 0];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(vec![14, 0, 1, 0, 14, 0, 1, 0, 14, 0, 1, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.condition());
         test_vm.registers[1] = 11;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.flags.condition());
         test_vm.registers[1] = 10;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.flags.condition());
     }
 
     #[test]
     fn test_jeq_opcode() {
         let mut test_vm = get_test_vm();
-        test_vm.registers[0] = 7;
-        test_vm.equal_flag = true;
-        test_vm.program = vec![15, 0, 0, 0, 16, 0, 0, 0, 16, 0, 0, 0];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.registers[0] = 73;
+        test_vm.flags = Flags::default().with_condition(true);
+        test_vm.program = Arc::new(vec![15, 0, 0, 0, 16, 0, 0, 0, 16, 0, 0, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.run_once();
-        assert_eq!(test_vm.pc, 7);
+        assert_eq!(test_vm.pc, 73);
     }
 
     #[test]
     fn test_jneq_opcode() {
         let mut test_vm = get_test_vm();
-        test_vm.registers[0] = 7;
-        test_vm.equal_flag = false;
-        test_vm.program = vec![16, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.registers[0] = 73;
+        test_vm.flags = Flags::default();
+        test_vm.program = Arc::new(vec![16, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.run_once();
-        assert_eq!(test_vm.pc, 7);
+        assert_eq!(test_vm.pc, 73);
     }
 
     #[test]
     fn test_aloc_opcode() {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 1024;
-        test_vm.program = vec![17, 0, 0, 0];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(vec![17, 0, 0, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.run_once();
         assert_eq!(test_vm.heap.len(), 1024);
+        assert_eq!(test_vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_free_opcode() {
+        // Allocates two blocks, frees the first, allocates into the space it left, and frees that
+        // twice.
+        let program = crate::assembler::Assembler::new()
+            .assemble(
+                ".data\n.code\nload $0 #16\naloc $0\nload $1 #8\naloc $1\nfree $0\nload $2 #12\naloc $2\nfree $2\nfree $2",
+            )
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        let events = test_vm.run();
+        assert_eq!(test_vm.registers[1], 16);
+        assert_eq!(test_vm.registers[2], 0);
+        assert_eq!(test_vm.heap().len(), 24);
+        assert_eq!(
+            events.last().unwrap().event,
+            VMEventType::Crash {
+                code: 1,
+                reason: CrashReason::InvalidFree { pointer: 0 },
+                pc: 96
+            }
+        );
+    }
+
+    #[test]
+    fn test_callhost_opcode() {
+        let mut test_vm = get_test_vm();
+        let id = test_vm.register_host_function("sum", |call| {
+            call.arg(0).unwrap() + call.arg(1).unwrap()
+        });
+        test_vm.registers[1] = 3;
+        test_vm.registers[2] = 4;
+        test_vm.program = Arc::new(vec![21, 0, id as u8, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 7);
     }
 
     #[test]
     fn test_inc_opdcode() {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 1;
-        test_vm.program = vec![18, 0, 0, 0];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(vec![18, 0, 0, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.run_once();
         assert_eq!(test_vm.registers[0], 2);
     }
@@ -519,9 +4015,262 @@ mod tests {
     fn test_dec_opdcode() {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 1;
-        test_vm.program = vec![19, 0, 0, 0];
-        test_vm.program = prepend_header(test_vm.program);
+        test_vm.program = Arc::new(vec![19, 0, 0, 0].into());
+        test_vm.program = Arc::new(prepend_header(test_vm.program.to_vec()).into());
         test_vm.run_once();
         assert_eq!(test_vm.registers[0], 0);
+        assert!(test_vm.flags.zero());
+    }
+
+    #[test]
+    fn test_one_register_instructions_take_four_bytes() {
+        // INC, DEC, ALOC, JEQ and JNEQ are padded to a whole instruction like the others.
+        let mut test_vm = get_test_vm();
+        test_vm.program = Arc::new(
+            prepend_header(vec![
+                18, 0, 0, 0, 19, 1, 0, 0, 17, 2, 0, 0, 15, 3, 0, 0, 16, 3, 0, 0,
+            ])
+            .into(),
+        );
+        test_vm.registers[2] = 4;
+        let start = test_vm.pc;
+        for i in 1..=3 {
+            test_vm.run_once();
+            assert_eq!(test_vm.pc, start + 4 * i);
+        }
+        // Neither jump is taken, as the condition flag is clear for JEQ and set for JNEQ.
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, start + 16);
+        test_vm.flags = Flags::default().with_condition(true);
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, start + 20);
+        assert_eq!(&test_vm.registers[..2], &[1, -1]);
+        assert_eq!(test_vm.heap.len(), 4);
+    }
+
+    #[test]
+    fn test_run_executes_until_hlt() {
+        // `run` keeps going after an instruction that does not stop the VM.
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\ninc $0\ninc $0\nload $1 @end\njneq $1\ninc $0\nend: hlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        let events = test_vm.run();
+        assert_eq!(test_vm.registers[0], 2);
+        assert_eq!(
+            events.last().unwrap().event,
+            VMEventType::GracefulStop { code: 0 }
+        );
+    }
+
+    #[test]
+    fn test_inc_and_dec_overflow() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #-1\nlui $0 #32767\ninc $0\nload $1 #0\ndec $1\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        test_vm.run_with_budget(3);
+        // `i32::MAX + 1` wraps and overflows as a signed value, but does not carry.
+        assert_eq!(test_vm.registers[0], i32::MIN);
+        assert!(test_vm.flags.overflow() && test_vm.flags.negative());
+        assert!(!test_vm.flags.carry());
+        test_vm.resume();
+        // `0 - 1` borrows as an unsigned value, but does not overflow.
+        assert_eq!(test_vm.registers[1], -1);
+        assert!(test_vm.flags.carry() && !test_vm.flags.overflow());
+    }
+
+    #[test]
+    fn test_serialize_vm() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[3] = 42;
+        test_vm.program = Arc::new(prepend_header(vec![0, 0, 0, 0]).into());
+        test_vm.run();
+        let json = serde_json::to_string(&test_vm).unwrap();
+        let restored: VM = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.registers[3], 42);
+        assert_eq!(restored.program, test_vm.program);
+        assert_eq!(restored.id, test_vm.id);
+        assert_eq!(restored.events, test_vm.events);
+    }
+
+    #[test]
+    fn test_predecode() {
+        // Counts $0 up to 10 in a loop.
+        let program = crate::assembler::Assembler::new()
+            .assemble(
+                ".data\n.code\nload $1 #10\nload $2 #1\nload $3 #76\nadd $0 $2 $0\nneq $0 $1\njeq $3\nhlt",
+            )
+            .unwrap();
+        let mut plain = get_test_vm();
+        plain.add_bytes(program.clone());
+        plain.run();
+
+        let mut predecoded = get_test_vm();
+        predecoded.add_bytes(program);
+        predecoded.predecode();
+        assert!(predecoded.decoded.is_some());
+        predecoded.run();
+        assert_eq!(predecoded.registers, plain.registers);
+        assert_eq!(predecoded.registers[0], 10);
+
+        predecoded.add_byte(0);
+        assert!(predecoded.decoded.is_none());
+    }
+
+    #[test]
+    fn test_fuse_instructions() {
+        // Counts $0 up to 10, with a fused comparison and jump closing the loop.
+        let program = crate::assembler::Assembler::new()
+            .assemble(
+                ".data\n.code\nload $1 #10\nload $2 #1\nload $3 #76\nadd $0 $2 $0\nneq $0 $1\njeq $3\nhlt",
+            )
+            .unwrap();
+        let mut fused = get_test_vm();
+        fused.add_bytes(program);
+        fused.fuse_instructions();
+        let handlers = &fused.decoded.as_ref().unwrap().fused;
+        assert!(handlers[0].is_none());
+        assert!(handlers[3].is_none());
+        assert!(handlers[4].is_some());
+        fused.run();
+        assert_eq!(fused.registers[0], 10);
+
+        // The pair runs as one step, but jumping to its second half executes that alone.
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $1 #64\neq $0 $2\njeq $1\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        test_vm.fuse_instructions();
+        test_vm.registers[1] = 64;
+        test_vm.pc = 68;
+        test_vm.execute_fused_instruction();
+        assert_eq!(test_vm.pc, 64);
+        test_vm.pc = 72;
+        test_vm.registers[1] = 76;
+        test_vm.execute_fused_instruction();
+        assert_eq!(test_vm.pc, 76);
+    }
+
+    #[test]
+    fn test_breakpoint_in_fused_pair() {
+        let program = crate::assembler::Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\nadd $0 $0 $1\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(program);
+        test_vm.fuse_instructions();
+        assert!(test_vm.decoded.as_ref().unwrap().fused[0].is_some());
+        test_vm.set_breakpoint(68);
+        test_vm.run();
+        assert!(test_vm.is_paused());
+        assert_eq!(test_vm.pc, 68);
+        assert_eq!(test_vm.registers[1], 0);
+        test_vm.resume();
+        assert!(!test_vm.is_paused());
+        assert_eq!(test_vm.registers[1], 2);
+    }
+
+    #[test]
+    fn test_bulk_loading() {
+        let mut test_vm = get_test_vm();
+        let program = prepend_header(vec![0]);
+        let buffer = program.as_ptr();
+        test_vm.add_bytes(program);
+        // The first load takes the buffer over instead of copying it.
+        assert_eq!(test_vm.program.as_ptr(), buffer);
+        test_vm.add_slice(&[1, 0, 1, 244]);
+        test_vm.add_bytes(vec![0]);
+        assert_eq!(
+            test_vm.program[PIE_HEADER_LENGTH + 1..],
+            [0, 1, 0, 1, 244, 0]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(
+            crate::assembler::Assembler::new()
+                .assemble(".data\n.code\nload $0 #7\nhlt")
+                .unwrap(),
+        );
+        assert_eq!(test_vm.validate(), Ok(()));
+        test_vm.add_slice(&[2, 0, 32, 1]);
+        // Appending to the program breaks its checksum.
+        assert!(matches!(
+            test_vm.validate(),
+            Err(ValidationError::BadHeader {
+                error: HeaderError::ChecksumMismatch { .. }
+            })
+        ));
+        update_checksum(&mut test_vm);
+        assert_eq!(
+            test_vm.validate(),
+            Err(ValidationError::InvalidRegister {
+                offset: PIE_HEADER_LENGTH + 8,
+                register: 32
+            })
+        );
+        test_vm.add_byte(0);
+        update_checksum(&mut test_vm);
+        assert_eq!(
+            test_vm.validate(),
+            Err(ValidationError::TruncatedInstruction {
+                offset: PIE_HEADER_LENGTH + 12
+            })
+        );
+        assert_eq!(
+            get_test_vm().validate(),
+            Err(ValidationError::BadHeader {
+                error: HeaderError::Truncated
+            })
+        );
+    }
+
+    #[cfg(feature = "unchecked")]
+    #[test]
+    fn test_run_unchecked() {
+        let mut test_vm = get_test_vm();
+        test_vm.add_bytes(
+            crate::assembler::Assembler::new()
+                .assemble(".data\n.code\nload $0 #7\nhlt")
+                .unwrap(),
+        );
+        let events = unsafe { test_vm.run_unchecked() };
+        assert_eq!(
+            events.last().unwrap().event,
+            VMEventType::GracefulStop { code: 0 }
+        );
+        assert_eq!(test_vm.registers[0], 7);
+
+        let mut invalid = get_test_vm();
+        invalid.add_bytes(prepend_header(vec![1, 40, 0, 0]));
+        let events = unsafe { invalid.run_unchecked() };
+        assert_eq!(
+            events[0].event,
+            VMEventType::Crash {
+                code: 1,
+                reason: CrashReason::InvalidProgram,
+                pc: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_shared_program() {
+        let program = Arc::new(Program::from(prepend_header(vec![1, 0, 0, 7, 0])));
+        let mut first = VM::with_program(program.clone());
+        let second = VM::with_program(first.shared_program());
+        assert!(Arc::ptr_eq(&first.program, &second.program));
+        assert_ne!(first.id, second.id);
+
+        // Adding to one VM's program leaves the others untouched.
+        first.add_byte(0);
+        assert!(!Arc::ptr_eq(&first.program, &program));
+        assert_eq!(second.program.len(), program.len());
     }
 }